@@ -10,6 +10,8 @@ pub enum ErrorCode {
     NetworkError(#[from] std::io::Error),
     #[error("delete not exists key: {0}")]
     RmError(String),
+    #[error("invalid compaction config: {0}")]
+    InvalidConfig(String),
 }
 
 pub type Result<T> = std::result::Result<T, KvError>;
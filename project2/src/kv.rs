@@ -25,7 +25,9 @@ impl Command {
     }
 
     fn rm(key: &str) -> Command {
-        Command::Rm { key: key.to_owned() }
+        Command::Rm {
+            key: key.to_owned(),
+        }
     }
 }
 
@@ -34,6 +36,63 @@ pub const COMPACTABLE_THRESHOLD: u64 = 32 * 1024; // 32KB
 pub const COMPACTED_ONCE_BYTES: u64 = 16 * 1024; // 16KB
 pub const FILE_THRESHOLD: u64 = 32 * 1024; // 32KB
 
+/// When a `KvStore` compacts and how large its logs grow, by default the constants above.
+///
+/// A tiny embedded store can compact more often to stay small, while a large one can compact
+/// less often to spend less time rewriting logs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactionConfig {
+    threshold: u64,
+    once_bytes: u64,
+    file_size: u64,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        CompactionConfig {
+            threshold: COMPACTABLE_THRESHOLD,
+            once_bytes: COMPACTED_ONCE_BYTES,
+            file_size: FILE_THRESHOLD,
+        }
+    }
+}
+
+impl CompactionConfig {
+    /// Compact once the uncompacted data of all logs reaches `bytes`
+    pub fn threshold(mut self, bytes: u64) -> Self {
+        self.threshold = bytes;
+        self
+    }
+
+    /// Compact the logs with the most uncompacted data until they add up to `bytes`
+    pub fn once_bytes(mut self, bytes: u64) -> Self {
+        self.once_bytes = bytes;
+        self
+    }
+
+    /// Roll over to a new log once the current one reaches `bytes`
+    pub fn file_size(mut self, bytes: u64) -> Self {
+        self.file_size = bytes;
+        self
+    }
+
+    /// A compaction can't rewrite more uncompacted data than there is when it is triggered,
+    /// and logs must hold at least a byte.
+    fn validate(&self) -> Result<()> {
+        if self.once_bytes > self.threshold {
+            return Err(ErrorCode::InvalidConfig(format!(
+                "once bytes {} exceed the threshold {}",
+                self.once_bytes, self.threshold
+            ))
+            .into());
+        }
+        if self.threshold == 0 || self.once_bytes == 0 || self.file_size == 0 {
+            return Err(ErrorCode::InvalidConfig("sizes must not be 0".to_owned()).into());
+        }
+        Ok(())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct Pointer {
     // data file version
@@ -63,6 +122,8 @@ pub struct KvStore {
     index: HashMap<String, Pointer>,
     // uncompacted data
     stats: Statistics,
+    // when to compact and roll over the logs
+    config: CompactionConfig,
 }
 
 /// 1.How much memory do you need? a fixed memory
@@ -74,7 +135,16 @@ pub struct KvStore {
 /// First replace memory index and second clean old log in one trafic
 impl KvStore {
     pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with_config(path, CompactionConfig::default())
+    }
+
+    /// Open the store at `path` with `config` rather than the default compaction settings.
+    ///
+    /// It fails with `ErrorCode::InvalidConfig` if `config` is not valid.
+    pub fn open_with_config(path: &Path, config: CompactionConfig) -> Result<Self> {
+        config.validate()?;
         std::fs::create_dir_all(path)?;
+        Self::clean_dangling_compaction(path)?;
         let mut seq_list: Vec<u64> = fs::read_dir(path)?
             .flat_map(|res| -> Result<_> { Ok(res?.path()) })
             .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
@@ -95,10 +165,7 @@ impl KvStore {
 
         //println!("load from {:#?}", seq_list);
         for seq in seq_list.iter() {
-            readers.insert(
-                *seq,
-                Self::load(path, *seq, &mut index, &mut stats)?,
-            );
+            readers.insert(*seq, Self::load(path, *seq, &mut index, &mut stats)?);
         }
         let sequence_no = seq_list.pop().map_or(1, |seq| seq + 1);
         //println!("open writer {}", sequence_no);
@@ -123,9 +190,27 @@ impl KvStore {
             writer,
             index,
             stats,
+            config,
         })
     }
 
+    /// Remove `.tmp` files left behind by a compaction that was interrupted before its commit.
+    ///
+    /// Compacted files are renamed from `.tmp` to `.log` first and the compacted logs are only
+    /// deleted after every rename succeeded, so the data of a dangling `.tmp` is always still
+    /// present in the old logs. If the `.log` with the same seq exists the rename has already
+    /// been done and the `.tmp` is a leftover; otherwise it may be incomplete. Either way it is
+    /// safe to delete.
+    fn clean_dangling_compaction(path: &Path) -> Result<()> {
+        for entry in fs::read_dir(path)? {
+            let tmp_path = entry?.path();
+            if tmp_path.is_file() && tmp_path.extension() == Some("tmp".as_ref()) {
+                std::fs::remove_file(&tmp_path)?;
+            }
+        }
+        Ok(())
+    }
+
     /// Reload all data into memory, build memory index
     fn load(
         path: &Path,
@@ -231,26 +316,27 @@ impl KvStore {
     }
 
     pub fn remove(&mut self, key: String) -> Result<()> {
+        // a missing key is not logged, its tombstone would only be a stale record
+        if !self.index.contains_key(&key) {
+            return Err(ErrorCode::RmError(key).into());
+        }
         let rm = Command::rm(&key);
         let pos = self.writer.pos()?;
         serde_json::to_writer(&mut self.writer, &rm)?;
+        self.writer.flush()?;
         let new_pos = self.writer.pos()?;
-        match self.index.remove(&key) {
-            Some(old_record) => {
-                self.stats
-                    .uncompacted
-                    .entry(self.sequence_no)
-                    .and_modify(|x| *x += new_pos - pos)
-                    .or_insert(new_pos - pos);
-                self.stats
-                    .uncompacted
-                    .entry(old_record.seq)
-                    .and_modify(|f| *f += old_record.len)
-                    .or_insert(old_record.len);
-                self.stats.total_uncompacted += old_record.len + new_pos - pos
-            }
-            None => return Err(ErrorCode::RmError(key).into()),
-        }
+        let old_record = self.index.remove(&key).expect("key not found");
+        self.stats
+            .uncompacted
+            .entry(self.sequence_no)
+            .and_modify(|x| *x += new_pos - pos)
+            .or_insert(new_pos - pos);
+        self.stats
+            .uncompacted
+            .entry(old_record.seq)
+            .and_modify(|f| *f += old_record.len)
+            .or_insert(old_record.len);
+        self.stats.total_uncompacted += old_record.len + new_pos - pos;
 
         self.try_trigger_compact()?;
         self.try_trigger_scroll()?;
@@ -258,7 +344,7 @@ impl KvStore {
     }
 
     fn try_trigger_compact(&mut self) -> Result<()> {
-        if self.stats.total_uncompacted >= COMPACTABLE_THRESHOLD {
+        if self.stats.total_uncompacted >= self.config.threshold {
             // sort it by uncompacted bytes
             let mut to_be_compacted_bytes = 0_u64;
             let mut to_be_compacted_seqs = Vec::new();
@@ -270,7 +356,7 @@ impl KvStore {
             for entry in uncompacted_entrys.iter() {
                 to_be_compacted_seqs.push(*entry.0);
                 to_be_compacted_bytes += *entry.1;
-                if to_be_compacted_bytes >= COMPACTED_ONCE_BYTES {
+                if to_be_compacted_bytes >= self.config.once_bytes {
                     break;
                 }
             }
@@ -299,19 +385,17 @@ impl KvStore {
                         if reader.pos()? != pointer.pos {
                             reader.seek(SeekFrom::Start(pointer.pos))?;
                         }
-                        reader.take(pointer.len);
                         let pos = compact_writer.pos()?;
                         new_index.insert(key.clone(), Pointer {
                             seq: compact_seq,
                             pos,
                             len: pointer.len,
                         });
-                        std::io::copy(reader, &mut compact_writer)?;
+                        std::io::copy(&mut reader.take(pointer.len), &mut compact_writer)?;
                         //println!("compact new record {} to {}", pos, pos+pointer.len);
-                        compact_writer.seek(SeekFrom::Start(pos + pointer.len))?;
 
                         // once writer over threshold, scroll it
-                        if compact_writer.pos()? >= FILE_THRESHOLD {
+                        if compact_writer.pos()? >= self.config.file_size {
                             compact_seq += 1;
                             compact_writer = Writer::new(
                                 OpenOptions::new()
@@ -344,14 +428,17 @@ impl KvStore {
     ) -> Result<()> {
         // rename file
         for after_compact_seq in after_compact_seqs {
-            std::fs::rename(
-                self.path.join(after_compact_seq.to_string() + ".tmp"),
-                self.path.join(after_compact_seq.to_string() + ".log"),
-            )?;
+            let log = self.path.join(after_compact_seq.to_string() + ".log");
+            std::fs::rename(self.path.join(after_compact_seq.to_string() + ".tmp"), &log)?;
+            self.readers.insert(
+                after_compact_seq,
+                Reader::new(OpenOptions::new().read(true).open(log)?),
+            );
         }
         // delete file
         for seq in to_be_compacted_seqs.iter() {
             std::fs::remove_file(self.path.join(seq.to_string() + ".log"))?;
+            self.readers.remove(seq);
         }
         // remove stats
         for compacted_seq in to_be_compacted_seqs.iter() {
@@ -367,7 +454,7 @@ impl KvStore {
     }
 
     fn try_trigger_scroll(&mut self) -> Result<()> {
-        if self.writer.pos()? >= FILE_THRESHOLD {
+        if self.writer.pos()? >= self.config.file_size {
             self.scroll(1)?;
         }
         Ok(())
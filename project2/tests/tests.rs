@@ -1,8 +1,9 @@
 use assert_cmd::prelude::*;
-use kvs::error::Result;
-use kvs::kv::KvStore;
+use kvs::error::{ErrorCode, Result};
+use kvs::kv::{CompactionConfig, KvStore, COMPACTABLE_THRESHOLD};
 use predicates::ord::eq;
 use predicates::str::{contains, is_empty, PredicateStrExt};
+use std::fs;
 use std::process::Command;
 use tempfile::TempDir;
 use walkdir::WalkDir;
@@ -245,6 +246,34 @@ fn remove_non_existent_key() -> Result<()> {
     Ok(())
 }
 
+// Removing a missing key should write nothing to the log.
+#[test]
+fn remove_non_existent_key_not_logged() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_size = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum::<u64>()
+    };
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let size = log_size();
+    assert!(store.remove("key2".to_owned()).is_err());
+    assert_eq!(log_size(), size);
+
+    // Open from disk again, no tombstone of the missing key is replayed.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(log_size(), size);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.remove("key2".to_owned()).is_err());
+    assert_eq!(log_size(), size);
+    Ok(())
+}
+
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -300,3 +329,66 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should remove a `.tmp` file left by an interrupted compaction and keep the store consistent.
+#[test]
+fn open_cleans_dangling_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // A half-written compaction file that has never been renamed to `.log`.
+    let tmp_path = temp_dir.path().join("100.tmp");
+    fs::write(
+        &tmp_path,
+        r#"{"Set":{"key":"key1","value":"stale"}}{"Set":{"ke"#,
+    )?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!tmp_path.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should compact as soon as the uncompacted data reaches the configured threshold, whether it
+// is small or large.
+#[test]
+fn compaction_config() -> Result<()> {
+    for threshold in [1024, 256 * 1024] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let config = CompactionConfig::default()
+            .threshold(threshold)
+            .once_bytes(threshold)
+            .file_size(u64::MAX);
+        let mut store = KvStore::open_with_config(temp_dir.path(), config)?;
+        let first_log = temp_dir.path().join("1.log");
+        store.set("key".to_owned(), "value".to_owned())?;
+        let record_len = fs::metadata(&first_log)?.len();
+
+        // every set after the first one leaves a stale record behind
+        let sets = (threshold + record_len - 1) / record_len + 1;
+        for _ in 1..sets - 1 {
+            store.set("key".to_owned(), "value".to_owned())?;
+        }
+        assert!(first_log.exists());
+        store.set("key".to_owned(), "value".to_owned())?;
+        assert!(!first_log.exists());
+        assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let config = CompactionConfig::default().once_bytes(COMPACTABLE_THRESHOLD + 1);
+    let err = KvStore::open_with_config(temp_dir.path(), config)
+        .err()
+        .unwrap();
+    assert!(matches!(*err, ErrorCode::InvalidConfig(_)));
+    Ok(())
+}
@@ -97,17 +97,27 @@ where
     }
 }
 
+/// The max bytes of a serialized request or response, as its length is sent as a `u16`
+pub const MAX_FRAME_LEN: usize = u16::MAX as usize;
+
 pub fn handle_send<T>(stream: &mut TcpStream, value: &T) -> crate::error::Result<()>
 where
     T: serde::ser::Serialize,
 {
     let b_value = serde_json::to_vec(&value)?;
-    if b_value.len() > u16::MAX as usize {
-        return Err(ErrorCode::InternalError("valid len for send".to_string()).into());
+    if b_value.len() > MAX_FRAME_LEN {
+        return Err(ErrorCode::FrameTooLarge {
+            size: b_value.len(),
+            limit: MAX_FRAME_LEN,
+        }
+        .into());
     }
 
-    stream.write_all(&(b_value.len() as u16).to_be_bytes())?;
-    stream.write_all(&b_value)?;
+    // write the length and the payload at once, nothing is written if the frame is invalid
+    let mut frame = Vec::with_capacity(2 + b_value.len());
+    frame.extend_from_slice(&(b_value.len() as u16).to_be_bytes());
+    frame.extend_from_slice(&b_value);
+    stream.write_all(&frame)?;
     Ok(())
 }
 
@@ -122,9 +132,15 @@ where
             warn!("Another side close socket");
             return Ok(None);
         }
+        // the length may arrive in two reads
+        Ok(1) => stream.read_exact(&mut b_len[1..])?,
         _ => (),
     }
 
-    let cmd = serde_json::from_reader(stream.take(u16::from_be_bytes(b_len) as u64))?;
+    // read the whole frame before parsing, so the stream stays at a frame boundary even if
+    // the payload is malformed
+    let mut b_value = vec![0_u8; u16::from_be_bytes(b_len) as usize];
+    stream.read_exact(&mut b_value)?;
+    let cmd = serde_json::from_slice(&b_value)?;
     Ok(cmd)
 }
@@ -16,6 +16,8 @@ pub enum ErrorCode {
     Utf8(#[from] std::string::FromUtf8Error),
     #[error("Rm Key not found")]
     RmKeyNotFound,
+    #[error("Frame of {size} bytes exceeds the limit of {limit} bytes")]
+    FrameTooLarge { size: usize, limit: usize },
 }
 
 pub type Result<T> = std::result::Result<T, KvError>;
@@ -0,0 +1,48 @@
+use kvs::common::{handle_receive, handle_send, MAX_FRAME_LEN};
+use kvs::Result;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+
+fn stream_pair() -> Result<(TcpStream, TcpStream)> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let client = TcpStream::connect(listener.local_addr()?)?;
+    let (server, _) = listener.accept()?;
+    Ok((client, server))
+}
+
+// Frames up to the length limit should round-trip, and a larger one should fail
+// without writing anything to the stream.
+#[test]
+fn frame_length_boundary() -> Result<()> {
+    let (mut client, mut server) = stream_pair()?;
+
+    // a JSON string is serialized with two quotes
+    let max_value = "v".repeat(MAX_FRAME_LEN - 2);
+    handle_send(&mut client, &max_value)?;
+    assert_eq!(
+        handle_receive::<String>(&mut server)?,
+        Some(max_value.clone())
+    );
+
+    let err = handle_send(&mut client, &format!("{}v", max_value)).unwrap_err();
+    assert!(err.to_string().contains("exceeds the limit"));
+
+    handle_send(&mut client, &"v".to_owned())?;
+    assert_eq!(handle_receive::<String>(&mut server)?, Some("v".to_owned()));
+    Ok(())
+}
+
+// An empty frame should be an error, and leave the stream at the next frame.
+#[test]
+fn empty_frame() -> Result<()> {
+    let (mut client, mut server) = stream_pair()?;
+    client.write_all(&[0, 0])?;
+    handle_send(&mut client, &"v".to_owned())?;
+
+    assert!(handle_receive::<String>(&mut server).is_err());
+    assert_eq!(handle_receive::<String>(&mut server)?, Some("v".to_owned()));
+
+    drop(client);
+    assert_eq!(handle_receive::<String>(&mut server)?, None);
+    Ok(())
+}
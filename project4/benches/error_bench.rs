@@ -0,0 +1,31 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use kvs::error::{ErrorCode, KvError};
+use kvs::Result;
+
+fn remove_missing() -> Result<()> {
+    Err(ErrorCode::KeyNotFound)?
+}
+
+fn read_failed() -> Result<()> {
+    Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
+}
+
+/// Cost of creating errors, compare with `--no-default-features` to see the cost of
+/// capturing backtraces. Set `RUST_BACKTRACE=1` as well, otherwise a backtrace is
+/// captured without resolving any frame.
+fn error_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("error_group");
+    group.bench_function("from error code", |b| {
+        b.iter(|| KvError::from(black_box(ErrorCode::KeyNotFound)))
+    });
+    group.bench_function("question mark", |b| {
+        b.iter(|| black_box(remove_missing()).unwrap_err())
+    });
+    group.bench_function("io error", |b| {
+        b.iter(|| black_box(read_failed()).unwrap_err())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, error_group);
+criterion_main!(benches);
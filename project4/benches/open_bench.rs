@@ -0,0 +1,66 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::{KvStore, KvStoreOpts, KvsEngine, LogCodec};
+use tempfile::TempDir;
+
+/// Replay a store of 100k keys with and without the readahead hint.
+///
+/// The hint only takes effect when built with `--features fadvise`.
+fn open_group(c: &mut Criterion) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    (0..100_000).for_each(|i| {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    });
+    drop(store);
+
+    let mut group = c.benchmark_group("open_group");
+    group.sample_size(10);
+    for readahead_hint in [false, true] {
+        group.bench_with_input(
+            BenchmarkId::new("Test open bench", readahead_hint),
+            &readahead_hint,
+            |b, &readahead_hint| {
+                b.iter(|| {
+                    let opts = KvStoreOpts {
+                        readahead_hint,
+                        ..Default::default()
+                    };
+                    KvStore::open_with_opts(temp_dir.path(), opts).unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Replay a log of 100k commands written with each codec.
+fn codec_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("codec_group");
+    group.sample_size(10);
+    for codec in [LogCodec::Json, LogCodec::Binary] {
+        let temp_dir = TempDir::new().unwrap();
+        let opts = KvStoreOpts {
+            codec,
+            ..Default::default()
+        };
+        let store = KvStore::open_with_opts(temp_dir.path(), opts).unwrap();
+        (0..100_000).for_each(|i| {
+            store
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        });
+        drop(store);
+
+        group.bench_with_input(
+            BenchmarkId::new("Test replay bench", format!("{:?}", codec)),
+            &temp_dir,
+            |b, temp_dir| b.iter(|| KvStore::open(temp_dir.path()).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, open_group, codec_group);
+criterion_main!(benches);
@@ -0,0 +1,46 @@
+use std::thread;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use kvs::{KvStoreOpts, KvsEngine, ShardedKvStore};
+use tempfile::TempDir;
+
+const WRITERS: usize = 8;
+const WRITES: usize = 1000;
+
+/// 8 threads writing 1000 keys each at once, into a single shard and into 8 shards.
+fn shard_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("shard_group");
+    group.sample_size(10);
+    for shards in [1, 8] {
+        let temp_dir = TempDir::new().unwrap();
+        let store = ShardedKvStore::open_with_opts(temp_dir.path(), shards, KvStoreOpts::default())
+            .unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("Test contended writes bench", shards),
+            &store,
+            |b, store| {
+                b.iter(|| {
+                    let writers: Vec<_> = (0..WRITERS)
+                        .map(|writer| {
+                            let store = store.clone();
+                            thread::spawn(move || {
+                                for i in 0..WRITES {
+                                    store
+                                        .set(format!("key{}-{}", writer, i), format!("value{}", i))
+                                        .unwrap();
+                                }
+                            })
+                        })
+                        .collect();
+                    writers
+                        .into_iter()
+                        .for_each(|writer| writer.join().unwrap());
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, shard_group);
+criterion_main!(benches);
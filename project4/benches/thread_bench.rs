@@ -5,19 +5,25 @@ use std::net::Ipv4Addr;
 use std::net::SocketAddr;
 use std::net::SocketAddrV4;
 use std::thread::spawn;
+use std::time::Duration;
 
 use criterion::BenchmarkId;
 use criterion::Criterion;
 use criterion::{criterion_group, criterion_main};
 use crossbeam_utils::sync::WaitGroup;
 use kvs::thread_pool;
+use kvs::thread_pool::NaiveThreadPool;
 use kvs::thread_pool::RayonThreadPool;
 use kvs::thread_pool::SharedQueueThreadPool;
 use kvs::thread_pool::ThreadPool;
+use kvs::thread_pool::WorkStealingThreadPool;
 use kvs::KvClient;
+use kvs::KvClientPool;
 use kvs::KvServer;
 use kvs::KvStore;
 use kvs::KvsEngine;
+use kvs::ReadLockFreeKvStore;
+use kvs::ServerOpts;
 use kvs::SledStore;
 use kvs::ThreadHandle;
 use log::info;
@@ -25,6 +31,11 @@ use tempfile::TempDir;
 
 static A: i32 = 3;
 
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Starts a server in the directory with the given number of threads
+type StartServer = fn(&TempDir, u32) -> ThreadHandle;
+
 lazy_static! {
     static ref SERVER_ADDR: SocketAddr =
         SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 5005).into();
@@ -34,9 +45,10 @@ fn write_group<SetUp>(c: &mut Criterion, setup: SetUp)
 where
     SetUp: Fn(&TempDir, u32) -> ThreadHandle,
 {
-    tracing_subscriber::fmt()
+    // every group sets up the subscriber, only the first one installs it
+    let _ = tracing_subscriber::fmt()
         .with_max_level(tracing::Level::INFO)
-        .init();
+        .try_init();
     info!("begin bench");
 
     // init common tools
@@ -45,19 +57,31 @@ where
     let num_cpus = num_cpus::get() as u32;
     let pool = RayonThreadPool::new(num_cpus * 2).unwrap();
 
-    for threads in [1, 2, 4, 8, num_cpus, num_cpus * 2].iter() {
+    for threads in thread_counts().iter() {
         let handle = setup(&temp_dir, *threads);
+        // a connection holds a server thread, so there can't be more than the server threads
+        let clients = KvClientPool::new(*SERVER_ADDR, *threads as usize).unwrap();
         group.bench_with_input(
             BenchmarkId::new("Test write bench", threads),
             threads,
-            |b, _| b.iter(|| write(&pool)),
+            |b, _| b.iter(|| write(&pool, &clients)),
         );
+        drop(clients);
         // when exit scope pool and server exit.
         teardown_with_check(handle);
     }
     group.finish();
 }
 
+/// Numbers of server threads to bench with, which repeat on machines with up to 8 cores
+fn thread_counts() -> Vec<u32> {
+    let num_cpus = num_cpus::get() as u32;
+    let mut counts = vec![1, 2, 4, 8, num_cpus, num_cpus * 2];
+    counts.sort_unstable();
+    counts.dedup();
+    counts
+}
+
 fn write_rayon_sledkvengine(c: &mut Criterion) {
     write_group(c, startup_with_rayon_sled);
 }
@@ -75,7 +99,9 @@ fn teardown_with_check(handle: ThreadHandle) {
             format!("value{}", i)
         );
     });
-    handle.shutdown().unwrap();
+    // the next server binds the same address and opens the same store once this one has
+    // returned
+    handle.shutdown_graceful(SHUTDOWN_TIMEOUT).unwrap();
 }
 
 /// startup with different thread pool and different server
@@ -85,6 +111,12 @@ fn startup_with_shared(temp_dir: &TempDir, threads: u32) -> ThreadHandle {
     KvServer::serve(engine, thread_pool, *SERVER_ADDR).unwrap()
 }
 
+fn startup_with_stealing(temp_dir: &TempDir, threads: u32) -> ThreadHandle {
+    let thread_pool = WorkStealingThreadPool::new(threads).unwrap();
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    KvServer::serve(engine, thread_pool, *SERVER_ADDR).unwrap()
+}
+
 fn startup_with_rayon(temp_dir: &TempDir, threads: u32) -> ThreadHandle {
     let thread_pool = RayonThreadPool::new(threads).unwrap();
     let engine = KvStore::open(temp_dir.path()).unwrap();
@@ -97,24 +129,178 @@ fn startup_with_rayon_sled(temp_dir: &TempDir, threads: u32) -> ThreadHandle {
     KvServer::serve(engine, thread_pool, *SERVER_ADDR).unwrap()
 }
 
-fn write<P: ThreadPool>(thread_pool: &P) {
+fn write<P: ThreadPool>(thread_pool: &P, clients: &KvClientPool) {
     // for 1000 inputs write
     let wg = WaitGroup::new();
     (0..1000).for_each(|i| {
         let wg = wg.clone();
+        let clients = clients.clone();
         thread_pool.spawn(move || {
-            let mut client = KvClient::new(*SERVER_ADDR).unwrap();
-            client
+            clients
+                .get()
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
-            client.shutdown().unwrap();
             drop(wg);
         });
     });
     wg.wait();
 }
 
-fn read_queued_kvstore() {}
+/// The workload of `write_group` against `KvStore` served by each pool with every number of
+/// threads. `SharedQueueThreadPool` hands every connection over one channel, while the
+/// workers of `WorkStealingThreadPool` take them from deques of their own.
+fn pool_write_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pool_write_group");
+    let num_cpus = num_cpus::get() as u32;
+    let pool = RayonThreadPool::new(num_cpus * 2).unwrap();
+    let pools: [(&str, StartServer); 2] = [
+        ("SharedQueueThreadPool", startup_with_shared),
+        ("WorkStealingThreadPool", startup_with_stealing),
+    ];
+
+    for threads in thread_counts().iter() {
+        for (name, setup) in pools.iter() {
+            let temp_dir = TempDir::new().unwrap();
+            let handle = setup(&temp_dir, *threads);
+            let clients = KvClientPool::new(*SERVER_ADDR, *threads as usize).unwrap();
+            group.bench_with_input(BenchmarkId::new(*name, threads), threads, |b, _| {
+                b.iter(|| write(&pool, &clients))
+            });
+            drop(clients);
+            teardown_with_check(handle);
+        }
+    }
+    group.finish();
+}
+
+/// 100 clients each connect and set 10 keys at once, against `KvStore` served with each
+/// number of threads. Unlike `write_group`, every client opens a connection of its own, which
+/// waits for a server thread to be free.
+fn connect_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("connect_group");
+    for threads in thread_counts().iter() {
+        let temp_dir = TempDir::new().unwrap();
+        let handle = startup_with_shared(&temp_dir, *threads);
+        group.bench_with_input(BenchmarkId::new("KvStore", threads), threads, |b, _| {
+            b.iter(connect_and_write)
+        });
+        handle.shutdown_graceful(SHUTDOWN_TIMEOUT).unwrap();
+    }
+    group.finish();
+}
+
+fn connect_and_write() {
+    let clients: Vec<_> = (0..100)
+        .map(|client| {
+            spawn(move || {
+                let mut kv_client = KvClient::new(*SERVER_ADDR).unwrap();
+                (0..10).for_each(|i| {
+                    kv_client
+                        .set(format!("key{}-{}", client, i), format!("value{}", i))
+                        .unwrap();
+                });
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+}
+
+/// Get 1000 pre-populated keys from as many clients as server threads, with each engine.
+///
+/// `KvStore` reads under the read half of the lock of the whole store, `ReadLockFreeKvStore`
+/// without a lock, which shows how far reads of each scale with the threads.
+fn read_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_group");
+    let num_cpus = num_cpus::get() as u32;
+    let pool = RayonThreadPool::new(num_cpus * 2).unwrap();
+    let engines: [(&str, StartServer); 2] = [
+        ("KvStore", startup_populated::<KvStore>),
+        (
+            "ReadLockFreeKvStore",
+            startup_populated::<ReadLockFreeKvStore<NaiveThreadPool>>,
+        ),
+    ];
+
+    for threads in thread_counts().iter() {
+        for (name, setup) in engines.iter() {
+            let temp_dir = TempDir::new().unwrap();
+            let handle = setup(&temp_dir, *threads);
+            let clients = KvClientPool::new(*SERVER_ADDR, *threads as usize).unwrap();
+            group.bench_with_input(BenchmarkId::new(*name, threads), threads, |b, _| {
+                b.iter(|| read(&pool, &clients))
+            });
+            drop(clients);
+            handle.shutdown_graceful(SHUTDOWN_TIMEOUT).unwrap();
+        }
+    }
+    group.finish();
+}
+
+/// Serve a store of engine `E` holding the keys `read` gets
+fn startup_populated<E: KvsEngine>(temp_dir: &TempDir, threads: u32) -> ThreadHandle {
+    let engine = E::open(temp_dir.path()).unwrap();
+    (0..1000).for_each(|i| {
+        engine
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    });
+    let thread_pool = SharedQueueThreadPool::new(threads).unwrap();
+    KvServer::serve(engine, thread_pool, *SERVER_ADDR).unwrap()
+}
+
+fn read<P: ThreadPool>(thread_pool: &P, clients: &KvClientPool) {
+    // for 1000 inputs read
+    let wg = WaitGroup::new();
+    (0..1000).for_each(|i| {
+        let wg = wg.clone();
+        let clients = clients.clone();
+        thread_pool.spawn(move || {
+            assert_eq!(
+                clients.get().get(format!("key{}", i)).unwrap(),
+                Some(format!("value{}", i))
+            );
+            drop(wg);
+        });
+    });
+    wg.wait();
+}
+
+/// A set and a get of one key after another on a single connection, with and without
+/// `TCP_NODELAY` on both ends, which shows the latency Nagle's algorithm adds to every request.
+fn latency_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("latency_group");
+    for nodelay in [false, true] {
+        let temp_dir = TempDir::new().unwrap();
+        let opts = ServerOpts {
+            nagle: !nodelay,
+            ..Default::default()
+        };
+        let engine = KvStore::open(temp_dir.path()).unwrap();
+        let thread_pool = SharedQueueThreadPool::new(1).unwrap();
+        let handle = KvServer::serve_with_opts(engine, thread_pool, *SERVER_ADDR, opts).unwrap();
+        let mut client = KvClient::new(*SERVER_ADDR).unwrap();
+        client.set_nodelay(nodelay).unwrap();
+        group.bench_with_input(BenchmarkId::new("nodelay", nodelay), &nodelay, |b, _| {
+            b.iter(|| {
+                client.set("key".to_owned(), "value".to_owned()).unwrap();
+                client.get("key".to_owned()).unwrap();
+            })
+        });
+        client.shutdown().unwrap();
+        handle.shutdown_graceful(SHUTDOWN_TIMEOUT).unwrap();
+    }
+    group.finish();
+}
 
-criterion_group!(benches, write_queued_kvstore, write_rayon_sledkvengine);
+criterion_group!(
+    benches,
+    write_queued_kvstore,
+    write_rayon_sledkvengine,
+    read_group,
+    connect_group,
+    latency_group,
+    pool_write_group
+);
 criterion_main!(benches);
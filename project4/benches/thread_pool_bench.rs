@@ -0,0 +1,38 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use crossbeam_utils::sync::WaitGroup;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool};
+
+const JOBS: usize = 10_000;
+
+/// Submit a lot of tiny jobs as fast as possible, so the pool's queue is the bottleneck.
+fn submit<P: ThreadPool>(pool: &P) {
+    let wg = WaitGroup::new();
+    for _ in 0..JOBS {
+        let wg = wg.clone();
+        pool.spawn(move || drop(wg));
+    }
+    wg.wait();
+}
+
+fn submit_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("submit_group");
+    let num_cpus = num_cpus::get() as u32;
+    for threads in [1, 2, 4, num_cpus, num_cpus * 2].iter() {
+        let pool = SharedQueueThreadPool::new(*threads).unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("shared queue", threads),
+            threads,
+            |b, _| b.iter(|| submit(&pool)),
+        );
+        let pool = WorkStealingThreadPool::new(*threads).unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("work stealing", threads),
+            threads,
+            |b, _| b.iter(|| submit(&pool)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, submit_group);
+criterion_main!(benches);
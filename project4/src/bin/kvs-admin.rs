@@ -0,0 +1,112 @@
+use std::env::current_dir;
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+
+use clap::{Parser, Subcommand};
+use kvs::common::Ipv4Port;
+use kvs::error::{ErrorCode, Result};
+use kvs::{KvClient, KvStore, KvsEngine, SledStore, StoreStats};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Opts {
+    #[command(subcommand)]
+    cmd: Command,
+    /// ask the server listening on this address instead of opening a directory
+    #[arg(long, global = true)]
+    #[arg(value_parser = Ipv4Port::from_str)]
+    addr: Option<Ipv4Port>,
+    /// data directory of a store which no server is running on, the current one by default
+    #[arg(long, global = true)]
+    dir: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Clone)]
+enum Command {
+    /// Rewrite the logs without their stale commands
+    Compact,
+    /// Print the statistics of the store
+    Stats,
+    /// Print every key/value pair as a line of JSON
+    Dump,
+}
+
+fn main() {
+    let opts = Opts::parse();
+    if let Err(e) = run(opts) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opts: Opts) -> Result<()> {
+    if let Some(addr) = opts.addr {
+        let mut client = KvClient::new((IpAddr::V4(addr.ipv4), addr.port))?;
+        return match opts.cmd {
+            Command::Stats => print_stats(&client.stats()?),
+            Command::Compact | Command::Dump => Err(ErrorCode::Unsupported(
+                "compact and dump of a running server".to_owned(),
+            )
+            .into()),
+        };
+    }
+
+    let dir = match opts.dir {
+        Some(dir) => dir,
+        None => current_dir()?,
+    };
+    // the directory of a sled server is marked by `kvs-server`, anything else is a `KvStore`
+    let engine = fs::read_to_string(dir.join(".engine")).unwrap_or_default();
+    if engine == "sled" {
+        run_engine(SledStore::open(&dir)?, opts.cmd, |_| {
+            Err(ErrorCode::Unsupported("compact".to_owned()).into())
+        })
+    } else {
+        run_engine(open_kvs(&dir)?, opts.cmd, KvStore::compact)
+    }
+}
+
+/// Open an existing `KvStore`, rather than create an empty one in a mistyped directory
+fn open_kvs(dir: &Path) -> Result<KvStore> {
+    if !dir.is_dir() {
+        return Err(
+            ErrorCode::InternalError(format!("{} is not a directory", dir.display())).into(),
+        );
+    }
+    KvStore::open(dir)
+}
+
+fn run_engine<E: KvsEngine>(
+    engine: E,
+    cmd: Command,
+    compact: impl FnOnce(&E) -> Result<()>,
+) -> Result<()> {
+    match cmd {
+        Command::Compact => compact(&engine),
+        Command::Stats => print_stats(&engine.stats()?),
+        Command::Dump => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            engine.dump(&mut stdout)?;
+            Ok(stdout.flush()?)
+        }
+    }
+}
+
+fn print_stats(stats: &StoreStats) -> Result<()> {
+    println!("keys: {}", stats.num_keys);
+    println!("uncompacted bytes: {}", stats.uncompacted_bytes);
+    println!("generations: {}", stats.num_generations);
+    println!("total bytes: {}", stats.total_bytes);
+    println!("bytes written: {}", stats.bytes_written);
+    println!("compactions: {}", stats.compactions);
+    println!("disk reads: {}", stats.disk_reads);
+    println!("index memory: {}", stats.memory.index_bytes);
+    println!("reader cache memory: {}", stats.memory.reader_cache_bytes);
+    println!("other memory: {}", stats.memory.other);
+    Ok(())
+}
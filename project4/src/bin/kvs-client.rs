@@ -1,7 +1,6 @@
 #![feature(let_chains)]
 
 use std::net::IpAddr;
-use std::net::TcpStream;
 use std::process::exit;
 use std::str::FromStr;
 
@@ -36,8 +35,7 @@ fn main() -> Result<()> {
         .init();
 
     // begin connect
-    let stream = TcpStream::connect((IpAddr::V4(opts.addr.ipv4), opts.addr.port))?;
-    let mut client = KvClient { stream };
+    let mut client = KvClient::new((IpAddr::V4(opts.addr.ipv4), opts.addr.port))?;
     match opts.cmd {
         Command::Get { key } => {
             client.get(key).map_or_else(
@@ -5,20 +5,27 @@ use std::{
     fmt::Display,
     fs::{self},
     net::SocketAddr,
+    path::Path,
     process::exit,
     str::FromStr,
+    time::Duration,
 };
 
 use clap::{Parser, ValueEnum};
 use kvs::{
-    common::Ipv4Port,
-    error::{ErrorCode, Result},
-    thread_pool::{SharedQueueThreadPool, ThreadPool},
-    KvServer, KvStore, KvsEngine, SledStore,
+    common::{DirLock, Ipv4Port},
+    error::{ErrorCode, KvError, Result},
+    thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool},
+    KvServer, KvStore, KvsEngine, ReadLockFreeKvStore, ServerOpts, SledStore,
 };
 use log::warn;
+#[cfg(unix)]
+use nix::sys::signal::{SigSet, Signal};
 use tracing::{error, info};
 
+/// How long a shutdown waits for the requests being served
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Opts {
@@ -30,6 +37,14 @@ struct Opts {
     #[arg(default_value_t)]
     #[arg(value_enum)]
     engine: Engine,
+    /// leave Nagle's algorithm on for client connections rather than set TCP_NODELAY
+    #[arg(long)]
+    nagle: bool,
+    /// serve Prometheus metrics on this address
+    #[cfg(feature = "metrics-http")]
+    #[arg(long)]
+    #[arg(value_parser = crate::Ipv4Port::from_str)]
+    metrics_addr: Option<Ipv4Port>,
 }
 
 impl Display for Opts {
@@ -49,6 +64,9 @@ impl Display for Opts {
 enum Engine {
     Kvs,
     Sled,
+    /// `ReadLockFreeKvStore`, whose reads never wait for writes
+    #[value(name = "kvs-lockfree")]
+    KvsLockFree,
 }
 
 impl Default for Engine {
@@ -64,6 +82,7 @@ impl FromStr for Engine {
         match s {
             "kvs" => Ok(Engine::Kvs),
             "sled" => Ok(Engine::Sled),
+            "kvs-lockfree" => Ok(Engine::KvsLockFree),
             _ => Err(ErrorCode::InternalError(format!(
                 "error transfor from {}",
                 s
@@ -80,6 +99,7 @@ impl Display for Engine {
             match self {
                 Engine::Kvs => "kvs",
                 Engine::Sled => "sled",
+                Engine::KvsLockFree => "kvs-lockfree",
             }
         )
     }
@@ -89,6 +109,7 @@ fn main() {
     let cli = Opts::parse();
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::INFO)
+        .with_writer(std::io::stderr)
         .init();
     info!(
         "Welcome to use {}:{}",
@@ -97,19 +118,39 @@ fn main() {
     );
     info!("Backend engine: {}", cli.engine);
     info!("Listen on {}", cli.addr);
-    let res = current_engine().and_then(move |curr_engine| {
-        if let Some(curr_engine) = curr_engine && cli.engine != curr_engine {
-            error!("wrong engine!");
-            exit(1)
-        }
+    let res = current_dir().map_err(KvError::from).and_then(move |path| {
+        // held until the server exits, so a second server on the directory fails here rather
+        // than write the same logs
+        let _lock = DirLock::exclusive(&path, ".lock")?;
+        current_engine(&path, &cli.engine)?;
 
-        let path = std::env::current_dir()?;
+        // before the threads of the pool are spawned
+        block_signals()?;
         fs::write(path.join(".engine"), format!("{}", cli.engine))?;
         let pool = SharedQueueThreadPool::new(10)?;
         let addr: SocketAddr = (cli.addr.ipv4, cli.addr.port).into();
+        let opts = ServerOpts {
+            nagle: cli.nagle,
+            #[cfg(feature = "metrics-http")]
+            metrics_addr: cli
+                .metrics_addr
+                .map(|metrics_addr| (metrics_addr.ipv4, metrics_addr.port).into()),
+            ..Default::default()
+        };
         match cli.engine {
-            Engine::Kvs => KvServer::serve(KvStore::open(&path)?, pool, addr),
-            Engine::Sled => KvServer::serve(SledStore::open(&path)?, pool, addr),
+            Engine::Kvs => {
+                let store = KvStore::open(&path)?;
+                serve_until_signal(store.clone(), pool, addr, opts)?;
+                // the next start has no stale commands to replay
+                store.compact()
+            }
+            Engine::Sled => serve_until_signal(SledStore::open(&path)?, pool, addr, opts),
+            Engine::KvsLockFree => serve_until_signal(
+                ReadLockFreeKvStore::<NaiveThreadPool>::open(&path)?,
+                pool,
+                addr,
+                opts,
+            ),
         }
     });
 
@@ -119,17 +160,78 @@ fn main() {
     }
 }
 
-fn current_engine() -> Result<Option<Engine>> {
-    let engine = current_dir()?.join(".engine");
+/// Serve until SIGINT or SIGTERM, then let the requests being served complete and flush the
+/// engine
+fn serve_until_signal<E: KvsEngine, P: ThreadPool>(
+    engine: E,
+    pool: P,
+    addr: SocketAddr,
+    opts: ServerOpts,
+) -> Result<()> {
+    let handle = KvServer::serve_with_opts(engine.clone(), pool, addr, opts)?;
+    let signal = wait_for_signal()?;
+    info!("Received {}, shutting down", signal);
+    if let Err(e) = handle.shutdown_graceful(SHUTDOWN_TIMEOUT) {
+        warn!("{}", e);
+    }
+    engine.flush()
+}
+
+#[cfg(unix)]
+fn shutdown_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals
+}
+
+/// Block the shutdown signals, so that they wait for `wait_for_signal` rather than kill the
+/// process. Threads inherit the blocked signals, so it is called before any is spawned.
+#[cfg(unix)]
+fn block_signals() -> Result<()> {
+    shutdown_signals()
+        .thread_block()
+        .map_err(std::io::Error::from)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn wait_for_signal() -> Result<Signal> {
+    Ok(shutdown_signals().wait().map_err(std::io::Error::from)?)
+}
+
+#[cfg(not(unix))]
+fn block_signals() -> Result<()> {
+    Ok(())
+}
+
+/// Signals are not handled, the server runs until the process is killed
+#[cfg(not(unix))]
+fn wait_for_signal() -> Result<&'static str> {
+    loop {
+        std::thread::park();
+    }
+}
+
+/// Check that the data in `dir`, if any, was written by `expected`
+///
+/// # Errors
+///
+/// It fails with `ErrorCode::EngineMismatch` if `.engine` names another engine, or anything
+/// which is not an engine.
+fn current_engine(dir: &Path, expected: &Engine) -> Result<()> {
+    let engine = dir.join(".engine");
     if !engine.exists() {
-        return Ok(None);
+        return Ok(());
     }
 
-    match fs::read_to_string(engine)?.parse() {
-        Ok(engine) => Ok(Some(engine)),
-        Err(e) => {
-            warn!("The content of engine file is invalid: {}", e);
-            Ok(None)
+    let found = fs::read_to_string(engine)?;
+    match found.trim().parse::<Engine>() {
+        Ok(engine) if engine == *expected => Ok(()),
+        _ => Err(ErrorCode::EngineMismatch {
+            expected: expected.to_string(),
+            found: found.trim().to_owned(),
         }
+        .into()),
     }
 }
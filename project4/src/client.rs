@@ -1,22 +1,210 @@
+use std::io::{self, Read, Write};
 use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::ops::{Deref, DerefMut};
+use std::slice;
+use std::thread;
+use std::time::{Duration, SystemTime};
 
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use log::debug;
+
+use crate::common::KeysPage;
 use crate::common::KvsRequest;
 use crate::common::KvsResponse;
 use crate::common::ServiceProxy;
-use crate::{error::ErrorCode, Result};
+use crate::common::{
+    handle_receive_counted, handle_send_counted, Codec, IoStats, Unread, HANDSHAKE, MAX_FRAME_LEN,
+};
+use crate::common::{is_timeout, unix_millis};
+use crate::error::KvError;
+use crate::transport::{Endpoint, Stream};
+use crate::{error::ErrorCode, Result, StoreStats};
 
 pub struct KvClient {
-    pub stream: TcpStream,
+    pub stream: Stream,
+    // the server `stream` is connected to, to reconnect to it
+    endpoint: Endpoint,
+    // times a request is sent again on a new connection after the server closed the connection
+    retries: u32,
+    // the wait before the first retry, doubled for each following one
+    backoff: Duration,
+    // reconnect to the server a request is redirected to and retry once
+    follow_redirect: bool,
+    // attached to every request, the server skips requests it can't start before it
+    deadline: Option<SystemTime>,
+    // bytes sent and received since the client was created, to profile the bandwidth
+    io: IoStats,
+    // the codec asked for in a handshake on every new connection, none for plain JSON
+    asked_codec: Option<Codec>,
+    // the codec the server agreed on
+    codec: Codec,
+    // reads and writes on the connection fail after it
+    timeout: Option<Duration>,
+    // a response may still arrive after a timeout, so the next request goes on a new connection
+    timed_out: bool,
+    // `TCP_NODELAY` of every connection
+    nodelay: bool,
 }
 
 // todo: KvClient和proxy简化成一个类
 impl ServiceProxy<KvsRequest, KvsResponse> for KvClient {}
 
 impl KvClient {
+    /// Connect to `addr` over TCP, with `TCP_NODELAY` set, see `set_nodelay`
     pub fn new<Addr: ToSocketAddrs>(addr: Addr) -> Result<KvClient> {
-        Ok(KvClient {
-            stream: TcpStream::connect(addr)?,
-        })
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Self::with_stream(
+            Endpoint::Tcp(stream.peer_addr()?),
+            Stream::Tcp(stream),
+        ))
+    }
+
+    /// Connect to `endpoint`, a TCP address or the path of a Unix socket
+    pub fn connect(endpoint: impl Into<Endpoint>) -> Result<KvClient> {
+        let endpoint = endpoint.into();
+        let stream = Stream::connect(&endpoint)?;
+        stream.set_nodelay(true)?;
+        Ok(Self::with_stream(endpoint, stream))
+    }
+
+    /// Connect to `endpoint` and ask the server to speak `codec` on the connection. A server
+    /// which doesn't know it falls back to JSON, see `codec`.
+    pub fn with_codec(endpoint: impl Into<Endpoint>, codec: Codec) -> Result<KvClient> {
+        let mut client = Self::connect(endpoint)?;
+        client.asked_codec = Some(codec);
+        client.handshake()?;
+        Ok(client)
+    }
+
+    /// Connect to `addr`, and fail a request with `ErrorCode::Timeout` if sending it or
+    /// receiving its response is blocked for longer than `timeout`.
+    ///
+    /// A timed out request may or may not have been applied. The next request is sent on a new
+    /// connection, so that it isn't answered with the late response.
+    pub fn with_timeout<Addr: ToSocketAddrs>(addr: Addr, timeout: Duration) -> Result<KvClient> {
+        let mut client = Self::new(addr)?;
+        client.timeout = Some(timeout);
+        client.stream.set_timeout(client.timeout)?;
+        Ok(client)
+    }
+
+    fn with_stream(endpoint: Endpoint, stream: Stream) -> KvClient {
+        KvClient {
+            endpoint,
+            stream,
+            retries: 0,
+            backoff: Duration::ZERO,
+            follow_redirect: false,
+            deadline: None,
+            io: IoStats::default(),
+            asked_codec: None,
+            codec: Codec::default(),
+            timeout: None,
+            timed_out: false,
+            nodelay: true,
+        }
+    }
+
+    /// Returns the codec the server agreed to speak on the connection
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// Connect to the endpoint again, after a redirect, a closed connection or a timeout
+    fn reconnect(&mut self) -> Result<()> {
+        self.stream = Stream::connect(&self.endpoint)?;
+        self.stream.set_timeout(self.timeout)?;
+        self.stream.set_nodelay(self.nodelay)?;
+        self.timed_out = false;
+        self.codec = Codec::default();
+        self.handshake()
+    }
+
+    /// Get a new connection if a request has timed out on this one
+    fn recover(&mut self) -> Result<()> {
+        if self.timed_out {
+            debug!("Reconnect after a timeout");
+            self.reconnect()?;
+        }
+        Ok(())
+    }
+
+    /// Ask for the codec of the client on a new connection, if it has one
+    fn handshake(&mut self) -> Result<()> {
+        let Some(asked) = self.asked_codec else {
+            return Ok(());
+        };
+        self.stream.write_all(&[HANDSHAKE, asked as u8])?;
+        self.io.bytes_sent += 2;
+        let mut answer = [0_u8; 2];
+        self.stream.read_exact(&mut answer[..1])?;
+        if answer[0] != HANDSHAKE {
+            // a server rejecting the connection answers with a frame right away
+            let res = handle_receive_counted::<KvsResponse>(
+                &mut Unread::new(Some(answer[0]), &mut self.stream),
+                &mut self.io,
+                Codec::Json,
+            );
+            return match res {
+                Ok(Some(KvsResponse::Error(fn_err))) => Err(fn_err.into()),
+                Ok(Some(msg)) => Err(unexpected_response(msg)),
+                Ok(None) => Err(ErrorCode::ConnectionClosed.into()),
+                Err(rpc_err) => Err(rpc_error(rpc_err)),
+            };
+        }
+        self.stream.read_exact(&mut answer[1..])?;
+        self.io.bytes_received += 2;
+        self.codec = Codec::from_byte(answer[1])
+            .ok_or_else(|| ErrorCode::InternalError(format!("Unknown codec {}", answer[1])))?;
+        debug!("Server agreed on {:?}, asked for {:?}", self.codec, asked);
+        Ok(())
+    }
+
+    /// Returns the bytes sent and received by this client so far, including the length prefix
+    /// of every frame. A redirected client keeps counting on its new connection.
+    pub fn io_stats(&self) -> IoStats {
+        self.io
+    }
+
+    /// Attach `deadline` to the following requests. A request which has not been started by
+    /// the server before the deadline fails with `ErrorCode::DeadlineExceeded`.
+    pub fn set_deadline(&mut self, deadline: Option<SystemTime>) {
+        self.deadline = deadline;
+    }
+
+    /// Send a request again up to `retries` times, each on a new connection, when the server
+    /// closes the connection before responding. A failed reconnection, e.g. to a restarting
+    /// server, takes a retry too.
+    ///
+    /// A retried request may be applied twice. Gets and sets end the same either way, and so
+    /// does a `rm`, though it fails with `ErrorCode::KeyNotFound` if its key was removed by the
+    /// first attempt. `increment` and `get_delete` would not, so they are never retried.
+    ///
+    /// Without retries, such a request fails with `ErrorCode::ConnectionClosed`.
+    pub fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    /// Wait `backoff` before the first retry, and twice as long before each following one, to
+    /// give a restarting server time to come back. Retries are immediate by default.
+    pub fn set_backoff(&mut self, backoff: Duration) {
+        self.backoff = backoff;
+    }
+
+    /// In redirect-aware mode, a request redirected by a shard is transparently sent again
+    /// to the indicated server, and the client stays connected to it.
+    pub fn set_follow_redirect(&mut self, follow_redirect: bool) {
+        self.follow_redirect = follow_redirect;
+    }
+
+    /// Whether requests are sent at once rather than held back by Nagle's algorithm to
+    /// coalesce them with the next write, i.e. `TCP_NODELAY`. It's on by default, as a request
+    /// is a small frame whose response is waited for. It stays set on new connections.
+    pub fn set_nodelay(&mut self, nodelay: bool) -> Result<()> {
+        self.stream.set_nodelay(nodelay)?;
+        self.nodelay = nodelay;
+        Ok(())
     }
 
     pub fn shutdown(&mut self) -> Result<()> {
@@ -26,32 +214,456 @@ impl KvClient {
 
     // 模版代码，装包解包，其实是KvServerProxy，可以通过宏自动生成
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let request = Self::request(&mut self.stream, &KvsRequest::Set { key, value });
+        let request = self.call(&KvsRequest::Set { key, value });
         match request {
             Ok(KvsResponse::Set(Ok(res))) => Ok(res),
-            Ok(KvsResponse::Set(Err(fn_err))) => Err(ErrorCode::InternalError(fn_err).into()),
-            Ok(msg) => panic!("invalid return type! {:#?}", msg),
-            Err(rpc_err) => Err(ErrorCode::InternalError(rpc_err.to_string()).into()),
+            Ok(KvsResponse::Set(Err(fn_err))) => Err(fn_err.into()),
+            Ok(msg) => Err(unexpected_response(msg)),
+            Err(rpc_err) => Err(rpc_error(rpc_err)),
         }
     }
 
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let request = Self::request(&mut self.stream, &KvsRequest::Get { key });
+        let request = self.call(&KvsRequest::Get { key });
         match request {
             Ok(KvsResponse::Get(Ok(res))) => Ok(res),
-            Ok(KvsResponse::Get(Err(fn_err))) => Err(ErrorCode::InternalError(fn_err).into()),
-            Ok(msg) => panic!("invalid return type! {:#?}", msg),
-            Err(rpc_err) => Err(ErrorCode::InternalError(rpc_err.to_string()).into()),
+            Ok(KvsResponse::Get(Err(fn_err))) => Err(fn_err.into()),
+            Ok(msg) => Err(unexpected_response(msg)),
+            Err(rpc_err) => Err(rpc_error(rpc_err)),
         }
     }
 
+    /// Removes `key`, failing with `ErrorCode::KeyNotFound` if it doesn't exist
     pub fn rm(&mut self, key: String) -> Result<()> {
-        let request = Self::request(&mut self.stream, &KvsRequest::Rm { key });
+        let request = self.call(&KvsRequest::Rm { key });
         match request {
             Ok(KvsResponse::Rm(Ok(res))) => Ok(res),
-            Ok(KvsResponse::Rm(Err(fn_err))) => Err(ErrorCode::InternalError(fn_err).into()),
-            Ok(msg) => panic!("invalid return type! {:#?}", msg),
-            Err(rpc_err) => Err(ErrorCode::InternalError(rpc_err.to_string()).into()),
+            Ok(KvsResponse::Rm(Err(fn_err))) => Err(fn_err.into()),
+            Ok(msg) => Err(unexpected_response(msg)),
+            Err(rpc_err) => Err(rpc_error(rpc_err)),
         }
     }
+
+    /// Returns when `key` was last written in milliseconds since the unix epoch
+    pub fn last_modified(&mut self, key: String) -> Result<Option<u64>> {
+        let request = self.call(&KvsRequest::LastModified { key });
+        match request {
+            Ok(KvsResponse::LastModified(Ok(res))) => Ok(res),
+            Ok(KvsResponse::LastModified(Err(fn_err))) => Err(fn_err.into()),
+            Ok(msg) => Err(unexpected_response(msg)),
+            Err(rpc_err) => Err(rpc_error(rpc_err)),
+        }
+    }
+
+    /// Whether `key` exists. Its value is neither sent back nor, on most engines, read.
+    pub fn contains(&mut self, key: String) -> Result<bool> {
+        let request = self.call(&KvsRequest::Contains { key });
+        match request {
+            Ok(KvsResponse::Contains(Ok(res))) => Ok(res),
+            Ok(KvsResponse::Contains(Err(fn_err))) => Err(fn_err.into()),
+            Ok(msg) => Err(unexpected_response(msg)),
+            Err(rpc_err) => Err(rpc_error(rpc_err)),
+        }
+    }
+
+    /// Sets `key` to `new`, or removes it for `None`, only if its value is `expected`.
+    /// Returns whether the value was swapped.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let request = self.call(&KvsRequest::Cas { key, expected, new });
+        match request {
+            Ok(KvsResponse::Cas(Ok(res))) => Ok(res),
+            Ok(KvsResponse::Cas(Err(fn_err))) => Err(fn_err.into()),
+            Ok(msg) => Err(unexpected_response(msg)),
+            Err(rpc_err) => Err(rpc_error(rpc_err)),
+        }
+    }
+
+    /// Adds `delta` to the integer value of `key`, 0 if it doesn't exist, and returns the new
+    /// value. It fails with `ErrorCode::NotAnInteger` if the value is not an integer.
+    ///
+    /// It is never retried, as a retry could add `delta` twice, so it fails with
+    /// `ErrorCode::ConnectionClosed` if the connection is lost before the response.
+    pub fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        let request = self.call_once(&KvsRequest::Incr { key, delta });
+        match request {
+            Ok(KvsResponse::Incr(Ok(res))) => Ok(res),
+            Ok(KvsResponse::Incr(Err(fn_err))) => Err(fn_err.into()),
+            Ok(msg) => Err(unexpected_response(msg)),
+            Err(rpc_err) => Err(rpc_error(rpc_err)),
+        }
+    }
+
+    /// Returns the statistics of the data kept by the server
+    pub fn stats(&mut self) -> Result<StoreStats> {
+        let request = self.call(&KvsRequest::Stats);
+        match request {
+            Ok(KvsResponse::Stats(Ok(res))) => Ok(res),
+            Ok(KvsResponse::Stats(Err(fn_err))) => Err(fn_err.into()),
+            Ok(msg) => Err(unexpected_response(msg)),
+            Err(rpc_err) => Err(rpc_error(rpc_err)),
+        }
+    }
+
+    /// Check that the server is up and answers requests, without touching its engine
+    pub fn ping(&mut self) -> Result<()> {
+        let request = self.call(&KvsRequest::Ping);
+        match request {
+            Ok(KvsResponse::Pong) => Ok(()),
+            Ok(msg) => Err(unexpected_response(msg)),
+            Err(rpc_err) => Err(rpc_error(rpc_err)),
+        }
+    }
+
+    /// Returns the live keys starting with `prefix`, or all of them for `None`, in key order.
+    /// They are requested in pages of `MAX_KEYS_PER_RESPONSE` keys, so keys written in between
+    /// may or may not be seen.
+    pub fn keys(&mut self, prefix: Option<String>) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut after = None;
+        loop {
+            let page = self.keys_page(prefix.clone(), after)?;
+            keys.extend(page.keys);
+            match page.next {
+                Some(next) => after = Some(next),
+                None => return Ok(keys),
+            }
+        }
+    }
+
+    /// Returns a page of the live keys starting with `prefix` after the key `after`. The rest
+    /// of the keys follow `next` of the page.
+    pub fn keys_page(&mut self, prefix: Option<String>, after: Option<String>) -> Result<KeysPage> {
+        let request = self.call(&KvsRequest::Keys { prefix, after });
+        match request {
+            Ok(KvsResponse::Keys(Ok(res))) => Ok(res),
+            Ok(KvsResponse::Keys(Err(fn_err))) => Err(fn_err.into()),
+            Ok(msg) => Err(unexpected_response(msg)),
+            Err(rpc_err) => Err(rpc_error(rpc_err)),
+        }
+    }
+
+    /// Gets the value of `key` and removes it in one round trip. Among clients racing for the
+    /// same key, only one gets its value.
+    ///
+    /// It is never retried, as a retry after the first attempt removed the key would return
+    /// `None` and lose the value, so it fails with `ErrorCode::ConnectionClosed` instead.
+    pub fn get_delete(&mut self, key: String) -> Result<Option<String>> {
+        let request = self.call_once(&KvsRequest::GetDelete { key });
+        match request {
+            Ok(KvsResponse::GetDelete(Ok(res))) => Ok(res),
+            Ok(KvsResponse::GetDelete(Err(fn_err))) => Err(fn_err.into()),
+            Ok(msg) => Err(unexpected_response(msg)),
+            Err(rpc_err) => Err(rpc_error(rpc_err)),
+        }
+    }
+
+    /// Sets all `pairs` in order with a single request, rather than a round trip per key.
+    ///
+    /// The batch is sent in one frame, so it fails with `ErrorCode::FrameTooLarge` if it doesn't
+    /// fit. The first failed set is returned, the following ones are still applied.
+    pub fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let reqs = pairs
+            .into_iter()
+            .map(|(key, value)| KvsRequest::Set { key, value })
+            .collect();
+        let request = self.call(&KvsRequest::Batch(reqs));
+        match request {
+            Ok(KvsResponse::Batch(responses)) => {
+                for res in responses {
+                    match res {
+                        KvsResponse::Set(Ok(())) => (),
+                        KvsResponse::Set(Err(fn_err)) => return Err(fn_err.into()),
+                        msg => return Err(unexpected_response(msg)),
+                    }
+                }
+                Ok(())
+            }
+            Ok(msg) => Err(unexpected_response(msg)),
+            Err(rpc_err) => Err(rpc_error(rpc_err)),
+        }
+    }
+
+    /// Gets the values of `keys`, in the same order.
+    ///
+    /// A batch too large for one frame is split into several requests. Each is sent once the
+    /// response of the previous one is read, as the server doesn't read the next request while
+    /// it is blocked writing a large response. Redirects are not followed for batches.
+    pub fn get_multi(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut empty_len = self
+            .codec
+            .serialize(&self.wrap(&KvsRequest::GetMulti { keys: vec![] }))?
+            .len();
+        if self.codec == Codec::MsgPack {
+            // the header of an array grows up to 4 bytes with its length
+            empty_len += 4;
+        }
+        let batches = split_keys(keys, MAX_FRAME_LEN.saturating_sub(empty_len), self.codec)?;
+        let mut values = Vec::new();
+        for keys in batches {
+            let req = self.wrap(&KvsRequest::GetMulti { keys });
+            match self.send_pipelined(slice::from_ref(&req))?.pop().flatten() {
+                Some(KvsResponse::GetMulti(Ok(res))) => values.extend(res),
+                Some(KvsResponse::GetMulti(Err(fn_err))) => return Err(fn_err.into()),
+                Some(msg) => return Err(unexpected_response(msg)),
+                None => return Err(ErrorCode::ConnectionClosed.into()),
+            }
+        }
+        Ok(values)
+    }
+
+    /// Start a pipeline of requests, which are sent back to back once it is executed
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            reqs: Vec::new(),
+        }
+    }
+
+    /// Send all `reqs` at once, then receive their responses. A `None` response means the
+    /// connection was closed before it.
+    fn send_pipelined(&mut self, reqs: &[KvsRequest]) -> Result<Vec<Option<KvsResponse>>> {
+        self.recover()?;
+        match self.send_and_receive(reqs) {
+            Err(e) if is_timeout(&e) => {
+                self.timed_out = true;
+                Err(ErrorCode::Timeout.into())
+            }
+            res => res,
+        }
+    }
+
+    fn send_and_receive(&mut self, reqs: &[KvsRequest]) -> Result<Vec<Option<KvsResponse>>> {
+        for req in reqs {
+            handle_send_counted(&mut self.stream, req, &mut self.io, self.codec)?;
+        }
+        // read all responses before failing, so that the connection is left at a frame boundary
+        let mut responses = Vec::with_capacity(reqs.len());
+        for _ in reqs {
+            responses.push(handle_receive_counted::<KvsResponse>(
+                &mut self.stream,
+                &mut self.io,
+                self.codec,
+            )?);
+        }
+        Ok(responses)
+    }
+
+    /// Attach the deadline to `req` if there is one
+    fn wrap(&self, req: &KvsRequest) -> KvsRequest {
+        match self.deadline {
+            Some(deadline) => KvsRequest::Deadline {
+                deadline: unix_millis(deadline),
+                req: Box::new(req.clone()),
+            },
+            None => req.clone(),
+        }
+    }
+
+    fn call(&mut self, req: &KvsRequest) -> Result<KvsResponse> {
+        self.call_retrying(req, self.retries)
+    }
+
+    /// Send a request which isn't safe to apply twice, without retrying it whatever
+    /// `set_retries` asked for
+    fn call_once(&mut self, req: &KvsRequest) -> Result<KvsResponse> {
+        self.call_retrying(req, 0)
+    }
+
+    fn call_retrying(&mut self, req: &KvsRequest, retries: u32) -> Result<KvsResponse> {
+        let req = &self.wrap(req);
+
+        match self.call_with_retries(req, retries)? {
+            KvsResponse::Redirect { addr } if self.follow_redirect => {
+                debug!("Request is redirected to {}", addr);
+                self.endpoint = Endpoint::Tcp(addr);
+                self.reconnect()?;
+                self.call_with_retries(req, retries)
+            }
+            res => Ok(res),
+        }
+    }
+
+    fn call_with_retries(&mut self, req: &KvsRequest, mut retries: u32) -> Result<KvsResponse> {
+        let mut backoff = self.backoff;
+        self.recover()?;
+        let mut res = Self::request(&mut self.stream, req, &mut self.io, self.codec);
+        loop {
+            match res {
+                Err(e) if matches!(*e, ErrorCode::Timeout) => {
+                    self.timed_out = true;
+                    return Err(e);
+                }
+                Err(e) if is_retryable(&e) && retries > 0 => {
+                    debug!(
+                        "Retry the {} request on a new connection in {:?}",
+                        req.op(),
+                        backoff
+                    );
+                    retries -= 1;
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                    res = self.reconnect().and_then(|_| {
+                        Self::request(&mut self.stream, req, &mut self.io, self.codec)
+                    });
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Requests sent back to back on the connection of a `KvClient`, without waiting for the
+/// response of each. The server serves the requests of a connection one by one, so their
+/// responses come back in order.
+///
+/// The requests are neither retried nor redirected, as some of them may have been applied.
+/// They should fit in the socket buffers, as the responses are only read once all of the
+/// requests are sent.
+pub struct Pipeline<'a> {
+    client: &'a mut KvClient,
+    reqs: Vec<KvsRequest>,
+}
+
+impl Pipeline<'_> {
+    /// Queue `req`, with the deadline of the client if there is one
+    pub fn push(&mut self, req: KvsRequest) -> &mut Self {
+        self.reqs.push(self.client.wrap(&req));
+        self
+    }
+
+    /// Send the queued requests and return their responses in the same order. Each request
+    /// fails on its own in its response.
+    ///
+    /// # Errors
+    ///
+    /// It fails with `ErrorCode::ConnectionClosed` if the connection is closed before all
+    /// the responses, or `ErrorCode::Timeout` once the timeout of the client is exceeded.
+    pub fn execute(self) -> Result<Vec<KvsResponse>> {
+        self.client
+            .send_pipelined(&self.reqs)?
+            .into_iter()
+            .map(|res| res.ok_or_else(|| ErrorCode::ConnectionClosed.into()))
+            .collect()
+    }
+}
+
+/// A fixed set of clients connected up front, handed out one at a time so that each operation
+/// doesn't pay for a new connection. Clones share the same clients.
+///
+/// Each client holds a connection, and so a thread of the server, for as long as the pool lives.
+#[derive(Clone)]
+pub struct KvClientPool {
+    tx: Sender<KvClient>,
+    rx: Receiver<KvClient>,
+}
+
+impl KvClientPool {
+    /// Connect `size` clients to `addr`
+    pub fn new<Addr: ToSocketAddrs>(addr: Addr, size: usize) -> Result<KvClientPool> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| ErrorCode::InternalError("no address to connect".to_owned()))?;
+        let (tx, rx) = unbounded();
+        for _ in 0..size {
+            tx.send(KvClient::new(addr)?).unwrap();
+        }
+        Ok(KvClientPool { tx, rx })
+    }
+
+    /// Take a client, waiting for one to be returned if all are in use. The client goes back
+    /// to the pool when the guard is dropped.
+    pub fn get(&self) -> PooledClient {
+        // the pool holds a sender itself, so the channel can't be disconnected
+        let client = self.rx.recv().unwrap();
+        PooledClient {
+            client: Some(client),
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+/// A client taken from a `KvClientPool`
+pub struct PooledClient {
+    client: Option<KvClient>,
+    tx: Sender<KvClient>,
+}
+
+impl Deref for PooledClient {
+    type Target = KvClient;
+
+    fn deref(&self) -> &KvClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut KvClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            // the client is just dropped if the pool is gone
+            let _ = self.tx.send(client);
+        }
+    }
+}
+
+/// Split `keys` into batches whose arrays serialized with `codec` are at most `limit` bytes.
+/// A key too large for a batch of its own still gets one, which fails to be sent.
+fn split_keys(keys: Vec<String>, limit: usize, codec: Codec) -> Result<Vec<Vec<String>>> {
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut batch_len = 0;
+    for key in keys {
+        // `[` and `]`, or a `,` after the previous key in JSON
+        let key_len = codec.serialize(&key)?.len() + 1;
+        if !batch.is_empty() && batch_len + key_len > limit {
+            batches.push(std::mem::take(&mut batch));
+            batch_len = 0;
+        }
+        batch_len += key_len;
+        batch.push(key);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    Ok(batches)
+}
+
+/// Whether a request failing with `err` is sent again, which is when the connection was closed
+/// or a new one couldn't be made
+fn is_retryable(err: &KvError) -> bool {
+    match &**err {
+        ErrorCode::ConnectionClosed => true,
+        ErrorCode::NetworkError(e) => e.kind() == io::ErrorKind::ConnectionRefused,
+        _ => false,
+    }
+}
+
+/// Map an error of the connection into an internal error, but a closed connection or a
+/// timeout, which the caller may retry
+fn rpc_error(err: KvError) -> KvError {
+    if matches!(*err, ErrorCode::ConnectionClosed | ErrorCode::Timeout) {
+        err
+    } else {
+        ErrorCode::InternalError(err.to_string()).into()
+    }
+}
+
+/// Map a response which doesn't match the request into an error
+fn unexpected_response(res: KvsResponse) -> KvError {
+    match res {
+        KvsResponse::Error(err) => err.into(),
+        KvsResponse::Redirect { addr } => ErrorCode::Redirect { addr }.into(),
+        msg => ErrorCode::UnexpectedResponse(format!("{:?}", msg)).into(),
+    }
 }
@@ -1,15 +1,19 @@
 use std::{
     fmt::Display,
-    io::{Read, Write},
-    net::{Ipv4Addr, TcpStream},
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Read, Write},
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
     str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use log::{debug, warn};
 use serde_derive::{Deserialize, Serialize};
 
-use crate::error::ErrorCode;
+use crate::engine::StoreStats;
 use crate::error::Result;
+use crate::error::{ErrorCode, KvError};
 
 #[derive(Clone, Debug)]
 pub struct Ipv4Port {
@@ -51,19 +55,341 @@ impl FromStr for Ipv4Port {
 }
 
 // todo: 自动映射
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum KvsRequest {
-    Set { key: String, value: String },
-    Rm { key: String },
-    Get { key: String },
+    Set {
+        key: String,
+        value: String,
+    },
+    Rm {
+        key: String,
+    },
+    Get {
+        key: String,
+    },
+    LastModified {
+        key: String,
+    },
+    /// Whether `key` exists, without sending its value
+    Contains {
+        key: String,
+    },
+    /// Set `key` to `new` if its value is `expected`, `None` for a key which doesn't exist
+    Cas {
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    },
+    /// Add `delta` to the integer value of `key`
+    Incr {
+        key: String,
+        delta: i64,
+    },
+    /// Statistics of the data kept by the engine
+    Stats,
+    /// Get the value of `key` and remove it at once
+    GetDelete {
+        key: String,
+    },
+    /// Get the values of all `keys` at once
+    GetMulti {
+        keys: Vec<String>,
+    },
+    /// Apply the requests in order, answered by a response for each of them
+    Batch(Vec<KvsRequest>),
+    /// Skip `req` if it is not started before `deadline`, in milliseconds since the unix epoch
+    Deadline {
+        deadline: u64,
+        req: Box<KvsRequest>,
+    },
+    /// The live keys starting with `prefix`, all of them for `None`, after the key `after`.
+    /// At most `MAX_KEYS_PER_RESPONSE` of them are answered at once.
+    Keys {
+        prefix: Option<String>,
+        after: Option<String>,
+    },
+    /// A liveness probe, answered by `KvsResponse::Pong` without touching the engine
+    Ping,
+}
+
+impl KvsRequest {
+    /// The keys this request operates on
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            KvsRequest::Set { key, .. }
+            | KvsRequest::Rm { key }
+            | KvsRequest::Get { key }
+            | KvsRequest::LastModified { key }
+            | KvsRequest::Contains { key }
+            | KvsRequest::Cas { key, .. }
+            | KvsRequest::Incr { key, .. }
+            | KvsRequest::GetDelete { key } => vec![key],
+            KvsRequest::GetMulti { keys } => keys.iter().map(String::as_str).collect(),
+            KvsRequest::Batch(reqs) => reqs.iter().flat_map(KvsRequest::keys).collect(),
+            KvsRequest::Stats | KvsRequest::Keys { .. } | KvsRequest::Ping => vec![],
+            KvsRequest::Deadline { req, .. } => req.keys(),
+        }
+    }
+
+    /// Name of the operation, used to label metrics
+    pub fn op(&self) -> &'static str {
+        match self {
+            KvsRequest::Set { .. } => "set",
+            KvsRequest::Rm { .. } => "rm",
+            KvsRequest::Get { .. } => "get",
+            KvsRequest::LastModified { .. } => "last_modified",
+            KvsRequest::Contains { .. } => "contains",
+            KvsRequest::Cas { .. } => "cas",
+            KvsRequest::Incr { .. } => "incr",
+            KvsRequest::Stats => "stats",
+            KvsRequest::GetDelete { .. } => "get_delete",
+            KvsRequest::GetMulti { .. } => "get_multi",
+            KvsRequest::Batch(_) => "batch",
+            KvsRequest::Deadline { req, .. } => req.op(),
+            KvsRequest::Keys { .. } => "keys",
+            KvsRequest::Ping => "ping",
+        }
+    }
+}
+
+/// Whether `err` means the other side has closed the connection
+fn is_closed(err: &KvError) -> bool {
+    match &**err {
+        ErrorCode::NetworkError(e) => matches!(
+            e.kind(),
+            ErrorKind::UnexpectedEof
+                | ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe
+        ),
+        _ => false,
+    }
+}
+
+/// Whether `err` means a read or a write on the connection timed out
+pub(crate) fn is_timeout(err: &KvError) -> bool {
+    match &**err {
+        // a timed out read fails with `WouldBlock` on unix
+        ErrorCode::NetworkError(e) => {
+            matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+        }
+        _ => false,
+    }
+}
+
+/// Milliseconds since the unix epoch of `time`
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+/// An advisory lock of a data directory, held on a file in it until dropped. Only unix
+/// systems are locked.
+#[derive(Debug)]
+pub struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Lock `dir` by the file `name` in it, which is created if missing
+    ///
+    /// # Errors
+    ///
+    /// It fails with `ErrorCode::DirectoryLocked` if another lock of the file is held, even by
+    /// this process.
+    pub fn exclusive(dir: &Path, name: &str) -> Result<DirLock> {
+        let path = dir.join(name);
+        let file = OpenOptions::new().create(true).write(true).open(&path)?;
+        DirLock::lock(file, path, true)
+    }
+
+    /// Lock `dir` by the file `name` in it along with other shared locks, without writing to
+    /// the directory. `None` if the file is missing, as nothing has ever locked `dir`.
+    ///
+    /// # Errors
+    ///
+    /// It fails with `ErrorCode::DirectoryLocked` if an exclusive lock of the file is held.
+    pub fn shared(dir: &Path, name: &str) -> Result<Option<DirLock>> {
+        let path = dir.join(name);
+        match File::open(&path) {
+            Ok(file) => DirLock::lock(file, path, false).map(Some),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    fn lock(file: File, path: PathBuf, exclusive: bool) -> Result<DirLock> {
+        #[cfg(unix)]
+        {
+            use nix::fcntl::{flock, FlockArg};
+            use std::os::unix::io::AsRawFd;
+
+            let arg = if exclusive {
+                FlockArg::LockExclusiveNonblock
+            } else {
+                FlockArg::LockSharedNonblock
+            };
+            match flock(file.as_raw_fd(), arg) {
+                Ok(()) => {}
+                Err(nix::errno::Errno::EWOULDBLOCK) => {
+                    return Err(ErrorCode::DirectoryLocked { path }.into())
+                }
+                Err(e) => return Err(std::io::Error::from(e).into()),
+            }
+        }
+        Ok(DirLock { _file: file })
+    }
 }
 
 // todo: 自动映射
 #[derive(Serialize, Deserialize, Debug)]
 pub enum KvsResponse {
-    Set(core::result::Result<(), String>),
-    Rm(core::result::Result<(), String>),
-    Get(core::result::Result<Option<String>, String>),
+    Set(core::result::Result<(), RemoteError>),
+    Rm(core::result::Result<(), RemoteError>),
+    Get(core::result::Result<Option<String>, RemoteError>),
+    LastModified(core::result::Result<Option<u64>, RemoteError>),
+    Contains(core::result::Result<bool, RemoteError>),
+    /// Whether the value was swapped
+    Cas(core::result::Result<bool, RemoteError>),
+    /// The value after the increment
+    Incr(core::result::Result<i64, RemoteError>),
+    Stats(core::result::Result<StoreStats, RemoteError>),
+    GetDelete(core::result::Result<Option<String>, RemoteError>),
+    /// The values in the order of the requested keys
+    GetMulti(core::result::Result<Vec<Option<String>>, RemoteError>),
+    /// The responses in the order of the batched requests
+    Batch(Vec<KvsResponse>),
+    Keys(core::result::Result<KeysPage, RemoteError>),
+    /// The answer to a `KvsRequest::Ping`
+    Pong,
+    /// The server rejects a request without a response of its own type
+    Error(RemoteError),
+    /// The key of the request belongs to the server at `addr`
+    Redirect {
+        addr: SocketAddr,
+    },
+}
+
+/// A page of the keys answering a `KvsRequest::Keys`
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct KeysPage {
+    /// keys in key order
+    pub keys: Vec<String>,
+    /// continue after this key for the rest of the keys, `None` if there are no more
+    pub next: Option<String>,
+}
+
+/// An error of the server sent to the client, which keeps the errors a client acts on apart
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RemoteError {
+    KeyNotFound,
+    /// The value of `key` can't be incremented
+    NotAnInteger {
+        key: String,
+    },
+    /// The server had no room for the connection
+    ServerBusy,
+    /// The store of the server was opened read-only
+    ReadOnly,
+    /// The key of a write exceeds the limit of the server
+    KeyTooLarge {
+        size: usize,
+        limit: usize,
+    },
+    /// The value of a write exceeds the limit of the server
+    ValueTooLarge {
+        size: usize,
+        limit: usize,
+    },
+    /// A request or a response which couldn't be serialized or deserialized, by its message
+    SerDe(String),
+    /// Any other error, by its message
+    Internal(String),
+    /// The response exceeds the budget of the server
+    ResponseTooLarge {
+        size: usize,
+        limit: usize,
+    },
+    /// A frame exceeds the limit of the server
+    FrameTooLarge {
+        size: usize,
+        limit: usize,
+    },
+    /// The deadline of the request passed before it started
+    DeadlineExceeded,
+    /// The key belongs to the shard at `addr`
+    Redirect {
+        addr: SocketAddr,
+    },
+}
+
+impl From<KvError> for RemoteError {
+    fn from(err: KvError) -> Self {
+        match &*err {
+            ErrorCode::KeyNotFound => RemoteError::KeyNotFound,
+            ErrorCode::NotAnInteger { key } => RemoteError::NotAnInteger { key: key.clone() },
+            ErrorCode::ServerBusy => RemoteError::ServerBusy,
+            ErrorCode::ReadOnly => RemoteError::ReadOnly,
+            &ErrorCode::KeyTooLarge { size, limit } => RemoteError::KeyTooLarge { size, limit },
+            &ErrorCode::ValueTooLarge { size, limit } => RemoteError::ValueTooLarge { size, limit },
+            &ErrorCode::ResponseTooLarge { size, limit } => {
+                RemoteError::ResponseTooLarge { size, limit }
+            }
+            &ErrorCode::FrameTooLarge { size, limit } => RemoteError::FrameTooLarge { size, limit },
+            ErrorCode::DeadlineExceeded => RemoteError::DeadlineExceeded,
+            &ErrorCode::Redirect { addr } => RemoteError::Redirect { addr },
+            ErrorCode::SerDeError(_) => RemoteError::SerDe(err.to_string()),
+            _ => RemoteError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<RemoteError> for KvError {
+    fn from(err: RemoteError) -> Self {
+        match err {
+            RemoteError::KeyNotFound => ErrorCode::KeyNotFound.into(),
+            RemoteError::NotAnInteger { key } => ErrorCode::NotAnInteger { key }.into(),
+            RemoteError::ServerBusy => ErrorCode::ServerBusy.into(),
+            RemoteError::ReadOnly => ErrorCode::ReadOnly.into(),
+            RemoteError::KeyTooLarge { size, limit } => {
+                ErrorCode::KeyTooLarge { size, limit }.into()
+            }
+            RemoteError::ValueTooLarge { size, limit } => {
+                ErrorCode::ValueTooLarge { size, limit }.into()
+            }
+            RemoteError::SerDe(msg) => ErrorCode::SerDeError(serde::de::Error::custom(msg)).into(),
+            RemoteError::Internal(msg) => ErrorCode::InternalError(msg).into(),
+            RemoteError::ResponseTooLarge { size, limit } => {
+                ErrorCode::ResponseTooLarge { size, limit }.into()
+            }
+            RemoteError::FrameTooLarge { size, limit } => {
+                ErrorCode::FrameTooLarge { size, limit }.into()
+            }
+            RemoteError::DeadlineExceeded => ErrorCode::DeadlineExceeded.into(),
+            RemoteError::Redirect { addr } => ErrorCode::Redirect { addr }.into(),
+        }
+    }
+}
+
+impl KvsResponse {
+    /// Whether the request failed, a redirect is not a failure
+    pub fn is_err(&self) -> bool {
+        match self {
+            KvsResponse::Set(res) => res.is_err(),
+            KvsResponse::Rm(res) => res.is_err(),
+            KvsResponse::Get(res) | KvsResponse::GetDelete(res) => res.is_err(),
+            KvsResponse::LastModified(res) => res.is_err(),
+            KvsResponse::Contains(res) | KvsResponse::Cas(res) => res.is_err(),
+            KvsResponse::Incr(res) => res.is_err(),
+            KvsResponse::Stats(res) => res.is_err(),
+            KvsResponse::GetMulti(res) => res.is_err(),
+            KvsResponse::Keys(res) => res.is_err(),
+            KvsResponse::Batch(responses) => responses.iter().any(KvsResponse::is_err),
+            KvsResponse::Error(_) => true,
+            KvsResponse::Redirect { .. } | KvsResponse::Pong => false,
+        }
+    }
 }
 
 pub trait Service<Req, Res>
@@ -73,10 +399,42 @@ where
 {
     fn handle(&mut self, req: Req) -> Res;
 
-    /// This is for Server
-    fn response(&mut self, stream: &mut TcpStream) -> Result<bool> {
-        handle_receive::<Req>(stream)?.map_or(Ok(false), |req| {
-            handle_send(stream, &(self.handle(req)))?;
+    /// Build a response rejecting the request with `err`
+    fn handle_error(&mut self, err: KvError) -> Res;
+
+    /// This is for Server, over any transport
+    ///
+    /// A response serialized larger than `budget` bytes, or than a frame can hold, is replaced by
+    /// an error.
+    fn response<S: Read + Write>(
+        &mut self,
+        stream: &mut S,
+        budget: Option<usize>,
+        codec: Codec,
+    ) -> Result<bool> {
+        let req = match handle_receive_counted::<Req>(stream, &mut IoStats::default(), codec) {
+            // the whole frame has been read, so the connection can go on after a bad request
+            Err(e) if matches!(*e, ErrorCode::SerDeError(_)) => {
+                warn!("Reject malformed request: {}", e);
+                handle_send_with_budget(stream, &self.handle_error(e), None, codec)?;
+                return Ok(true);
+            }
+            req => req?,
+        };
+        req.map_or(Ok(false), |req| {
+            let res = self.handle(req);
+            match handle_send_with_budget(stream, &res, budget, codec) {
+                Err(e)
+                    if matches!(
+                        *e,
+                        ErrorCode::ResponseTooLarge { .. } | ErrorCode::FrameTooLarge { .. }
+                    ) =>
+                {
+                    warn!("Abort response: {}", e);
+                    handle_send_with_budget(stream, &self.handle_error(e), None, codec)?;
+                }
+                res => res?,
+            }
             Ok(true)
         })
     }
@@ -87,45 +445,299 @@ where
     Req: serde::ser::Serialize + serde::de::DeserializeOwned,
     Res: serde::ser::Serialize + serde::de::DeserializeOwned,
 {
-    /// This is for client
-    fn request(stream: &mut TcpStream, req: &Req) -> Result<Res> {
-        handle_send(stream, req)?;
-        handle_receive::<Res>(stream)?.ok_or(
-            ErrorCode::NetworkError(std::io::Error::from(std::io::ErrorKind::ConnectionAborted))
-                .into(),
-        )
+    /// This is for client over any transport, the bytes of the request and the response are added to `io`
+    ///
+    /// A connection closed by the server before the whole response fails with
+    /// `ErrorCode::ConnectionClosed`, and a read or write timing out with `ErrorCode::Timeout`.
+    fn request<S: Read + Write>(
+        stream: &mut S,
+        req: &Req,
+        io: &mut IoStats,
+        codec: Codec,
+    ) -> Result<Res> {
+        let res = handle_send_counted(stream, req, io, codec)
+            .and_then(|_| handle_receive_counted::<Res>(stream, io, codec));
+        match res {
+            Ok(Some(res)) => Ok(res),
+            Ok(None) => Err(ErrorCode::ConnectionClosed.into()),
+            Err(e) if is_closed(&e) => Err(ErrorCode::ConnectionClosed.into()),
+            Err(e) if is_timeout(&e) => Err(ErrorCode::Timeout.into()),
+            Err(e) => Err(e),
+        }
     }
 }
 
-pub fn handle_send<T>(stream: &mut TcpStream, value: &T) -> crate::error::Result<()>
+/// The max bytes of a serialized request or response. The length is sent as a `u32`, but a
+/// larger frame is refused so that a corrupted prefix can't make the receiver allocate gigabytes.
+pub const MAX_FRAME_LEN: usize = 16 << 20;
+
+/// The max keys of a response to `KvsRequest::Keys`, the rest are left to the next requests
+pub const MAX_KEYS_PER_RESPONSE: usize = 1000;
+
+/// Bytes of the length prefix in front of every frame.
+///
+/// It used to be a `u16`, which capped values near 64KB; peers on the old protocol can't talk
+/// to this one.
+pub const LEN_PREFIX: usize = 4;
+
+/// How the frames of a connection are serialized
+///
+/// A client asks for a codec by sending `HANDSHAKE` and the byte of the codec when it connects,
+/// and the server answers with `HANDSHAKE` and the byte of the codec it agrees on, JSON if it
+/// doesn't know the one asked for. A client starting with a frame right away speaks JSON.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Json = 0,
+    /// MessagePack, which is more compact than JSON and faster to parse
+    MsgPack = 1,
+}
+
+impl Codec {
+    /// The codec of a handshake `byte`, `None` if it is unknown
+    pub fn from_byte(byte: u8) -> Option<Codec> {
+        match byte {
+            0 => Some(Codec::Json),
+            1 => Some(Codec::MsgPack),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn serialize<T>(self, value: &T) -> Result<Vec<u8>>
+    where
+        T: serde::ser::Serialize,
+    {
+        Ok(match self {
+            Codec::Json => serde_json::to_vec(value)?,
+            Codec::MsgPack => crate::msgpack::to_vec(value)?,
+        })
+    }
+
+    fn deserialize<T>(self, payload: &[u8]) -> Result<T>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        Ok(match self {
+            Codec::Json => serde_json::from_slice(payload)?,
+            Codec::MsgPack => crate::msgpack::from_slice(payload)?,
+        })
+    }
+}
+
+/// Starts a handshake. A frame never starts with it, as the first byte of its length prefix is
+/// at most 1 below `MAX_FRAME_LEN`.
+pub const HANDSHAKE: u8 = 0xFE;
+
+/// A stream with a byte read off it put back in front of the rest
+pub(crate) struct Unread<S> {
+    first: Option<u8>,
+    stream: S,
+}
+
+impl<S> Unread<S> {
+    pub(crate) fn new(first: Option<u8>, stream: S) -> Unread<S> {
+        Unread { first, stream }
+    }
+}
+
+impl<S: Read> Read for Unread<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !buf.is_empty()
+            && let Some(first) = self.first.take()
+        {
+            buf[0] = first;
+            return Ok(1);
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Write> Write for Unread<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Agree on the codec of a new connection on the server side, see `Codec`. Returns the codec
+/// with the stream to read the frames from, `None` if the client closed without sending
+/// anything.
+pub(crate) fn accept_codec<S: Read + Write>(mut stream: S) -> Result<Option<(Codec, Unread<S>)>> {
+    let mut first = [0_u8; 1];
+    match stream.read_exact(&mut first) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    }
+    if first[0] != HANDSHAKE {
+        return Ok(Some((Codec::Json, Unread::new(Some(first[0]), stream))));
+    }
+
+    let mut asked = [0_u8; 1];
+    stream.read_exact(&mut asked)?;
+    let codec = Codec::from_byte(asked[0]).unwrap_or_else(|| {
+        warn!("Unknown codec {}, fall back to JSON", asked[0]);
+        Codec::Json
+    });
+    stream.write_all(&[HANDSHAKE, codec as u8])?;
+    Ok(Some((codec, Unread::new(None, stream))))
+}
+
+/// Bytes a connection has sent and received, including the length prefixes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+pub fn handle_send<T>(stream: &mut impl Write, value: &T) -> crate::error::Result<()>
 where
     T: serde::ser::Serialize,
 {
-    let b_value = serde_json::to_vec(&value)?;
-    if b_value.len() > u16::MAX as usize {
-        return Err(ErrorCode::InternalError("valid len for send".to_string()).into());
-    }
+    handle_send_counted(stream, value, &mut IoStats::default(), Codec::Json)
+}
+
+/// Same as `handle_send` with `codec`, and add the bytes of the frame to `io`
+pub fn handle_send_counted<T>(
+    stream: &mut impl Write,
+    value: &T,
+    io: &mut IoStats,
+    codec: Codec,
+) -> crate::error::Result<()>
+where
+    T: serde::ser::Serialize,
+{
+    let frame = codec.encode_frame(value, None)?;
+    stream.write_all(&frame)?;
+    io.bytes_sent += frame.len() as u64;
+    Ok(())
+}
 
-    stream.write_all(&(b_value.len() as u16).to_be_bytes())?;
-    stream.write_all(&b_value)?;
+/// Same as `handle_send` with `codec`, but refuse to send anything if the serialized value is
+/// larger than `budget`
+pub fn handle_send_with_budget<T>(
+    stream: &mut impl Write,
+    value: &T,
+    budget: Option<usize>,
+    codec: Codec,
+) -> crate::error::Result<()>
+where
+    T: serde::ser::Serialize,
+{
+    stream.write_all(&codec.encode_frame(value, budget)?)?;
     Ok(())
 }
 
-pub fn handle_receive<T>(stream: &mut TcpStream) -> crate::error::Result<Option<T>>
+/// Serialize `value` with its length in front, so that it is written at once and nothing is
+/// written if the frame is invalid.
+///
+/// With `frame_len` and `decode_frame`, it lets a transport other than a blocking stream, e.g.
+/// an async one, speak the protocol. Frames are JSON, see `Codec::encode_frame` for the others.
+pub fn encode_frame<T>(value: &T, budget: Option<usize>) -> crate::error::Result<Vec<u8>>
+where
+    T: serde::ser::Serialize,
+{
+    Codec::Json.encode_frame(value, budget)
+}
+
+impl Codec {
+    /// Same as `encode_frame`, serialized with this codec
+    pub fn encode_frame<T>(self, value: &T, budget: Option<usize>) -> Result<Vec<u8>>
+    where
+        T: serde::ser::Serialize,
+    {
+        let b_value = self.serialize(value)?;
+        if let Some(limit) = budget
+            && b_value.len() > limit
+        {
+            return Err(ErrorCode::ResponseTooLarge {
+                size: b_value.len(),
+                limit,
+            }
+            .into());
+        }
+        if b_value.len() > MAX_FRAME_LEN {
+            return Err(ErrorCode::FrameTooLarge {
+                size: b_value.len(),
+                limit: MAX_FRAME_LEN,
+            }
+            .into());
+        }
+
+        let mut frame = Vec::with_capacity(LEN_PREFIX + b_value.len());
+        frame.extend_from_slice(&(b_value.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&b_value);
+        Ok(frame)
+    }
+
+    /// Same as `decode_frame`, deserialized with this codec
+    pub fn decode_frame<T>(self, payload: &[u8]) -> Result<T>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        self.deserialize(payload)
+    }
+}
+
+pub fn handle_receive<T>(stream: &mut impl Read) -> crate::error::Result<Option<T>>
+where
+    T: serde::de::DeserializeOwned,
+{
+    handle_receive_counted(stream, &mut IoStats::default(), Codec::Json)
+}
+
+/// Same as `handle_receive` with `codec`, and add the bytes of the frame to `io`
+pub fn handle_receive_counted<T>(
+    stream: &mut impl Read,
+    io: &mut IoStats,
+    codec: Codec,
+) -> crate::error::Result<Option<T>>
 where
     T: serde::de::DeserializeOwned,
 {
-    let mut b_len = [0_u8; 2];
-    match stream.read(&mut b_len) {
-        Err(e) => return Err(e.into()),
-        Ok(0) => {
+    // the length may arrive in several reads, only an EOF before its first byte is a clean close
+    let mut b_len = [0_u8; LEN_PREFIX];
+    match stream.read_exact(&mut b_len[..1]) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
             // 因为这里无法区分是异常关闭还是正常 try去拉去数据导致的关闭，所以记录debug日志
             debug!("Another side close socket");
             return Ok(None);
         }
-        _ => (),
+        res => res?,
+    }
+    stream.read_exact(&mut b_len[1..])?;
+
+    // there is no way to find the next frame after a bogus length, so the connection is given up
+    let len = frame_len(b_len)?;
+
+    // read the whole frame before parsing, so the stream stays at a frame boundary even if
+    // the payload is malformed
+    let mut b_value = vec![0_u8; len];
+    stream.read_exact(&mut b_value)?;
+    io.bytes_received += (LEN_PREFIX + len) as u64;
+    codec.decode_frame(&b_value)
+}
+
+/// The bytes of the payload following the length `prefix` of a frame. Fails with
+/// `ErrorCode::FrameTooLarge` for a length over `MAX_FRAME_LEN`.
+pub fn frame_len(prefix: [u8; LEN_PREFIX]) -> crate::error::Result<usize> {
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(ErrorCode::FrameTooLarge {
+            size: len,
+            limit: MAX_FRAME_LEN,
+        }
+        .into());
     }
+    Ok(len)
+}
 
-    let cmd = serde_json::from_reader(stream.take(u16::from_be_bytes(b_len) as u64))?;
-    Ok(cmd)
+/// Deserialize the JSON payload of a frame, without its length prefix
+pub fn decode_frame<T>(payload: &[u8]) -> crate::error::Result<T>
+where
+    T: serde::de::DeserializeOwned,
+{
+    Codec::Json.decode_frame(payload)
 }
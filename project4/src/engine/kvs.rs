@@ -1,27 +1,40 @@
 use std::borrow::BorrowMut;
-use std::cell::RefCell;
-use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{hash_map, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::mem;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::ops::{Range, RangeBounds};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex, RwLock};
-use std::thread::{spawn, JoinHandle};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
 
+use arc_swap::ArcSwap;
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender, TryRecvError};
 use crossbeam_skiplist::map::Entry;
 use crossbeam_skiplist::SkipMap;
-use log::warn;
+use log::{debug, error, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 
-use super::KvsEngine;
-use crate::error::ErrorCode;
+use super::{
+    check_sizes, import_dump, incremented, JsonRecord, KvsEngine, MemoryReport, StoreStats,
+};
+use crate::common::{unix_millis, DirLock};
+use crate::engine::lz4;
+use crate::error::{ErrorCode, KvError};
+use crate::thread_pool::{NaiveThreadPool, ThreadPool};
 use crate::Result;
 use std::ffi::OsStr;
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 const READER_CLEAN_THRESHOLD: u64 = 1024;
+const MAX_OPEN_FILES: usize = 256;
+const LOCK_FILE: &str = "LOCK";
 
 /// The `KvStore` stores string key/value pairs.
 ///
@@ -29,12 +42,16 @@ const READER_CLEAN_THRESHOLD: u64 = 1024;
 /// monotonically increasing generation numbers with a `log` extension name.
 /// A `BTreeMap` in memory stores the keys and the value locations for fast query.
 ///
+/// Reads only take a read lock of the store and read the logs at their positions, so they run
+/// concurrently with each other and only wait for writes. Compactions run on a thread of the
+/// store, which rewrites the logs without holding the lock.
+///
 /// ```rust
 /// # use kvs::{KvStore, Result};
 /// # fn try_main() -> Result<()> {
 /// use std::env::current_dir;
 /// use kvs::KvsEngine;
-/// let mut store = KvStore::open(current_dir()?)?;
+/// let mut store = KvStore::open(&current_dir()?)?;
 /// store.set("key".to_owned(), "value".to_owned())?;
 /// let val = store.get("key".to_owned())?;
 /// assert_eq!(val, Some("value".to_owned()));
@@ -44,11 +61,173 @@ const READER_CLEAN_THRESHOLD: u64 = 1024;
 #[derive(Clone)]
 pub struct KvStore {
     inner: Arc<RwLock<SharedKvStore>>,
+    // dropped after `inner`, so the compactor stops once the last clone is dropped
+    _compactor: Arc<Compactor>,
+}
+
+/// The result of validating the live records of a `KvStore`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ValidationReport {
+    /// number of live records checked
+    pub checked: u64,
+    /// keys whose record doesn't read back as the same `Set` command, in key order
+    pub corrupted: Vec<String>,
+}
+
+/// A write of `KvStore::write_batch`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BatchOp {
+    Set { key: String, value: String },
+    Remove { key: String },
+}
+
+/// What a compaction of a `KvStore` would do, estimated without rewriting anything
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactionEstimate {
+    /// bytes of stale commands the compaction would remove
+    pub reclaimable_bytes: u64,
+    /// bytes of the live commands
+    pub live_bytes: u64,
+    /// bytes of all logs now, `current_bytes - reclaimable_bytes` is left after the compaction
+    pub current_bytes: u64,
+}
+
+/// Options to open a `KvStore`
+#[derive(Clone, Debug)]
+pub struct KvStoreOpts {
+    /// Generations whose number is less than `compaction_min_age` behind the active log are
+    /// still hot and likely to be overwritten soon, so compaction leaves them as they are until
+    /// they age out. `0` compacts all generations.
+    pub compaction_min_age: u64,
+    /// Hint the kernel to read ahead each log replayed during open. It only takes effect
+    /// with the `fadvise` feature on Linux.
+    pub readahead_hint: bool,
+    /// Returns the current time in milliseconds since the unix epoch, which is stored with
+    /// every write. Tests can replace it with a mock clock.
+    pub clock: fn() -> u64,
+    /// Read back every record rewritten by a compaction and check it before the compaction is
+    /// committed. A failed check aborts the compaction, leaving the old logs in place.
+    pub verify_compaction: bool,
+    /// Values of at most this many bytes are also kept in the index, so getting them reads
+    /// nothing from the log. `None` keeps all values in the log only.
+    pub inline_value_max: Option<usize>,
+    /// Name new logs after the generation number zero-padded to a fixed width, e.g.
+    /// `00000000000000000010.log`, so that directory listings are in generation order.
+    /// Logs named either way are read.
+    pub padded_gen_names: bool,
+    /// Sync the log to the disk after every write, so a write which returned survives a power
+    /// loss. Otherwise writes only reach the page cache of the OS. Each write then waits for
+    /// the disk, which costs a lot of throughput, especially on spinning disks.
+    pub sync: bool,
+    /// Compact in the background once the stale records in the logs take more than this many
+    /// bytes. It only rewrites the logs with the most stale records, which hold at least half
+    /// of them, while `compact` rewrites all of them. `u64::MAX` leaves compaction to explicit
+    /// `compact` calls.
+    pub compaction_threshold: u64,
+    /// The encoding of the commands written to the logs. Logs are read whatever encoding
+    /// they were written with, so it can be changed between opens.
+    pub codec: LogCodec,
+    /// Compress the values of new `Set` commands, which are then written in binary whatever
+    /// the codec. A value which doesn't get smaller is written as it is, so logs mix compressed
+    /// and plain commands, and are read whatever compression they were written with.
+    pub compression: Option<Compression>,
+    /// Roll over to a new log once the active one reaches this many bytes, so no log grows
+    /// without bound until a compaction. Every roll also ages the older logs by a generation
+    /// for `compaction_min_age`. `None` keeps writing to one log until the next compaction.
+    pub max_log_size: Option<u64>,
+    /// Writes of a key longer than this many bytes fail with `ErrorCode::KeyTooLarge` before
+    /// anything is written. `None` means no limit.
+    pub max_key_size: Option<usize>,
+    /// Writes of a value longer than this many bytes fail with `ErrorCode::ValueTooLarge`
+    /// before anything is written. `None` means no limit.
+    pub max_value_size: Option<usize>,
+    /// Keep the values of up to this many keys read by `get` in memory, so reading a hot key
+    /// again doesn't go to the log. The least recently read value is evicted first, and a
+    /// write drops the value of its key. `None` reads every value from the log.
+    pub value_cache_capacity: Option<usize>,
+    /// Write every record with a CRC32 of its bytes, which is checked whenever it is read.
+    /// A record of the newest log failing its check at the end of the log was torn by a crash
+    /// during the write, so `open` cuts it off; anywhere else it fails with
+    /// `ErrorCode::ChecksumMismatch`. Logs are read whether they were written with checksums
+    /// or not. On by default.
+    pub checksums: bool,
+}
+
+impl KvStoreOpts {
+    /// Path of a new log of generation `gen` in `dir`
+    fn log_path(&self, dir: &Path, gen: u64) -> PathBuf {
+        if self.padded_gen_names {
+            padded_log_path(dir, gen)
+        } else {
+            log_path(dir, gen)
+        }
+    }
+
+    /// Encode `cmd` for the log, with its value compressed if that makes it smaller
+    fn encode(&self, cmd: &Command) -> Result<Vec<u8>> {
+        let compressed = self
+            .compression
+            .and_then(|compression| compression.encode(cmd));
+        let record = match compressed {
+            Some(record) => record,
+            None => self.codec.encode(cmd)?,
+        };
+        Ok(self.frame(record))
+    }
+
+    /// Prefix `record` with its checksum, if the options ask for them
+    fn frame(&self, record: Vec<u8>) -> Vec<u8> {
+        if self.checksums {
+            encode_checked_record(&record)
+        } else {
+            record
+        }
+    }
+
+    /// Check a write of `key` against the size limits
+    fn check_sizes(&self, key: &str, value_len: usize) -> Result<()> {
+        check_sizes(key, value_len, self.max_key_size, self.max_value_size)
+    }
+
+    /// Returns the value to keep in the index, if it is small enough
+    fn inline(&self, value: String) -> Option<String> {
+        self.inline_value_max
+            .filter(|&max| value.len() <= max)
+            .map(|_| value)
+    }
+}
+
+impl Default for KvStoreOpts {
+    fn default() -> Self {
+        Self {
+            compaction_min_age: 0,
+            readahead_hint: true,
+            clock: system_clock,
+            verify_compaction: false,
+            inline_value_max: None,
+            padded_gen_names: false,
+            sync: false,
+            compaction_threshold: COMPACTION_THRESHOLD,
+            codec: LogCodec::Json,
+            compression: None,
+            max_log_size: None,
+            max_key_size: None,
+            max_value_size: None,
+            value_cache_capacity: None,
+            checksums: true,
+        }
+    }
+}
+
+fn system_clock() -> u64 {
+    unix_millis(SystemTime::now())
 }
 
 pub struct SharedKvStore {
     // directory for the log and other data
     path: PathBuf,
+    // options the store was opened with
+    opts: KvStoreOpts,
     // map generation number to the file reader
     readers: HashMap<u64, BufReaderWithPos<File>>,
     // writer of the current log
@@ -58,16 +237,100 @@ pub struct SharedKvStore {
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction
     uncompacted: u64,
+    // the stale bytes in each log, which add up to `uncompacted`
+    stale: HashMap<u64, u64>,
+    // bytes appended by writes, and compactions run, since the store was opened
+    bytes_written: u64,
+    compactions: u64,
+    // wakes the compactor up, which stops once this is dropped
+    compaction_requests: Sender<()>,
+    // held by the compaction in progress
+    compacting: Arc<Mutex<()>>,
+    // recently read values, only locked by readers as writers have the store to themselves
+    cache: Option<Mutex<ValueCache>>,
+    // values read from the logs since the store was opened
+    disk_reads: AtomicU64,
+    // keeps other stores from opening the directory until the last clone is dropped
+    _lock: DirLock,
 }
 
-#[derive(Clone)]
-pub struct ReadLockFreeKvStore {
+/// The values of the keys read last, which evicts the least recently read one when full
+struct ValueCache {
+    capacity: usize,
+    // key to its value and the tick it was last read at
+    values: HashMap<String, (String, u64)>,
+    // keys by the tick they were last read at, the least recent first
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl ValueCache {
+    fn new(capacity: usize) -> Self {
+        ValueCache {
+            capacity,
+            values: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Returns the value of `key` if it is cached, making it the most recently read
+    fn get(&mut self, key: &str) -> Option<String> {
+        let (value, last_read) = self.values.get_mut(key)?;
+        self.tick += 1;
+        let key = self.recency.remove(last_read).expect("key not in recency");
+        *last_read = self.tick;
+        self.recency.insert(self.tick, key);
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: String, value: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.invalidate(&key);
+        if self.values.len() >= self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.values.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key.clone());
+        self.values.insert(key, (value, self.tick));
+    }
+
+    fn invalidate(&mut self, key: &str) {
+        if let Some((_, last_read)) = self.values.remove(key) {
+            self.recency.remove(&last_read);
+        }
+    }
+}
+
+/// A `KvStore` whose reads never wait for writes or compactions.
+///
+/// Compactions run as jobs of the pool `P`, which can be shared by many stores to cap the
+/// number of concurrent compactions. The default spawns a thread per compaction.
+///
+/// All clones of a store read through the same capped set of open log files, so the number
+/// of file descriptors doesn't grow with the number of connections.
+pub struct ReadLockFreeKvStore<P = NaiveThreadPool> {
     path: Arc<PathBuf>,
     reader: SharedReader,
-    writer: Arc<Mutex<SharedWriter>>,
+    writer: Arc<Mutex<SharedWriter<P>>>,
     index: Arc<HierarchicalIndex>,
 }
 
+impl<P> Clone for ReadLockFreeKvStore<P> {
+    fn clone(&self) -> Self {
+        Self {
+            path: Arc::clone(&self.path),
+            reader: self.reader.clone(),
+            writer: Arc::clone(&self.writer),
+            index: Arc::clone(&self.index),
+        }
+    }
+}
+
 /// Load the whole log file and store value locations in the index map.
 ///
 /// Returns how many bytes can be saved after a compaction.
@@ -83,8 +346,12 @@ fn rebuild_index(
     while let Some(cmd) = stream.next() {
         let new_pos = stream.byte_offset() as u64;
         match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.insert(key, (gen, pos..new_pos).into()) {
+            Command::Set { key, ts, .. } => {
+                let cmd_pos = CommandPos {
+                    ts,
+                    ..(gen, pos..new_pos).into()
+                };
+                if let Some(old_cmd) = index.insert(key, cmd_pos) {
                     uncompacted += old_cmd.len;
                 }
             }
@@ -102,19 +369,26 @@ fn rebuild_index(
     Ok(uncompacted)
 }
 
-impl KvsEngine for ReadLockFreeKvStore {
-    fn open(path: &Path) -> Result<Self>
-    where
-        Self: Sized,
-    {
+impl<P: ThreadPool + Sync> ReadLockFreeKvStore<P> {
+    /// Opens a store at `path`, running its compactions in `pool` and keeping at most
+    /// `max_open_files` logs open for reads. A read waits when all of them are in use.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open_with_pool(path: &Path, pool: Arc<P>, max_open_files: usize) -> Result<Self> {
+        assert!(max_open_files > 0, "reads need at least one open file");
         fs::create_dir_all(path)?;
+        clean_dangling_compaction(path)?;
 
         // rebuild index
         let mut gen_list = sorted_gen_list(path)?;
         let mut uncompacted = 0;
         let index = Arc::new(HierarchicalIndex::default());
         for &gen in &gen_list {
-            let reader = BufReaderWithPos::new(File::open(log_path(path, gen))?)?;
+            let file = File::open(log_path(path, gen))?;
+            advise_sequential(&file);
+            let reader = BufReaderWithPos::new(file)?;
             uncompacted += rebuild_index(gen, reader, &index)?;
         }
 
@@ -122,8 +396,7 @@ impl KvsEngine for ReadLockFreeKvStore {
         let path = Arc::new(PathBuf::from(path));
         let reader = SharedReader {
             index: index.clone(),
-            path: path.clone(),
-            readers: RefCell::new(BTreeMap::new()),
+            files: Arc::new(FilePool::new(path.clone(), max_open_files)),
             count: AtomicU64::new(0),
         };
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
@@ -135,10 +408,12 @@ impl KvsEngine for ReadLockFreeKvStore {
         )?;
         let writer = Arc::new(Mutex::new(SharedWriter {
             path: path.clone(),
-            current_gen: gen_list.last().cloned().unwrap_or(1),
+            current_gen,
             uncompacted,
             writer,
             index: index.clone(),
+            pool,
+            compaction: None,
         }));
 
         Ok(ReadLockFreeKvStore {
@@ -148,6 +423,16 @@ impl KvsEngine for ReadLockFreeKvStore {
             index,
         })
     }
+}
+
+impl<P: ThreadPool + Sync> KvsEngine for ReadLockFreeKvStore<P> {
+    /// Opens a store at `path` with a pool of its own, which runs one compaction at a time
+    fn open(path: &Path) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::open_with_pool(path, Arc::new(P::new(1)?), MAX_OPEN_FILES)
+    }
 
     fn set(&self, key: String, value: String) -> Result<()> {
         self.writer.lock().unwrap().set(key, value)
@@ -157,19 +442,32 @@ impl KvsEngine for ReadLockFreeKvStore {
         self.reader.get(&key)
     }
 
+    fn contains(&self, key: String) -> Result<bool> {
+        Ok(self.index.get(&key).is_some())
+    }
+
     fn remove(&self, key: String) -> Result<()> {
         self.writer.lock().unwrap().remove(key)
     }
+
+    fn last_modified(&self, key: String) -> Result<Option<u64>> {
+        Ok(self.index.get(&key).map(|cmd_pos| cmd_pos.ts))
+    }
+}
+
+impl<P> ReadLockFreeKvStore<P> {
+    /// Number of log files open for reads now, shared by all clones
+    pub fn open_files(&self) -> usize {
+        self.reader.files.open_files()
+    }
 }
 
 // SharedReader cannot sync in thread
 struct SharedReader {
     // a index to get from it
     index: Arc<HierarchicalIndex>,
-    // a path to get record from it.
-    path: Arc<PathBuf>,
-    // a seq of readers associated with different gen
-    readers: RefCell<BTreeMap<u64, BufReaderWithPos<File>>>,
+    // log files shared by all clones
+    files: Arc<FilePool>,
     // read count
     count: AtomicU64,
 }
@@ -178,8 +476,7 @@ impl Clone for SharedReader {
     fn clone(&self) -> Self {
         Self {
             index: Arc::clone(&self.index),
-            path: Arc::clone(&self.path),
-            readers: RefCell::new(BTreeMap::new()),
+            files: Arc::clone(&self.files),
             count: AtomicU64::new(0),
         }
     }
@@ -188,29 +485,120 @@ impl Clone for SharedReader {
 impl SharedReader {
     fn get(&self, key: &String) -> Result<Option<String>> {
         if self.count.fetch_add(1, Ordering::SeqCst) % READER_CLEAN_THRESHOLD == 0 {
-            let safe_point = self.index.safe_point();
-            self.readers.borrow_mut().retain(|k, _| *k >= safe_point);
+            self.files.retain(self.index.safe_point());
         }
 
         self.index
             .get(&key)
             .map_or(Ok(None), |pos| -> Result<Option<String>> {
-                if !self.readers.borrow().contains_key(&pos.gen) {
-                    let reader = BufReaderWithPos::new(File::open(log_path(&self.path, pos.gen))?)?;
-                    self.readers.borrow_mut().insert(pos.gen, reader);
+                let mut reader = self.files.take(pos.gen)?;
+                let cmd = read_command(&mut reader, &pos);
+                // the file is put back even after a failed read, as every read seeks first
+                self.files.put_back(pos.gen, reader);
+                if let Command::Set { value, .. } = cmd? {
+                    Ok(Some(value))
+                } else {
+                    Err(ErrorCode::UnexpectedCommandType.into())
                 }
-
-                let mut binding = self.readers.borrow_mut();
-                let reader = binding.get_mut(&pos.gen).unwrap();
-                // seek and read
-                reader.seek(SeekFrom::Start(pos.pos))?;
-                let value = serde_json::from_reader(reader.take(pos.len))?;
-                Ok(Some(value))
             })
     }
 }
 
-struct SharedWriter {
+/// Read the command at `pos` from its log
+fn read_command(reader: &mut BufReaderWithPos<File>, pos: &CommandPos) -> Result<Command> {
+    reader.seek(SeekFrom::Start(pos.pos))?;
+    Ok(serde_json::from_reader(reader.take(pos.len))?)
+}
+
+/// Log files open for reads, shared by all readers of a store.
+///
+/// At most `cap` files are open at once. A reader takes a file for a single read and puts it
+/// back after. Files no reader is using are closed least recently used first to open others,
+/// and a reader waits if all files are in use.
+struct FilePool {
+    // base file path
+    path: Arc<PathBuf>,
+    cap: usize,
+    state: Mutex<FilePoolState>,
+    // notified when a file is put back or closed
+    released: Condvar,
+}
+
+struct FilePoolState {
+    // number of files open, in use or idle
+    open: usize,
+    // files not in use with their generation, the least recently used first
+    idle: VecDeque<(u64, BufReaderWithPos<File>)>,
+}
+
+impl FilePool {
+    fn new(path: Arc<PathBuf>, cap: usize) -> Self {
+        FilePool {
+            path,
+            cap,
+            state: Mutex::new(FilePoolState {
+                open: 0,
+                idle: VecDeque::new(),
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Take a file of generation `gen`, which must be put back after
+    fn take(&self, gen: u64) -> Result<BufReaderWithPos<File>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(i) = state
+                .idle
+                .iter()
+                .rposition(|(idle_gen, _)| *idle_gen == gen)
+            {
+                return Ok(state.idle.remove(i).unwrap().1);
+            }
+            if state.open < self.cap {
+                break;
+            }
+            if state.idle.pop_front().is_some() {
+                state.open -= 1;
+                break;
+            }
+            state = self.released.wait(state).unwrap();
+        }
+        // reserve the slot before opening the file out of the lock
+        state.open += 1;
+        drop(state);
+
+        let reader = File::open(log_path(&self.path, gen))
+            .map_err(KvError::from)
+            .and_then(BufReaderWithPos::new);
+        if reader.is_err() {
+            self.state.lock().unwrap().open -= 1;
+            self.released.notify_one();
+        }
+        reader
+    }
+
+    fn put_back(&self, gen: u64, reader: BufReaderWithPos<File>) {
+        self.state.lock().unwrap().idle.push_back((gen, reader));
+        self.released.notify_one();
+    }
+
+    /// Close the idle files of generations older than `safe_point`, which have been compacted
+    fn retain(&self, safe_point: u64) {
+        let mut state = self.state.lock().unwrap();
+        let idle = state.idle.len();
+        state.idle.retain(|(gen, _)| *gen >= safe_point);
+        state.open -= idle - state.idle.len();
+        self.released.notify_all();
+    }
+
+    /// Number of files open now
+    fn open_files(&self) -> usize {
+        self.state.lock().unwrap().open
+    }
+}
+
+struct SharedWriter<P> {
     // base file path
     path: Arc<PathBuf>,
     // the current writer gen
@@ -222,23 +610,29 @@ struct SharedWriter {
     writer: BufWriterWithPos<File>,
     // a index is needed for update index
     index: Arc<HierarchicalIndex>,
+    // compactions are run by this pool
+    pool: Arc<P>,
+    // the outcome of the last compaction, until it has been checked
+    compaction: Option<Receiver<Result<()>>>,
 }
 
-impl SharedWriter {
+impl<P: ThreadPool> SharedWriter<P> {
     fn set(&mut self, key: String, value: String) -> Result<()> {
         // 1. write kv into current writer
         // 2. check if index has a key, if has, update it; if not insert it(index is thread safe)
         // 3. check uncompacted bytes > COMPACT_THREHOLD? scroll it and compact
-        let cmd = Command::set(key, value);
+        self.check_compaction(false)?;
+        let cmd = Command::set(key, value, system_clock(), None);
         let pos = self.writer.pos;
         serde_json::to_writer(&mut self.writer, &cmd)?;
         self.writer.flush()?;
 
-        if let Command::Set { key, .. } = cmd {
-            if let Some(cmd_pos) = self
-                .index
-                .insert(key, (self.current_gen, (pos..self.writer.pos)).into())
-            {
+        if let Command::Set { key, ts, .. } = cmd {
+            let cmd_pos = CommandPos {
+                ts,
+                ..(self.current_gen, (pos..self.writer.pos)).into()
+            };
+            if let Some(cmd_pos) = self.index.insert(key, cmd_pos) {
                 self.uncompacted += cmd_pos.len;
             }
         }
@@ -253,6 +647,11 @@ impl SharedWriter {
         // 1. write kv into current writer
         // 2. check if index has a key, if has, delte it; if not, return an err(index is thread safe)
         // 3. check uncompacted bytes > COMPACT_THREHOLD? scroll it and compact
+        self.check_compaction(false)?;
+        // writes are serialized by the lock of the writer, so the key can't appear in between
+        if self.index.get(&key).is_none() {
+            return Err(ErrorCode::KeyNotFound.into());
+        }
         let cmd = Command::remove(key);
         let pos = self.writer.pos;
         serde_json::to_writer(&mut self.writer, &cmd)?;
@@ -270,8 +669,31 @@ impl SharedWriter {
         Ok(())
     }
 
-    // NOTICE: it has limit that it can onlu compact before last compact finish
+    /// Fails with `ErrorCode::CompactionFailed` if the last compaction has failed or panicked.
+    /// The failure is only reported once.
+    ///
+    /// With `wait`, a compaction still running is waited for.
+    fn check_compaction(&mut self, wait: bool) -> Result<()> {
+        let res = match &self.compaction {
+            None => return Ok(()),
+            Some(rx) if wait => rx.recv().map_err(|_| TryRecvError::Disconnected),
+            Some(rx) => rx.try_recv(),
+        };
+        let res = match res {
+            Err(TryRecvError::Empty) => return Ok(()),
+            Ok(res) => res.map_err(|e| e.to_string()),
+            // the job is dropped without sending its outcome when it panics
+            Err(TryRecvError::Disconnected) => Err("compaction panicked".to_owned()),
+        };
+        self.compaction = None;
+        res.map_err(|e| ErrorCode::CompactionFailed(e).into())
+    }
+
+    // NOTICE: it has limit that it can onlu compact before last compact finish, so a new
+    // compaction waits for the last one and fails if it failed
     fn compact(&mut self) -> Result<()> {
+        self.check_compaction(true)?;
+
         // 1. snapshot the index
         // 2. keep gen sequential, the file gen during compaction is lager than the last file gen when snapshot,
         // the file gen in normal wirte after compaction trigger is lager than all gen in compaction
@@ -289,7 +711,7 @@ impl SharedWriter {
             )?;
             let readers = SkipMap::<u64, File>::new();
 
-            index.snapshot_rewrite(
+            let res = index.snapshot_rewrite(
                 |_, cmd_pos| {
                     // rewrite it into new log
                     if !readers.contains_key(&cmd_pos.gen) {
@@ -303,29 +725,57 @@ impl SharedWriter {
                     let pos = writer.pos;
                     io::copy(&mut reader.take(cmd_pos.len), &mut writer)?;
                     writer.flush()?;
-                    Ok((gen, (pos..writer.pos)).into())
+                    Ok(cmd_pos.moved(gen, pos..writer.pos))
                 },
                 || {
-                    for reader in &readers {
+                    // every live record older than `gen` is in the rewritten log now, so the
+                    // older logs are all stale, including those without any live record left
+                    let stale_gens = match sorted_gen_list(&path) {
+                        Ok(gen_list) => gen_list.into_iter().filter(|&stale_gen| stale_gen < gen),
+                        Err(e) => {
+                            warn!("List stale logs failed, {}", e);
+                            return gen;
+                        }
+                    };
+                    for stale_gen in stale_gens {
                         // only log err because delete file cann't recover
-                        let useless_gen = reader.key();
-                        if let Err(e) = fs::remove_file(log_path(&path, *useless_gen)) {
-                            warn!("Remove useless old index file file, {}", e);
+                        if let Err(e) = fs::remove_file(log_path(&path, stale_gen)) {
+                            warn!("Remove stale log {} failed, {}", stale_gen, e);
                         }
                     }
                     gen
                 },
-            )
+            );
+            if res.is_err() {
+                // nothing refers to the rewritten log, which would bring back records removed
+                // since the snapshot if it was replayed on open
+                if let Err(e) = fs::remove_file(log_path(&path, gen)) {
+                    warn!("Remove aborted compaction log {} failed, {}", gen, e);
+                }
+            }
+            res
         }
 
+        // freeze the records written so far before the compact task reads them, otherwise
+        // records merged into snapshot during the rewrite would be lost on commit
+        let stale = self.index.snapshot();
+        debug!("Snapshot index with {} stale bytes", stale);
+
         // submit compact task
         let index = self.index.clone();
         let gen = self.current_gen + 1;
         let path = (*self.path).clone();
-        spawn(move || compact_process(index, gen, path));
+        let (tx, rx) = bounded(1);
+        self.pool.spawn(move || {
+            let res = compact_process(index, gen, path);
+            if let Err(e) = &res {
+                error!("Compaction of gen {} failed: {}", gen, e);
+            }
+            let _ = tx.send(res);
+        });
+        self.compaction = Some(rx);
 
         // after spawn compact
-        self.index.snapshot();
         self.uncompacted = 0;
         self.current_gen += 2;
         self.writer = BufWriterWithPos::new(
@@ -343,156 +793,487 @@ enum CommandIdx {
     Tombstone,
 }
 
+/// The two levels of a `HierarchicalIndex`, replaced as a whole
+#[derive(Clone, Default)]
+struct IndexLevels {
+    // snapshot is last level, so it can't be has a delete record
+    snapshot: Arc<SkipMap<String, CommandPos>>,
+    active: Arc<SkipMap<String, CommandIdx>>,
+}
+
 /// A thread safe index, it can share between different thread safety
+///
+/// Reads never lock: the levels are swapped atomically, so a reader sees them either
+/// before or after a snapshot, never in the middle of a merge.
 #[derive(Default)]
 struct HierarchicalIndex {
-    // snapshot is last level, so it can't be has a delete record
-    snapshot: SkipMap<String, CommandPos>,
-    active: SkipMap<String, CommandIdx>,
-    safe_point: RwLock<u64>,
+    levels: ArcSwap<IndexLevels>,
+    safe_point: AtomicU64,
 }
 
 impl HierarchicalIndex {
     // return old record if replace a record, return none if not
+    // writes are serialized by the caller, so the old record can't change in between
     fn insert(&self, key: String, value: CommandPos) -> Option<CommandPos> {
-        let mut old_pos = None;
-        if let Some(old_idx) = self.active.get(&key)
-            && let CommandIdx::Index(old_cmd) = old_idx.value()
-        {
-            old_pos = Some(old_cmd.clone())
-        }
-
-        self.active.insert(key, CommandIdx::Index(value));
+        let levels = self.levels.load();
+        let old_pos = Self::get_in(&levels, &key);
+        levels.active.insert(key, CommandIdx::Index(value));
         old_pos
     }
 
     // return pos if remove a record, return none if not
     fn remove(&self, key: &String) -> Option<CommandPos> {
-        let mut old_pos = None;
-        if let Some(old_idx) = self.active.get(key)
-            && let CommandIdx::Index(old_cmd) = old_idx.value()
-        {
-            old_pos = Some(old_cmd.clone())
-        }
-
-        self.active.insert(key.clone(), CommandIdx::Tombstone);
+        let levels = self.levels.load();
+        let old_pos = Self::get_in(&levels, key);
+        levels.active.insert(key.clone(), CommandIdx::Tombstone);
         old_pos
     }
 
-    // get from low level first
-    // it may be resulting in read amplificatio
     fn get(&self, key: &String) -> Option<CommandPos> {
-        if let Some(idx) = self.active.get(key) 
-            && let CommandIdx::Index(cmd) = idx.value() 
-        {
-            return Some(cmd.clone());
-        } 
-        
-        let _lock = self.safe_point.read().unwrap();
-        if  let Some(idx) = self.snapshot.get(key) {
-            Some(idx.value().clone())
-        } else {
-            None
+        Self::get_in(&self.levels.load(), key)
+    }
+
+    // get from low level first, a tombstone hides the record in snapshot
+    // it may be resulting in read amplificatio
+    fn get_in(levels: &IndexLevels, key: &String) -> Option<CommandPos> {
+        if let Some(idx) = levels.active.get(key) {
+            return match idx.value() {
+                CommandIdx::Index(cmd) => Some(cmd.clone()),
+                CommandIdx::Tombstone => None,
+            };
         }
+        levels.snapshot.get(key).map(|idx| idx.value().clone())
     }
 
     // get the safe_point gen. safe point is the minuium gen in index
     fn safe_point(&self) -> u64 {
-        *self.safe_point.read().unwrap()
+        self.safe_point.load(Ordering::SeqCst)
     }
 
-    /// Merge all records in active into snapshot, it will merge delete records into insert records
-    fn snapshot(&self) {
-        let _lock = self.safe_point.write().unwrap();
-        while let Some(item) = self.active.pop_back() {
+    /// Merge all records in active into a new snapshot, it will merge delete records into
+    /// insert records. The merged snapshot and an empty active level are installed at once.
+    ///
+    /// Returns the bytes of the records in the old snapshot which became stale.
+    fn snapshot(&self) -> u64 {
+        let levels = self.levels.load_full();
+        let merged = SkipMap::new();
+        for item in levels.snapshot.iter() {
+            merged.insert(item.key().clone(), item.value().clone());
+        }
+
+        let mut stale = 0;
+        for item in levels.active.iter() {
+            if let Some(old) = merged.get(item.key()) {
+                stale += old.value().len;
+            }
             match item.value() {
                 CommandIdx::Index(cmd_pos) => {
-                    self.snapshot.insert(item.key().clone(), cmd_pos.clone());
+                    merged.insert(item.key().clone(), cmd_pos.clone());
+                }
+                CommandIdx::Tombstone => {
+                    merged.remove(item.key());
                 }
-                CommandIdx::Tombstone => ()
             }
         }
+
+        self.levels.store(Arc::new(IndexLevels {
+            snapshot: Arc::new(merged),
+            active: Arc::default(),
+        }));
+        stale
     }
 
     /// Rewrite all records in snapshot into new files.
+    ///
+    /// The rewrite is dropped without calling `commit` if another snapshot is taken meanwhile.
     fn snapshot_rewrite<Write, Commit>(&self, mut write: Write, mut commit: Commit) -> Result<()>
     where
         Write: FnMut(&String, &CommandPos) -> Result<CommandPos>,
         Commit: FnMut() -> u64,
     {
+        let snapshot = self.levels.load().snapshot.clone();
         let rewrite_snapshot = SkipMap::new();
-        for item in (&self.snapshot).into_iter() {
+        for item in snapshot.iter() {
             rewrite_snapshot.insert(item.key().clone(), write(item.key(), item.value())?);
         }
 
-        let mut lock = self.safe_point.write().unwrap();
-        *lock = commit();
-        self.snapshot.clear();
-        rewrite_snapshot.into_iter().for_each(|(k, v)| {
-            self.snapshot.insert(k, v);
+        // keep the active level, records may have been written into it during the rewrite
+        let rewrite_snapshot = Arc::new(rewrite_snapshot);
+        let prev = self.levels.rcu(|levels| {
+            if Arc::ptr_eq(&levels.snapshot, &snapshot) {
+                IndexLevels {
+                    snapshot: rewrite_snapshot.clone(),
+                    active: levels.active.clone(),
+                }
+            } else {
+                IndexLevels::clone(levels)
+            }
         });
-        drop(lock);
+        if !Arc::ptr_eq(&prev.snapshot, &snapshot) {
+            return Err(ErrorCode::InternalError(
+                "index snapshot changed during compaction".to_string(),
+            )
+            .into());
+        }
 
+        // old files are only removed once no new reader can find them in the index
+        self.safe_point.store(commit(), Ordering::SeqCst);
         Ok(())
     }
 }
 
+/// The logs a compaction of a `KvStore` rewrites, picked under the write lock
+struct CompactionPlan {
+    // generation of the new log, which the writer has skipped
+    gen: u64,
+    // generations of the logs rewritten
+    targets: HashSet<u64>,
+    // the live records of the targets, where they were when the compaction started
+    live: Vec<(String, CommandPos)>,
+    // expired keys of the targets, which no older log is left for
+    expired: Vec<(String, CommandPos)>,
+    // targets whose removes must be carried over, as an older log is left
+    removes_gens: Vec<u64>,
+}
+
+/// Compact the logs of the store in three steps, so writes only wait for the first and the last.
+///
+/// - pick: under the write lock, pick the logs to rewrite and move the writer on to a new log,
+///   so the logs picked are no longer written
+/// - rewrite: without the lock, copy their live records into a new log. Writes go on in the
+///   meantime, and reads still find the records in the old logs
+/// - commit: under the write lock again, point the index to the new records, unless their keys
+///   have been written since, and remove the old logs
+///
+/// A compaction failing before the commit leaves the old logs and the index as they are.
+/// `full` compacts all cold logs, otherwise only the stalest logs are, if the uncompacted bytes
+/// are still over the threshold. Only one compaction runs at a time.
+fn compact(inner: &RwLock<SharedKvStore>, full: bool) -> Result<()> {
+    let running = inner.read().unwrap().compacting.clone();
+    let _running = running.lock().unwrap();
+    let plan = match inner.write().unwrap().plan_compaction(full)? {
+        Some(plan) => plan,
+        None => return Ok(()),
+    };
+    let (path, opts) = {
+        let inner = inner.read().unwrap();
+        (inner.path.clone(), inner.opts.clone())
+    };
+    let log = opts.log_path(&path, plan.gen);
+    // write into a `.tmp` file first, the new log is only read once it is renamed to `.log`
+    let tmp = log.with_extension("tmp");
+    let rewritten = match rewrite(inner, &plan, &path, &opts, &tmp) {
+        Ok(rewritten) => rewritten,
+        Err(err) => {
+            let _ = fs::remove_file(&tmp);
+            return Err(err);
+        }
+    };
+    fs::rename(&tmp, &log)?;
+    inner
+        .write()
+        .unwrap()
+        .commit_compaction(plan, rewritten, &log)
+}
+
+/// Copy the live records of the logs `plan` picked into `tmp`, without holding the lock.
+///
+/// Returns the new position of each of `plan.live`.
+fn rewrite(
+    inner: &RwLock<SharedKvStore>,
+    plan: &CompactionPlan,
+    path: &Path,
+    opts: &KvStoreOpts,
+    tmp: &Path,
+) -> Result<Vec<CommandPos>> {
+    let mut compaction_writer =
+        BufWriterWithPos::new(OpenOptions::new().create_new(true).write(true).open(tmp)?)?;
+
+    // the logs picked are never written again, and only the commit removes them
+    let mut readers = HashMap::new();
+    let mut new_cmd_pos = Vec::with_capacity(plan.live.len());
+    let mut new_pos = 0; // pos in the new log file
+    for (_, cmd_pos) in &plan.live {
+        let reader = match readers.entry(cmd_pos.gen) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => entry.insert(BufReaderWithPos::new(File::open(
+                find_log_path(path, cmd_pos.gen),
+            )?)?),
+        };
+        if reader.pos != cmd_pos.pos {
+            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        }
+
+        let mut entry_reader = reader.take(cmd_pos.len);
+        let len = io::copy(&mut entry_reader, &mut compaction_writer)?;
+        new_cmd_pos.push(cmd_pos.moved(plan.gen, new_pos..new_pos + len));
+        new_pos += len;
+    }
+
+    // a remove is carried over unless its key has been set again, even after the pick, which
+    // is then replayed after the remove
+    let mut removed = BTreeSet::new();
+    for &gen in &plan.removes_gens {
+        let mut reader = BufReaderWithPos::new(File::open(find_log_path(path, gen))?)?;
+        while let Some(record) = Record::read_from(&mut reader)? {
+            if let Record::Command(Command::Remove { key }) = record {
+                removed.insert(key);
+            }
+        }
+    }
+    {
+        let inner = inner.read().unwrap();
+        removed.retain(|key| !inner.index.contains_key(key));
+    }
+    for key in removed {
+        compaction_writer.write_all(&opts.encode(&Command::remove(key))?)?;
+    }
+    compaction_writer.flush()?;
+
+    if opts.verify_compaction {
+        let rewritten = plan.live.iter().map(|(key, _)| key).zip(&new_cmd_pos);
+        let report = check_records(|_| tmp.to_path_buf(), rewritten)?;
+        if !report.corrupted.is_empty() {
+            return Err(ErrorCode::CompactionVerificationFailed {
+                keys: report.corrupted,
+            }
+            .into());
+        }
+    }
+    Ok(new_cmd_pos)
+}
+
+/// Runs the compactions which writes of a `KvStore` request on a thread of its own.
+///
+/// The thread only holds the store while it compacts it, and stops once the store is dropped.
+/// Dropping the last clone of the store waits for it, so the directory is unlocked once a
+/// compaction in progress has finished.
+struct Compactor {
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    fn spawn(inner: Weak<RwLock<SharedKvStore>>, requests: Receiver<()>) -> Compactor {
+        let thread = thread::spawn(move || {
+            for () in requests {
+                let inner = match inner.upgrade() {
+                    Some(inner) => inner,
+                    None => return,
+                };
+                if let Err(err) = compact(&inner, false) {
+                    error!("Background compaction failed: {}", err);
+                }
+            }
+        });
+        Compactor {
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
 impl SharedKvStore {
-    /// - execute opportunity：a separate thread to execute it ，just rewrite those data into new file and lock free
-    /// - index data race：lock index and replace those rewrite data key into current index when the compact is completed.
-    ///  write operation and compact operation will both update index，so they should lock when they update index
-    /// - compact fail tolerated: should keep  data consistency even if an error occurred during compact process
-    /// （just do not replace those new compacted file key into index ）
-    /// - Hierarchical index：it's a little bit like lsm index, but now it has only two level, one is for write, which
-    ///   could be modify ;one is for compact, it's a snapshot and it cann't be modify.
-    /// - Tombstone mechanism：now it is a lsm index,so delete record should be recored as a tombstone.
-    pub fn compact(&mut self) -> Result<()> {
+    /// Pick the logs to compact and move the writer on, skipping a generation for the new log.
+    ///
+    /// `full` picks all cold logs. Otherwise only the cold logs with the most stale bytes are
+    /// picked, taking them until they hold half of the stale bytes of the store, so the logs
+    /// which are mostly live aren't rewritten. Returns `None` if there is nothing to compact.
+    fn plan_compaction(&mut self, full: bool) -> Result<Option<CompactionPlan>> {
+        // only cold generations are rewritten, hot ones are left for a later compaction
+        let active_gen = self.current_gen;
+        let min_age = self.opts.compaction_min_age;
+        let is_cold = |gen: u64| active_gen - gen >= min_age;
+        let targets = if full {
+            self.readers
+                .keys()
+                .copied()
+                .filter(|&gen| is_cold(gen))
+                .collect()
+        } else if self.uncompacted > self.opts.compaction_threshold {
+            self.stalest(is_cold)
+        } else {
+            HashSet::new()
+        };
+        if targets.is_empty() {
+            return Ok(None);
+        }
+
         // increase current gen by 2. current_gen + 1 is for the compaction file
-        let compaction_gen = self.current_gen + 1;
+        let gen = self.current_gen + 1;
         self.current_gen += 2;
         self.writer = self.new_log_file(self.current_gen)?;
 
-        let mut compaction_writer = self.new_log_file(compaction_gen)?;
+        // a record which is not live may only be dropped if no older log is left, otherwise an
+        // older record of its key would show through on the next open
+        let oldest_kept = self
+            .readers
+            .keys()
+            .copied()
+            .filter(|&kept| kept < gen && !targets.contains(&kept))
+            .min()
+            .unwrap_or(gen);
+
+        let now = (self.opts.clock)();
+        let mut live = Vec::new();
+        let mut expired = Vec::new();
+        for (key, cmd_pos) in &self.index {
+            if !targets.contains(&cmd_pos.gen) {
+                continue;
+            }
+            if cmd_pos.expired(now) && cmd_pos.gen < oldest_kept {
+                expired.push((key.clone(), cmd_pos.clone()));
+            } else {
+                live.push((key.clone(), cmd_pos.clone()));
+            }
+        }
+        // the removes are carried over for the same reason
+        let mut removes_gens: Vec<u64> = targets
+            .iter()
+            .copied()
+            .filter(|&target| oldest_kept < target)
+            .collect();
+        removes_gens.sort_unstable();
 
-        let mut new_pos = 0; // pos in the new log file
-        for cmd_pos in &mut self.index.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            if reader.pos != cmd_pos.pos {
-                reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        Ok(Some(CompactionPlan {
+            gen,
+            targets,
+            live,
+            expired,
+            removes_gens,
+        }))
+    }
+
+    /// The cold logs with the most stale bytes, until they hold half of the stale bytes
+    fn stalest(&self, is_cold: impl Fn(u64) -> bool) -> HashSet<u64> {
+        let mut candidates: Vec<(u64, u64)> = self
+            .stale
+            .iter()
+            .filter(|&(&gen, &stale)| stale > 0 && is_cold(gen))
+            .map(|(&gen, &stale)| (gen, stale))
+            .collect();
+        candidates.sort_unstable_by_key(|&(gen, stale)| (Reverse(stale), gen));
+
+        let mut targets = HashSet::new();
+        let mut reclaimed = 0;
+        for (gen, stale) in candidates {
+            if reclaimed >= self.uncompacted / 2 {
+                break;
             }
+            targets.insert(gen);
+            reclaimed += stale;
+        }
+        targets
+    }
 
-            let mut entry_reader = reader.take(cmd_pos.len);
-            let len = io::copy(&mut entry_reader, &mut compaction_writer)?;
-            *cmd_pos = (compaction_gen, new_pos..new_pos + len).into();
-            new_pos += len;
+    /// Point the index to the records which the compaction of `plan` has `rewritten` into
+    /// `log`, unless their keys have been written since, and remove the logs it rewrote.
+    fn commit_compaction(
+        &mut self,
+        plan: CompactionPlan,
+        rewritten: Vec<CommandPos>,
+        log: &Path,
+    ) -> Result<()> {
+        self.readers
+            .insert(plan.gen, BufReaderWithPos::new(File::open(log)?)?);
+        let unchanged = |cmd_pos: &CommandPos, old: &CommandPos| {
+            cmd_pos.gen == old.gen && cmd_pos.pos == old.pos
+        };
+        for ((key, old), new_cmd_pos) in plan.live.into_iter().zip(rewritten) {
+            if let Some(cmd_pos) = self.index.get_mut(&key)
+                && unchanged(cmd_pos, &old)
+            {
+                *cmd_pos = new_cmd_pos;
+            }
+        }
+        for (key, old) in plan.expired {
+            if self
+                .index
+                .get(&key)
+                .is_some_and(|cmd_pos| unchanged(cmd_pos, &old))
+            {
+                self.uncache(&key);
+                self.index.remove(&key);
+            }
         }
-        compaction_writer.flush()?;
 
         // remove stale log files
-        let stale_gens: Vec<_> = self
-            .readers
-            .keys()
-            .filter(|&&gen| gen < compaction_gen)
-            .cloned()
-            .collect();
-        for stale_gen in stale_gens {
+        for stale_gen in plan.targets {
             self.readers.remove(&stale_gen);
-            fs::remove_file(log_path(&self.path, stale_gen))?;
+            fs::remove_file(find_log_path(&self.path, stale_gen))?;
         }
 
-        self.uncompacted = 0;
+        // what is left to compact are the stale commands in the logs left, including the
+        // records rewritten for keys written since
+        let mut live_bytes = HashMap::new();
+        for cmd_pos in self.index.values() {
+            *live_bytes.entry(cmd_pos.gen).or_insert(0) += cmd_pos.len;
+        }
+        self.stale.clear();
+        for &gen in self.readers.keys() {
+            let bytes = fs::metadata(find_log_path(&self.path, gen))?.len();
+            let live = live_bytes.get(&gen).copied().unwrap_or(0);
+            self.stale.insert(gen, bytes.saturating_sub(live));
+        }
+        self.uncompacted = self.stale.values().sum();
+        self.compactions += 1;
 
         Ok(())
     }
 
+    /// Ask the compactor for a compaction once enough records are stale. A request it hasn't
+    /// taken yet covers this one.
+    fn request_compaction(&self) {
+        if self.uncompacted > self.opts.compaction_threshold {
+            let _ = self.compaction_requests.try_send(());
+        }
+    }
+    /// Returns the position of `key` unless it doesn't exist or has expired.
+    ///
+    /// An expired key is left in the index until a compaction drops it.
+    fn live(&self, key: &String) -> Option<&CommandPos> {
+        let now = (self.opts.clock)();
+        self.index.get(key).filter(|cmd_pos| !cmd_pos.expired(now))
+    }
+
     /// Create a new log file with given generation number and add the reader to the readers map.
     ///
     /// Returns the writer to the log.
     fn new_log_file(&mut self, gen: u64) -> Result<BufWriterWithPos<File>> {
-        new_log_file(&self.path, gen, &mut self.readers)
+        new_log_file(&self.opts.log_path(&self.path, gen), gen, &mut self.readers)
+    }
+
+    /// Continue in a log of the next generation if the active one has reached `max_log_size`
+    fn roll_if_full(&mut self) -> Result<()> {
+        if let Some(max_log_size) = self.opts.max_log_size
+            && self.writer.pos >= max_log_size
+        {
+            self.current_gen += 1;
+            self.writer = self.new_log_file(self.current_gen)?;
+        }
+        Ok(())
+    }
+
+    /// Append `records` to the current log
+    fn append(&mut self, records: &[u8]) -> io::Result<()> {
+        self.writer.write_all(records)?;
+        self.bytes_written += records.len() as u64;
+        Ok(())
+    }
+
+    /// Flush the commands written to the log, and sync them to the disk with the `sync` option
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if self.opts.sync {
+            self.writer.writer.get_ref().sync_data()?;
+        }
+        Ok(())
     }
 
     /// Sets the value of a string key to a string.
@@ -501,68 +1282,240 @@ impl SharedKvStore {
     ///
     /// # Errors
     ///
+    /// It returns `ErrorCode::KeyTooLarge` or `ErrorCode::ValueTooLarge` if the key or the
+    /// value exceeds the limits of the options, before anything is written.
+    ///
     /// It propagates I/O or serialization errors during writing the log.
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
-        let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self
-                .index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into())
-            {
-                self.uncompacted += old_cmd.len;
-            }
+        self.set_until(key, value, None)
+    }
+
+    /// Sets a key which expires at `expires`, `None` for never
+    fn set_until(&mut self, key: String, value: String, expires: Option<u64>) -> Result<()> {
+        self.opts.check_sizes(&key, value.len())?;
+        let cmd = Command::set(key, value, (self.opts.clock)(), expires);
+        let pos = self.writer.pos;
+        self.append(&self.opts.encode(&cmd)?)?;
+        self.flush()?;
+        if let Command::Set {
+            key,
+            value,
+            ts,
+            expires,
+        } = cmd
+        {
+            let cmd_pos = CommandPos {
+                ts,
+                expires,
+                value: self.opts.inline(value),
+                ..(self.current_gen, pos..self.writer.pos).into()
+            };
+            self.insert(key, cmd_pos)?;
         }
+        Ok(())
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+    /// Sets a key to a binary value, which is written as a binary record
+    fn set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        self.opts.check_sizes(&key, value.len())?;
+        let ts = (self.opts.clock)();
+        let pos = self.writer.pos;
+        self.append(&self.opts.frame(encode_bytes_record(&key, &value, ts)))?;
+        self.flush()?;
+        let cmd_pos = CommandPos {
+            ts,
+            ..(self.current_gen, pos..self.writer.pos).into()
+        };
+        self.insert(key, cmd_pos)
+    }
+
+    /// Point `key` to the record just written, and request a compaction once enough records are
+    /// stale
+    fn insert(&mut self, key: String, cmd_pos: CommandPos) -> Result<()> {
+        self.uncache(&key);
+        if let Some(old_cmd) = self.index.insert(key, cmd_pos) {
+            self.mark_stale(&old_cmd);
         }
+        self.roll_if_full()?;
+        self.request_compaction();
         Ok(())
     }
 
+    /// Drop the cached value of `key`, once it is overwritten or removed
+    fn uncache(&mut self, key: &str) {
+        if let Some(cache) = &mut self.cache {
+            cache.get_mut().unwrap().invalidate(key);
+        }
+    }
+
+    /// Count the record at `cmd_pos` as stale, once it is overwritten or removed
+    fn mark_stale(&mut self, cmd_pos: &CommandPos) {
+        self.uncompacted += cmd_pos.len;
+        *self.stale.entry(cmd_pos.gen).or_insert(0) += cmd_pos.len;
+    }
+
     /// Gets the string value of a given string key.
     ///
     /// Returns `None` if the given key does not exist.
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(cmd_pos) = self.index.get(&key) {
-            let reader = self
-                .readers
-                .get_mut(&cmd_pos.gen)
-                .expect("Cannot find log reader");
-            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
-            let cmd_reader = reader.take(cmd_pos.len);
-            if let Command::Set { value, .. } = serde_json::from_reader(cmd_reader)? {
-                Ok(Some(value))
-            } else {
-                Err(ErrorCode::UnexpectedCommandType.into())
-            }
-        } else {
-            Ok(None)
+    ///
+    /// # Errors
+    ///
+    /// It returns `ErrorCode::Utf8` if the value was set as bytes which are not UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let now = (self.opts.clock)();
+        let cmd_pos = match self.index.get(&key).filter(|cmd_pos| !cmd_pos.expired(now)) {
+            Some(cmd_pos) => cmd_pos,
+            None => return Ok(None),
+        };
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return Ok(Some(String::from_utf8(self.read_value(cmd_pos)?)?)),
+        };
+        if let Some(value) = cache.lock().unwrap().get(&key) {
+            return Ok(Some(value));
         }
+        let value = String::from_utf8(self.read_value(cmd_pos)?)?;
+        cache.lock().unwrap().insert(key, value.clone());
+        Ok(Some(value))
+    }
+
+    /// Gets the value of a given string key as bytes, whether it was set as bytes or not.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        let now = (self.opts.clock)();
+        match self.index.get(&key).filter(|cmd_pos| !cmd_pos.expired(now)) {
+            Some(cmd_pos) => Ok(Some(self.read_value(cmd_pos)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Read the value of the record at `cmd_pos`, counting the reads which go to the log
+    fn read_value(&self, cmd_pos: &CommandPos) -> Result<Vec<u8>> {
+        if cmd_pos.value.is_none() {
+            self.disk_reads.fetch_add(1, Ordering::Relaxed);
+        }
+        read_value(&self.readers, &self.path, cmd_pos)
     }
 
     /// Removes a given key.
     ///
     /// # Error
     ///
-    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    /// It returns `ErrorCode::KeyNotFound` if the given key is not found.
     ///
     /// It propagates I/O or serialization errors during writing the log.
     fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
+        if self.live(&key).is_some() {
             let cmd = Command::remove(key);
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
+            self.append(&self.opts.encode(&cmd)?)?;
+            self.flush()?;
             if let Command::Remove { key } = cmd {
+                self.uncache(&key);
                 let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.len;
+                self.mark_stale(&old_cmd);
             }
-            Ok(())
+            self.roll_if_full()
         } else {
-            Err(ErrorCode::RmKeyNotFound.into())
+            Err(ErrorCode::KeyNotFound.into())
+        }
+    }
+
+    /// Write `ops` in order with a single append and flush of the log. The index is only
+    /// updated once all of them are in the log, so a failed batch is not applied at all.
+    ///
+    /// # Error
+    ///
+    /// It returns `ErrorCode::KeyNotFound` if a key removed by the batch doesn't exist at that
+    /// point, or `ErrorCode::KeyTooLarge`/`ValueTooLarge` if a set exceeds the size limits,
+    /// before anything is written.
+    fn write_batch(&mut self, ops: Vec<BatchOp>) -> Result<()> {
+        // whether the keys written by the batch exist after the ops checked so far
+        let mut exists = HashMap::new();
+        for op in &ops {
+            match op {
+                BatchOp::Set { key, value } => {
+                    self.opts.check_sizes(key, value.len())?;
+                    exists.insert(key, true);
+                }
+                BatchOp::Remove { key } => {
+                    if !exists
+                        .insert(key, false)
+                        .unwrap_or_else(|| self.live(key).is_some())
+                    {
+                        return Err(ErrorCode::KeyNotFound.into());
+                    }
+                }
+            }
+        }
+
+        let ts = (self.opts.clock)();
+        let pos = self.writer.pos;
+        let mut records = Vec::new();
+        let mut cmds = Vec::with_capacity(ops.len());
+        for op in ops {
+            let cmd = match op {
+                BatchOp::Set { key, value } => Command::set(key, value, ts, None),
+                BatchOp::Remove { key } => Command::remove(key),
+            };
+            let start = pos + records.len() as u64;
+            records.extend(self.opts.encode(&cmd)?);
+            cmds.push((cmd, start..pos + records.len() as u64));
+        }
+        let written = match self.append(&records) {
+            Ok(()) => self.flush(),
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = written {
+            self.truncate_log(pos)?;
+            return Err(err);
         }
+
+        for (cmd, range) in cmds {
+            let old_cmd = match cmd {
+                Command::Set {
+                    key,
+                    value,
+                    ts,
+                    expires,
+                } => {
+                    let cmd_pos = CommandPos {
+                        ts,
+                        expires,
+                        value: self.opts.inline(value),
+                        ..(self.current_gen, range).into()
+                    };
+                    self.uncache(&key);
+                    self.index.insert(key, cmd_pos)
+                }
+                Command::Remove { key } => {
+                    self.uncache(&key);
+                    self.index.remove(&key)
+                }
+            };
+            if let Some(old_cmd) = old_cmd {
+                self.mark_stale(&old_cmd);
+            }
+        }
+        self.roll_if_full()?;
+        self.request_compaction();
+        Ok(())
+    }
+
+    /// Cut the active log back to `pos` after a write which failed half way, so no part of it
+    /// is replayed on the next open
+    fn truncate_log(&mut self, pos: u64) -> Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .append(true)
+            .open(self.opts.log_path(&self.path, self.current_gen))?;
+        file.set_len(pos)?;
+        let mut writer = BufWriterWithPos::new(file)?;
+        writer.pos = pos;
+        // the old writer may still buffer a part of the write, which must not be flushed
+        let old_writer = mem::replace(&mut self.writer, writer);
+        drop(old_writer.writer.into_parts());
+        Ok(())
     }
 }
 
@@ -575,49 +1528,604 @@ impl KvsEngine for KvStore {
     ///
     /// It propagates I/O or deserialization errors during the log replay.
     fn open(path: &Path) -> Result<KvStore> {
-        fs::create_dir_all(path)?;
+        KvStore::open_with_opts(path, KvStoreOpts::default())
+    }
 
-        let mut readers = HashMap::new();
-        let mut index = BTreeMap::new();
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.inner.write().unwrap().set(key, value)
+    }
 
-        let gen_list = sorted_gen_list(path)?;
-        let mut uncompacted = 0;
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        let expires = (inner.opts.clock)().saturating_add(ttl.as_millis() as u64);
+        inner.set_until(key, value, Some(expires))
+    }
 
-        for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &mut index)?;
-            readers.insert(gen, reader);
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.inner.read().unwrap().get(key)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let inner = self.inner.read().unwrap();
+        keys.into_iter().map(|key| inner.get(key)).collect()
+    }
+
+    fn contains(&self, key: String) -> Result<bool> {
+        Ok(self.inner.read().unwrap().live(&key).is_some())
+    }
+
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.inner.write().unwrap().set_bytes(key, value)
+    }
+
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        self.inner.read().unwrap().get_bytes(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.inner.write().unwrap().remove(key)
+    }
+
+    fn get_delete(&self, key: String) -> Result<Option<String>> {
+        let mut inner = self.inner.write().unwrap();
+        let value = inner.get(key.clone())?;
+        if value.is_some() {
+            inner.remove(key)?;
         }
+        Ok(value)
+    }
 
-        let current_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = new_log_file(path, current_gen, &mut readers)?;
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let mut inner = self.inner.write().unwrap();
+        if inner.get(key.clone())? != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => inner.set(key, value)?,
+            None if expected.is_some() => inner.remove(key)?,
+            // the key doesn't exist and shouldn't
+            None => (),
+        }
+        Ok(true)
+    }
 
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        let mut inner = self.inner.write().unwrap();
+        let value = incremented(&key, inner.get(key.clone())?.as_deref(), delta)?;
+        inner.set(key, value.to_string())?;
+        Ok(value)
+    }
+
+    fn keys(&self, range: impl RangeBounds<String>, limit: usize) -> Result<Vec<String>> {
+        let inner = self.inner.read().unwrap();
+        let now = (inner.opts.clock)();
+        Ok(inner
+            .index
+            .range(range)
+            .filter(|(_, cmd_pos)| !cmd_pos.expired(now))
+            .take(limit)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    /// The live keys in `range` are removed by a single batch, under the write lock
+    fn delete_range(&self, range: impl RangeBounds<String>) -> Result<usize> {
+        let mut inner = self.inner.write().unwrap();
+        let now = (inner.opts.clock)();
+        let ops: Vec<BatchOp> = inner
+            .index
+            .range(range)
+            .filter(|(_, cmd_pos)| !cmd_pos.expired(now))
+            .map(|(key, _)| BatchOp::Remove { key: key.clone() })
+            .collect();
+        let removed = ops.len();
+        if removed > 0 {
+            inner.write_batch(ops)?;
+        }
+        Ok(removed)
+    }
+
+    /// Reads the values one by one rather than all at once like `scan`
+    fn dump(&self, writer: &mut impl Write) -> Result<()> {
+        let inner = self.inner.read().unwrap();
+        let keys: Vec<String> = inner.index.keys().cloned().collect();
+        for key in keys {
+            if let Some(value) = inner.get(key.clone())? {
+                serde_json::to_writer(&mut *writer, &JsonRecord { key, value })?;
+                writer.write_all(b"\n")?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn scan(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        let inner = self.inner.read().unwrap();
+        let keys: Vec<String> = inner
+            .index
+            .range(range)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = inner.get(key.clone())? {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn last_modified(&self, key: String) -> Result<Option<u64>> {
+        let inner = self.inner.read().unwrap();
+        Ok(inner.live(&key).map(|cmd_pos| cmd_pos.ts))
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        inner.writer.flush()?;
+        inner.writer.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        // taken before the lock, which `memory_usage` takes too
+        let memory = self.memory_usage();
+        let inner = self.inner.read().unwrap();
+        let mut total_bytes = 0;
+        for &gen in inner.readers.keys() {
+            total_bytes += fs::metadata(find_log_path(&inner.path, gen))?.len();
+        }
+        Ok(StoreStats {
+            num_keys: inner.index.len() as u64,
+            uncompacted_bytes: inner.uncompacted,
+            num_generations: inner.readers.len() as u64,
+            total_bytes,
+            bytes_written: inner.bytes_written,
+            compactions: inner.compactions,
+            disk_reads: inner.disk_reads.load(Ordering::Relaxed),
+            memory,
+        })
+    }
+
+    fn memory_usage(&self) -> MemoryReport {
+        let inner = self.inner.read().unwrap();
+        let entry_size = (mem::size_of::<String>() + mem::size_of::<CommandPos>()) as u64;
+        MemoryReport {
+            index_bytes: inner
+                .index
+                .iter()
+                .map(|(key, cmd_pos)| {
+                    let value_size = cmd_pos.value.as_ref().map_or(0, String::capacity);
+                    (key.capacity() + value_size) as u64 + entry_size
+                })
+                .sum(),
+            reader_cache_bytes: inner
+                .readers
+                .values()
+                .map(|reader| reader.reader.capacity() as u64)
+                .sum(),
+            other: (inner.writer.writer.capacity() + mem::size_of::<SharedKvStore>()) as u64,
+        }
+    }
+}
+
+impl KvStore {
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// This will create a new directory if the given one does not exist. The directory is
+    /// locked until the store and all its clones are dropped.
+    ///
+    /// A record which the newest log ends in the middle of was torn by a crash during its
+    /// write, which never returned, so it is cut off the log.
+    ///
+    /// # Errors
+    ///
+    /// It fails with `ErrorCode::DirectoryLocked` if another store, even of this process, has
+    /// the directory open. It propagates I/O or deserialization errors during the log replay.
+    pub fn open_with_opts(path: &Path, opts: KvStoreOpts) -> Result<KvStore> {
+        fs::create_dir_all(path)?;
+        let lock = DirLock::exclusive(path, LOCK_FILE)?;
+        clean_dangling_compaction(path)?;
+
+        let mut readers = HashMap::new();
+        let mut index = BTreeMap::new();
+        let mut stale = HashMap::new();
+        let last_gen = load_logs(path, &opts, &mut readers, &mut index, &mut stale, true)?;
+
+        let current_gen = last_gen + 1;
+        let writer = new_log_file(&opts.log_path(path, current_gen), current_gen, &mut readers)?;
+        let cache = opts
+            .value_cache_capacity
+            .map(|capacity| Mutex::new(ValueCache::new(capacity)));
+        let (compaction_requests, requests) = bounded(1);
+
+        let inner = Arc::new(RwLock::new(SharedKvStore {
+            path: path.to_path_buf(),
+            opts,
+            readers,
+            writer,
+            current_gen,
+            index,
+            uncompacted: stale.values().sum(),
+            stale,
+            bytes_written: 0,
+            compactions: 0,
+            cache,
+            disk_reads: AtomicU64::new(0),
+            compaction_requests,
+            compacting: Arc::new(Mutex::new(())),
+            _lock: lock,
+        }));
+        let compactor = Arc::new(Compactor::spawn(Arc::downgrade(&inner), requests));
         Ok(KvStore {
-            inner: Arc::new(RwLock::new(SharedKvStore {
+            inner,
+            _compactor: compactor,
+        })
+    }
+
+    /// Opens the store at `path` to read it as it is, without creating a log or cleaning up
+    /// after a compaction, so it never writes to the directory. Any number of processes can
+    /// read a store this way, e.g. to back it up, and a `KvStore` can't be opened on the
+    /// directory until they are dropped.
+    ///
+    /// Writes fail with `ErrorCode::ReadOnly`.
+    ///
+    /// # Errors
+    ///
+    /// It fails with `ErrorCode::DirectoryLocked` if a `KvStore` has the directory open. It
+    /// propagates I/O or deserialization errors during the log replay, e.g. if `path` does
+    /// not exist.
+    pub fn open_read_only(path: &Path) -> Result<ReadOnlyKvStore> {
+        let lock = DirLock::shared(path, LOCK_FILE)?;
+        let opts = KvStoreOpts::default();
+        let mut readers = HashMap::new();
+        let mut index = BTreeMap::new();
+        load_logs(
+            path,
+            &opts,
+            &mut readers,
+            &mut index,
+            &mut HashMap::new(),
+            false,
+        )?;
+        Ok(ReadOnlyKvStore {
+            inner: Arc::new(Mutex::new(ReadOnlyInner {
                 path: path.to_path_buf(),
+                opts,
                 readers,
-                writer,
-                current_gen,
                 index,
-                uncompacted,
+                _lock: lock,
             })),
         })
     }
 
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.inner.write().unwrap().set(key, value)
+    /// Iterate over the live key/value pairs in key order. Each value is read from the log when
+    /// it is reached, so the iteration takes the same memory however large the store is.
+    ///
+    /// The store is only locked within each step, so writes go on during the iteration. A key
+    /// written after the iteration passed it is not seen, and one removed before it is reached
+    /// is skipped.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, String)>> {
+        let store = self.clone();
+        let mut last: Option<String> = None;
+        std::iter::from_fn(move || loop {
+            let inner = store.inner.read().unwrap();
+            let next = match &last {
+                Some(last) => inner
+                    .index
+                    .range::<String, _>((Excluded(last), Unbounded))
+                    .next(),
+                None => inner.index.iter().next(),
+            };
+            let key = next?.0.clone();
+            last = Some(key.clone());
+            match inner.get(key.clone()) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                // expired, it is left in the index until a compaction
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        })
+    }
+
+    /// Write all live key/value pairs in key order to `writer` as newline-delimited JSON
+    /// objects `{"key":...,"value":...}`, which tools like jq can consume.
+    /// This is the same as `KvsEngine::dump`.
+    pub fn export_json<W: Write>(&self, mut writer: W) -> Result<()> {
+        self.dump(&mut writer)
+    }
+
+    /// Set all key/value pairs read from newline-delimited JSON in the format of `export_json`,
+    /// like `KvsEngine::restore` does for a new store.
+    ///
+    /// Returns the number of imported pairs.
+    pub fn import_json<R: Read>(&self, mut reader: R) -> Result<u64> {
+        import_dump(self, &mut reader)
+    }
+
+    /// Apply `ops` in order under a single acquisition of the lock and a single flush, with
+    /// `sync` a single sync, which is much faster than writing them one by one. Other writers
+    /// see all of the batch or none of it, and a batch which fails is not applied at all.
+    ///
+    /// # Errors
+    ///
+    /// It returns `ErrorCode::KeyNotFound` if a key removed by the batch doesn't exist at that
+    /// point, and propagates I/O or serialization errors during writing the log.
+    pub fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        self.inner.write().unwrap().write_batch(ops)
+    }
+
+    /// Rewrite the live records into a new log and remove the stale logs, without waiting for
+    /// the uncompacted bytes to reach the threshold.
+    ///
+    /// Writes only wait for the compaction to pick the logs to rewrite and to commit it, as
+    /// with the compactions writes request, which run in the background.
+    pub fn compact(&self) -> Result<()> {
+        compact(&self.inner, true)
+    }
+
+    /// Run `f` while no compaction can start, e.g. to observe the requests of the writes in
+    /// tests before the compactor runs them. The compactions requested meanwhile run after.
+    #[doc(hidden)]
+    pub fn with_compactions_paused<T>(&self, f: impl FnOnce() -> T) -> T {
+        let running = self.inner.read().unwrap().compacting.clone();
+        let _running = running.lock().unwrap();
+        f()
+    }
+
+    /// Estimate what `compact` would reclaim now, following `compaction_min_age`.
+    pub fn compaction_estimate(&self) -> Result<CompactionEstimate> {
+        let inner = self.inner.read().unwrap();
+        let min_age = inner.opts.compaction_min_age;
+        let is_cold = |gen: u64| inner.current_gen - gen >= min_age;
+
+        let mut estimate = CompactionEstimate::default();
+        for &gen in inner.readers.keys() {
+            let size = fs::metadata(find_log_path(&inner.path, gen))?.len();
+            estimate.current_bytes += size;
+            if is_cold(gen) {
+                estimate.reclaimable_bytes += size;
+            }
+        }
+        for cmd_pos in inner.index.values() {
+            estimate.live_bytes += cmd_pos.len;
+            if is_cold(cmd_pos.gen) {
+                estimate.reclaimable_bytes -= cmd_pos.len;
+            }
+        }
+        Ok(estimate)
+    }
+
+    /// Read back every live record from the log and check it round-trips to the same bytes.
+    pub fn validate(&self) -> Result<ValidationReport> {
+        let inner = self.inner.read().unwrap();
+        validate_records(&inner.path, inner.index.iter())
+    }
+
+    /// Same as `validate`, but the records are distributed across `workers` jobs on `pool`.
+    ///
+    /// Every job opens its own readers, and the store is read locked until all jobs finish
+    /// so that no log file is removed by a compaction in the meantime.
+    pub fn validate_parallel<P: ThreadPool>(
+        &self,
+        pool: &P,
+        workers: usize,
+    ) -> Result<ValidationReport> {
+        let inner = self.inner.read().unwrap();
+        let entries: Vec<(String, CommandPos)> = inner
+            .index
+            .iter()
+            .map(|(key, cmd_pos)| (key.clone(), cmd_pos.clone()))
+            .collect();
+        let chunk_size = (entries.len() / workers.max(1)).max(1);
+
+        let (tx, rx) = unbounded();
+        let mut jobs = 0;
+        for chunk in entries.chunks(chunk_size) {
+            let chunk = chunk.to_vec();
+            let path = inner.path.clone();
+            let tx = tx.clone();
+            pool.spawn(move || {
+                let report = validate_records(&path, chunk.iter().map(|(k, pos)| (k, pos)));
+                // the receiver only goes away if another job has already failed
+                let _ = tx.send(report);
+            });
+            jobs += 1;
+        }
+        drop(tx);
+
+        let mut report = ValidationReport::default();
+        for _ in 0..jobs {
+            let part = rx
+                .recv()
+                .map_err(|_| ErrorCode::InternalError("validation job panicked".to_string()))??;
+            report.checked += part.checked;
+            report.corrupted.extend(part.corrupted);
+        }
+        report.corrupted.sort_unstable();
+        Ok(report)
+    }
+}
+
+/// A `KvStore` opened by `KvStore::open_read_only`, which fails all writes with
+/// `ErrorCode::ReadOnly`
+#[derive(Clone)]
+pub struct ReadOnlyKvStore {
+    inner: Arc<Mutex<ReadOnlyInner>>,
+}
+
+struct ReadOnlyInner {
+    path: PathBuf,
+    opts: KvStoreOpts,
+    readers: HashMap<u64, BufReaderWithPos<File>>,
+    index: BTreeMap<String, CommandPos>,
+    _lock: Option<DirLock>,
+}
+
+impl ReadOnlyInner {
+    fn live(&self, key: &String) -> Option<&CommandPos> {
+        let now = (self.opts.clock)();
+        self.index.get(key).filter(|cmd_pos| !cmd_pos.expired(now))
+    }
+}
+
+impl KvsEngine for ReadOnlyKvStore {
+    fn open(path: &Path) -> Result<Self> {
+        KvStore::open_read_only(path)
+    }
+
+    fn set(&self, _key: String, _value: String) -> Result<()> {
+        Err(ErrorCode::ReadOnly.into())
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
-        self.inner.write().unwrap().get(key)
+        Ok(self.get_bytes(key)?.map(String::from_utf8).transpose()?)
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        self.inner.write().unwrap().remove(key)
+    fn contains(&self, key: String) -> Result<bool> {
+        Ok(self.inner.lock().unwrap().live(&key).is_some())
+    }
+
+    fn set_bytes(&self, _key: String, _value: Vec<u8>) -> Result<()> {
+        Err(ErrorCode::ReadOnly.into())
+    }
+
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        let mut inner = self.inner.lock().unwrap();
+        let ReadOnlyInner {
+            path,
+            opts,
+            readers,
+            index,
+            ..
+        } = &mut *inner;
+        let now = (opts.clock)();
+        match index.get(&key).filter(|cmd_pos| !cmd_pos.expired(now)) {
+            Some(cmd_pos) => Ok(Some(read_value(readers, path, cmd_pos)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn remove(&self, _key: String) -> Result<()> {
+        Err(ErrorCode::ReadOnly.into())
+    }
+
+    fn get_delete(&self, _key: String) -> Result<Option<String>> {
+        Err(ErrorCode::ReadOnly.into())
+    }
+
+    fn compare_and_swap(
+        &self,
+        _key: String,
+        _expected: Option<String>,
+        _new: Option<String>,
+    ) -> Result<bool> {
+        Err(ErrorCode::ReadOnly.into())
+    }
+
+    fn delete_range(&self, _range: impl RangeBounds<String>) -> Result<usize> {
+        Err(ErrorCode::ReadOnly.into())
+    }
+
+    fn keys(&self, range: impl RangeBounds<String>, limit: usize) -> Result<Vec<String>> {
+        let inner = self.inner.lock().unwrap();
+        let now = (inner.opts.clock)();
+        Ok(inner
+            .index
+            .range(range)
+            .filter(|(_, cmd_pos)| !cmd_pos.expired(now))
+            .take(limit)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    fn scan(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        let mut inner = self.inner.lock().unwrap();
+        let ReadOnlyInner {
+            path,
+            opts,
+            readers,
+            index,
+            ..
+        } = &mut *inner;
+        let now = (opts.clock)();
+        index
+            .range(range)
+            .filter(|(_, cmd_pos)| !cmd_pos.expired(now))
+            .map(|(key, cmd_pos)| {
+                let value = String::from_utf8(read_value(readers, path, cmd_pos)?)?;
+                Ok((key.clone(), value))
+            })
+            .collect()
+    }
+
+    fn last_modified(&self, key: String) -> Result<Option<u64>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.live(&key).map(|cmd_pos| cmd_pos.ts))
+    }
+}
+
+/// Validate the records at the given positions, opening readers on demand.
+fn validate_records<'a>(
+    path: &Path,
+    entries: impl Iterator<Item = (&'a String, &'a CommandPos)>,
+) -> Result<ValidationReport> {
+    check_records(|gen| find_log_path(path, gen), entries)
+}
+
+/// Validate the records at the given positions, the log of a generation is at `log(gen)`.
+fn check_records<'a>(
+    log: impl Fn(u64) -> PathBuf,
+    entries: impl Iterator<Item = (&'a String, &'a CommandPos)>,
+) -> Result<ValidationReport> {
+    let mut readers = HashMap::new();
+    let mut report = ValidationReport::default();
+    for (key, cmd_pos) in entries {
+        let reader = match readers.entry(cmd_pos.gen) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert(BufReaderWithPos::new(File::open(log(cmd_pos.gen))?)?)
+            }
+        };
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        let mut raw = Vec::with_capacity(cmd_pos.len as usize);
+        reader.take(cmd_pos.len).read_to_end(&mut raw)?;
+
+        // the checksum of a checked record is verified by reading it, leaving the record itself
+        let record = match raw.first() {
+            Some(&CHECKED_MARKER) if raw.len() > CHECKED_HEADER_LEN => &raw[CHECKED_HEADER_LEN..],
+            _ => &raw[..],
+        };
+        let valid = match Record::read_from(&mut &raw[..]) {
+            Ok(Some(Record::Command(cmd @ Command::Set { .. }))) => {
+                matches!(&cmd, Command::Set { key: k, .. } if k == key)
+                    && match record[0] {
+                        COMPRESSED_MARKER => {
+                            Compression::of(record[1])?.encode(&cmd).as_deref() == Some(record)
+                        }
+                        first => LogCodec::of(first).encode(&cmd)? == record,
+                    }
+            }
+            Ok(Some(Record::Bytes { key: k, value, ts })) => {
+                &k == key && encode_bytes_record(&k, &value, ts) == record
+            }
+            _ => false,
+        };
+        report.checked += 1;
+        if !valid {
+            report.corrupted.push(key.clone());
+        }
     }
+    Ok(report)
 }
 
-/// Create a new log file with given generation number and add the reader to the readers map.
+/// Create a new log file at `path` with given generation number and add the reader to the
+/// readers map.
 ///
 /// Returns the writer to the log.
 fn new_log_file(
@@ -625,18 +2133,106 @@ fn new_log_file(
     gen: u64,
     readers: &mut HashMap<u64, BufReaderWithPos<File>>,
 ) -> Result<BufWriterWithPos<File>> {
-    let path = log_path(&path, gen);
     let writer = BufWriterWithPos::new(
         OpenOptions::new()
             .create(true)
             .write(true)
             .append(true)
-            .open(&path)?,
+            .open(path)?,
     )?;
-    readers.insert(gen, BufReaderWithPos::new(File::open(&path)?)?);
+    readers.insert(gen, BufReaderWithPos::new(File::open(path)?)?);
     Ok(writer)
 }
 
+/// Read the value of the record at `cmd_pos`, or take it from the index if it is inlined
+fn read_value(
+    readers: &HashMap<u64, BufReaderWithPos<File>>,
+    dir: &Path,
+    cmd_pos: &CommandPos,
+) -> Result<Vec<u8>> {
+    if let Some(value) = &cmd_pos.value {
+        return Ok(value.clone().into_bytes());
+    }
+    let record = read_record(readers, dir, cmd_pos)?;
+    match Record::read_from(&mut record.as_slice())? {
+        Some(Record::Command(Command::Set { value, .. })) => Ok(value.into_bytes()),
+        Some(Record::Bytes { value, .. }) => Ok(value),
+        _ => Err(ErrorCode::UnexpectedCommandType.into()),
+    }
+}
+
+/// Read the bytes of the record at `cmd_pos` without moving the cursor of its reader, so any
+/// number of threads can read through the same readers.
+///
+/// A log without a reader is opened for this read only.
+fn read_record(
+    readers: &HashMap<u64, BufReaderWithPos<File>>,
+    dir: &Path,
+    cmd_pos: &CommandPos,
+) -> Result<Vec<u8>> {
+    let mut record = vec![0; cmd_pos.len as usize];
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+        let reopened;
+        let file = match readers.get(&cmd_pos.gen) {
+            Some(reader) => reader.reader.get_ref(),
+            None => {
+                warn!("Reopen the log of generation {}", cmd_pos.gen);
+                reopened = File::open(find_log_path(dir, cmd_pos.gen))?;
+                &reopened
+            }
+        };
+        file.read_exact_at(&mut record, cmd_pos.pos)?;
+    }
+    // elsewhere a positioned read moves the cursor, so the log is read through a handle of its own
+    #[cfg(not(unix))]
+    {
+        let _ = readers;
+        let mut file = File::open(find_log_path(dir, cmd_pos.gen))?;
+        file.seek(SeekFrom::Start(cmd_pos.pos))?;
+        file.read_exact(&mut record)?;
+    }
+    Ok(record)
+}
+
+/// Replay the logs in `path` into `index`, keeping a reader of every log in `readers` and
+/// how many bytes of each can be saved after a compaction in `stale`.
+///
+/// Returns the generation of the last log, 0 if there is none.
+///
+/// A torn record at the end of the newest log is cut off the log if `truncate`, otherwise it is
+/// only left out of the index.
+fn load_logs(
+    path: &Path,
+    opts: &KvStoreOpts,
+    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
+    index: &mut BTreeMap<String, CommandPos>,
+    stale: &mut HashMap<u64, u64>,
+    truncate: bool,
+) -> Result<u64> {
+    let gen_list = sorted_gen_list(path)?;
+    check_formats(path, &gen_list)?;
+
+    for &gen in &gen_list {
+        let log = find_log_path(path, gen);
+        let file = File::open(&log)?;
+        if opts.readahead_hint {
+            advise_sequential(&file);
+        }
+        let mut reader = BufReaderWithPos::new(file)?;
+        let newest = Some(&gen) == gen_list.last();
+        if let Some(torn) = load(gen, &mut reader, index, stale, opts, newest)? {
+            warn!("Torn record at the end of {:?} from offset {}", log, torn);
+            if truncate {
+                OpenOptions::new().write(true).open(&log)?.set_len(torn)?;
+            }
+        }
+        readers.insert(gen, reader);
+    }
+    Ok(gen_list.last().cloned().unwrap_or(0))
+}
+
 /// Returns sorted generation numbers in the given directory
 fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     let mut gen_list: Vec<u64> = fs::read_dir(&path)?
@@ -651,61 +2247,201 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
         .flatten()
         .collect();
     gen_list.sort_unstable();
+    // the same generation may have a padded and an un-padded name
+    gen_list.dedup();
     Ok(gen_list)
 }
 
-/// Load the whole log file and store value locations in the index map.
+/// Remove compaction files left behind by a crash before the compaction was committed.
 ///
-/// Returns how many bytes can be saved after a compaction.
+/// A compaction is committed by renaming `{gen}.tmp` to `{gen}.log`, and the stale logs are
+/// only removed after that. So if `{gen}.log` exists, the `.tmp` is a leftover of a finished
+/// commit; if not, the compaction never committed and all its data is still in the stale logs.
+/// In both cases the `.tmp` file can be deleted.
+fn clean_dangling_compaction(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let tmp_path = entry?.path();
+        if !tmp_path.is_file() || tmp_path.extension() != Some("tmp".as_ref()) {
+            continue;
+        }
+        if tmp_path.with_extension("log").exists() {
+            warn!("Remove leftover of committed compaction {:?}", tmp_path);
+        } else {
+            warn!("Remove uncommitted compaction {:?}", tmp_path);
+        }
+        fs::remove_file(&tmp_path)?;
+    }
+    Ok(())
+}
+
+/// Check all logs are in a format of the store before any is replayed, as the commands of a log
+/// in another format would be replayed as garbage or fail half way.
+///
+/// Logs have no header, so a log is taken as a log of the store if it is empty or starts with a
+/// record the store writes: a JSON command, a command of the binary codec, a binary value, or a
+/// compressed or checksummed record.
+fn check_formats(path: &Path, gen_list: &[u64]) -> Result<()> {
+    let mut files = Vec::new();
+    for &gen in gen_list {
+        let log = find_log_path(path, gen);
+        let mut first = [0_u8; 1];
+        let markers = [
+            b'{',
+            CHECKED_MARKER,
+            BINARY_MARKER,
+            SET_MARKER,
+            REMOVE_MARKER,
+            COMPRESSED_MARKER,
+        ];
+        if File::open(&log)?.read(&mut first)? == 1 && !markers.contains(&first[0]) {
+            files.push(log);
+        }
+    }
+    if files.is_empty() {
+        Ok(())
+    } else {
+        Err(ErrorCode::MixedFormats { files }.into())
+    }
+}
+
+/// Load the records of log `gen` into the index. The commands made stale are counted in `stale`
+/// for the logs they are in.
+///
+/// Returns the offset of the last record if it was torn by a crash at the end of the `newest`
+/// log, which is then left out.
 fn load(
     gen: u64,
     reader: &mut BufReaderWithPos<File>,
     index: &mut BTreeMap<String, CommandPos>,
-) -> Result<u64> {
+    stale: &mut HashMap<u64, u64>,
+    opts: &KvStoreOpts,
+    newest: bool,
+) -> Result<Option<u64>> {
     // To make sure we read from the beginning of the file
     let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-    let mut uncompacted = 0; // number of bytes that can be saved after a compaction
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.insert(key, (gen, pos..new_pos).into()) {
-                    uncompacted += old_cmd.len;
+    stale.entry(gen).or_insert(0);
+    loop {
+        let record = match Record::read_from(reader) {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(err) if newest && torn(&err, reader)? => return Ok(Some(pos)),
+            Err(err) => return Err(err),
+        };
+        let new_pos = reader.pos;
+        let cmd = match record {
+            Record::Command(cmd) => cmd,
+            Record::Bytes { key, ts, .. } => {
+                let cmd_pos = CommandPos {
+                    ts,
+                    ..(gen, pos..new_pos).into()
+                };
+                if let Some(old_cmd) = index.insert(key, cmd_pos) {
+                    *stale.entry(old_cmd.gen).or_insert(0) += old_cmd.len;
+                }
+                pos = new_pos;
+                continue;
+            }
+        };
+        match cmd {
+            Command::Set {
+                key,
+                value,
+                ts,
+                expires,
+            } => {
+                let cmd_pos = CommandPos {
+                    ts,
+                    expires,
+                    value: opts.inline(value),
+                    ..(gen, pos..new_pos).into()
+                };
+                if let Some(old_cmd) = index.insert(key, cmd_pos) {
+                    *stale.entry(old_cmd.gen).or_insert(0) += old_cmd.len;
                 }
             }
             Command::Remove { key } => {
                 if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.len;
+                    *stale.entry(old_cmd.gen).or_insert(0) += old_cmd.len;
                 }
                 // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                uncompacted += new_pos - pos;
+                // so we count its length as stale
+                *stale.entry(gen).or_insert(0) += new_pos - pos;
             }
         }
         pos = new_pos;
     }
-    Ok(uncompacted)
+    Ok(None)
+}
+
+/// Hint the kernel that `file` is going to be read sequentially, so it reads ahead aggressively.
+#[cfg(all(feature = "fadvise", target_os = "linux"))]
+fn advise_sequential(file: &File) {
+    use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+    use std::os::unix::io::AsRawFd;
+
+    // it is only a hint, replay works without it
+    if let Err(e) = posix_fadvise(
+        file.as_raw_fd(),
+        0,
+        0,
+        PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL,
+    ) {
+        warn!("Fail to advise sequential read: {}", e);
+    }
 }
 
+#[cfg(not(all(feature = "fadvise", target_os = "linux")))]
+fn advise_sequential(_file: &File) {}
+
 fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
-fn log_compact_path(dir: &Path, gen: u64) -> PathBuf {
-    dir.join(format!("{}.tmp", gen))
+fn padded_log_path(dir: &Path, gen: u64) -> PathBuf {
+    // wide enough for any u64
+    dir.join(format!("{:020}.log", gen))
+}
+
+/// Path of the existing log of generation `gen`, whether its name is padded or not
+fn find_log_path(dir: &Path, gen: u64) -> PathBuf {
+    let padded = padded_log_path(dir, gen);
+    if padded.exists() {
+        padded
+    } else {
+        log_path(dir, gen)
+    }
 }
 
 /// Struct representing a command
 #[derive(Serialize, Deserialize, Debug)]
 enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+    Set {
+        key: String,
+        value: String,
+        // milliseconds since the unix epoch, logs written before timestamps were added have none
+        #[serde(default, skip_serializing_if = "is_zero")]
+        ts: u64,
+        // milliseconds since the unix epoch after which the key is gone, `None` for never
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+    },
+    Remove {
+        key: String,
+    },
+}
+
+fn is_zero(ts: &u64) -> bool {
+    *ts == 0
 }
 
 impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
+    fn set(key: String, value: String, ts: u64, expires: Option<u64>) -> Command {
+        Command::Set {
+            key,
+            value,
+            ts,
+            expires,
+        }
     }
 
     fn remove(key: String) -> Command {
@@ -713,12 +2449,364 @@ impl Command {
     }
 }
 
+/// Whether `err` reading the last record of a log means the record was torn by a crash during
+/// its write: the log ends in the middle of it, or it fails its checksum right at the end
+fn torn(err: &KvError, reader: &BufReaderWithPos<File>) -> Result<bool> {
+    Ok(match &**err {
+        ErrorCode::SerDeError(err) => err.is_eof(),
+        ErrorCode::NetworkError(err) => err.kind() == io::ErrorKind::UnexpectedEof,
+        ErrorCode::ChecksumMismatch => reader.pos == reader.reader.get_ref().metadata()?.len(),
+        _ => false,
+    })
+}
+
+/// Starts a binary record in a log. It never starts a JSON command, so both kinds of records
+/// can be mixed in a log.
+const BINARY_MARKER: u8 = 0xFF;
+/// Starts a record prefixed with the CRC32 and the length of its bytes as big-endian `u32`s
+const CHECKED_MARKER: u8 = 0xFB;
+/// Bytes of the marker, the checksum and the length before a checked record
+const CHECKED_HEADER_LEN: usize = 9;
+
+/// A record of a log, a JSON command or a binary value, which JSON would have to escape
+enum Record {
+    Command(Command),
+    Bytes {
+        key: String,
+        value: Vec<u8>,
+        ts: u64,
+    },
+}
+
+impl Record {
+    /// Read the next record of a log, `None` at its end
+    fn read_from(reader: &mut impl Read) -> Result<Option<Record>> {
+        let mut first = [0_u8; 1];
+        loop {
+            if reader.read(&mut first)? == 0 {
+                return Ok(None);
+            }
+            if !first[0].is_ascii_whitespace() {
+                break;
+            }
+        }
+        if first[0] == CHECKED_MARKER {
+            let checksum = read_u32(reader)?;
+            let len = read_u32(reader)? as u64;
+            // the bytes are read rather than allocated up front, so a corrupted length takes no
+            // more memory than the rest of the log
+            let mut record = Vec::new();
+            reader.take(len).read_to_end(&mut record)?;
+            if record.len() as u64 != len || crc32(&record) != checksum {
+                return Err(ErrorCode::ChecksumMismatch.into());
+            }
+            return match Record::read_from(&mut record.as_slice())? {
+                Some(record) => Ok(Some(record)),
+                None => Err(ErrorCode::ChecksumMismatch.into()),
+            };
+        }
+        if first[0] != BINARY_MARKER {
+            let cmd = LogCodec::of(first[0]).decode(first[0], reader)?;
+            return Ok(Some(Record::Command(cmd)));
+        }
+
+        let mut header = [0_u8; 16];
+        reader.read_exact(&mut header)?;
+        let ts = u64::from_be_bytes(header[..8].try_into().unwrap());
+        let key_len = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        let value_len = u32::from_be_bytes(header[12..].try_into().unwrap());
+        let mut key = vec![0; key_len as usize];
+        reader.read_exact(&mut key)?;
+        let mut value = vec![0; value_len as usize];
+        reader.read_exact(&mut value)?;
+        Ok(Some(Record::Bytes {
+            key: String::from_utf8(key)?,
+            value,
+            ts,
+        }))
+    }
+}
+
+/// The encoding of the commands written to the logs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogCodec {
+    /// A JSON object per command, which tools like jq can read
+    #[default]
+    Json,
+    /// A compact binary encoding, which makes smaller logs and is faster to replay
+    Binary,
+}
+
+impl LogCodec {
+    /// The codec which wrote a record starting with `first`
+    fn of(first: u8) -> LogCodec {
+        match first {
+            SET_MARKER | REMOVE_MARKER | COMPRESSED_MARKER => LogCodec::Binary,
+            _ => LogCodec::Json,
+        }
+    }
+
+    fn codec(self) -> &'static dyn Codec {
+        match self {
+            LogCodec::Json => &JsonCodec,
+            LogCodec::Binary => &BinaryCodec,
+        }
+    }
+
+    fn encode(self, cmd: &Command) -> Result<Vec<u8>> {
+        self.codec().encode(cmd)
+    }
+
+    fn decode(self, first: u8, reader: &mut dyn Read) -> Result<Command> {
+        self.codec().decode(first, reader)
+    }
+}
+
+/// Encodes the commands of a log and decodes them back.
+///
+/// The records of each codec start with bytes of their own, so a log is read whatever codecs
+/// wrote it. A decoder reads exactly the bytes of a record, so the position of the reader after
+/// a record is where the next one starts.
+trait Codec {
+    fn encode(&self, cmd: &Command) -> Result<Vec<u8>>;
+
+    /// Decode a command whose first byte `first` has already been read from `reader`
+    fn decode(&self, first: u8, reader: &mut dyn Read) -> Result<Command>;
+}
+
+struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode(&self, cmd: &Command) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(cmd)?)
+    }
+
+    fn decode(&self, first: u8, reader: &mut dyn Read) -> Result<Command> {
+        let mut de = Deserializer::from_reader(io::Cursor::new([first]).chain(reader));
+        Ok(Command::deserialize(&mut de)?)
+    }
+}
+
+/// Starts a `Set` of the binary codec
+const SET_MARKER: u8 = 0xFE;
+/// Starts a `Remove` of the binary codec
+const REMOVE_MARKER: u8 = 0xFD;
+/// Starts a `Set` whose value is compressed, which is written in binary whatever the codec
+const COMPRESSED_MARKER: u8 = 0xFC;
+
+/// A marker, then the fields as big-endian integers and length-prefixed strings. A `Set` is
+/// the timestamp, the expiry or `u64::MAX` for none, the key and the value. A `Remove` is the key.
+struct BinaryCodec;
+
+impl Codec for BinaryCodec {
+    fn encode(&self, cmd: &Command) -> Result<Vec<u8>> {
+        let mut record = Vec::new();
+        match cmd {
+            Command::Set {
+                key,
+                value,
+                ts,
+                expires,
+            } => {
+                record.push(SET_MARKER);
+                record.extend_from_slice(&ts.to_be_bytes());
+                record.extend_from_slice(&expires.unwrap_or(u64::MAX).to_be_bytes());
+                put_bytes(&mut record, key.as_bytes());
+                put_bytes(&mut record, value.as_bytes());
+            }
+            Command::Remove { key } => {
+                record.push(REMOVE_MARKER);
+                put_bytes(&mut record, key.as_bytes());
+            }
+        }
+        Ok(record)
+    }
+
+    fn decode(&self, first: u8, reader: &mut dyn Read) -> Result<Command> {
+        match first {
+            SET_MARKER => Ok(Command::Set {
+                ts: read_u64(reader)?,
+                expires: Some(read_u64(reader)?).filter(|&expires| expires != u64::MAX),
+                key: String::from_utf8(read_bytes(reader)?)?,
+                value: String::from_utf8(read_bytes(reader)?)?,
+            }),
+            REMOVE_MARKER => Ok(Command::Remove {
+                key: String::from_utf8(read_bytes(reader)?)?,
+            }),
+            COMPRESSED_MARKER => {
+                let mut flag = [0_u8; 1];
+                reader.read_exact(&mut flag)?;
+                let compression = Compression::of(flag[0])?;
+                let ts = read_u64(reader)?;
+                let expires = Some(read_u64(reader)?).filter(|&expires| expires != u64::MAX);
+                let key = String::from_utf8(read_bytes(reader)?)?;
+                let mut len = [0_u8; 4];
+                reader.read_exact(&mut len)?;
+                let value = compression
+                    .decompress(&read_bytes(reader)?, u32::from_be_bytes(len) as usize)
+                    .ok_or_else(|| {
+                        ErrorCode::InternalError(format!("corrupted compressed value of {}", key))
+                    })?;
+                Ok(Command::Set {
+                    key,
+                    value: String::from_utf8(value)?,
+                    ts,
+                    expires,
+                })
+            }
+            _ => Err(ErrorCode::UnexpectedCommandType.into()),
+        }
+    }
+}
+
+/// How the values of `Set` commands are compressed in the logs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// The LZ4 block format, which is fast to compress and to decompress
+    Lz4,
+}
+
+impl Compression {
+    /// The byte after the marker of the records it compressed
+    fn flag(self) -> u8 {
+        match self {
+            Compression::Lz4 => 1,
+        }
+    }
+
+    fn of(flag: u8) -> Result<Compression> {
+        match flag {
+            1 => Ok(Compression::Lz4),
+            _ => Err(ErrorCode::UnexpectedCommandType.into()),
+        }
+    }
+
+    fn compress(self, value: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::Lz4 => lz4::compress(value),
+        }
+    }
+
+    fn decompress(self, value: &[u8], len: usize) -> Option<Vec<u8>> {
+        match self {
+            Compression::Lz4 => lz4::decompress(value, len),
+        }
+    }
+
+    /// Encode a `Set` with its value compressed: the marker and the flag, then the fields as in
+    /// the binary codec, but the value is its length before compression and the compressed bytes.
+    ///
+    /// Returns `None` for a `Remove`, or if the value doesn't get smaller.
+    fn encode(self, cmd: &Command) -> Option<Vec<u8>> {
+        let (key, value, ts, expires) = match cmd {
+            Command::Set {
+                key,
+                value,
+                ts,
+                expires,
+            } => (key, value, ts, expires),
+            Command::Remove { .. } => return None,
+        };
+        let compressed = self.compress(value.as_bytes());
+        // the length before compression takes 4 bytes more
+        if compressed.len() + 4 >= value.len() {
+            return None;
+        }
+        let mut record = vec![COMPRESSED_MARKER, self.flag()];
+        record.extend_from_slice(&ts.to_be_bytes());
+        record.extend_from_slice(&expires.unwrap_or(u64::MAX).to_be_bytes());
+        put_bytes(&mut record, key.as_bytes());
+        record.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        put_bytes(&mut record, &compressed);
+        Some(record)
+    }
+}
+
+/// Append `bytes` prefixed with their length as a big-endian `u32`
+fn put_bytes(record: &mut Vec<u8>, bytes: &[u8]) {
+    record.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    record.extend_from_slice(bytes);
+}
+
+fn read_u32(reader: &mut dyn Read) -> Result<u32> {
+    let mut buf = [0_u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(reader: &mut dyn Read) -> Result<u64> {
+    let mut buf = [0_u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Read bytes prefixed with their length as a big-endian `u32`
+fn read_bytes(reader: &mut dyn Read) -> Result<Vec<u8>> {
+    let mut len = [0_u8; 4];
+    reader.read_exact(&mut len)?;
+    let mut bytes = vec![0; u32::from_be_bytes(len) as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Encode a binary record: the marker, then the timestamp and the lengths of the key and the
+/// value as big-endian integers, then the key and the value
+fn encode_bytes_record(key: &str, value: &[u8], ts: u64) -> Vec<u8> {
+    let mut record = Vec::with_capacity(17 + key.len() + value.len());
+    record.push(BINARY_MARKER);
+    record.extend_from_slice(&ts.to_be_bytes());
+    record.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    record.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    record.extend_from_slice(key.as_bytes());
+    record.extend_from_slice(value);
+    record
+}
+
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(bytes);
+    hasher.finalize()
+}
+
+/// Frame a record of any kind with its checksum, so a corrupted record can be told apart
+fn encode_checked_record(record: &[u8]) -> Vec<u8> {
+    let mut checked = Vec::with_capacity(CHECKED_HEADER_LEN + record.len());
+    checked.push(CHECKED_MARKER);
+    checked.extend_from_slice(&crc32(record).to_be_bytes());
+    checked.extend_from_slice(&(record.len() as u32).to_be_bytes());
+    checked.extend_from_slice(record);
+    checked
+}
+
 /// Represents the position and length of a json-serialized command in the log
 #[derive(Clone)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
+    // timestamp of the command, 0 if unknown
+    ts: u64,
+    // when the key expires, `None` for never
+    expires: Option<u64>,
+    // the value itself, if it is small enough to be inlined
+    value: Option<String>,
+}
+
+impl CommandPos {
+    /// The same command after it is copied to `range` of generation `gen`
+    fn moved(&self, gen: u64, range: Range<u64>) -> CommandPos {
+        CommandPos {
+            ts: self.ts,
+            expires: self.expires,
+            value: self.value.clone(),
+            ..(gen, range).into()
+        }
+    }
+
+    /// Whether the key has expired at `now`
+    fn expired(&self, now: u64) -> bool {
+        self.expires.map_or(false, |expires| expires <= now)
+    }
 }
 
 impl From<(u64, Range<u64>)> for CommandPos {
@@ -727,6 +2815,9 @@ impl From<(u64, Range<u64>)> for CommandPos {
             gen,
             pos: range.start,
             len: range.end - range.start,
+            ts: 0,
+            expires: None,
+            value: None,
         }
     }
 }
@@ -794,3 +2885,429 @@ impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
         Ok(self.pos)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    use super::*;
+
+    /// A single thread pool recording how many of its jobs run at the same time
+    #[derive(Default)]
+    struct CountingPool {
+        inner: Option<crate::thread_pool::SharedQueueThreadPool>,
+        spawned: AtomicU64,
+        running: Arc<AtomicU64>,
+        max_running: Arc<AtomicU64>,
+        finished: Arc<AtomicU64>,
+    }
+
+    impl ThreadPool for CountingPool {
+        fn new(threads: u32) -> Result<Self> {
+            Ok(CountingPool {
+                inner: Some(ThreadPool::new(threads)?),
+                ..Default::default()
+            })
+        }
+
+        fn spawn<F>(&self, job: F)
+        where
+            F: FnOnce() + Send + 'static,
+        {
+            self.spawned.fetch_add(1, Ordering::SeqCst);
+            let running = self.running.clone();
+            let max_running = self.max_running.clone();
+            let finished = self.finished.clone();
+            self.inner.as_ref().unwrap().spawn(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                job();
+                running.fetch_sub(1, Ordering::SeqCst);
+                finished.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+    }
+
+    fn pos(gen: u64, n: u64) -> CommandPos {
+        (gen, n..n * 2).into()
+    }
+
+    #[test]
+    fn hierarchical_index_levels() {
+        let index = HierarchicalIndex::default();
+        assert!(index.insert("a".to_owned(), pos(1, 1)).is_none());
+        assert!(index.insert("b".to_owned(), pos(1, 2)).is_none());
+        index.snapshot();
+
+        // the previous record is found in snapshot
+        assert_eq!(index.insert("a".to_owned(), pos(2, 3)).unwrap().pos, 1);
+        assert_eq!(index.get(&"a".to_owned()).unwrap().pos, 3);
+        assert_eq!(index.remove(&"b".to_owned()).unwrap().pos, 2);
+        assert!(index.get(&"b".to_owned()).is_none());
+        assert!(index.remove(&"b".to_owned()).is_none());
+
+        // a tombstone removes the record from snapshot
+        assert_eq!(index.snapshot(), 1 + 2);
+        assert_eq!(index.get(&"a".to_owned()).unwrap().pos, 3);
+        assert!(index.get(&"b".to_owned()).is_none());
+        assert!(index.insert("b".to_owned(), pos(3, 4)).is_none());
+    }
+
+    #[test]
+    fn hierarchical_index_snapshot_mid_read() {
+        let index = Arc::new(HierarchicalIndex::default());
+        for n in 1..1000 {
+            index.insert(n.to_string(), pos(0, n));
+        }
+        let done = Arc::new(AtomicBool::new(false));
+
+        // keys are only overwritten, so they must never disappear
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let (index, done) = (index.clone(), done.clone());
+                thread::spawn(move || {
+                    while !done.load(Ordering::SeqCst) {
+                        for n in 1..1000 {
+                            assert!(index.get(&n.to_string()).is_some(), "key {} is lost", n);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for round in 1..100 {
+            for n in (round..1000).step_by(7) {
+                index.insert(n.to_string(), pos(round, n));
+            }
+            index.snapshot();
+        }
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn hierarchical_index_concurrent_reads() {
+        let index = Arc::new(HierarchicalIndex::default());
+        let done = Arc::new(AtomicBool::new(false));
+
+        // a record of key `n` always has `pos == n` and `len == n`
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let (index, done) = (index.clone(), done.clone());
+                thread::spawn(move || {
+                    while !done.load(Ordering::SeqCst) {
+                        for n in 1..100 {
+                            if let Some(cmd_pos) = index.get(&n.to_string()) {
+                                assert_eq!((cmd_pos.pos, cmd_pos.len), (n, n));
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for round in 1..50 {
+            for n in 1..100 {
+                if (n + round) % 3 == 0 {
+                    index.remove(&n.to_string());
+                } else {
+                    index.insert(n.to_string(), pos(round, n));
+                }
+            }
+            if round % 5 == 0 {
+                index.snapshot();
+            }
+        }
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        index.snapshot();
+        for n in 1..100 {
+            let cmd_pos = index.get(&n.to_string());
+            if (n + 49) % 3 == 0 {
+                assert!(cmd_pos.is_none());
+            } else {
+                assert_eq!(cmd_pos.unwrap().gen, 49);
+            }
+        }
+    }
+
+    #[test]
+    fn get_reopens_missing_reader() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        store.inner.write().unwrap().readers.clear();
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+        // without the log, the get fails rather than panics
+        store.inner.write().unwrap().readers.clear();
+        for gen in sorted_gen_list(temp_dir.path())? {
+            fs::remove_file(find_log_path(temp_dir.path(), gen))?;
+        }
+        assert!(store.get("key1".to_owned()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn gets_share_read_lock() -> Result<()> {
+        let temp_dir = tempfile::TempDir::new()?;
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        // a get goes on while another reader holds the lock, it would wait for a write lock
+        let guard = store.inner.read().unwrap();
+        let (tx, rx) = bounded(1);
+        let reader = {
+            let store = store.clone();
+            thread::spawn(move || tx.send(store.get("key1".to_owned())))
+        };
+        let value = rx.recv_timeout(Duration::from_secs(5));
+        drop(guard);
+        reader.join().unwrap().ok();
+        assert_eq!(
+            value.expect("get blocked by a reader")?,
+            Some("value1".to_owned())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn compactions_share_pool() -> Result<()> {
+        let pool = Arc::new(CountingPool::new(1)?);
+        let dirs = [tempfile::TempDir::new()?, tempfile::TempDir::new()?];
+        let shards = dirs
+            .iter()
+            .map(|dir| {
+                ReadLockFreeKvStore::open_with_pool(dir.path(), pool.clone(), MAX_OPEN_FILES)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // overwrite a key until each shard has compacted once
+        let value = "v".repeat(1024);
+        for round in 0..1100 {
+            for shard in &shards {
+                shard.set("key".to_owned(), format!("{}{}", value, round))?;
+            }
+        }
+        while pool.finished.load(Ordering::SeqCst) < 2 {
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        assert_eq!(pool.max_running.load(Ordering::SeqCst), 1);
+        for shard in &shards {
+            assert_eq!(shard.get("key".to_owned())?, Some(format!("{}1099", value)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn compaction_removes_stale_logs() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let pool = Arc::new(CountingPool::new(1)?);
+        let store = ReadLockFreeKvStore::open_with_pool(dir.path(), pool.clone(), MAX_OPEN_FILES)?;
+        let value = "v".repeat(1024);
+        // overwrite a key until a compaction is triggered, and wait for it
+        let compact = |store: &ReadLockFreeKvStore<CountingPool>, round: u64| -> Result<()> {
+            let spawned = pool.spawned.load(Ordering::SeqCst);
+            for n in 0.. {
+                store.set("hot".to_owned(), format!("{}{}-{}", value, round, n))?;
+                if pool.spawned.load(Ordering::SeqCst) > spawned {
+                    break;
+                }
+            }
+            while pool.finished.load(Ordering::SeqCst) < pool.spawned.load(Ordering::SeqCst) {
+                thread::sleep(std::time::Duration::from_millis(10));
+            }
+            Ok(())
+        };
+
+        for n in 0..100 {
+            store.set(format!("key{}", n), "value".to_owned())?;
+        }
+        // the keys are rewritten into the compacted log along with the hot key
+        compact(&store, 1)?;
+        for n in 0..100 {
+            store.remove(format!("key{}", n))?;
+        }
+        // the previous compacted log has no live record left
+        compact(&store, 2)?;
+
+        let check = |store: &ReadLockFreeKvStore<CountingPool>| -> Result<()> {
+            for n in 0..100 {
+                assert_eq!(store.get(format!("key{}", n))?, None, "key{}", n);
+            }
+            assert!(store.get("hot".to_owned())?.unwrap().starts_with(&value));
+            Ok(())
+        };
+        check(&store)?;
+        // the last compacted log and the active log
+        assert_eq!(sorted_gen_list(dir.path())?.len(), 2);
+
+        // removed keys are not brought back by stale logs
+        drop(store);
+        let store = ReadLockFreeKvStore::open_with_pool(dir.path(), pool, MAX_OPEN_FILES)?;
+        check(&store)
+    }
+
+    #[test]
+    fn reopen_then_compact() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let pool = Arc::new(CountingPool::new(1)?);
+        let store = ReadLockFreeKvStore::open_with_pool(dir.path(), pool.clone(), MAX_OPEN_FILES)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+
+        // the writes after a reopen go to a new log, and are read back from it
+        let store = ReadLockFreeKvStore::open_with_pool(dir.path(), pool.clone(), MAX_OPEN_FILES)?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        // overwrite a key until a compaction, whose log must not be the active one
+        let value = "v".repeat(1024);
+        for round in 0.. {
+            store.set("hot".to_owned(), format!("{}{}", value, round))?;
+            if pool.spawned.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+        }
+        while pool.finished.load(Ordering::SeqCst) < 1 {
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        // a failed compaction would be reported here
+        store.set("key3".to_owned(), "value3".to_owned())?;
+
+        for i in 1..=3 {
+            let value = store.get(format!("key{}", i))?;
+            assert_eq!(value, Some(format!("value{}", i)));
+        }
+        assert!(store.get("hot".to_owned())?.unwrap().starts_with(&value));
+        Ok(())
+    }
+
+    #[test]
+    fn failed_compaction_reported() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let pool = Arc::new(CountingPool::new(1)?);
+        let store = ReadLockFreeKvStore::open_with_pool(dir.path(), pool.clone(), MAX_OPEN_FILES)?;
+        // the first compaction can't create its log, which is next to the active log 1
+        fs::create_dir(log_path(dir.path(), 2))?;
+
+        let value = "v".repeat(1024);
+        for round in 0.. {
+            store.set("key".to_owned(), format!("{}{}", value, round))?;
+            if pool.spawned.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+        }
+        while pool.finished.load(Ordering::SeqCst) < 1 {
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let err = store.set("key".to_owned(), "next".to_owned()).unwrap_err();
+        assert!(matches!(*err, ErrorCode::CompactionFailed(_)));
+        // the index is left as it was, and the failure is reported once
+        assert!(store.get("key".to_owned())?.unwrap().starts_with(&value));
+        store.set("key".to_owned(), "next".to_owned())?;
+        assert_eq!(store.get("key".to_owned())?, Some("next".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn capped_open_files() -> Result<()> {
+        const MAX_OPEN: usize = 2;
+        let dir = tempfile::TempDir::new()?;
+        // a key in each of 5 generations
+        for gen in 1..=5 {
+            let cmd = Command::set(format!("key{}", gen), format!("value{}", gen), 0, None);
+            fs::write(log_path(dir.path(), gen), serde_json::to_vec(&cmd)?)?;
+        }
+        let store = ReadLockFreeKvStore::<NaiveThreadPool>::open_with_pool(
+            dir.path(),
+            Arc::new(NaiveThreadPool::new(1)?),
+            MAX_OPEN,
+        )?;
+
+        let readers: Vec<_> = (0..8)
+            .map(|i| {
+                let store = store.clone();
+                thread::spawn(move || -> Result<()> {
+                    for round in 0..200 {
+                        let gen = (i + round) % 5 + 1;
+                        let value = store.get(format!("key{}", gen))?;
+                        assert_eq!(value, Some(format!("value{}", gen)));
+                        assert!(store.open_files() <= MAX_OPEN);
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for reader in readers {
+            reader.join().unwrap()?;
+        }
+        assert!(store.open_files() <= MAX_OPEN);
+        Ok(())
+    }
+
+    #[test]
+    fn misses_open_no_files() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        for gen in 1..=5 {
+            let cmd = Command::set(format!("key{}", gen), format!("value{}", gen), 0, None);
+            fs::write(log_path(dir.path(), gen), serde_json::to_vec(&cmd)?)?;
+        }
+        let store = ReadLockFreeKvStore::<NaiveThreadPool>::open_with_pool(
+            dir.path(),
+            Arc::new(NaiveThreadPool::new(1)?),
+            MAX_OPEN_FILES,
+        )?;
+
+        // the index has the generation of every key, so a miss is answered from memory
+        for i in 0..1000 {
+            assert_eq!(store.get(format!("missing{}", i))?, None);
+        }
+        assert_eq!(store.open_files(), 0);
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.open_files(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn failed_batch_not_applied() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let store = KvStore::open(dir.path())?;
+        store.set("key".to_owned(), "value".to_owned())?;
+
+        // swap in a writer of a read-only handle, so the write of the batch fails
+        let log = log_path(dir.path(), 1);
+        let len = fs::metadata(&log)?.len();
+        let mut writer = BufWriterWithPos::new(File::open(&log)?)?;
+        writer.pos = len;
+        store.inner.write().unwrap().writer = writer;
+        let ops = (0..500)
+            .map(|i| BatchOp::Set {
+                key: format!("key{}", i),
+                value: format!("value{}", i),
+            })
+            .collect();
+        assert!(store.write_batch(ops).is_err());
+        assert_eq!(fs::metadata(&log)?.len(), len);
+        assert_eq!(store.get("key0".to_owned())?, None);
+
+        // the log is cut back to where the batch started and written again
+        store.set("key0".to_owned(), "value0".to_owned())?;
+        drop(store);
+        let store = KvStore::open(dir.path())?;
+        assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,170 @@
+//! The LZ4 block format: sequences of a token, literals, and a match copied from the output
+//! already decoded. Blocks written here are read by any LZ4 block decoder and the other way
+//! round, though the matcher is a plain greedy one.
+
+/// Matches are at least this long
+const MIN_MATCH: usize = 4;
+/// The last match starts at least this many bytes before the end of a block
+const MF_LIMIT: usize = 12;
+/// A block always ends with at least this many literals
+const LAST_LITERALS: usize = 5;
+/// The max distance of a match back in the output
+const MAX_OFFSET: usize = u16::MAX as usize;
+/// Bits of the hash of 4 bytes, which indexes the positions they were last seen at
+const HASH_LOG: u32 = 12;
+
+fn read_u32(bytes: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+}
+
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+/// Compress `input` into a block
+pub(crate) fn compress(input: &[u8]) -> Vec<u8> {
+    let mut block = Vec::with_capacity(input.len() / 2 + 16);
+    // `usize::MAX` for 4 bytes not seen yet
+    let mut table = vec![usize::MAX; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+    if input.len() > MF_LIMIT {
+        let match_limit = input.len() - MF_LIMIT;
+        let end_limit = input.len() - LAST_LITERALS;
+        while pos < match_limit {
+            let seq = read_u32(input, pos);
+            let candidate = std::mem::replace(&mut table[hash(seq)], pos);
+            if candidate == usize::MAX
+                || pos - candidate > MAX_OFFSET
+                || read_u32(input, candidate) != seq
+            {
+                pos += 1;
+                continue;
+            }
+            let mut len = MIN_MATCH;
+            while pos + len < end_limit && input[candidate + len] == input[pos + len] {
+                len += 1;
+            }
+            write_sequence(
+                &mut block,
+                &input[anchor..pos],
+                Some((pos - candidate, len)),
+            );
+            pos += len;
+            anchor = pos;
+        }
+    }
+    write_sequence(&mut block, &input[anchor..], None);
+    block
+}
+
+/// Append a sequence of `literals` followed by a match of `(offset, len)`, which only the last
+/// sequence of a block goes without
+fn write_sequence(block: &mut Vec<u8>, literals: &[u8], copy: Option<(usize, usize)>) {
+    let match_len = copy.map_or(0, |(_, len)| len - MIN_MATCH);
+    block.push(((literals.len().min(15) as u8) << 4) | match_len.min(15) as u8);
+    if literals.len() >= 15 {
+        write_len(block, literals.len() - 15);
+    }
+    block.extend_from_slice(literals);
+    if let Some((offset, _)) = copy {
+        block.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            write_len(block, match_len - 15);
+        }
+    }
+}
+
+/// The rest of a length which didn't fit in its 4 bits of the token
+fn write_len(block: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        block.push(255);
+        len -= 255;
+    }
+    block.push(len as u8);
+}
+
+fn read_len(block: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut len = 0_usize;
+    loop {
+        let byte = *block.get(*pos)?;
+        *pos += 1;
+        len = len.checked_add(byte as usize)?;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+/// Decompress a block of `len` bytes, `None` if it is corrupted
+pub(crate) fn decompress(block: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(len);
+    let mut pos = 0;
+    loop {
+        let token = *block.get(pos)?;
+        pos += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals = literals.checked_add(read_len(block, &mut pos)?)?;
+        }
+        output.extend_from_slice(block.get(pos..pos.checked_add(literals)?)?);
+        pos += literals;
+        if pos == block.len() {
+            break;
+        }
+
+        let offset = u16::from_le_bytes([*block.get(pos)?, *block.get(pos + 1)?]) as usize;
+        pos += 2;
+        let mut match_len = (token & 15) as usize;
+        if match_len == 15 {
+            match_len = match_len.checked_add(read_len(block, &mut pos)?)?;
+        }
+        match_len += MIN_MATCH;
+        if offset == 0 || offset > output.len() || output.len() + match_len > len {
+            return None;
+        }
+        // a match may overlap the bytes it produces, so it is copied byte by byte
+        let start = output.len() - offset;
+        for i in start..start + match_len {
+            output.push(output[i]);
+        }
+    }
+    Some(output).filter(|output| output.len() == len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8]) -> Vec<u8> {
+        let block = compress(input);
+        assert_eq!(decompress(&block, input.len()).as_deref(), Some(input));
+        block
+    }
+
+    #[test]
+    fn short_inputs() {
+        for len in 0..=MF_LIMIT + 1 {
+            round_trip(&vec![b'a'; len]);
+        }
+    }
+
+    #[test]
+    fn repeated_input_shrinks() {
+        let input = "value ".repeat(1000);
+        assert!(round_trip(input.as_bytes()).len() < input.len() / 10);
+        // long literals and matches need extra length bytes
+        let input: Vec<u8> = (0..600_u32).map(|i| (i * 7 % 251) as u8).collect();
+        round_trip(&[input.clone(), input].concat());
+    }
+
+    #[test]
+    fn corrupted_block() {
+        let input = "value ".repeat(100);
+        let block = compress(input.as_bytes());
+        assert!(decompress(&block, input.len() + 1).is_none());
+        assert!(decompress(&block[..block.len() - 1], input.len()).is_none());
+        // a match reaching back before the start of the output
+        assert!(decompress(&[0x00, 0xFF, 0xFF], 4).is_none());
+    }
+}
@@ -1,6 +1,52 @@
-use std::path::Path;
+use std::{
+    io::{Read, Write},
+    ops::RangeBounds,
+    path::Path,
+    time::Duration,
+};
 
-use crate::Result;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{error::ErrorCode, Result};
+
+/// Approximate memory held by an engine, in bytes
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryReport {
+    /// keys and value locations kept in memory
+    pub index_bytes: u64,
+    /// buffers of the open log readers
+    pub reader_cache_bytes: u64,
+    /// everything else, e.g. write buffers
+    pub other: u64,
+}
+
+/// Health of the data kept by an engine
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreStats {
+    /// number of live keys
+    pub num_keys: u64,
+    /// bytes of stale commands which a compaction would reclaim
+    pub uncompacted_bytes: u64,
+    /// number of log files, 0 for engines without logs of their own
+    pub num_generations: u64,
+    /// bytes of all the data on disk
+    pub total_bytes: u64,
+    /// bytes appended to the logs by writes since the store was opened, not counting the logs
+    /// rewritten by compactions. 0 for engines which don't count them.
+    #[serde(default)]
+    pub bytes_written: u64,
+    /// compactions run since the store was opened, 0 for engines which don't count them
+    #[serde(default)]
+    pub compactions: u64,
+    /// values read from the disk by gets since the store was opened, 0 for engines which don't
+    /// count them
+    #[serde(default)]
+    pub disk_reads: u64,
+    /// memory held by the engine as `KvsEngine::memory_usage` estimates it, so a server
+    /// reports it along with the rest
+    #[serde(default)]
+    pub memory: MemoryReport,
+}
 
 pub trait KvsEngine: Clone + Send + 'static {
     fn open(path: &Path) -> Result<Self>
@@ -9,10 +55,212 @@ pub trait KvsEngine: Clone + Send + 'static {
 
     fn set(&self, key: String, value: String) -> Result<()>;
 
+    /// Sets `key` to `value` for `ttl`, after which the key reads as if it had been removed.
+    /// The expiry survives a restart. Engines without expiry keep the key until it is removed.
+    fn set_with_ttl(&self, key: String, value: String, _ttl: Duration) -> Result<()> {
+        self.set(key, value)
+    }
+
     fn get(&self, key: String) -> Result<Option<String>>;
 
+    /// Gets the values of `keys` in their order. Engines behind a lock look them all up in a
+    /// single acquisition of it.
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Whether `key` exists. Engines which can tell from memory don't read the value.
+    fn contains(&self, key: String) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// Sets `key` to a binary `value`, which doesn't have to be UTF-8. Engines which only
+    /// store strings fail with `ErrorCode::Utf8` for other values.
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.set(key, String::from_utf8(value)?)
+    }
+
+    /// Gets the value of `key` as bytes, whether it was set with `set` or `set_bytes`. `get`
+    /// fails with `ErrorCode::Utf8` for a value set as bytes which are not UTF-8.
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(key)?.map(String::into_bytes))
+    }
+
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Removes `key` and returns its value, or `None` if it does not exist. The read and the
+    /// removal are atomic, so a value is never returned to two callers.
+    fn get_delete(&self, _key: String) -> Result<Option<String>> {
+        Err(ErrorCode::Unsupported("get_delete".to_string()).into())
+    }
+
+    /// Sets `key` to `new`, or removes it for `None`, if its value is `expected` and returns
+    /// `true`. `None` expects the key not to exist. Returns `false` and leaves the key alone
+    /// otherwise. The comparison and the write are atomic.
+    fn compare_and_swap(
+        &self,
+        _key: String,
+        _expected: Option<String>,
+        _new: Option<String>,
+    ) -> Result<bool> {
+        Err(ErrorCode::Unsupported("compare_and_swap".to_string()).into())
+    }
+
+    /// Adds `delta` to the integer value of `key`, which is 0 if the key doesn't exist, and
+    /// returns the new value. It fails with `ErrorCode::NotAnInteger` for a value which is not
+    /// an `i64`.
+    ///
+    /// Engines without a lock of their own retry a compare-and-swap until no other write
+    /// comes in between.
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        loop {
+            let current = self.get(key.clone())?;
+            let value = incremented(&key, current.as_deref(), delta)?;
+            if self.compare_and_swap(key.clone(), current, Some(value.to_string()))? {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Returns the key/value pairs whose key is in `range`, in key order
+    fn scan(&self, _range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        Err(ErrorCode::Unsupported("scan".to_string()).into())
+    }
+
+    /// Returns up to `limit` keys in `range` in key order, without their values. Engines with
+    /// an index in memory don't read the logs.
+    fn keys(&self, range: impl RangeBounds<String>, limit: usize) -> Result<Vec<String>> {
+        Ok(self
+            .scan(range)?
+            .into_iter()
+            .take(limit)
+            .map(|(key, _)| key)
+            .collect())
+    }
+
+    /// Removes the keys in `range` and returns how many were removed, e.g. all keys of a
+    /// prefix. Engines without a lock of their own remove the keys of a `scan` one by one, so
+    /// a key written in between may be left.
+    fn delete_range(&self, range: impl RangeBounds<String>) -> Result<usize> {
+        let mut removed = 0;
+        for (key, _) in self.scan(range)? {
+            match self.remove(key) {
+                Ok(()) => removed += 1,
+                // removed by another write in between
+                Err(err) if matches!(*err, ErrorCode::KeyNotFound) => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Returns when `key` was last written in milliseconds since the unix epoch, or `None`
+    /// if the key does not exist. `Some(0)` means the write time is unknown.
+    fn last_modified(&self, _key: String) -> Result<Option<u64>> {
+        Err(ErrorCode::Unsupported("last_modified".to_string()).into())
+    }
+
+    /// Writes whatever the engine has buffered to the disk
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Best-effort estimate of the memory used by the engine, all zero if unknown
+    fn memory_usage(&self) -> MemoryReport {
+        MemoryReport::default()
+    }
+
+    /// Statistics of the stored data, all zero if unknown
+    fn stats(&self) -> Result<StoreStats> {
+        Ok(StoreStats::default())
+    }
+
+    /// Write all key/value pairs in key order to `writer` as newline-delimited JSON objects
+    /// `{"key":...,"value":...}`, a backup which doesn't depend on the format of the engine.
+    /// Engines without `scan` can't be dumped.
+    fn dump(&self, writer: &mut impl Write) -> Result<()> {
+        for (key, value) in self.scan(..)? {
+            serde_json::to_writer(&mut *writer, &JsonRecord { key, value })?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Open the store at `path` and set all key/value pairs of a `dump` read from `reader`.
+    /// The dump of an engine restores into any other, e.g. to migrate from `KvStore` to
+    /// `SledStore`.
+    fn restore(path: &Path, reader: &mut impl Read) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let store = Self::open(path)?;
+        import_dump(&store, reader)?;
+        Ok(store)
+    }
+}
+
+/// Set all key/value pairs of a `KvsEngine::dump` read from `reader` in `store`, and flush them.
+///
+/// Returns the number of pairs set.
+pub(crate) fn import_dump(store: &impl KvsEngine, reader: &mut impl Read) -> Result<u64> {
+    let mut imported = 0;
+    for record in serde_json::Deserializer::from_reader(reader).into_iter::<JsonRecord>() {
+        let JsonRecord { key, value } = record?;
+        store.set(key, value)?;
+        imported += 1;
+    }
+    store.flush()?;
+    Ok(imported)
+}
+
+/// A key/value pair dumped as one line of JSON
+#[derive(Serialize, Deserialize)]
+pub(crate) struct JsonRecord {
+    pub(crate) key: String,
+    pub(crate) value: String,
+}
+
+/// The value of the counter `key` after adding `delta` to its current `value`
+pub(crate) fn incremented(key: &str, value: Option<&str>, delta: i64) -> Result<i64> {
+    let value = match value {
+        Some(value) => value.parse::<i64>().map_err(|_| ErrorCode::NotAnInteger {
+            key: key.to_owned(),
+        })?,
+        None => 0,
+    };
+    Ok(value
+        .checked_add(delta)
+        .ok_or_else(|| ErrorCode::IncrementOverflow {
+            key: key.to_owned(),
+        })?)
+}
+
+/// Check a written key and the length of its value against the limits, `None` for no limit
+pub(crate) fn check_sizes(
+    key: &str,
+    value_len: usize,
+    max_key_size: Option<usize>,
+    max_value_size: Option<usize>,
+) -> Result<()> {
+    if let Some(limit) = max_key_size.filter(|&limit| key.len() > limit) {
+        return Err(ErrorCode::KeyTooLarge {
+            size: key.len(),
+            limit,
+        }
+        .into());
+    }
+    if let Some(limit) = max_value_size.filter(|&limit| value_len > limit) {
+        return Err(ErrorCode::ValueTooLarge {
+            size: value_len,
+            limit,
+        }
+        .into());
+    }
+    Ok(())
 }
 
 pub mod kvs;
+mod lz4;
+pub mod sharded;
 pub mod sled;
@@ -0,0 +1,243 @@
+use std::fs;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::kvs::{crc32, KvStore, KvStoreOpts};
+use super::{KvsEngine, MemoryReport, StoreStats};
+use crate::error::ErrorCode;
+use crate::Result;
+
+const DEFAULT_SHARDS: usize = 8;
+const SHARDS_FILE: &str = "SHARDS";
+
+/// A store whose keys are hashed into shards, each a `KvStore` with its own logs and lock.
+///
+/// Writes to keys of different shards run concurrently, and every shard compacts on its own.
+/// Reads and writes of a key go to the shard of its hash, while `scan`, `keys` and
+/// `delete_range` visit all shards and are not atomic across them.
+///
+/// The shards are kept in the `shard-<n>` subdirectories, and their number is recorded in the
+/// directory, as the shard of a key depends on it.
+///
+/// ```rust
+/// # use kvs::{ShardedKvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+/// let store = ShardedKvStore::open(&current_dir()?)?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// let val = store.get("key".to_owned())?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ShardedKvStore {
+    shards: Arc<Vec<KvStore>>,
+}
+
+impl ShardedKvStore {
+    /// Opens a `ShardedKvStore` of `shards` shards with the given path, every shard with
+    /// `opts`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `shards` is 0.
+    ///
+    /// # Errors
+    ///
+    /// It fails with `ErrorCode::ShardMismatch` if the directory was created with another
+    /// number of shards. Otherwise it fails as `KvStore::open_with_opts` does for any shard.
+    pub fn open_with_opts(path: &Path, shards: usize, opts: KvStoreOpts) -> Result<Self> {
+        assert!(shards > 0, "a sharded store needs at least one shard");
+        fs::create_dir_all(path)?;
+        match recorded_shards(path)? {
+            Some(found) if found != shards => {
+                return Err(ErrorCode::ShardMismatch {
+                    expected: shards,
+                    found: found.to_string(),
+                }
+                .into())
+            }
+            Some(_) => (),
+            None => fs::write(path.join(SHARDS_FILE), shards.to_string())?,
+        }
+
+        let shards = (0..shards)
+            .map(|i| KvStore::open_with_opts(&path.join(format!("shard-{}", i)), opts.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ShardedKvStore {
+            shards: Arc::new(shards),
+        })
+    }
+
+    /// The number of shards
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Compact every shard, one after another
+    pub fn compact(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvStore::compact)
+    }
+
+    /// The shard `key` is kept in. The hash is a checksum of the key rather than the
+    /// `DefaultHasher`, whose algorithm may change between releases of Rust and would move
+    /// the keys kept on the disk to other shards.
+    fn shard(&self, key: &str) -> &KvStore {
+        &self.shards[crc32(key.as_bytes()) as usize % self.shards.len()]
+    }
+}
+
+impl KvsEngine for ShardedKvStore {
+    /// Opens a `ShardedKvStore` with the number of shards the directory was created with, or
+    /// 8 shards for a new directory.
+    ///
+    /// # Errors
+    ///
+    /// It fails with `ErrorCode::ShardMismatch` if the recorded number of shards is not a
+    /// number above 0.
+    fn open(path: &Path) -> Result<Self> {
+        let shards = recorded_shards(path)?.unwrap_or(DEFAULT_SHARDS);
+        ShardedKvStore::open_with_opts(path, shards, KvStoreOpts::default())
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set(key, value)
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.shard(&key).set_with_ttl(key, value, ttl)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
+
+    fn contains(&self, key: String) -> Result<bool> {
+        self.shard(&key).contains(key)
+    }
+
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.shard(&key).set_bytes(key, value)
+    }
+
+    fn get_bytes(&self, key: String) -> Result<Option<Vec<u8>>> {
+        self.shard(&key).get_bytes(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)
+    }
+
+    fn get_delete(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get_delete(key)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.shard(&key).compare_and_swap(key, expected, new)
+    }
+
+    fn increment(&self, key: String, delta: i64) -> Result<i64> {
+        self.shard(&key).increment(key, delta)
+    }
+
+    fn scan(&self, range: impl RangeBounds<String>) -> Result<Vec<(String, String)>> {
+        let range = owned_bounds(&range);
+        let mut pairs = Vec::new();
+        for shard in self.shards.iter() {
+            pairs.extend(shard.scan(range.clone())?);
+        }
+        pairs.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(pairs)
+    }
+
+    fn keys(&self, range: impl RangeBounds<String>, limit: usize) -> Result<Vec<String>> {
+        let range = owned_bounds(&range);
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            keys.extend(shard.keys(range.clone(), limit)?);
+        }
+        keys.sort_unstable();
+        keys.truncate(limit);
+        Ok(keys)
+    }
+
+    /// Every shard removes its keys of `range` by a single batch, so a key written in between
+    /// may be left in a shard not yet visited
+    fn delete_range(&self, range: impl RangeBounds<String>) -> Result<usize> {
+        let range = owned_bounds(&range);
+        let mut removed = 0;
+        for shard in self.shards.iter() {
+            removed += shard.delete_range(range.clone())?;
+        }
+        Ok(removed)
+    }
+
+    fn last_modified(&self, key: String) -> Result<Option<u64>> {
+        self.shard(&key).last_modified(key)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvStore::flush)
+    }
+
+    fn memory_usage(&self) -> MemoryReport {
+        let mut report = MemoryReport::default();
+        for shard in self.shards.iter() {
+            let usage = shard.memory_usage();
+            report.index_bytes += usage.index_bytes;
+            report.reader_cache_bytes += usage.reader_cache_bytes;
+            report.other += usage.other;
+        }
+        report
+    }
+
+    /// The sums of the statistics of all shards
+    fn stats(&self) -> Result<StoreStats> {
+        let mut stats = StoreStats::default();
+        for shard in self.shards.iter() {
+            let shard = shard.stats()?;
+            stats.num_keys += shard.num_keys;
+            stats.uncompacted_bytes += shard.uncompacted_bytes;
+            stats.num_generations += shard.num_generations;
+            stats.total_bytes += shard.total_bytes;
+            stats.bytes_written += shard.bytes_written;
+            stats.compactions += shard.compactions;
+            stats.disk_reads += shard.disk_reads;
+            stats.memory.index_bytes += shard.memory.index_bytes;
+            stats.memory.reader_cache_bytes += shard.memory.reader_cache_bytes;
+            stats.memory.other += shard.memory.other;
+        }
+        Ok(stats)
+    }
+}
+
+/// The number of shards recorded in `dir`, or `None` for a directory without shards
+fn recorded_shards(dir: &Path) -> Result<Option<usize>> {
+    let file = dir.join(SHARDS_FILE);
+    if !file.exists() {
+        return Ok(None);
+    }
+    let recorded = fs::read_to_string(file)?;
+    match recorded.trim().parse() {
+        Ok(shards) if shards > 0 => Ok(Some(shards)),
+        _ => Err(ErrorCode::ShardMismatch {
+            expected: DEFAULT_SHARDS,
+            found: recorded.trim().to_owned(),
+        }
+        .into()),
+    }
+}
+
+/// `range` with owned bounds, to pass it on to every shard
+fn owned_bounds(range: &impl RangeBounds<String>) -> (Bound<String>, Bound<String>) {
+    (range.start_bound().cloned(), range.end_bound().cloned())
+}
@@ -1,9 +1,54 @@
-use std::sync::Arc;
+use std::{
+    convert::TryInto,
+    ops::RangeBounds,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use crate::{error::ErrorCode, KvsEngine};
+use crate::{common::unix_millis, error::ErrorCode, KvsEngine, StoreStats};
 
 use sled::{Db, IVec, Tree};
 
+/// Marks a value stored with an expiry. It is never the first byte of UTF-8, so values stored
+/// without one are told apart. A binary value starting with it is stored with an expiry header
+/// which never expires.
+const EXPIRY_MARKER: u8 = 0xFF;
+
+/// The stored bytes of `value`, behind an expiry header if it expires or could be taken for one
+fn encode(value: &[u8], expires: Option<u64>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(value.len() + 9);
+    if expires.is_some() || value.first() == Some(&EXPIRY_MARKER) {
+        bytes.push(EXPIRY_MARKER);
+        bytes.extend_from_slice(&expires.unwrap_or(u64::MAX).to_be_bytes());
+    }
+    bytes.extend_from_slice(value);
+    bytes
+}
+
+/// The value in the stored bytes, or `None` if it has expired
+fn live(bytes: &[u8]) -> Option<&[u8]> {
+    match bytes.split_first() {
+        Some((&EXPIRY_MARKER, rest)) if rest.len() >= 8 => {
+            let (expires, value) = rest.split_at(8);
+            if u64::from_be_bytes(expires.try_into().unwrap()) <= unix_millis(SystemTime::now()) {
+                return None;
+            }
+            Some(value)
+        }
+        _ => Some(bytes),
+    }
+}
+
+/// The value of the stored bytes, or `None` if it has expired
+fn decode(bytes: IVec) -> Option<Vec<u8>> {
+    live(&bytes).map(<[u8]>::to_vec)
+}
+
+/// The value of the stored bytes as a string, or `None` if it has expired
+fn decode_str(bytes: IVec) -> crate::Result<Option<String>> {
+    Ok(decode(bytes).map(String::from_utf8).transpose()?)
+}
+
 #[derive(Clone)]
 pub struct SledStore {
     tree: Db,
@@ -24,18 +69,127 @@ impl KvsEngine for SledStore {
         Ok(())
     }
 
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> crate::Result<()> {
+        let expires = unix_millis(SystemTime::now()).saturating_add(ttl.as_millis() as u64);
+        self.tree
+            .insert(key, encode(value.as_bytes(), Some(expires)))?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
     fn get(&self, key: String) -> crate::Result<Option<String>> {
+        Ok(self.tree.get(key)?.map(decode_str).transpose()?.flatten())
+    }
+
+    fn contains(&self, key: String) -> crate::Result<bool> {
+        // a key set with a TTL may have expired, which takes its header but no copy of the value
         Ok(self
             .tree
             .get(key)?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+            .map_or(false, |bytes| live(&bytes).is_some()))
+    }
+
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> crate::Result<()> {
+        self.tree.insert(key, encode(&value, None))?;
+        self.tree.flush()?;
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: String) -> crate::Result<Option<Vec<u8>>> {
+        Ok(self.tree.get(key)?.and_then(decode))
     }
 
     fn remove(&self, key: String) -> crate::Result<()> {
-        self.tree.remove(key)?.ok_or(ErrorCode::RmKeyNotFound)?;
+        // an expired key is removed all the same, but reported as not found
+        let value = self.tree.remove(key)?.ok_or(ErrorCode::KeyNotFound)?;
+        self.tree.flush()?;
+        decode(value).ok_or(ErrorCode::KeyNotFound)?;
+        Ok(())
+    }
+
+    fn get_delete(&self, key: String) -> crate::Result<Option<String>> {
+        let value = self.tree.remove(key)?;
+        if value.is_some() {
+            self.tree.flush()?;
+        }
+        Ok(value.map(decode_str).transpose()?.flatten())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> crate::Result<bool> {
+        let new = new.map(|value| encode(value.as_bytes(), None));
+        loop {
+            // the stored bytes may have an expiry header, so the value is compared and then
+            // the bytes it was read from are swapped, again if they changed in between
+            let current = self.tree.get(&key)?;
+            if current.clone().map(decode_str).transpose()?.flatten() != expected {
+                return Ok(false);
+            }
+            if self
+                .tree
+                .compare_and_swap(&key, current, new.clone())?
+                .is_ok()
+            {
+                self.tree.flush()?;
+                return Ok(true);
+            }
+        }
+    }
+
+    fn scan(&self, range: impl RangeBounds<String>) -> crate::Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for item in self.tree.range(range) {
+            let (key, value) = item?;
+            if let Some(value) = decode_str(value)? {
+                pairs.push((String::from_utf8(key.to_vec())?, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn keys(&self, range: impl RangeBounds<String>, limit: usize) -> crate::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for item in self.tree.range(range) {
+            if keys.len() == limit {
+                break;
+            }
+            let (key, value) = item?;
+            if live(&value).is_some() {
+                keys.push(String::from_utf8(key.to_vec())?);
+            }
+        }
+        Ok(keys)
+    }
+
+    fn delete_range(&self, range: impl RangeBounds<String>) -> crate::Result<usize> {
+        let mut removed = 0;
+        for item in self.tree.range(range) {
+            let (key, _) = item?;
+            // an expired key is removed all the same, but not counted
+            if let Some(value) = self.tree.remove(key)? {
+                if live(&value).is_some() {
+                    removed += 1;
+                }
+            }
+        }
+        self.tree.flush()?;
+        Ok(removed)
+    }
+
+    fn flush(&self) -> crate::Result<()> {
         self.tree.flush()?;
         Ok(())
     }
+
+    fn stats(&self) -> crate::Result<StoreStats> {
+        Ok(StoreStats {
+            num_keys: self.tree.len() as u64,
+            total_bytes: self.tree.size_on_disk()?,
+            ..Default::default()
+        })
+    }
 }
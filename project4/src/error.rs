@@ -14,10 +14,60 @@ pub enum ErrorCode {
     SledError(#[from] sled::Error),
     #[error("UTF-8 error: {0}")]
     Utf8(#[from] std::string::FromUtf8Error),
-    #[error("Rm Key not found")]
-    RmKeyNotFound,
+    #[error("Key not found")]
+    KeyNotFound,
     #[error("Read Unexpected command")]
     UnexpectedCommandType,
+    #[error("Frame of {size} bytes exceeds the limit of {limit} bytes")]
+    FrameTooLarge { size: usize, limit: usize },
+    #[error("Logs not in a format of the store: {files:?}")]
+    MixedFormats { files: Vec<std::path::PathBuf> },
+    #[error("Background compaction failed: {0}")]
+    CompactionFailed(String),
+    #[error("Compaction rewrote {} records wrongly: {:?}", keys.len(), keys)]
+    CompactionVerificationFailed { keys: Vec<String> },
+    #[error("Response too large: {size} bytes exceeds the budget of {limit} bytes")]
+    ResponseTooLarge { size: usize, limit: usize },
+    #[error("Key of {size} bytes exceeds the limit of {limit} bytes")]
+    KeyTooLarge { size: usize, limit: usize },
+    #[error("Value of {size} bytes exceeds the limit of {limit} bytes")]
+    ValueTooLarge { size: usize, limit: usize },
+    #[error("Rate limit exceeded")]
+    RateLimited,
+    #[error("Deadline exceeded before the request started")]
+    DeadlineExceeded,
+    #[error("Key belongs to the shard at {addr}")]
+    Redirect { addr: std::net::SocketAddr },
+    #[error("{0} is not supported by this engine")]
+    Unsupported(String),
+    /// The connection was closed before the whole response arrived, so the request may or may
+    /// not have been applied. It can be retried on a new connection.
+    #[error("Connection closed before the response")]
+    ConnectionClosed,
+    /// The server didn't answer in time, so the request may or may not have been applied
+    #[error("Request timed out")]
+    Timeout,
+    /// The server answered with a response of another type than the request's
+    #[error("Unexpected response: {0}")]
+    UnexpectedResponse(String),
+    #[error("Value of {key} is not an integer")]
+    NotAnInteger { key: String },
+    #[error("Incrementing {key} overflows")]
+    IncrementOverflow { key: String },
+    #[error("Server busy: too many connections")]
+    ServerBusy,
+    #[error("Store is opened read-only")]
+    ReadOnly,
+    #[error("Shutdown timed out with {in_flight} requests in flight")]
+    ShutdownTimeout { in_flight: usize },
+    #[error("Data directory belongs to the {found} engine, not {expected}")]
+    EngineMismatch { expected: String, found: String },
+    #[error("Data directory is locked by another process: {path:?}")]
+    DirectoryLocked { path: std::path::PathBuf },
+    #[error("Log record fails its checksum")]
+    ChecksumMismatch,
+    #[error("Data directory has {found} shards, not {expected}")]
+    ShardMismatch { expected: usize, found: String },
 }
 
 pub type Result<T> = std::result::Result<T, KvError>;
@@ -27,7 +77,8 @@ pub type Result<T> = std::result::Result<T, KvError>;
 pub struct KvError {
     #[source]
     inner: Box<ErrorCode>,
-    backtrace: Box<Backtrace>,
+    // only captured with the `backtrace` feature
+    backtrace: Option<Box<Backtrace>>,
 }
 
 impl Deref for KvError {
@@ -42,20 +93,22 @@ impl From<ErrorCode> for KvError {
     fn from(value: ErrorCode) -> Self {
         KvError {
             inner: Box::new(value),
-            backtrace: Box::new(Backtrace::capture()),
+            backtrace: if cfg!(feature = "backtrace") {
+                Some(Box::new(Backtrace::capture()))
+            } else {
+                None
+            },
         }
     }
 }
 
 impl core::fmt::Debug for KvError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}\n{}",
-            self.inner,
-            // Use inner error's backtrace by default, otherwise use the generated one in `From`.
-            std::error::request_ref::<Backtrace>(&self.inner).unwrap_or(&*self.backtrace)
-        )
+        // Use inner error's backtrace by default, otherwise use the generated one in `From`.
+        match std::error::request_ref::<Backtrace>(&self.inner).or(self.backtrace.as_deref()) {
+            Some(backtrace) => write!(f, "{}\n{}", self.inner, backtrace),
+            None => write!(f, "{}", self.inner),
+        }
     }
 }
 
@@ -2,16 +2,38 @@
 #![feature(let_chains)]
 
 pub use client::KvClient;
+pub use client::KvClientPool;
+pub use client::Pipeline;
+pub use client::PooledClient;
+pub use common::IoStats;
+pub use engine::kvs::BatchOp;
+pub use engine::kvs::CompactionEstimate;
+pub use engine::kvs::Compression;
 pub use engine::kvs::KvStore;
+pub use engine::kvs::KvStoreOpts;
+pub use engine::kvs::LogCodec;
+pub use engine::kvs::ReadLockFreeKvStore;
+pub use engine::kvs::ReadOnlyKvStore;
+pub use engine::kvs::ValidationReport;
+pub use engine::sharded::ShardedKvStore;
 pub use engine::sled::SledStore;
 pub use engine::KvsEngine;
+pub use engine::MemoryReport;
+pub use engine::StoreStats;
 pub use error::Result;
 pub use server::KvServer;
+pub use server::ServerOpts;
+pub use server::ShardConfig;
 pub use server::ThreadHandle;
+pub use transport::Endpoint;
 pub mod common;
 pub mod error;
+pub mod metrics;
+pub mod middleware;
 pub mod thread_pool;
+pub mod transport;
 
 mod client;
 mod engine;
+mod msgpack;
 mod server;
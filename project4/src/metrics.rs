@@ -0,0 +1,252 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::common::KvsResponse;
+use crate::engine::StoreStats;
+
+/// Counters of the requests served by a `KvServer`, by operation
+#[derive(Default)]
+pub struct ServerMetrics {
+    ops: Mutex<BTreeMap<&'static str, OpMetrics>>,
+    // keys looked up by gets which were found, and which were not
+    hits: AtomicU64,
+    misses: AtomicU64,
+    // connections accepted and not closed yet
+    connections: AtomicU64,
+}
+
+/// The counters of a `KvServer` at some point, with those of its engine
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    /// requests handled, by operation, e.g. `get`
+    pub requests: BTreeMap<&'static str, u64>,
+    /// requests failed, by operation
+    pub errors: BTreeMap<&'static str, u64>,
+    /// keys looked up by gets which were found
+    pub hits: u64,
+    /// keys looked up by gets which were not found
+    pub misses: u64,
+    /// connections accepted and not closed yet
+    pub connections: u64,
+    /// the statistics of the engine, with the bytes it has written and its compactions
+    pub store: StoreStats,
+}
+
+impl MetricsSnapshot {
+    /// The share of the keys looked up by gets which were found, `None` before any lookup
+    pub fn hit_ratio(&self) -> Option<f64> {
+        let lookups = self.hits + self.misses;
+        (lookups > 0).then_some(self.hits as f64 / lookups as f64)
+    }
+}
+
+#[derive(Default)]
+struct OpMetrics {
+    requests: u64,
+    errors: u64,
+    latency: Duration,
+}
+
+impl ServerMetrics {
+    /// Record a request of operation `op` which took `latency` to handle
+    pub fn record(&self, op: &'static str, failed: bool, latency: Duration) {
+        let mut ops = self.ops.lock().unwrap();
+        let metrics = ops.entry(op).or_default();
+        metrics.requests += 1;
+        if failed {
+            metrics.errors += 1;
+        }
+        metrics.latency += latency;
+    }
+
+    /// Record the keys looked up by the gets of `res`, and whether they were found
+    pub fn record_lookups(&self, res: &KvsResponse) {
+        let found = |value: &Option<String>| {
+            let counter = if value.is_some() {
+                &self.hits
+            } else {
+                &self.misses
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        };
+        match res {
+            KvsResponse::Get(Ok(value)) => found(value),
+            KvsResponse::GetMulti(Ok(values)) => values.iter().for_each(found),
+            KvsResponse::Batch(responses) => {
+                responses.iter().for_each(|res| self.record_lookups(res))
+            }
+            _ => {}
+        }
+    }
+
+    /// Count a connection accepted, `opened`, or closed
+    pub(crate) fn record_connection(&self, opened: bool) {
+        if opened {
+            self.connections.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Take the counters, with the engine `stats`
+    pub fn snapshot(&self, stats: StoreStats) -> MetricsSnapshot {
+        let ops = self.ops.lock().unwrap();
+        MetricsSnapshot {
+            requests: ops
+                .iter()
+                .map(|(&op, metrics)| (op, metrics.requests))
+                .collect(),
+            errors: ops
+                .iter()
+                .map(|(&op, metrics)| (op, metrics.errors))
+                .collect(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+            store: stats,
+        }
+    }
+
+    /// Render the counters and the engine `stats` in the Prometheus text format
+    pub fn render(&self, stats: &StoreStats) -> String {
+        let ops = self.ops.lock().unwrap();
+        let mut out = String::new();
+        header(
+            &mut out,
+            "kvs_requests_total",
+            "counter",
+            "Requests handled, by operation.",
+        );
+        for (op, metrics) in ops.iter() {
+            out += &format!("kvs_requests_total{{op=\"{}\"}} {}\n", op, metrics.requests);
+        }
+        header(
+            &mut out,
+            "kvs_request_errors_total",
+            "counter",
+            "Requests failed, by operation.",
+        );
+        for (op, metrics) in ops.iter() {
+            out += &format!(
+                "kvs_request_errors_total{{op=\"{}\"}} {}\n",
+                op, metrics.errors
+            );
+        }
+        header(
+            &mut out,
+            "kvs_request_duration_seconds",
+            "summary",
+            "Time spent handling requests, by operation.",
+        );
+        for (op, metrics) in ops.iter() {
+            out += &format!(
+                "kvs_request_duration_seconds_sum{{op=\"{}\"}} {}\n",
+                op,
+                metrics.latency.as_secs_f64()
+            );
+            out += &format!(
+                "kvs_request_duration_seconds_count{{op=\"{}\"}} {}\n",
+                op, metrics.requests
+            );
+        }
+        header(
+            &mut out,
+            "kvs_uncompacted_bytes",
+            "gauge",
+            "Bytes of stale commands a compaction would reclaim.",
+        );
+        out += &format!("kvs_uncompacted_bytes {}\n", stats.uncompacted_bytes);
+        header(&mut out, "kvs_keys", "gauge", "Number of live keys.");
+        out += &format!("kvs_keys {}\n", stats.num_keys);
+        header(
+            &mut out,
+            "kvs_get_hits_total",
+            "counter",
+            "Keys looked up by gets which were found.",
+        );
+        out += &format!("kvs_get_hits_total {}\n", self.hits.load(Ordering::Relaxed));
+        header(
+            &mut out,
+            "kvs_get_misses_total",
+            "counter",
+            "Keys looked up by gets which were not found.",
+        );
+        out += &format!(
+            "kvs_get_misses_total {}\n",
+            self.misses.load(Ordering::Relaxed)
+        );
+        header(
+            &mut out,
+            "kvs_connections",
+            "gauge",
+            "Connections accepted and not closed yet.",
+        );
+        out += &format!(
+            "kvs_connections {}\n",
+            self.connections.load(Ordering::Relaxed)
+        );
+        header(
+            &mut out,
+            "kvs_written_bytes_total",
+            "counter",
+            "Bytes appended to the logs by writes.",
+        );
+        out += &format!("kvs_written_bytes_total {}\n", stats.bytes_written);
+        header(
+            &mut out,
+            "kvs_compactions_total",
+            "counter",
+            "Compactions run.",
+        );
+        out += &format!("kvs_compactions_total {}\n", stats.compactions);
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    *out += &format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind);
+}
+
+/// Serve `GET /metrics` on `listener` until `stop` is set
+#[cfg(feature = "metrics-http")]
+pub(crate) fn serve_http<E: crate::KvsEngine>(
+    engine: E,
+    metrics: std::sync::Arc<ServerMetrics>,
+    listener: std::net::TcpListener,
+    stop: std::sync::Arc<std::sync::atomic::AtomicBool>,
+) {
+    use std::io::{BufRead, BufReader, Write as _};
+
+    use log::error;
+
+    for stream in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let res = stream.map_err(Into::into).and_then(|mut stream| {
+            let mut request_line = String::new();
+            BufReader::new(&stream).read_line(&mut request_line)?;
+            let response = if request_line.starts_with("GET /metrics ") {
+                let body = metrics.render(&engine.stats()?);
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+            };
+            stream.write_all(response.as_bytes())?;
+            crate::Result::Ok(())
+        });
+        if let Err(e) = res {
+            error!("Error on serve metrics: {}", e);
+        }
+    }
+}
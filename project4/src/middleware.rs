@@ -0,0 +1,136 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use log::info;
+
+use crate::{
+    common::{KvsRequest, KvsResponse},
+    error::{ErrorCode, KvError},
+    metrics::ServerMetrics,
+};
+
+/// A step of the pipeline a `KvServer` runs every request through, for concerns like auth,
+/// logging or rate limiting.
+pub trait Middleware: Send + Sync + 'static {
+    /// Handle `req`, usually by passing it on with `next.run(req)` and looking at the response.
+    /// A middleware can also answer by itself without calling `next`.
+    fn handle(&self, req: KvsRequest, next: Next<'_>) -> KvsResponse;
+}
+
+impl fmt::Debug for dyn Middleware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Middleware")
+    }
+}
+
+/// The rest of the pipeline after a middleware, ending with the engine
+pub struct Next<'a> {
+    chain: &'a [Arc<dyn Middleware>],
+    endpoint: &'a mut dyn FnMut(KvsRequest) -> KvsResponse,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        chain: &'a [Arc<dyn Middleware>],
+        endpoint: &'a mut dyn FnMut(KvsRequest) -> KvsResponse,
+    ) -> Self {
+        Next { chain, endpoint }
+    }
+
+    /// Pass `req` to the next middleware, or to the engine after the last one
+    pub fn run(self, req: KvsRequest) -> KvsResponse {
+        match self.chain.split_first() {
+            Some((middleware, chain)) => middleware.handle(
+                req,
+                Next {
+                    chain,
+                    endpoint: self.endpoint,
+                },
+            ),
+            None => (self.endpoint)(req),
+        }
+    }
+}
+
+/// Record the operation, the outcome and the latency of every request
+pub struct Metrics {
+    metrics: Arc<ServerMetrics>,
+}
+
+impl Metrics {
+    pub fn new(metrics: Arc<ServerMetrics>) -> Self {
+        Metrics { metrics }
+    }
+}
+
+impl Middleware for Metrics {
+    fn handle(&self, req: KvsRequest, next: Next<'_>) -> KvsResponse {
+        let op = req.op();
+        let start = Instant::now();
+        let res = next.run(req);
+        self.metrics.record(op, res.is_err(), start.elapsed());
+        self.metrics.record_lookups(&res);
+        res
+    }
+}
+
+/// Log every request with its keys and whether it failed
+pub struct Audit;
+
+impl Middleware for Audit {
+    fn handle(&self, req: KvsRequest, next: Next<'_>) -> KvsResponse {
+        let op = req.op();
+        let keys = format!("{:?}", req.keys());
+        let res = next.run(req);
+        let outcome = if res.is_err() { "failed" } else { "ok" };
+        info!("{} {} {}", op, keys, outcome);
+        res
+    }
+}
+
+/// Reject requests over `per_second` per second with `ErrorCode::RateLimited`, counted over all
+/// connections. Bursts of up to `per_second` requests are let through.
+pub struct RateLimit {
+    per_second: u32,
+    // the tokens left and when they were counted
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimit {
+    pub fn new(per_second: u32) -> Self {
+        RateLimit {
+            per_second,
+            bucket: Mutex::new((per_second as f64, Instant::now())),
+        }
+    }
+
+    /// Take a token if there is one
+    fn acquire(&self) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, counted) = *bucket;
+        let now = Instant::now();
+        let refill = now.duration_since(counted).as_secs_f64() * self.per_second as f64;
+        let tokens = (tokens + refill).min(self.per_second as f64);
+        if tokens >= 1.0 {
+            *bucket = (tokens - 1.0, now);
+            true
+        } else {
+            *bucket = (tokens, now);
+            false
+        }
+    }
+}
+
+impl Middleware for RateLimit {
+    fn handle(&self, req: KvsRequest, next: Next<'_>) -> KvsResponse {
+        if self.acquire() {
+            next.run(req)
+        } else {
+            let err: KvError = ErrorCode::RateLimited.into();
+            KvsResponse::Error(err.into())
+        }
+    }
+}
@@ -0,0 +1,783 @@
+//! MessagePack, a binary encoding of the serde data model which is more compact than JSON and
+//! faster to parse. Structs are maps keyed by their field names and enum variants are their
+//! names, as in JSON, so fields skipped or defaulted by serde attributes decode the same way.
+//!
+//! Errors are `serde_json::Error`s, so the protocol handles a malformed frame the same way
+//! whichever codec the connection speaks.
+
+use std::fmt::Display;
+
+use serde::de::{self, DeserializeSeed, Visitor};
+use serde::ser::{self, Serialize};
+use serde::Deserialize;
+use serde_json::Error;
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn error(msg: impl Display) -> Error {
+    de::Error::custom(msg)
+}
+
+/// Serialize `value` into MessagePack
+pub(crate) fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    value.serialize(&mut Serializer(&mut output))?;
+    Ok(output)
+}
+
+/// Deserialize a value from all of `input`
+pub(crate) fn from_slice<'de, T: Deserialize<'de>>(input: &'de [u8]) -> Result<T> {
+    let mut de = Deserializer { input };
+    let value = T::deserialize(&mut de)?;
+    if !de.input.is_empty() {
+        return Err(error(format!("{} trailing bytes", de.input.len())));
+    }
+    Ok(value)
+}
+
+struct Serializer<'a>(&'a mut Vec<u8>);
+
+impl Serializer<'_> {
+    fn write_uint(&mut self, v: u64) {
+        match v {
+            0..=0x7f => self.0.push(v as u8),
+            0x80..=0xff => self.0.extend_from_slice(&[0xcc, v as u8]),
+            0x100..=0xffff => {
+                self.0.push(0xcd);
+                self.0.extend_from_slice(&(v as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                self.0.push(0xce);
+                self.0.extend_from_slice(&(v as u32).to_be_bytes());
+            }
+            _ => {
+                self.0.push(0xcf);
+                self.0.extend_from_slice(&v.to_be_bytes());
+            }
+        }
+    }
+
+    fn write_int(&mut self, v: i64) {
+        if v >= 0 {
+            self.write_uint(v as u64);
+        } else if v >= -32 {
+            self.0.push(v as u8);
+        } else if v >= i8::MIN as i64 {
+            self.0.extend_from_slice(&[0xd0, v as u8]);
+        } else if v >= i16::MIN as i64 {
+            self.0.push(0xd1);
+            self.0.extend_from_slice(&(v as i16).to_be_bytes());
+        } else if v >= i32::MIN as i64 {
+            self.0.push(0xd2);
+            self.0.extend_from_slice(&(v as i32).to_be_bytes());
+        } else {
+            self.0.push(0xd3);
+            self.0.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+
+    /// Write the header of a string, binary, array or map of `len`, with the marker of the
+    /// fixed size form if it has one, then of the 8, 16 and 32-bit lengths
+    fn write_header(
+        &mut self,
+        len: usize,
+        fixed: Option<(u8, usize)>,
+        markers: [u8; 3],
+    ) -> Result<()> {
+        match (len, fixed) {
+            (len, Some((marker, max))) if len <= max => self.0.push(marker | len as u8),
+            (0..=0xff, _) if markers[0] != 0 => self.0.extend_from_slice(&[markers[0], len as u8]),
+            (0..=0xffff, _) => {
+                self.0.push(markers[1]);
+                self.0.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            (0x1_0000..=0xffff_ffff, _) => {
+                self.0.push(markers[2]);
+                self.0.extend_from_slice(&(len as u32).to_be_bytes());
+            }
+            _ => return Err(error(format!("length {} is too large", len))),
+        }
+        Ok(())
+    }
+
+    fn write_str(&mut self, v: &str) -> Result<()> {
+        self.write_header(v.len(), Some((0xa0, 31)), [0xd9, 0xda, 0xdb])?;
+        self.0.extend_from_slice(v.as_bytes());
+        Ok(())
+    }
+
+    /// Start a variant with content, a map of its name to the content
+    fn write_variant(&mut self, variant: &str) -> Result<()> {
+        self.0.push(0x81);
+        self.write_str(variant)
+    }
+
+    fn compound(&mut self, map: bool) -> Compound<'_> {
+        Compound {
+            output: self.0,
+            items: Vec::new(),
+            len: 0,
+            map,
+        }
+    }
+}
+
+impl<'a, 'b> ser::Serializer for &'b mut Serializer<'a> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'b>;
+    type SerializeTuple = Compound<'b>;
+    type SerializeTupleStruct = Compound<'b>;
+    type SerializeTupleVariant = Compound<'b>;
+    type SerializeMap = Compound<'b>;
+    type SerializeStruct = Compound<'b>;
+    type SerializeStructVariant = Compound<'b>;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.0.push(if v { 0xc3 } else { 0xc2 });
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.write_int(v);
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.write_uint(v);
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.0.push(0xca);
+        self.0.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        self.0.push(0xcb);
+        self.0.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.write_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        self.write_str(v)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        self.write_header(v.len(), None, [0xc4, 0xc5, 0xc6])?;
+        self.0.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        self.0.push(0xc0);
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<()> {
+        self.write_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.write_variant(variant)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Compound<'b>> {
+        Ok(self.compound(false))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Compound<'b>> {
+        Ok(self.compound(false))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Compound<'b>> {
+        Ok(self.compound(false))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'b>> {
+        self.write_variant(variant)?;
+        Ok(self.compound(false))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Compound<'b>> {
+        Ok(self.compound(true))
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Compound<'b>> {
+        Ok(self.compound(true))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Compound<'b>> {
+        self.write_variant(variant)?;
+        Ok(self.compound(true))
+    }
+}
+
+/// An array or a map being serialized. Its items are buffered, as its header is written in
+/// front of them once their number is known.
+struct Compound<'a> {
+    output: &'a mut Vec<u8>,
+    items: Vec<u8>,
+    len: usize,
+    map: bool,
+}
+
+impl Compound<'_> {
+    fn item<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut Serializer(&mut self.items))
+    }
+
+    fn finish(self) -> Result<()> {
+        let mut header = Serializer(self.output);
+        if self.map {
+            header.write_header(self.len, Some((0x80, 15)), [0, 0xde, 0xdf])?;
+        } else {
+            header.write_header(self.len, Some((0x90, 15)), [0, 0xdc, 0xdd])?;
+        }
+        self.output.extend_from_slice(&self.items);
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.len += 1;
+        self.item(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeMap for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.len += 1;
+        self.item(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.len += 1;
+        self.item(key)?;
+        self.item(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for Compound<'_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+struct Deserializer<'de> {
+    input: &'de [u8],
+}
+
+impl<'de> Deserializer<'de> {
+    fn take(&mut self, len: usize) -> Result<&'de [u8]> {
+        if self.input.len() < len {
+            return Err(error("unexpected end of MessagePack"));
+        }
+        let (taken, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(taken)
+    }
+
+    fn peek(&self) -> Result<u8> {
+        self.input
+            .first()
+            .copied()
+            .ok_or_else(|| error("unexpected end of MessagePack"))
+    }
+
+    /// Read a big-endian unsigned integer of `len` bytes
+    fn uint(&mut self, len: usize) -> Result<u64> {
+        Ok(self
+            .take(len)?
+            .iter()
+            .fold(0, |uint, &byte| uint << 8 | byte as u64))
+    }
+
+    fn len(&mut self, bytes: usize) -> Result<usize> {
+        Ok(self.uint(bytes)? as usize)
+    }
+
+    fn str(&mut self, len: usize) -> Result<&'de str> {
+        std::str::from_utf8(self.take(len)?).map_err(error)
+    }
+
+    fn seq<V: Visitor<'de>>(&mut self, visitor: V, len: usize, map: bool) -> Result<V::Value> {
+        let mut items = Items {
+            de: self,
+            left: len,
+        };
+        let value = if map {
+            visitor.visit_map(&mut items)?
+        } else {
+            visitor.visit_seq(&mut items)?
+        };
+        if items.left > 0 {
+            return Err(error(format!("{} items left unread", items.left)));
+        }
+        Ok(value)
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for &'a mut Deserializer<'de> {
+    type Error = Error;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        let marker = self.take(1)?[0];
+        match marker {
+            0x00..=0x7f => visitor.visit_u64(marker as u64),
+            0x80..=0x8f => self.seq(visitor, (marker & 0x0f) as usize, true),
+            0x90..=0x9f => self.seq(visitor, (marker & 0x0f) as usize, false),
+            0xa0..=0xbf => visitor.visit_borrowed_str(self.str((marker & 0x1f) as usize)?),
+            0xc0 => visitor.visit_unit(),
+            0xc2 => visitor.visit_bool(false),
+            0xc3 => visitor.visit_bool(true),
+            0xc4..=0xc6 => {
+                let len = self.len(1 << (marker - 0xc4))?;
+                visitor.visit_borrowed_bytes(self.take(len)?)
+            }
+            0xca => visitor.visit_f32(f32::from_bits(self.uint(4)? as u32)),
+            0xcb => visitor.visit_f64(f64::from_bits(self.uint(8)?)),
+            0xcc..=0xcf => visitor.visit_u64(self.uint(1 << (marker - 0xcc))?),
+            0xd0 => visitor.visit_i64(self.uint(1)? as i8 as i64),
+            0xd1 => visitor.visit_i64(self.uint(2)? as i16 as i64),
+            0xd2 => visitor.visit_i64(self.uint(4)? as i32 as i64),
+            0xd3 => visitor.visit_i64(self.uint(8)? as i64),
+            0xd9..=0xdb => {
+                let len = self.len(1 << (marker - 0xd9))?;
+                visitor.visit_borrowed_str(self.str(len)?)
+            }
+            0xdc | 0xdd => {
+                let len = self.len(2 << (marker - 0xdc))?;
+                self.seq(visitor, len, false)
+            }
+            0xde | 0xdf => {
+                let len = self.len(2 << (marker - 0xde))?;
+                self.seq(visitor, len, true)
+            }
+            0xe0..=0xff => visitor.visit_i64(marker as i8 as i64),
+            // extension types are not used
+            _ => Err(error(format!(
+                "unsupported MessagePack marker {:#x}",
+                marker
+            ))),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if self.peek()? == 0xc0 {
+            self.take(1)?;
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        // a unit variant is its name, any other a map of its name to its content
+        match self.peek()? {
+            0x81 => {
+                self.take(1)?;
+                visitor.visit_enum(Variant {
+                    de: self,
+                    unit: false,
+                })
+            }
+            0xa0..=0xbf | 0xd9..=0xdb => visitor.visit_enum(Variant {
+                de: self,
+                unit: true,
+            }),
+            marker => Err(error(format!(
+                "expected an enum, found marker {:#x}",
+                marker
+            ))),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// The items of an array, or the entries of a map
+struct Items<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    left: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for Items<'_, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Items<'_, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+struct Variant<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    // a unit variant has no content after its name
+    unit: bool,
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for Variant<'a, 'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)> {
+        let variant = seed.deserialize(&mut *self.de)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'a> de::VariantAccess<'de> for Variant<'a, 'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        if self.unit {
+            Ok(())
+        } else {
+            <()>::deserialize(self.de)
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        if self.unit {
+            return Err(de::Error::invalid_type(
+                de::Unexpected::UnitVariant,
+                &"newtype variant",
+            ));
+        }
+        seed.deserialize(self.de)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        if self.unit {
+            return Err(de::Error::invalid_type(
+                de::Unexpected::UnitVariant,
+                &"tuple variant",
+            ));
+        }
+        de::Deserializer::deserialize_any(self.de, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        if self.unit {
+            return Err(de::Error::invalid_type(
+                de::Unexpected::UnitVariant,
+                &"struct variant",
+            ));
+        }
+        de::Deserializer::deserialize_any(self.de, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{KvsRequest, KvsResponse, RemoteError};
+    use crate::StoreStats;
+
+    /// Round trip `value`, which is compared by its JSON as the messages don't implement `Eq`
+    fn round_trip<T: Serialize + de::DeserializeOwned>(value: &T) -> Vec<u8> {
+        let bytes = to_vec(value).unwrap();
+        let decoded: T = from_slice(&bytes).unwrap();
+        assert_eq!(
+            serde_json::to_string(&decoded).unwrap(),
+            serde_json::to_string(value).unwrap()
+        );
+        bytes
+    }
+
+    #[test]
+    fn scalars() {
+        assert_eq!(round_trip(&5_u8), [0x05]);
+        assert_eq!(round_trip(&200_u32), [0xcc, 200]);
+        assert_eq!(round_trip(&-1_i64), [0xff]);
+        assert_eq!(round_trip(&-200_i64), [0xd1, 0xff, 0x38]);
+        assert_eq!(round_trip(&"abc".to_owned()), [0xa3, b'a', b'b', b'c']);
+        assert_eq!(round_trip(&None::<u8>), [0xc0]);
+        for v in [0, u8::MAX as u64 + 1, u16::MAX as u64 + 1, u64::MAX] {
+            round_trip(&v);
+        }
+        for v in [i64::MIN, i32::MIN as i64 - 1, i16::MIN as i64, -33, 127] {
+            round_trip(&v);
+        }
+        round_trip(&1.5_f64);
+        round_trip(&"s".repeat(300));
+        round_trip(&"s".repeat(70_000));
+    }
+
+    #[test]
+    fn messages() {
+        let requests = vec![
+            KvsRequest::Set {
+                key: "key".to_owned(),
+                value: "v".repeat(40),
+            },
+            KvsRequest::Cas {
+                key: "key".to_owned(),
+                expected: None,
+                new: Some("new".to_owned()),
+            },
+            KvsRequest::Incr {
+                key: "key".to_owned(),
+                delta: -5,
+            },
+            KvsRequest::Stats,
+            KvsRequest::GetMulti {
+                keys: (0..20).map(|i| i.to_string()).collect(),
+            },
+            KvsRequest::Deadline {
+                deadline: 1 << 40,
+                req: Box::new(KvsRequest::Get {
+                    key: "key".to_owned(),
+                }),
+            },
+        ];
+        let bytes = round_trip(&KvsRequest::Batch(requests.clone()));
+        assert!(
+            bytes.len()
+                < serde_json::to_vec(&KvsRequest::Batch(requests))
+                    .unwrap()
+                    .len()
+        );
+
+        round_trip(&KvsResponse::Batch(vec![
+            KvsResponse::Get(Ok(Some("value".to_owned()))),
+            KvsResponse::Get(Ok(None)),
+            KvsResponse::Rm(Err(RemoteError::KeyNotFound)),
+            KvsResponse::Set(Ok(())),
+            KvsResponse::Stats(Ok(StoreStats {
+                num_keys: 3,
+                ..Default::default()
+            })),
+            KvsResponse::Error(RemoteError::NotAnInteger {
+                key: "key".to_owned(),
+            }),
+            KvsResponse::Redirect {
+                addr: "127.0.0.1:4000".parse().unwrap(),
+            },
+        ]));
+    }
+
+    #[test]
+    fn malformed() {
+        let bytes = to_vec(&KvsRequest::Get {
+            key: "key".to_owned(),
+        })
+        .unwrap();
+        assert!(from_slice::<KvsRequest>(&bytes[..bytes.len() - 1]).is_err());
+        assert!(from_slice::<KvsRequest>(&[bytes.clone(), vec![0]].concat()).is_err());
+        assert!(from_slice::<KvsRequest>(&[0xc1]).is_err());
+        assert!(from_slice::<KvsRequest>(&[0x92, 0x01, 0x02]).is_err());
+        assert!(from_slice::<String>(&[0xa1, 0xff]).is_err());
+    }
+}
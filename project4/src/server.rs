@@ -1,40 +1,360 @@
 use std::{
+    collections::HashMap,
+    io,
     marker::PhantomData,
-    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    ops::Bound,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
     },
     thread::{spawn, JoinHandle},
+    time::{Duration, Instant, SystemTime},
 };
 
 use crossbeam_channel::bounded;
 use log::{debug, error, info, warn};
 
 use crate::{
-    common::{KvsRequest, KvsResponse, Service},
-    error::ErrorCode,
+    common::{
+        accept_codec, handle_send, unix_millis, KeysPage, KvsRequest, KvsResponse, Service,
+        MAX_KEYS_PER_RESPONSE,
+    },
+    engine::check_sizes,
+    error::{ErrorCode, KvError},
+    metrics::{MetricsSnapshot, ServerMetrics},
+    middleware::{Metrics, Middleware, Next},
     thread_pool::ThreadPool,
-    KvClient, KvsEngine, Result,
+    transport::{Endpoint, Listener, Stream},
+    KvClient, KvsEngine, Result, StoreStats,
 };
 
-impl<T: KvsEngine> Service<KvsRequest, KvsResponse> for T {
+/// Serve the requests of a connection with an engine
+struct KvsHandler<E> {
+    engine: E,
+    opts: Arc<ServerOpts>,
+    // middlewares run around `dispatch`, the outermost first
+    chain: Arc<Vec<Arc<dyn Middleware>>>,
+    in_flight: Arc<InFlight>,
+}
+
+impl<E: KvsEngine> Service<KvsRequest, KvsResponse> for KvsHandler<E> {
+    /// Every request runs in a `request` span of its operation, which ends with an event of
+    /// its latency. Its keys are only recorded with `TRACE` enabled, as they may be private.
     fn handle(&mut self, req: KvsRequest) -> KvsResponse {
+        let in_flight = self.in_flight.clone();
+        let _guard = in_flight.enter();
+        let span = tracing::info_span!("request", op = req.op(), keys = tracing::field::Empty);
+        if tracing::enabled!(tracing::Level::TRACE) {
+            span.record("keys", tracing::field::debug(req.keys()));
+        }
+        let _span = span.enter();
+        let start = Instant::now();
+        let chain = self.chain.clone();
+        let res = Next::new(&chain, &mut |req| self.dispatch(req)).run(req);
+        tracing::info!(
+            latency_us = start.elapsed().as_micros() as u64,
+            failed = res.is_err(),
+            "served"
+        );
+        res
+    }
+
+    fn handle_error(&mut self, err: KvError) -> KvsResponse {
+        KvsResponse::Error(err.into())
+    }
+}
+
+impl<E: KvsEngine> KvsHandler<E> {
+    fn dispatch(&mut self, req: KvsRequest) -> KvsResponse {
+        // all keys of a batch are expected in the same shard
+        if let Some(shard) = &self.opts.shard {
+            for key in req.keys() {
+                let owner = shard.owner(key);
+                if owner != shard.index {
+                    return KvsResponse::Redirect {
+                        addr: shard.shards[owner],
+                    };
+                }
+            }
+        }
+
+        if let Err(err) = self.check_sizes(&req) {
+            return self.handle_error(err);
+        }
+
         match req {
-            KvsRequest::Get { key } => self.get(key).map_or_else(
-                |x| KvsResponse::Get(Err(x.to_string())),
+            KvsRequest::Get { key } => self.engine.get(key).map_or_else(
+                |x| KvsResponse::Get(Err(x.into())),
                 |x| KvsResponse::Get(Ok(x)),
             ),
-            KvsRequest::Set { key, value } => self.set(key, value).map_or_else(
-                |x| KvsResponse::Set(Err(x.to_string())),
+            KvsRequest::Set { key, value } => self.engine.set(key, value).map_or_else(
+                |x| KvsResponse::Set(Err(x.into())),
                 |_| KvsResponse::Set(Ok(())),
             ),
-            KvsRequest::Rm { key } => self.remove(key).map_or_else(
-                |x| KvsResponse::Rm(Err(x.to_string())),
+            KvsRequest::Rm { key } => self.engine.remove(key).map_or_else(
+                |x| KvsResponse::Rm(Err(x.into())),
                 |_| KvsResponse::Rm(Ok(())),
             ),
+            KvsRequest::LastModified { key } => self.engine.last_modified(key).map_or_else(
+                |x| KvsResponse::LastModified(Err(x.into())),
+                |x| KvsResponse::LastModified(Ok(x)),
+            ),
+            KvsRequest::Contains { key } => self.engine.contains(key).map_or_else(
+                |x| KvsResponse::Contains(Err(x.into())),
+                |x| KvsResponse::Contains(Ok(x)),
+            ),
+            KvsRequest::Cas { key, expected, new } => self
+                .engine
+                .compare_and_swap(key, expected, new)
+                .map_or_else(
+                    |x| KvsResponse::Cas(Err(x.into())),
+                    |x| KvsResponse::Cas(Ok(x)),
+                ),
+            KvsRequest::Stats => self.engine.stats().map_or_else(
+                |x| KvsResponse::Stats(Err(x.into())),
+                |x| KvsResponse::Stats(Ok(x)),
+            ),
+            KvsRequest::Incr { key, delta } => self.engine.increment(key, delta).map_or_else(
+                |x| KvsResponse::Incr(Err(x.into())),
+                |x| KvsResponse::Incr(Ok(x)),
+            ),
+            KvsRequest::GetDelete { key } => self.engine.get_delete(key).map_or_else(
+                |x| KvsResponse::GetDelete(Err(x.into())),
+                |x| KvsResponse::GetDelete(Ok(x)),
+            ),
+            KvsRequest::GetMulti { keys } => self.engine.get_many(keys).map_or_else(
+                |x| KvsResponse::GetMulti(Err(x.into())),
+                |x| KvsResponse::GetMulti(Ok(x)),
+            ),
+            KvsRequest::Batch(reqs) => {
+                KvsResponse::Batch(reqs.into_iter().map(|req| self.dispatch(req)).collect())
+            }
+            KvsRequest::Keys { prefix, after } => self.keys(prefix, after).map_or_else(
+                |x| KvsResponse::Keys(Err(x.into())),
+                |x| KvsResponse::Keys(Ok(x)),
+            ),
+            KvsRequest::Deadline { deadline, req } => {
+                // the request may have waited in the thread pool queue until the client gave up on it
+                if unix_millis(SystemTime::now()) > deadline {
+                    warn!("Skip {} request after its deadline", req.op());
+                    return self.handle_error(ErrorCode::DeadlineExceeded.into());
+                }
+                self.dispatch(*req)
+            }
+            KvsRequest::Ping => KvsResponse::Pong,
         }
     }
+
+    /// A page of the keys starting with `prefix` after `after`. One key past the page is
+    /// looked up to tell whether there are more.
+    fn keys(&self, prefix: Option<String>, after: Option<String>) -> Result<KeysPage> {
+        let prefix = prefix.unwrap_or_default();
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix.clone()),
+        };
+        let mut keys = self
+            .engine
+            .keys((start, Bound::Unbounded), MAX_KEYS_PER_RESPONSE + 1)?;
+        // the keys with the prefix come first in the range
+        if let Some(end) = keys.iter().position(|key| !key.starts_with(&prefix)) {
+            keys.truncate(end);
+        }
+        let mut next = None;
+        if keys.len() > MAX_KEYS_PER_RESPONSE {
+            keys.truncate(MAX_KEYS_PER_RESPONSE);
+            next = keys.last().cloned();
+        }
+        Ok(KeysPage { keys, next })
+    }
+
+    /// Check the key and value written by `req` against the size limits. The requests of a
+    /// batch are checked as they are dispatched.
+    fn check_sizes(&self, req: &KvsRequest) -> Result<()> {
+        let (key, value) = match req {
+            KvsRequest::Set { key, value }
+            | KvsRequest::Cas {
+                key,
+                new: Some(value),
+                ..
+            } => (key, value.as_str()),
+            KvsRequest::Cas { key, .. } | KvsRequest::Incr { key, .. } => (key, ""),
+            _ => return Ok(()),
+        };
+        check_sizes(
+            key,
+            value.len(),
+            self.opts.max_key_size,
+            self.opts.max_value_size,
+        )
+    }
+}
+
+/// The number of requests being served, which a graceful shutdown waits for
+#[derive(Default)]
+struct InFlight {
+    count: Mutex<usize>,
+    // notified when the count drops to zero
+    idle: Condvar,
+}
+
+impl InFlight {
+    fn enter(&self) -> InFlightGuard<'_> {
+        *self.count.lock().unwrap() += 1;
+        InFlightGuard(self)
+    }
+
+    /// Wait until no request is served, returns the number of requests still served on timeout
+    fn wait_idle(&self, timeout: Duration) -> usize {
+        let count = self.count.lock().unwrap();
+        let (count, _) = self
+            .idle
+            .wait_timeout_while(count, timeout, |count| *count > 0)
+            .unwrap();
+        *count
+    }
+}
+
+struct InFlightGuard<'a>(&'a InFlight);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut count = self.0.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+/// The number of connections being served
+struct Connections {
+    count: AtomicUsize,
+    // reports the count
+    metrics: Arc<ServerMetrics>,
+    // handles to the streams being served by their id, for a shutdown to close them
+    open: Mutex<HashMap<u64, Stream>>,
+    next_id: AtomicU64,
+    // notified when a stream is no longer served
+    closed: Condvar,
+}
+
+impl Connections {
+    fn new(metrics: Arc<ServerMetrics>) -> Self {
+        Connections {
+            count: AtomicUsize::new(0),
+            metrics,
+            open: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            closed: Condvar::new(),
+        }
+    }
+
+    /// Count a new connection, or returns `None` if there are already `max` of them
+    fn enter(self: &Arc<Self>, max: Option<usize>) -> Option<ConnectionGuard> {
+        self.count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| match max {
+                Some(max) if count >= max => None,
+                _ => Some(count + 1),
+            })
+            .ok()?;
+        self.metrics.record_connection(true);
+        Some(ConnectionGuard {
+            connections: self.clone(),
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+        })
+    }
+
+    /// Shut down all streams being served, so their handlers return
+    fn close_all(&self) {
+        for stream in self.open.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Wait until no stream is served
+    fn wait_closed(&self) {
+        let mut open = self.open.lock().unwrap();
+        while !open.is_empty() {
+            open = self.closed.wait(open).unwrap();
+        }
+    }
+}
+
+struct ConnectionGuard {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl ConnectionGuard {
+    /// Keep a handle to `stream` until the connection is dropped, for a shutdown to close it
+    fn track(&self, stream: &Stream) -> Result<()> {
+        let stream = stream.try_clone()?;
+        let mut open = self.connections.open.lock().unwrap();
+        open.insert(self.id, stream);
+        Ok(())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let connections = &self.connections;
+        if connections.open.lock().unwrap().remove(&self.id).is_some() {
+            connections.closed.notify_all();
+        }
+        connections.count.fetch_sub(1, Ordering::SeqCst);
+        connections.metrics.record_connection(false);
+    }
+}
+
+/// Options of a `KvServer`
+#[derive(Clone, Debug, Default)]
+pub struct ServerOpts {
+    /// The max bytes of a serialized response, a larger response is aborted with
+    /// `ErrorCode::ResponseTooLarge`. `None` means no budget.
+    pub max_response_size: Option<usize>,
+    /// The shard this server owns, requests for keys of other shards are redirected.
+    /// `None` means the server owns all keys.
+    pub shard: Option<ShardConfig>,
+    /// Run around every request in order, the first one is the outermost. Metrics are
+    /// recorded outside of all of them.
+    pub middlewares: Vec<Arc<dyn Middleware>>,
+    /// The max connections served at once. A connection over it is answered with
+    /// `ErrorCode::ServerBusy` and closed, rather than waiting for a thread of the pool.
+    /// `None` means no limit.
+    pub max_connections: Option<usize>,
+    /// Writes of a key longer than this many bytes are rejected with
+    /// `ErrorCode::KeyTooLarge` before they reach the engine. `None` means no limit.
+    pub max_key_size: Option<usize>,
+    /// Writes of a value longer than this many bytes are rejected with
+    /// `ErrorCode::ValueTooLarge` before they reach the engine. `None` means no limit.
+    pub max_value_size: Option<usize>,
+    /// Leave Nagle's algorithm on for the accepted TCP connections, which may hold back a
+    /// small response to coalesce it. `TCP_NODELAY` is set on them by default.
+    pub nagle: bool,
+    /// Serve `/metrics` in the Prometheus text format on this address. `None` disables it.
+    #[cfg(feature = "metrics-http")]
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+/// The key space is split across `shards` by the hash of the keys
+#[derive(Clone, Debug)]
+pub struct ShardConfig {
+    /// addresses of all shards
+    pub shards: Vec<SocketAddr>,
+    /// index of this server in `shards`
+    pub index: usize,
+}
+
+impl ShardConfig {
+    /// Returns the index of the shard owning `key`. The hash is the CRC32 of the key, so
+    /// servers built with different releases of Rust agree on the owner.
+    pub fn owner(&self, key: &str) -> usize {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(key.as_bytes());
+        hasher.finalize() as usize % self.shards.len()
+    }
 }
 
 pub struct KvServer<E, P> {
@@ -44,42 +364,149 @@ pub struct KvServer<E, P> {
 
 /// A Server provide network rpc service for kv database
 impl<E: KvsEngine, P: ThreadPool> KvServer<E, P> {
-    pub fn serve(engine: E, thread_pool: P, addr: SocketAddr) -> Result<ThreadHandle> {
+    /// Serve on `endpoint`, a TCP address or the path of a Unix socket
+    ///
+    /// The listener is bound on the calling thread and only the accept loop runs on a thread
+    /// of its own, so clients can connect as soon as this returns.
+    ///
+    /// # Errors
+    ///
+    /// It fails if `endpoint` can't be bound, e.g. as another server listens on it.
+    pub fn serve(engine: E, thread_pool: P, endpoint: impl Into<Endpoint>) -> Result<ThreadHandle> {
+        Self::serve_with_opts(engine, thread_pool, endpoint, ServerOpts::default())
+    }
+
+    pub fn serve_with_opts(
+        engine: E,
+        thread_pool: P,
+        endpoint: impl Into<Endpoint>,
+        opts: ServerOpts,
+    ) -> Result<ThreadHandle> {
         let stop_flag = Arc::new(AtomicBool::new(false));
-        let listener = TcpListener::bind(addr)?;
+        let listener = Listener::bind(&endpoint.into())?;
+        let endpoint = listener.local_endpoint()?;
+        let metrics = Arc::new(ServerMetrics::default());
+        let in_flight = Arc::new(InFlight::default());
+
+        let connections = Arc::new(Connections::new(metrics.clone()));
+
+        #[cfg(feature = "metrics-http")]
+        let (metrics_addr, metrics_join) = match opts.metrics_addr {
+            Some(metrics_addr) => {
+                let listener = std::net::TcpListener::bind(metrics_addr)?;
+                let metrics_addr = listener.local_addr()?;
+                let (engine, metrics, flag) = (engine.clone(), metrics.clone(), stop_flag.clone());
+                let join =
+                    spawn(move || crate::metrics::serve_http(engine, metrics, listener, flag));
+                (Some(metrics_addr), Some(join))
+            }
+            None => (None, None),
+        };
+        #[cfg(not(feature = "metrics-http"))]
+        let (metrics_addr, metrics_join) = (None, None);
 
-        let flag = stop_flag.clone();
-        let join = spawn(move || Self::run(engine, thread_pool, listener, flag));
+        let stats_engine = Mutex::new(engine.clone());
+        let stats = Box::new(move || stats_engine.lock().unwrap().stats());
+        let (flag, served, conns) = (stop_flag.clone(), in_flight.clone(), connections.clone());
+        let join =
+            spawn(move || Self::run(engine, thread_pool, listener, flag, opts, conns, served));
         Ok(ThreadHandle {
             join,
             stop_flag,
-            addr,
+            endpoint,
+            metrics_addr,
+            metrics_join,
+            in_flight,
+            connections,
+            metrics,
+            stats,
         })
     }
 
-    fn run(engine: E, thread_pool: P, listener: TcpListener, cond: Arc<AtomicBool>) {
-        for stream in listener.incoming() {
+    fn run(
+        engine: E,
+        thread_pool: P,
+        listener: Listener,
+        cond: Arc<AtomicBool>,
+        opts: ServerOpts,
+        connections: Arc<Connections>,
+        in_flight: Arc<InFlight>,
+    ) {
+        let metrics = connections.metrics.clone();
+        let mut chain: Vec<Arc<dyn Middleware>> = vec![Arc::new(Metrics::new(metrics))];
+        chain.extend(opts.middlewares.iter().cloned());
+        let chain = Arc::new(chain);
+        let opts = Arc::new(opts);
+        loop {
+            let stream = listener.accept();
             // check and stop this thread
             if cond.load(Ordering::SeqCst) {
                 break;
             }
-            let mut engine = engine.clone();
-            thread_pool.spawn(move || match stream {
-                Ok(mut stream) => {
-                    if let Err(e) = handle_connection(&mut engine, &mut stream) {
-                        error!("Error on serve client: {}", e);
+            let connection = match connections.enter(opts.max_connections) {
+                Some(connection) => connection,
+                None => {
+                    if let Ok(stream) = stream {
+                        warn!("Reject a connection over the limit");
+                        if let Err(e) = reject(stream) {
+                            debug!("Error on reject client: {}", e);
+                        }
                     }
+                    continue;
+                }
+            };
+            if let Ok(stream) = &stream
+                && let Err(e) = connection.track(stream)
+            {
+                error!("Connection can't be closed by a shutdown: {}", e);
+            }
+            let handler = KvsHandler {
+                engine: engine.clone(),
+                opts: opts.clone(),
+                chain: chain.clone(),
+                in_flight: in_flight.clone(),
+            };
+            thread_pool.spawn(move || {
+                let _connection = connection;
+                // dropped before the connection, so the engine is released once a shutdown
+                // has seen every connection closed
+                let mut handler = handler;
+                match stream {
+                    Ok(mut stream) => {
+                        if let Err(e) = handle_connection(&mut handler, &mut stream) {
+                            error!("Error on serve client: {}", e);
+                        }
+                    }
+                    Err(e) => error!("Connection failed: {}", e),
                 }
-                Err(e) => error!("Connection failed: {}", e),
             })
         }
+        // a pool may wait for its workers when dropped, which may still serve idle connections
+        spawn(move || drop(thread_pool));
     }
 }
 
-fn handle_connection<E: KvsEngine>(engine: &mut E, stream: &mut TcpStream) -> Result<()> {
-    let peer = stream.peer_addr()?;
+/// Tell the client that the server is busy and close the connection
+fn reject(mut stream: Stream) -> Result<()> {
+    let err: KvError = ErrorCode::ServerBusy.into();
+    handle_send(&mut stream, &KvsResponse::Error(err.into()))?;
+    // drain a request already sent, as closing with unread data resets the connection, which
+    // may discard the response before the client reads it
+    stream.set_nonblocking(true)?;
+    let _ = io::copy(&mut stream, &mut io::sink());
+    stream.shutdown(Shutdown::Both)?;
+    Ok(())
+}
+
+fn handle_connection<E: KvsEngine>(handler: &mut KvsHandler<E>, stream: &mut Stream) -> Result<()> {
+    let peer = stream.peer()?;
     debug!("Connection for {} connected!", peer);
-    while engine.response(stream)? {}
+    stream.set_nodelay(!handler.opts.nagle)?;
+    let budget = handler.opts.max_response_size;
+    if let Some((codec, mut stream)) = accept_codec(&mut *stream)? {
+        debug!("Connection for {} speaks {:?}", peer, codec);
+        while handler.response(&mut stream, budget, codec)? {}
+    }
     stream.shutdown(Shutdown::Both)?;
     debug!("Connection for {} close!", peer);
     Ok(())
@@ -92,26 +519,100 @@ pub struct ThreadHandle {
     // a flag to stop this thread
     stop_flag: Arc<AtomicBool>,
 
-    // a server addr for fake connect to stop it.
-    addr: SocketAddr,
+    // the endpoint the server is bound to, with the port picked for port 0, for a fake connect
+    // to stop it
+    endpoint: Endpoint,
+
+    // the metrics endpoint, which is stopped the same way, and its thread
+    metrics_addr: Option<SocketAddr>,
+    metrics_join: Option<JoinHandle<()>>,
+
+    // the requests being served, for a graceful shutdown to wait for
+    in_flight: Arc<InFlight>,
+
+    // the connections being served, for a shutdown to close
+    connections: Arc<Connections>,
+
+    // the counters of the requests, and the statistics of the engine
+    metrics: Arc<ServerMetrics>,
+    stats: Box<dyn Fn() -> Result<StoreStats> + Send + Sync>,
 }
 
 impl ThreadHandle {
+    /// Returns the address the server is bound to, with the port the OS picked if it was
+    /// asked to serve on port 0. `None` for a server on a Unix socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self.endpoint {
+            Endpoint::Tcp(addr) => Some(addr),
+            #[cfg(unix)]
+            Endpoint::Unix(_) => None,
+        }
+    }
+
+    /// Returns the counters of the requests served so far, and of the engine, e.g. to tune the
+    /// compaction threshold or the size of the pool
+    pub fn metrics_snapshot(&self) -> Result<MetricsSnapshot> {
+        Ok(self.metrics.snapshot((self.stats)()?))
+    }
+
+    /// Stop accepting connections and close the connections open, cutting off the requests
+    /// they are serving, then wait for the server to drop its clones of the engine. A
+    /// `KvStore` served is no longer locked once this returns, so it can be opened again.
     pub fn shutdown(self) -> Result<()> {
+        self.stop()?;
+        let connections = self.connections.clone();
+        // no connection is accepted once the server thread has returned
+        self.join()?;
+        connections.close_all();
+        connections.wait_closed();
+        Ok(())
+    }
+
+    /// Stop accepting connections, then wait up to `timeout` for the requests being served to
+    /// complete. Fails with `ErrorCode::ShutdownTimeout` if some are still served by then.
+    ///
+    /// Requests not read off their connection yet are not waited for. The connections are
+    /// closed then, and the engine is released as by `shutdown` unless it timed out.
+    pub fn shutdown_graceful(self, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        self.stop()?;
+        let (connections, in_flight) = (self.connections.clone(), self.in_flight.clone());
+        // no connection is accepted once the server thread has returned
+        self.join()?;
+        let in_flight = in_flight.wait_idle(timeout.saturating_sub(start.elapsed()));
+        connections.close_all();
+        match in_flight {
+            0 => {
+                connections.wait_closed();
+                Ok(())
+            }
+            in_flight => Err(ErrorCode::ShutdownTimeout { in_flight }.into()),
+        }
+    }
+
+    fn stop(&self) -> Result<()> {
         // send message close and connect once dummy
         if let Ok(_) =
             self.stop_flag
                 .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
         {
             info!("close this kvserver.");
-            TcpStream::connect(self.addr)?;
+            Stream::connect(&self.endpoint)?;
+            if let Some(metrics_addr) = self.metrics_addr {
+                TcpStream::connect(metrics_addr)?;
+            }
         };
         warn!("This kv server may have been closed.");
         Ok(())
     }
 
     pub fn join(self) -> Result<()> {
-        match self.join.join() {
+        let metrics_join = self.metrics_join;
+        let joined = self.join.join().and_then(|_| match metrics_join {
+            Some(join) => join.join(),
+            None => Ok(()),
+        });
+        match joined {
             Ok(_) => Ok(()),
             Err(_) => Err(ErrorCode::InternalError("join thread failed".to_string()).into()),
         }
@@ -0,0 +1,169 @@
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, spawn, JoinHandle},
+};
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use log::error;
+
+use super::ThreadPool;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+enum Message {
+    Run(Job),
+    // the worker taking it exits
+    Retire,
+}
+
+/// A pool sharing one queue among its workers like `SharedQueueThreadPool`, whose number of
+/// workers can be changed with `resize` while it runs.
+///
+/// Dropping the pool runs the jobs already queued and waits for the workers.
+pub struct DynamicThreadPool {
+    // a sender to start task, dropped first to stop the workers
+    spawner: Option<Sender<Message>>,
+    // handed to the workers spawned by a resize
+    receiver: Receiver<Message>,
+    // the number of workers asked for, changed by one resize at a time
+    target: Mutex<u32>,
+    // the workers running, counted up as they are spawned and down as they exit
+    live: Arc<AtomicU32>,
+    // joined when the pool is dropped, with the workers spawned again after a panic
+    workers: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl ThreadPool for DynamicThreadPool {
+    fn new(threads: u32) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        let (tx, rx) = unbounded();
+        let pool = DynamicThreadPool {
+            spawner: Some(tx),
+            receiver: rx,
+            target: Mutex::new(0),
+            live: Arc::new(AtomicU32::new(0)),
+            workers: Arc::new(Mutex::new(Vec::new())),
+        };
+        pool.resize(threads)?;
+        Ok(pool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender()
+            .send(Message::Run(Box::new(job)))
+            .expect("Thread pool has no thread left")
+    }
+}
+
+impl DynamicThreadPool {
+    /// Grow or shrink the pool to `threads` workers.
+    ///
+    /// Growing spawns the new workers at once. Shrinking queues a message retiring a worker
+    /// for each one too many, so the workers exit once the jobs spawned before have started,
+    /// and a worker never leaves a job it is running.
+    pub fn resize(&self, threads: u32) -> crate::Result<()> {
+        let mut target = self.target.lock().unwrap();
+        if threads > *target {
+            // forget the handles of the workers which have retired
+            let mut workers = self.workers.lock().unwrap();
+            workers.retain(|worker| !worker.is_finished());
+            drop(workers);
+            for _ in *target..threads {
+                spawn_worker(
+                    self.receiver.clone(),
+                    self.live.clone(),
+                    self.workers.clone(),
+                );
+            }
+        } else {
+            for _ in threads..*target {
+                self.sender()
+                    .send(Message::Retire)
+                    .expect("Thread pool has no thread left");
+            }
+        }
+        *target = threads;
+        Ok(())
+    }
+
+    /// The number of workers running. It lags behind a `resize` shrinking the pool until the
+    /// retired workers have exited.
+    pub fn threads(&self) -> u32 {
+        self.live.load(Ordering::SeqCst)
+    }
+
+    fn sender(&self) -> &Sender<Message> {
+        self.spawner.as_ref().expect("Thread pool is dropped")
+    }
+}
+
+impl Drop for DynamicThreadPool {
+    fn drop(&mut self) {
+        // workers exit once the channel is disconnected
+        self.spawner.take();
+        loop {
+            // a worker panicking meanwhile pushes the one replacing it
+            let Some(worker) = self.workers.lock().unwrap().pop() else {
+                break;
+            };
+            // a job dropping the pool can't wait for its own worker
+            if worker.thread().id() != thread::current().id() && worker.join().is_err() {
+                error!("worker thread panic while the pool is dropped.");
+            }
+        }
+    }
+}
+
+fn spawn_worker(
+    rx: Receiver<Message>,
+    live: Arc<AtomicU32>,
+    workers: Arc<Mutex<Vec<JoinHandle<()>>>>,
+) {
+    live.fetch_add(1, Ordering::SeqCst);
+    let worker = {
+        let workers = workers.clone();
+        spawn(move || run(rx, live, workers))
+    };
+    workers.lock().unwrap().push(worker);
+}
+
+/// Counts the worker holding it out when it exits, and spawns a new worker if it unwinds, so
+/// the pool keeps its number of threads even if a panic escapes `catch_unwind`
+struct Sentinel {
+    rx: Receiver<Message>,
+    live: Arc<AtomicU32>,
+    workers: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        self.live.fetch_sub(1, Ordering::SeqCst);
+        if thread::panicking() {
+            error!("worker thread panic, spawn a new one.");
+            spawn_worker(self.rx.clone(), self.live.clone(), self.workers.clone());
+        }
+    }
+}
+
+fn run(rx: Receiver<Message>, live: Arc<AtomicU32>, workers: Arc<Mutex<Vec<JoinHandle<()>>>>) {
+    let _sentinel = Sentinel {
+        rx: rx.clone(),
+        live,
+        workers,
+    };
+    // the channel is only disconnected when the pool is dropped
+    while let Ok(Message::Run(f)) = rx.recv() {
+        if let Err(cause) = catch_unwind(AssertUnwindSafe(f)) {
+            error!("user task panic catch: \n{:#?}", cause);
+        }
+    }
+}
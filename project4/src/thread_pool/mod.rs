@@ -1,13 +1,17 @@
 use crate::Result;
 
-mod mpmc;
+mod dynamic;
+pub mod mpmc;
 mod native;
 mod rayon;
 mod shared_pool;
+mod work_stealing;
 
+pub use self::dynamic::DynamicThreadPool;
 pub use self::native::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
 pub use self::shared_pool::SharedQueueThreadPool;
+pub use self::work_stealing::WorkStealingThreadPool;
 
 pub trait ThreadPool: Send + 'static {
     /// Creates a new thread pool, immediately spawning the specified number of
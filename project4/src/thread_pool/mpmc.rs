@@ -1,48 +1,359 @@
 use std::{
-    any::Any,
-    collections::VecDeque,
-    sync::{Arc, Mutex},
+    mem::MaybeUninit,
+    sync::{
+        atomic::{fence, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
 };
 
+use crossbeam_epoch::{self as epoch, Atomic, Owned, Shared as Ptr};
+
+/// A lock-free unbounded queue of Michael and Scott: a linked list whose head is a sentinel
+/// node and whose tail is swung forward by whichever thread finds it lagging. Popped nodes are
+/// freed once no thread can still read them, by epoch-based reclamation.
+struct Queue<T> {
+    head: Atomic<Node<T>>,
+    tail: Atomic<Node<T>>,
+}
+
+struct Node<T> {
+    // uninit for the sentinel, moved out when the node becomes the sentinel
+    data: MaybeUninit<T>,
+    next: Atomic<Node<T>>,
+}
+
+// items are only ever moved between threads, never shared
+unsafe impl<T: Send> Send for Queue<T> {}
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+impl<T> Queue<T> {
+    fn new() -> Queue<T> {
+        let queue = Queue {
+            head: Atomic::null(),
+            tail: Atomic::null(),
+        };
+        let sentinel = Owned::new(Node {
+            data: MaybeUninit::uninit(),
+            next: Atomic::null(),
+        });
+        // nothing else can see the queue yet
+        let sentinel = sentinel.into_shared(unsafe { epoch::unprotected() });
+        queue.head.store(sentinel, Ordering::Relaxed);
+        queue.tail.store(sentinel, Ordering::Relaxed);
+        queue
+    }
+
+    fn push(&self, t: T) {
+        let guard = &epoch::pin();
+        let new = Owned::new(Node {
+            data: MaybeUninit::new(t),
+            next: Atomic::null(),
+        })
+        .into_shared(guard);
+        loop {
+            let tail = self.tail.load(Ordering::Acquire, guard);
+            // the tail is never freed before the head has moved past it
+            let tail_ref = unsafe { tail.deref() };
+            let next = tail_ref.next.load(Ordering::Acquire, guard);
+            if !next.is_null() {
+                // help a push which linked its node but hasn't swung the tail yet
+                let _ = self.tail.compare_exchange(
+                    tail,
+                    next,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                    guard,
+                );
+                continue;
+            }
+            if tail_ref
+                .next
+                .compare_exchange(
+                    Ptr::null(),
+                    new,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                    guard,
+                )
+                .is_ok()
+            {
+                let _ = self.tail.compare_exchange(
+                    tail,
+                    new,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                    guard,
+                );
+                return;
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<T> {
+        let guard = &epoch::pin();
+        loop {
+            let head = self.head.load(Ordering::Acquire, guard);
+            let next = unsafe { head.deref() }.next.load(Ordering::Acquire, guard);
+            let next_ref = unsafe { next.as_ref() }?;
+            if self
+                .head
+                .compare_exchange(head, next, Ordering::Release, Ordering::Relaxed, guard)
+                .is_ok()
+            {
+                // the tail must not be left behind on the node about to be freed
+                let tail = self.tail.load(Ordering::Relaxed, guard);
+                if head == tail {
+                    let _ = self.tail.compare_exchange(
+                        tail,
+                        next,
+                        Ordering::Release,
+                        Ordering::Relaxed,
+                        guard,
+                    );
+                }
+                // only the thread which moved the head past `next` takes its data, and the old
+                // sentinel is freed once no pinned thread can read it
+                unsafe {
+                    guard.defer_destroy(head);
+                    return Some(next_ref.data.as_ptr().read());
+                }
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        let guard = &epoch::pin();
+        let head = self.head.load(Ordering::Acquire, guard);
+        unsafe { head.deref() }
+            .next
+            .load(Ordering::Acquire, guard)
+            .is_null()
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+        // no other thread holds the queue anymore
+        unsafe {
+            let sentinel = self.head.load(Ordering::Relaxed, epoch::unprotected());
+            drop(sentinel.into_owned());
+        }
+    }
+}
+
+/// The items are pushed and popped without a lock. The lock is only taken by a thread going
+/// to sleep for an item or for room, and by a thread waking it, which does so only when the
+/// count of sleepers tells there is one.
+struct Shared<T> {
+    queue: Queue<T>,
+    // items in the queue, counted ahead of the push to keep a bounded queue within `cap`
+    len: AtomicUsize,
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+    // max items in the queue, `None` for limitless
+    cap: Option<usize>,
+    // held to register as a sleeper, and to wake one
+    lock: Mutex<()>,
+    // notified when an item is pushed or the last sender is dropped
+    not_empty: Condvar,
+    // notified when an item is popped or the last receiver is dropped
+    not_full: Condvar,
+    // receivers and senders asleep, or about to be, on `not_empty` and `not_full`
+    waiting_receivers: AtomicUsize,
+    waiting_senders: AtomicUsize,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.lock
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Count an item about to be pushed, or `false` if the queue is full
+    fn reserve(&self) -> bool {
+        match self.cap {
+            None => {
+                self.len.fetch_add(1, Ordering::SeqCst);
+                true
+            }
+            Some(cap) => self
+                .len
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |len| {
+                    (len < cap).then_some(len + 1)
+                })
+                .is_ok(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.cap
+            .map_or(false, |cap| self.len.load(Ordering::SeqCst) >= cap)
+    }
+
+    fn pop(&self) -> Option<T> {
+        let t = self.queue.pop()?;
+        self.len.fetch_sub(1, Ordering::SeqCst);
+        self.wake(&self.not_full, &self.waiting_senders);
+        Some(t)
+    }
+
+    /// Wake a thread asleep on `condvar`, if `waiting` tells there is one. The fence orders
+    /// the change it waits for before the count, which a sleeper reads the other way round.
+    fn wake(&self, condvar: &Condvar, waiting: &AtomicUsize) {
+        fence(Ordering::SeqCst);
+        if waiting.load(Ordering::SeqCst) > 0 {
+            let _guard = self.lock();
+            condvar.notify_one();
+        }
+    }
+
+    /// Sleep on `condvar` unless `ready` tells there is no need to after registering in
+    /// `waiting`, so that a change made before the registration is never slept through.
+    fn wait(&self, condvar: &Condvar, waiting: &AtomicUsize, ready: impl Fn() -> bool) {
+        let guard = self.lock();
+        waiting.fetch_add(1, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+        if !ready() {
+            drop(
+                condvar
+                    .wait(guard)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()),
+            );
+        }
+        waiting.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Wake all threads asleep on `condvar`, after the last sender or receiver is dropped
+    fn wake_all(&self, condvar: &Condvar) {
+        let _guard = self.lock();
+        condvar.notify_all();
+    }
+}
+
 pub struct Sender<T> {
-    tx: Arc<Mutex<VecDeque<T>>>,
+    tx: Arc<Shared<T>>,
 }
 
 impl<T> Clone for Sender<T> {
     fn clone(&self) -> Self {
+        self.tx.senders.fetch_add(1, Ordering::SeqCst);
         Self {
             tx: self.tx.clone(),
         }
     }
 }
 
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.tx.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // wake up the receivers to find the channel disconnected
+            self.tx.wake_all(&self.tx.not_empty);
+        }
+    }
+}
+
 impl<T> Sender<T> {
-    pub fn send(&self, t: T) {
-        self.tx
-            .lock()
-            .unwrap_or_else(|poisoned| poisoned.into_inner())
-            .push_back(t)
+    /// Same as `send_blocking`
+    pub fn send(&self, t: T) -> Result<(), T> {
+        self.send_blocking(t)
+    }
+
+    /// Push `t` to the queue, waiting for room if the channel is bounded and full, so that a
+    /// producer can't run ahead of slow consumers by more than the capacity.
+    ///
+    /// Returns `t` back if all receivers are dropped, as nobody would receive it.
+    pub fn send_blocking(&self, mut t: T) -> Result<(), T> {
+        let tx = &self.tx;
+        loop {
+            match self.try_send(t) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(back)) => return Err(back),
+                Err(TrySendError::Full(back)) => t = back,
+            }
+            tx.wait(&tx.not_full, &tx.waiting_senders, || {
+                !tx.is_full() || tx.receivers.load(Ordering::SeqCst) == 0
+            });
+        }
+    }
+
+    /// Push `t` to the queue if there is room for it, without waiting.
+    ///
+    /// Returns `t` back in `TrySendError::Full` if the channel is bounded and full, and in
+    /// `TrySendError::Disconnected` if all receivers are dropped.
+    pub fn try_send(&self, t: T) -> Result<(), TrySendError<T>> {
+        let tx = &self.tx;
+        if tx.receivers.load(Ordering::SeqCst) == 0 {
+            return Err(TrySendError::Disconnected(t));
+        }
+        if !tx.reserve() {
+            return Err(TrySendError::Full(t));
+        }
+        tx.queue.push(t);
+        tx.wake(&tx.not_empty, &tx.waiting_receivers);
+        Ok(())
+    }
+}
+
+/// Why `Sender::try_send` failed, with the item which wasn't sent
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is bounded and full
+    Full(T),
+    /// All receivers are dropped
+    Disconnected(T),
+}
+
+impl<T> TrySendError<T> {
+    /// The item which wasn't sent
+    pub fn into_inner(self) -> T {
+        match self {
+            TrySendError::Full(t) | TrySendError::Disconnected(t) => t,
+        }
     }
 }
 
 pub struct Receiver<T> {
-    rx: Arc<Mutex<VecDeque<T>>>,
+    rx: Arc<Shared<T>>,
 }
 
 impl<T> Clone for Receiver<T> {
     fn clone(&self) -> Self {
+        self.rx.receivers.fetch_add(1, Ordering::SeqCst);
         Self {
             rx: self.rx.clone(),
         }
     }
 }
 
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if self.rx.receivers.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // wake up the senders waiting for room which will never be made
+            self.rx.wake_all(&self.rx.not_full);
+        }
+    }
+}
+
 impl<T> Receiver<T> {
+    /// Pop an item, waiting until there is one.
+    ///
+    /// Returns `None` once the queue is empty and all senders are dropped.
     pub fn receive(&self) -> Option<T> {
-        self.rx
-            .lock()
-            .unwrap_or_else(|poisoned| poisoned.into_inner())
-            .pop_front()
+        let rx = &self.rx;
+        loop {
+            if let Some(t) = rx.pop() {
+                return Some(t);
+            }
+            if rx.senders.load(Ordering::SeqCst) == 0 {
+                // an item may have been pushed right before the last sender was dropped
+                return rx.pop();
+            }
+            rx.wait(&rx.not_empty, &rx.waiting_receivers, || {
+                !rx.queue.is_empty() || rx.senders.load(Ordering::SeqCst) == 0
+            });
+        }
     }
 }
 
@@ -50,6 +361,177 @@ impl<T> Receiver<T> {
 /// 2.高性能，无锁实现
 pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
     // default for limitless cap
-    let queue = Arc::new(Mutex::new(VecDeque::new()));
-    (Sender { tx: queue.clone() }, Receiver { rx: queue.clone() })
+    new_channel(None)
+}
+
+/// A channel holding at most `cap` items, `send_blocking` waits for room when it is full and
+/// `try_send` fails
+pub fn channel_bounded<T>(cap: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(cap > 0, "a bounded channel needs room for an item");
+    new_channel(Some(cap))
+}
+
+fn new_channel<T>(cap: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        queue: Queue::new(),
+        len: AtomicUsize::new(0),
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1),
+        cap,
+        lock: Mutex::new(()),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        waiting_receivers: AtomicUsize::new(0),
+        waiting_senders: AtomicUsize::new(0),
+    });
+    (Sender { tx: shared.clone() }, Receiver { rx: shared })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn blocking_receive() {
+        let (tx, rx) = channel();
+        let receiver = thread::spawn(move || rx.receive());
+        thread::sleep(Duration::from_millis(50));
+        assert!(!receiver.is_finished());
+
+        tx.send(1).unwrap();
+        assert_eq!(receiver.join().unwrap(), Some(1));
+    }
+
+    #[test]
+    fn bounded_backpressure() {
+        let (tx, rx) = channel_bounded(2);
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+
+        let sent = Arc::new(AtomicBool::new(false));
+        let sender = {
+            let (tx, sent) = (tx.clone(), sent.clone());
+            thread::spawn(move || {
+                tx.send(3).unwrap();
+                sent.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!sent.load(Ordering::SeqCst));
+
+        assert_eq!(rx.receive(), Some(1));
+        sender.join().unwrap();
+        assert_eq!(rx.receive(), Some(2));
+        assert_eq!(rx.receive(), Some(3));
+    }
+
+    #[test]
+    fn disconnect_on_last_sender() {
+        let (tx, rx) = channel();
+        let receivers: Vec<_> = (0..2)
+            .map(|_| {
+                let rx = rx.clone();
+                thread::spawn(move || {
+                    let mut items = Vec::new();
+                    while let Some(item) = rx.receive() {
+                        items.push(item);
+                    }
+                    items
+                })
+            })
+            .collect();
+
+        let tx2 = tx.clone();
+        tx.send(1).unwrap();
+        drop(tx);
+        tx2.send(2).unwrap();
+        drop(tx2);
+
+        // all waiting receivers wake up once the items are drained
+        let mut items: Vec<i32> = receivers
+            .into_iter()
+            .flat_map(|receiver| receiver.join().unwrap())
+            .collect();
+        items.sort();
+        assert_eq!(items, vec![1, 2]);
+        assert_eq!(rx.receive(), None);
+    }
+
+    #[test]
+    fn send_without_receiver() {
+        let (tx, rx) = channel_bounded(1);
+        tx.send(1).unwrap();
+        let sender = {
+            let tx = tx.clone();
+            thread::spawn(move || tx.send(2))
+        };
+        thread::sleep(Duration::from_millis(50));
+        drop(rx);
+        assert_eq!(sender.join().unwrap(), Err(2));
+        assert_eq!(tx.send(3), Err(3));
+    }
+
+    #[test]
+    fn try_send_full() {
+        let (tx, rx) = channel_bounded(4);
+        for item in 0..4 {
+            tx.try_send(item).unwrap();
+        }
+        assert_eq!(tx.try_send(4), Err(TrySendError::Full(4)));
+
+        assert_eq!(rx.receive(), Some(0));
+        tx.try_send(4).unwrap();
+        assert_eq!(tx.try_send(5).unwrap_err().into_inner(), 5);
+        drop(rx);
+        assert_eq!(tx.try_send(5), Err(TrySendError::Disconnected(5)));
+    }
+
+    #[test]
+    fn many_producers_and_consumers() {
+        const ITEMS: usize = 1_000_000;
+        const THREADS: usize = 8;
+        for (tx, rx) in [channel(), channel_bounded(64)] {
+            let producers: Vec<_> = (0..THREADS)
+                .map(|producer| {
+                    let tx = tx.clone();
+                    thread::spawn(move || {
+                        for item in (producer..ITEMS).step_by(THREADS) {
+                            tx.send(item).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            drop(tx);
+            let consumers: Vec<_> = (0..THREADS)
+                .map(|_| {
+                    let rx = rx.clone();
+                    thread::spawn(move || {
+                        let mut items = Vec::new();
+                        while let Some(item) = rx.receive() {
+                            items.push(item);
+                        }
+                        items
+                    })
+                })
+                .collect();
+            producers
+                .into_iter()
+                .for_each(|producer| producer.join().unwrap());
+
+            // every item is received exactly once
+            let mut received = vec![false; ITEMS];
+            for item in consumers
+                .into_iter()
+                .flat_map(|consumer| consumer.join().unwrap())
+            {
+                assert!(!received[item], "{} received twice", item);
+                received[item] = true;
+            }
+            assert!(received.into_iter().all(|received| received));
+        }
+    }
 }
@@ -1,13 +1,30 @@
 use super::ThreadPool;
+use crate::error::ErrorCode;
 
-pub struct NaiveThreadPool;
+/// Runs every job on a new thread of its own, which is detached
+pub struct NaiveThreadPool {
+    threads: u32,
+}
+
+impl NaiveThreadPool {
+    /// The thread count the pool was created with. It is only reported, the number of
+    /// threads is not capped.
+    pub fn threads(&self) -> u32 {
+        self.threads
+    }
+}
 
 impl ThreadPool for NaiveThreadPool {
-    fn new(_threads: u32) -> crate::Result<Self>
+    fn new(threads: u32) -> crate::Result<Self>
     where
         Self: Sized,
     {
-        Ok(NaiveThreadPool)
+        if threads == 0 {
+            return Err(
+                ErrorCode::InternalError("a thread pool needs a thread".to_string()).into(),
+            );
+        }
+        Ok(NaiveThreadPool { threads })
     }
 
     fn spawn<F>(&self, job: F)
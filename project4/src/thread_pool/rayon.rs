@@ -1,3 +1,5 @@
+use log::error;
+
 use crate::error::ErrorCode;
 
 use super::Result;
@@ -9,6 +11,8 @@ impl ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<Self> {
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(threads as usize)
+            // rayon aborts the process on a panicking job without a handler
+            .panic_handler(|_| error!("A job of the thread pool panicked"))
             .build()
             .map_err(|e| ErrorCode::InternalError(format!("{}", e)))?;
         Ok(RayonThreadPool(pool))
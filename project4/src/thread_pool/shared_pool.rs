@@ -1,20 +1,28 @@
 use std::{
     panic::{catch_unwind, AssertUnwindSafe},
-    thread::{sleep, spawn},
-    time::Duration,
+    sync::{Arc, Mutex},
+    thread::{self, spawn, JoinHandle},
 };
 
-use crossbeam_channel::{bounded, Receiver, Sender, TryRecvError};
+use crossbeam_channel::{bounded, Receiver, Sender};
 use log::error;
 
 use super::ThreadPool;
 
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Dropping the pool waits for its workers to complete the jobs they are running. A job is
+/// handed to a worker as it is spawned, the queue never holds one, so every job spawned before
+/// the drop has run once it returns.
 pub struct SharedQueueThreadPool {
     // total threads cap
     threads: u64,
 
-    // a sender to start task
-    spawner: Sender<Box<dyn FnOnce() + Send + 'static>>,
+    // a sender to start task, dropped first to stop the workers
+    spawner: Option<Sender<Job>>,
+
+    // joined when the pool is dropped, with the workers spawned again after a panic
+    workers: Arc<Mutex<Vec<JoinHandle<()>>>>,
 }
 
 impl ThreadPool for SharedQueueThreadPool {
@@ -24,13 +32,12 @@ impl ThreadPool for SharedQueueThreadPool {
     {
         // lanuch `threads` nums thread with zero buffer
         let (tx, rx) = bounded(0);
-        (0..threads).for_each(|_| {
-            let each_rx = rx.clone();
-            spawn(move || run(each_rx));
-        });
+        let workers = Arc::new(Mutex::new(Vec::new()));
+        (0..threads).for_each(|_| spawn_worker(rx.clone(), workers.clone()));
         Ok(SharedQueueThreadPool {
             threads: threads as u64,
-            spawner: tx,
+            spawner: Some(tx),
+            workers,
         })
     }
 
@@ -39,24 +46,58 @@ impl ThreadPool for SharedQueueThreadPool {
         F: FnOnce() + Send + 'static,
     {
         self.spawner
+            .as_ref()
+            .expect("Thread pool is dropped")
             .send(Box::new(job))
             .expect("Thread pool has no thread left")
     }
 }
 
-fn run(rx: Receiver<Box<dyn FnOnce() + Send + 'static>>) {
-    loop {
-        match rx.try_recv() {
-            Ok(f) => {
-                if let Err(cause) = catch_unwind(AssertUnwindSafe(|| f())) {
-                    error!("user task panic catch: \n{:#?}", cause);
-                }
-            }
-            Err(TryRecvError::Empty) => sleep(Duration::from_millis(100)),
-            Err(TryRecvError::Disconnected) => {
-                error!("thread pool is be destoryed.");
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        // workers exit once the channel is disconnected
+        self.spawner.take();
+        loop {
+            // a worker panicking meanwhile pushes the one replacing it
+            let Some(worker) = self.workers.lock().unwrap().pop() else {
                 break;
+            };
+            // a job dropping the pool can't wait for its own worker
+            if worker.thread().id() != thread::current().id() && worker.join().is_err() {
+                error!("worker thread panic while the pool is dropped.");
             }
         }
     }
 }
+
+fn spawn_worker(rx: Receiver<Job>, workers: Arc<Mutex<Vec<JoinHandle<()>>>>) {
+    let worker = {
+        let workers = workers.clone();
+        spawn(move || run(rx, workers))
+    };
+    workers.lock().unwrap().push(worker);
+}
+
+/// Spawns a new worker if the worker holding it unwinds, so the pool keeps its number of
+/// threads even if a panic escapes `catch_unwind`, e.g. from the drop of a panic payload
+struct Sentinel(Receiver<Job>, Arc<Mutex<Vec<JoinHandle<()>>>>);
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            error!("worker thread panic, spawn a new one.");
+            spawn_worker(self.0.clone(), self.1.clone());
+        }
+    }
+}
+
+fn run(rx: Receiver<Job>, workers: Arc<Mutex<Vec<JoinHandle<()>>>>) {
+    let _sentinel = Sentinel(rx.clone(), workers);
+    // park until a job arrives, the channel is only disconnected when the pool is dropped
+    while let Ok(f) = rx.recv() {
+        if let Err(cause) = catch_unwind(AssertUnwindSafe(|| f())) {
+            error!("user task panic catch: \n{:#?}", cause);
+        }
+    }
+    error!("thread pool is be destoryed.");
+}
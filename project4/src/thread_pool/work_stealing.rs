@@ -0,0 +1,116 @@
+use std::{
+    iter,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::spawn,
+    time::Duration,
+};
+
+use crossbeam_deque::{Injector, Stealer, Worker};
+use log::error;
+
+use super::ThreadPool;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+// an idle worker wakes up this often to steal jobs queued on other workers
+const STEAL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A pool where every worker runs jobs from its own deque, and steals from the global
+/// queue or other workers once it runs out of jobs.
+///
+/// Compared with `SharedQueueThreadPool`, workers mostly take jobs from their own deque,
+/// so they don't all contend on one queue.
+pub struct WorkStealingThreadPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    // jobs spawned into the pool but not yet taken by any worker
+    injector: Injector<Job>,
+    // steal jobs from the deque of each worker
+    stealers: Vec<Stealer<Job>>,
+    // idle workers wait here for new jobs
+    idle: Mutex<()>,
+    wakeup: Condvar,
+    // set when the pool is dropped, workers exit once there is no job left
+    shutdown: AtomicBool,
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(threads: u32) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        let workers: Vec<Worker<Job>> = (0..threads).map(|_| Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: workers.iter().map(Worker::stealer).collect(),
+            idle: Mutex::new(()),
+            wakeup: Condvar::new(),
+            shutdown: AtomicBool::new(false),
+        });
+        for worker in workers {
+            let shared = shared.clone();
+            spawn(move || run(worker, shared));
+        }
+        Ok(WorkStealingThreadPool { shared })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.injector.push(Box::new(job));
+        // notify under the lock, so a worker can't miss it between checking the queue and waiting
+        let _idle = self.shared.idle.lock().unwrap();
+        self.shared.wakeup.notify_one();
+    }
+}
+
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::SeqCst);
+        let _idle = self.shared.idle.lock().unwrap();
+        self.shared.wakeup.notify_all();
+    }
+}
+
+fn run(local: Worker<Job>, shared: Arc<Shared>) {
+    loop {
+        match find_job(&local, &shared) {
+            Some(job) => {
+                if let Err(cause) = catch_unwind(AssertUnwindSafe(job)) {
+                    error!("user task panic catch: \n{:#?}", cause);
+                }
+            }
+            None => {
+                let idle = shared.idle.lock().unwrap();
+                if shared.shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+                if shared.injector.is_empty() {
+                    drop(shared.wakeup.wait_timeout(idle, STEAL_INTERVAL).unwrap());
+                }
+            }
+        }
+    }
+}
+
+/// Take a job from the local deque, otherwise steal a batch from the global queue,
+/// otherwise steal from another worker.
+fn find_job(local: &Worker<Job>, shared: &Shared) -> Option<Job> {
+    local.pop().or_else(|| {
+        iter::repeat_with(|| {
+            shared
+                .injector
+                .steal_batch_and_pop(local)
+                .or_else(|| shared.stealers.iter().map(Stealer::steal).collect())
+        })
+        .find(|steal| !steal.is_retry())
+        .and_then(|steal| steal.success())
+    })
+}
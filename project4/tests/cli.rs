@@ -1,6 +1,9 @@
 use assert_cmd::prelude::*;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvServer, KvStore, KvsEngine, Result};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::net::SocketAddr;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -212,6 +215,39 @@ fn cli_wrong_engine() {
     }
 }
 
+// A second server on the data directory of a running one should fail, and so should a server
+// on a directory of an unknown engine.
+#[test]
+fn cli_locked_directory() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4043"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4044"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("locked"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    fs::write(temp_dir.path().join(".engine"), "unknown").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4044"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("belongs to the unknown engine, not kvs"));
+}
+
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -224,6 +260,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // the store is locked until the server is gone, and is reopened by the next server
+        child.wait().expect("server not waited for");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -305,6 +343,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // the store is locked until the server is gone, and is reopened by the next server
+        child.wait().expect("server not waited for");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -326,6 +366,51 @@ fn cli_access_server(engine: &str, addr: &str) {
     handle.join().unwrap();
 }
 
+// SIGTERM should stop the server cleanly, and what it has been sent should be found after
+// a restart.
+#[cfg(unix)]
+#[test]
+fn cli_graceful_sigterm() {
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::Pid;
+
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let serve = || {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+    let terminate = |mut child: std::process::Child| {
+        kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM).unwrap();
+        assert!(child.wait().unwrap().success());
+    };
+
+    let child = serve();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    terminate(child);
+
+    let child = serve();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    terminate(child);
+}
+
 #[test]
 fn cli_access_server_kvs_engine() {
     cli_access_server("kvs", "127.0.0.1:4004");
@@ -335,3 +420,56 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_kvs_lockfree_engine() {
+    cli_access_server("kvs-lockfree", "127.0.0.1:4047");
+}
+
+// `kvs-admin stats` should print the number of keys of a store directory, or of the store of a
+// running server.
+#[test]
+fn admin_cli_stats() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..3 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    store.set("key0".to_owned(), "overwritten".to_owned())?;
+    drop(store);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 3\n"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["compact", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["dump", "--dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains(r#"{"key":"key0","value":"overwritten"}"#));
+
+    let addr: SocketAddr = "127.0.0.1:4042".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(1)?,
+        addr,
+    )?;
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["stats", "--addr", "127.0.0.1:4042"])
+        .assert()
+        .success()
+        .stdout(contains("keys: 3\n"));
+    handle.shutdown()
+}
@@ -0,0 +1,17 @@
+use kvs::error::{ErrorCode, KvError};
+
+// Errors should display their message, and debug print it, whether or not backtraces are captured.
+#[test]
+fn error_display() {
+    let err = KvError::from(ErrorCode::KeyNotFound);
+    assert_eq!(err.to_string(), "Key not found");
+    assert!(format!("{:?}", err).starts_with("Key not found"));
+    assert!(matches!(*err, ErrorCode::KeyNotFound));
+
+    let err = KvError::from(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "disk on fire",
+    ));
+    assert_eq!(err.to_string(), "disk on fire");
+    assert!(format!("{:?}", err).starts_with("disk on fire"));
+}
@@ -0,0 +1,128 @@
+use kvs::common::{
+    handle_receive, handle_send, Codec, KvsRequest, KvsResponse, Service, ServiceProxy,
+};
+use kvs::error::KvError;
+use kvs::{IoStats, Result};
+use std::io::{self, Read, Write};
+
+/// A reader handing out at most one byte per read, like a TCP stream under a slow network
+struct ByteByByte(io::Cursor<Vec<u8>>);
+
+impl Read for ByteByByte {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(1);
+        self.0.read(&mut buf[..len])
+    }
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// A frame whose length prefix arrives one byte at a time should still decode.
+#[test]
+fn length_prefix_in_pieces() -> Result<()> {
+    let req = KvsRequest::Set {
+        key: "key".to_owned(),
+        value: "v".repeat(300),
+    };
+    let mut bytes = frame(&serde_json::to_vec(&req)?);
+    bytes.extend(frame(br#""next""#));
+    let mut reader = ByteByByte(io::Cursor::new(bytes));
+
+    let res = handle_receive::<KvsRequest>(&mut reader)?;
+    assert!(
+        matches!(res, Some(KvsRequest::Set { key, value }) if key == "key" && value.len() == 300)
+    );
+    assert_eq!(
+        handle_receive::<String>(&mut reader)?,
+        Some("next".to_owned())
+    );
+    // a close at a frame boundary is clean
+    assert!(handle_receive::<String>(&mut reader)?.is_none());
+    Ok(())
+}
+
+// A close in the middle of the length prefix is an error rather than a clean close.
+#[test]
+fn truncated_length_prefix() {
+    let mut reader = ByteByByte(io::Cursor::new(vec![0, 0]));
+    assert!(handle_receive::<String>(&mut reader).is_err());
+}
+
+/// One side of a connection in memory: reads what the other side wrote, keeps what it writes
+struct Pipe {
+    input: io::Cursor<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+impl Pipe {
+    fn new(input: Vec<u8>) -> Pipe {
+        Pipe {
+            input: io::Cursor::new(input),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Answers every get with the key as its value
+struct Echo;
+
+impl Service<KvsRequest, KvsResponse> for Echo {
+    fn handle(&mut self, req: KvsRequest) -> KvsResponse {
+        match req {
+            KvsRequest::Get { key } => KvsResponse::Get(Ok(Some(key))),
+            req => panic!("unexpected request {:?}", req),
+        }
+    }
+
+    fn handle_error(&mut self, err: KvError) -> KvsResponse {
+        KvsResponse::Error(err.into())
+    }
+}
+
+struct EchoProxy;
+
+impl ServiceProxy<KvsRequest, KvsResponse> for EchoProxy {}
+
+// A request and its response go through the framing of both sides without any socket.
+#[test]
+fn request_response_in_memory() -> Result<()> {
+    let req = KvsRequest::Get {
+        key: "key".to_owned(),
+    };
+    let mut sent = Vec::new();
+    handle_send(&mut sent, &req)?;
+
+    let mut server = Pipe::new(sent.clone());
+    assert!(Echo.response(&mut server, None, Codec::Json)?);
+    // the client is gone after its request
+    assert!(!Echo.response(&mut server, None, Codec::Json)?);
+
+    let mut client = Pipe::new(server.output);
+    let mut io = IoStats::default();
+    let res = EchoProxy::request(&mut client, &req, &mut io, Codec::Json)?;
+    assert!(matches!(res, KvsResponse::Get(Ok(Some(value))) if value == "key"));
+    assert_eq!(client.output, sent);
+    assert_eq!(io.bytes_sent, sent.len() as u64);
+    assert_eq!(io.bytes_received, client.input.get_ref().len() as u64);
+    Ok(())
+}
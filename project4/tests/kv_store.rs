@@ -1,6 +1,16 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::error::ErrorCode;
+use kvs::thread_pool::{RayonThreadPool, ThreadPool};
+use kvs::{
+    BatchOp, Compression, KvStore, KvStoreOpts, KvsEngine, LogCodec, Result, ShardedKvStore,
+    SledStore,
+};
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -127,29 +137,1240 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Should remove a `.tmp` file left by an interrupted compaction and keep the store consistent.
+#[test]
+fn open_cleans_dangling_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // A half-written compaction file that has never been renamed to `.log`.
+    let tmp_path = temp_dir.path().join("100.tmp");
+    fs::write(
+        &tmp_path,
+        r#"{"Set":{"key":"key1","value":"stale"}}{"Set":{"ke"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!tmp_path.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Parallel validation should report the same as the serial one, including corrupted records.
+#[test]
+fn validate_parallel() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // without checksums, so the record renamed below still parses
+    let opts = KvStoreOpts {
+        checksums: false,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    for i in 0..10000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("corrupt".to_owned(), "value".to_owned())?;
+
+    let report = store.validate()?;
+    assert_eq!(report.checked, 10001);
+    assert!(report.corrupted.is_empty());
+    let pool = RayonThreadPool::new(4)?;
+    assert_eq!(store.validate_parallel(&pool, 8)?, report);
+
+    // rename the key of the last record behind the store's back
+    let log = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("log".as_ref()))
+        .unwrap();
+    let content = fs::read_to_string(&log)?;
+    let pos = content.rfind("corrupt").unwrap();
+    let mut file = OpenOptions::new().write(true).open(&log)?;
+    file.seek(SeekFrom::Start(pos as u64))?;
+    file.write_all(b"CORRUPT")?;
+
+    let report = store.validate()?;
+    assert_eq!(report.corrupted, vec!["corrupt".to_owned()]);
+    assert_eq!(store.validate_parallel(&pool, 8)?, report);
+    Ok(())
+}
+
+// A record which doesn't read back correctly after a compaction should abort it before commit.
+#[test]
+fn verify_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // without checksums, so the record renamed below still parses
+    let opts = KvStoreOpts {
+        verify_compaction: true,
+        checksums: false,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("corrupt".to_owned(), "value".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // rename the key of the last record behind the store's back, so it is copied wrongly
+    let log = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            path.extension() == Some("log".as_ref())
+                && fs::read_to_string(path).unwrap().contains("corrupt")
+        })
+        .unwrap();
+    let content = fs::read_to_string(&log)?;
+    let pos = content.rfind("corrupt").unwrap();
+    let mut file = OpenOptions::new().write(true).open(&log)?;
+    file.seek(SeekFrom::Start(pos as u64))?;
+    file.write_all(b"CORRUPT")?;
+
+    let err = store.compact().unwrap_err();
+    assert!(
+        matches!(&*err, ErrorCode::CompactionVerificationFailed { keys } if keys == &["corrupt"])
+    );
+    // nothing is committed, the old log is still in place
+    assert!(log.exists());
+    let tmp_files = fs::read_dir(temp_dir.path())?
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("tmp".as_ref()))
+        .count();
+    assert_eq!(tmp_files, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Tiny values should be served from the index without reading the log, also after reopening.
+#[test]
+fn inline_tiny_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOpts {
+        inline_value_max: Some(8),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("v{}", i))?;
+    }
+    drop(store);
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+
+    // empty the log behind the store's back, a get reading it would fail
+    for entry in fs::read_dir(temp_dir.path())? {
+        OpenOptions::new()
+            .write(true)
+            .open(entry?.path())?
+            .set_len(0)?;
+    }
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("v{}", i)));
+    }
+    Ok(())
+}
+
+// Values larger than the inline limit should still be read from the log.
+#[test]
+fn large_values_not_inlined() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOpts {
+        inline_value_max: Some(8),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    store.set("tiny".to_owned(), "12345678".to_owned())?;
+    store.set("large".to_owned(), "123456789".to_owned())?;
+    assert_eq!(store.get("large".to_owned())?, Some("123456789".to_owned()));
+
+    for entry in fs::read_dir(temp_dir.path())? {
+        OpenOptions::new()
+            .write(true)
+            .open(entry?.path())?
+            .set_len(0)?;
+    }
+    assert_eq!(store.get("tiny".to_owned())?, Some("12345678".to_owned()));
+    assert!(store.get("large".to_owned()).is_err());
+    Ok(())
+}
+
+// A log in none of the formats of the store should fail the open with the offending file.
+#[test]
+fn mixed_formats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = |name: &str| temp_dir.path().join(name);
+    fs::write(log("1.log"), r#"{"Set":{"key":"key1","value":"value1"}}"#)?;
+    // `Set { key: "key2", value: "v" }` as bincode
+    let mut bincode = vec![0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0];
+    bincode.extend_from_slice(b"key2");
+    bincode.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, b'v']);
+    fs::write(log("2.log"), bincode)?;
+
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(matches!(&*err, ErrorCode::MixedFormats { files } if files == &[log("2.log")]));
+
+    // nothing is written by the failed open
+    fs::remove_file(log("2.log"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Writes synced to the disk should be found after reopening the store.
+#[test]
+fn sync_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOpts {
+        sync: true,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Logs named with and without padding should be replayed in numeric generation order.
+#[test]
+fn padded_gen_names() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = |name: &str| temp_dir.path().join(name);
+    // listed as 10, 2, 9 in lexicographic order
+    fs::write(log("2.log"), r#"{"Set":{"key":"key1","value":"gen2"}}"#)?;
+    fs::write(log("9.log"), r#"{"Set":{"key":"key1","value":"gen9"}}"#)?;
+    fs::write(
+        log("00000000000000000010.log"),
+        r#"{"Set":{"key":"key1","value":"gen10"}}"#,
+    )?;
+
+    let opts = KvStoreOpts {
+        padded_gen_names: true,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("gen10".to_owned()));
+    store.set("key2".to_owned(), "gen11".to_owned())?;
+    assert!(log("00000000000000000011.log").exists());
+
+    // a compaction removes both kinds of names
+    store.compact()?;
+    drop(store);
+    let mut names: Vec<String> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".log"))
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        vec!["00000000000000000012.log", "00000000000000000013.log"]
+    );
+
+    // readable without the option as well
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("gen10".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("gen11".to_owned()));
+    Ok(())
+}
+
+fn scan_range<E: KvsEngine>(engine: E) -> Result<()> {
+    for c in 'a'..='z' {
+        engine.set(c.to_string(), c.to_uppercase().to_string())?;
+    }
+    engine.remove("d".to_owned())?;
+    engine.set("e".to_owned(), "E2".to_owned())?;
+
+    let pairs = engine.scan("c".to_owned().."f".to_owned())?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("c".to_owned(), "C".to_owned()),
+            ("e".to_owned(), "E2".to_owned()),
+        ]
+    );
+    assert_eq!(engine.scan("y".to_owned()..)?.len(), 2);
+    assert!(engine.scan("zz".to_owned()..)?.is_empty());
+    Ok(())
+}
+
+// Should scan the key/value pairs in a range in key order, skipping removed keys.
+#[test]
+fn scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_range(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_range(SledStore::open(temp_dir.path())?)
+}
+
+fn contains_keys<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    for key in ["key1", "key2", "key3"] {
+        assert_eq!(
+            engine.contains(key.to_owned())?,
+            engine.get(key.to_owned())?.is_some()
+        );
+    }
+    assert!(engine.contains("key1".to_owned())?);
+    Ok(())
+}
+
+// `contains` should agree with `get` on present and removed keys, without reading the log.
+#[test]
+fn contains() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    contains_keys(&store)?;
+
+    // empty the log behind the store's back, a get reading it would fail
+    for entry in fs::read_dir(temp_dir.path())? {
+        OpenOptions::new()
+            .write(true)
+            .open(entry?.path())?
+            .set_len(0)?;
+    }
+    assert!(store.get("key1".to_owned()).is_err());
+    assert!(store.contains("key1".to_owned())?);
+    assert!(!store.contains("key2".to_owned())?);
+    Ok(())
+}
+
+#[test]
+fn sled_contains() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    contains_keys(&SledStore::open(temp_dir.path())?)
+}
+
+fn get_many_keys<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    let keys = ["key3", "key2", "key4", "key1", "key3"];
+    assert_eq!(
+        engine.get_many(keys.iter().map(|&key| key.to_owned()).collect())?,
+        vec![
+            Some("value3".to_owned()),
+            None,
+            None,
+            Some("value1".to_owned()),
+            Some("value3".to_owned()),
+        ]
+    );
+    assert_eq!(engine.get_many(vec![])?, vec![]);
+    Ok(())
+}
+
+// `get_many` should return the values in the order of the keys, `None` for missing ones.
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    get_many_keys(&KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    get_many_keys(&SledStore::open(temp_dir.path())?)
+}
+
+fn swap_values<E: KvsEngine>(engine: &E) -> Result<()> {
+    let key = || "key1".to_owned();
+    let value = |v: &str| Some(v.to_owned());
+    assert!(!engine.compare_and_swap(key(), value("value1"), value("value2"))?);
+    assert!(engine.compare_and_swap(key(), None, value("value1"))?);
+    assert!(!engine.compare_and_swap(key(), None, value("value2"))?);
+    assert!(!engine.compare_and_swap(key(), value("other"), value("value2"))?);
+    assert_eq!(engine.get(key())?, value("value1"));
+    assert!(engine.compare_and_swap(key(), value("value1"), value("value2"))?);
+    assert_eq!(engine.get(key())?, value("value2"));
+    assert!(engine.compare_and_swap(key(), value("value2"), None)?);
+    assert_eq!(engine.get(key())?, None);
+    assert!(engine.compare_and_swap(key(), None, None)?);
+
+    // a key which has expired no longer exists
+    engine.set_with_ttl(key(), "value3".to_owned(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+    assert!(engine.compare_and_swap(key(), None, value("value4"))?);
+    assert_eq!(engine.get(key())?, value("value4"));
+    Ok(())
+}
+
+// `compare_and_swap` should only write a key holding the expected value.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    swap_values(&KvStore::open(temp_dir.path())?)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value4".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sled_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    swap_values(&SledStore::open(temp_dir.path())?)
+}
+
+fn count_up<E: KvsEngine + Sync>(engine: &E, threads: i64, times: i64) -> Result<()> {
+    thread::scope(|scope| -> Result<()> {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| -> Result<()> {
+                    for _ in 0..times {
+                        engine.increment("counter".to_owned(), 1)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap()?;
+        }
+        Ok(())
+    })?;
+    assert_eq!(
+        engine.get("counter".to_owned())?,
+        Some((threads * times).to_string())
+    );
+    assert_eq!(engine.increment("counter".to_owned(), -threads * times)?, 0);
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let err = engine.increment("key1".to_owned(), 1).unwrap_err();
+    assert!(matches!(&*err, ErrorCode::NotAnInteger { key } if key == "key1"));
+    engine.set("key1".to_owned(), i64::MAX.to_string())?;
+    let err = engine.increment("key1".to_owned(), 1).unwrap_err();
+    assert!(matches!(&*err, ErrorCode::IncrementOverflow { .. }));
+    Ok(())
+}
+
+// Concurrent increments should all be counted.
+#[test]
+fn increment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    count_up(&KvStore::open(temp_dir.path())?, 10, 1000)
+}
+
+// Sled increments by compare-and-swap. Every write is flushed, so it counts less to stay quick.
+#[test]
+fn sled_increment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    count_up(&SledStore::open(temp_dir.path())?, 10, 100)
+}
+
+// null bytes, invalid UTF-8, and the markers of binary records and expiry headers
+const BINARY: &[u8] = b"\xff\0\xfe\0binary\xc3\x28";
+
+fn bytes_round_trip<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set_bytes("key1".to_owned(), BINARY.to_vec())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get_bytes("key1".to_owned())?, Some(BINARY.to_vec()));
+    let err = engine.get("key1".to_owned()).unwrap_err();
+    assert!(matches!(*err, ErrorCode::Utf8(_)));
+
+    // the string and bytes APIs read each other's values
+    assert_eq!(
+        engine.get_bytes("key2".to_owned())?,
+        Some(b"value2".to_vec())
+    );
+    engine.set_bytes("key3".to_owned(), b"value3".to_vec())?;
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Binary values should round trip, also through a reopen and a verified compaction.
+#[test]
+fn bytes_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    bytes_round_trip(&store)?;
+    // binary records count as written bytes like the others
+    let written = store.stats()?.bytes_written;
+    store.set_bytes("key4".to_owned(), BINARY.to_vec())?;
+    assert!(store.stats()?.bytes_written >= written + BINARY.len() as u64);
+    drop(store);
+
+    let opts = KvStoreOpts {
+        verify_compaction: true,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    store.compact()?;
+    assert_eq!(store.get_bytes("key1".to_owned())?, Some(BINARY.to_vec()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sled_bytes_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // a sled database is not reopened right away, its flusher thread may still hold the lock
+    bytes_round_trip(&SledStore::open(temp_dir.path())?)
+}
+
+fn log_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| entry.metadata().unwrap().len())
+        .sum()
+}
+
+// A store written with the binary codec should reopen with either codec, take smaller logs than
+// JSON, and keep its records through a verified compaction.
+#[test]
+fn binary_codec() -> Result<()> {
+    let json_dir = TempDir::new().expect("unable to create temporary working directory");
+    let binary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let binary = KvStoreOpts {
+        codec: LogCodec::Binary,
+        ..Default::default()
+    };
+    let json = KvStore::open(json_dir.path())?;
+    let store = KvStore::open_with_opts(binary_dir.path(), binary.clone())?;
+    for store in [&json, &store] {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.set("key1".to_owned(), "overwritten".to_owned())?;
+        store.remove("key2".to_owned())?;
+        store.set_with_ttl(
+            "key3".to_owned(),
+            "value3".to_owned(),
+            Duration::from_secs(3600),
+        )?;
+    }
+    drop(json);
+    drop(store);
+    assert!(log_size(binary_dir.path()) < log_size(json_dir.path()));
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+        assert_eq!(
+            store.get("key1".to_owned())?,
+            Some("overwritten".to_owned())
+        );
+        assert_eq!(store.get("key2".to_owned())?, None);
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key99".to_owned())?, Some("value99".to_owned()));
+        Ok(())
+    };
+    check(&KvStore::open_with_opts(binary_dir.path(), binary)?)?;
+
+    // records of both codecs in one log
+    let store = KvStore::open(binary_dir.path())?;
+    check(&store)?;
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    drop(store);
+
+    let opts = KvStoreOpts {
+        verify_compaction: true,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(binary_dir.path(), opts)?;
+    store.compact()?;
+    check(&store)?;
+    assert_eq!(store.get("key100".to_owned())?, Some("value100".to_owned()));
+    Ok(())
+}
+
+// Compressed values should take smaller logs, read back as written, and mix with plain ones.
+#[test]
+fn compressed_values() -> Result<()> {
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let lz4_dir = TempDir::new().expect("unable to create temporary working directory");
+    let lz4 = KvStoreOpts {
+        compression: Some(Compression::Lz4),
+        ..Default::default()
+    };
+    let value = |i| format!("value{} ", i).repeat(100);
+    let plain = KvStore::open(plain_dir.path())?;
+    let store = KvStore::open_with_opts(lz4_dir.path(), lz4.clone())?;
+    for store in [&plain, &store] {
+        for i in 0..50 {
+            store.set(format!("key{}", i), value(i))?;
+        }
+        // too short to get smaller, so it is written as it is
+        store.set("short".to_owned(), "value".to_owned())?;
+    }
+    drop(plain);
+    assert!(log_size(lz4_dir.path()) * 10 < log_size(plain_dir.path()));
+    assert_eq!(store.get("key7".to_owned())?, Some(value(7)));
+    drop(store);
+
+    // opened without compression, it still reads the compressed values and adds plain ones
+    let store = KvStore::open(lz4_dir.path())?;
+    for i in 0..50 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(value(i)));
+    }
+    assert_eq!(store.get("short".to_owned())?, Some("value".to_owned()));
+    store.set("key50".to_owned(), value(50))?;
+    assert!(store.validate()?.corrupted.is_empty());
+    drop(store);
+
+    let store = KvStore::open_with_opts(lz4_dir.path(), lz4)?;
+    store.compact()?;
+    assert_eq!(store.get("key0".to_owned())?, Some(value(0)));
+    assert_eq!(store.get("key50".to_owned())?, Some(value(50)));
+    Ok(())
+}
+
+// A store opened read-only twice at once should serve reads, refuse writes and leave its
+// directory as it is.
+#[test]
+fn read_only_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+    let files = |path: &Path| -> Vec<_> {
+        let mut files: Vec<_> = WalkDir::new(path)
+            .into_iter()
+            .map(|entry| entry.unwrap().path().to_owned())
+            .collect();
+        files.sort();
+        files
+    };
+    let before = files(temp_dir.path());
+
+    let barrier = Barrier::new(2);
+    thread::scope(|s| -> Result<()> {
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                s.spawn(|| -> Result<()> {
+                    let store = KvStore::open_read_only(temp_dir.path())?;
+                    barrier.wait();
+                    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+                    assert_eq!(store.get("key2".to_owned())?, None);
+                    let err = store
+                        .set("key1".to_owned(), "value2".to_owned())
+                        .unwrap_err();
+                    assert!(matches!(*err, ErrorCode::ReadOnly));
+                    let err = store.remove("key1".to_owned()).unwrap_err();
+                    assert!(matches!(*err, ErrorCode::ReadOnly));
+                    Ok(())
+                })
+            })
+            .collect();
+        for reader in readers {
+            reader.join().unwrap()?;
+        }
+        Ok(())
+    })?;
+    assert_eq!(files(temp_dir.path()), before);
+
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+    Ok(())
+}
+
+// A directory should be opened by one store at a time, until all its clones are dropped, and
+// read-only opens should only wait for it.
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let clone = store.clone();
+    drop(store);
+
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(matches!(*err, ErrorCode::DirectoryLocked { .. }));
+    let err = KvStore::open_read_only(temp_dir.path()).err().unwrap();
+    assert!(matches!(*err, ErrorCode::DirectoryLocked { .. }));
+    drop(clone);
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    let other = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(matches!(*err, ErrorCode::DirectoryLocked { .. }));
+    drop((reader, other));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Iterating should stream all live pairs in key order, and go on past writes in between.
+#[test]
+fn iter_pairs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut expected = 0;
+    for i in 0..10_000 {
+        let (key, value) = (format!("key{:05}", i), format!("value{}", i));
+        expected += key.len() + value.len();
+        store.set(key, value)?;
+    }
+
+    let (mut count, mut total) = (0, 0);
+    let mut last = String::new();
+    for pair in store.iter() {
+        let (key, value) = pair?;
+        assert!(key > last);
+        count += 1;
+        total += key.len() + value.len();
+        last = key;
+    }
+    assert_eq!(count, 10_000);
+    assert_eq!(total, expected);
+
+    let mut iter = store.iter();
+    assert_eq!(iter.next().unwrap()?.0, "key00000");
+    store.remove("key00001".to_owned())?;
+    store.set("key00000a".to_owned(), "inserted".to_owned())?;
+    assert_eq!(iter.next().unwrap()?.0, "key00000a");
+    assert_eq!(iter.next().unwrap()?.0, "key00002");
+    Ok(())
+}
+
+// Logs should roll over at the max size into contiguous generations, which all reopen.
+#[test]
+fn log_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOpts {
+        max_log_size: Some(1024),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let mut gens: Vec<u64> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .map(|path| path.file_stem().unwrap().to_str().unwrap().parse().unwrap())
+        .collect();
+    gens.sort_unstable();
+    assert!(gens.len() > 2);
+    assert!(gens.windows(2).all(|pair| pair[1] == pair[0] + 1));
+    assert_eq!(store.stats()?.num_generations, gens.len() as u64);
+    for &gen in &gens[..gens.len() - 1] {
+        let len = fs::metadata(temp_dir.path().join(format!("{}.log", gen)))?.len();
+        // a log rolls over after the record which reached the max size
+        assert!((1024..1024 + 64).contains(&len));
+    }
+    drop(store);
+
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..200 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+/// Wait until the background compactions of `store` have run `count` times
+fn wait_for_compactions(store: &KvStore, count: u64) -> Result<()> {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while store.stats()?.compactions < count {
+        assert!(Instant::now() < deadline, "No compaction detected");
+        thread::sleep(Duration::from_millis(1));
+    }
+    Ok(())
+}
+
+// Automatic compaction should only rewrite the logs with the most stale bytes and leave the
+// rest, without a removed key coming back from a log it left.
+#[test]
+fn partial_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let gens = || -> Result<Vec<u64>> {
+        let mut gens: Vec<u64> = fs::read_dir(temp_dir.path())?
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("log".as_ref()))
+            .map(|path| path.file_stem().unwrap().to_str().unwrap().parse().unwrap())
+            .collect();
+        gens.sort_unstable();
+        Ok(gens)
+    };
+    // every value fills a log by itself
+    let value = |c: &str| c.repeat(1100);
+    let opts = KvStoreOpts {
+        max_log_size: Some(1024),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    store.set("gone".to_owned(), "g".repeat(1030))?; // 1.log
+    store.set("b0".to_owned(), value("b"))?; // 2.log
+    store.set("b1".to_owned(), value("b"))?; // 3.log
+    store.set("a0".to_owned(), value("a"))?; // 4.log
+    store.remove("gone".to_owned())?; // 5.log
+    store.set("a1".to_owned(), value("a"))?; // 5.log
+    store.set("a0".to_owned(), value("x"))?; // 6.log
+    store.set("a1".to_owned(), value("x"))?; // 7.log
+    drop(store);
+
+    // 4.log and 5.log are stale as a whole, 1.log only holds the removed key
+    let store = KvStore::open_with_opts(
+        temp_dir.path(),
+        KvStoreOpts {
+            compaction_threshold: 3000,
+            ..opts.clone()
+        },
+    )?;
+    assert_eq!(gens()?, (1..=9).collect::<Vec<_>>());
+    store.set("c".to_owned(), "c".to_owned())?;
+    wait_for_compactions(&store, 1)?;
+    assert_eq!(gens()?, vec![1, 2, 3, 6, 7, 8, 9, 10, 11]);
+    assert!(store.stats()?.uncompacted_bytes < 3000);
+    drop(store);
+
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert_eq!(store.get("gone".to_owned())?, None);
+    assert_eq!(store.get("b0".to_owned())?, Some(value("b")));
+    assert_eq!(store.get("b1".to_owned())?, Some(value("b")));
+    assert_eq!(store.get("a0".to_owned())?, Some(value("x")));
+    assert_eq!(store.get("a1".to_owned())?, Some(value("x")));
+    assert_eq!(store.get("c".to_owned())?, Some("c".to_owned()));
+    Ok(())
+}
+
+// A batch should be applied in order as a whole, and not at all if one of its ops fails.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+    let mut ops: Vec<BatchOp> = (0..400)
+        .map(|i| BatchOp::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+        })
+        .collect();
+    ops.extend((0..100).map(|i| BatchOp::Remove {
+        key: format!("key{}", i * 2),
+    }));
+    store.write_batch(ops)?;
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..400 {
+            let expected = if i < 200 && i % 2 == 0 {
+                None
+            } else {
+                Some(format!("value{}", i))
+            };
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    // the remove of a missing key fails the batch before anything is written
+    let size = log_size(temp_dir.path());
+    let ops = vec![
+        BatchOp::Set {
+            key: "key1".to_owned(),
+            value: "new".to_owned(),
+        },
+        BatchOp::Remove {
+            key: "key3".to_owned(),
+        },
+        BatchOp::Remove {
+            key: "key3".to_owned(),
+        },
+    ];
+    assert!(matches!(
+        *store.write_batch(ops).unwrap_err(),
+        ErrorCode::KeyNotFound
+    ));
+    assert_eq!(log_size(temp_dir.path()), size);
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    check(&store)
+}
+
+// Stats should count the keys and the bytes of the logs, with overwrites as uncompacted bytes.
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.num_keys, 100);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    assert_eq!(stats.num_generations, 1);
+    assert_eq!(stats.total_bytes, log_size(temp_dir.path()));
+
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("overwritten{}", i))?;
+    }
+    let overwritten = store.stats()?;
+    assert_eq!(overwritten.num_keys, 100);
+    assert!(overwritten.uncompacted_bytes > 0);
+    assert!(overwritten.uncompacted_bytes < overwritten.total_bytes - stats.total_bytes);
+    assert_eq!(overwritten.total_bytes, log_size(temp_dir.path()));
+
+    store.compact()?;
+    let compacted = store.stats()?;
+    assert_eq!(compacted.num_keys, 100);
+    assert_eq!(compacted.uncompacted_bytes, 0);
+    assert_eq!(compacted.total_bytes, log_size(temp_dir.path()));
+    Ok(())
+}
+
+// With automatic compaction off, a manual compaction should still reclaim the overwrites.
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOpts {
+        compaction_threshold: u64::MAX,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    let log_lens = || -> Vec<u64> {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .filter(|&len| len > 0)
+            .collect()
+    };
+
+    // 2MB of overwrites, over the default threshold
+    for iter in 0..1000 {
+        store.set("key1".to_owned(), format!("{:02048}", iter))?;
+    }
+    assert!(log_lens().iter().sum::<u64>() > 1000 * 2048);
+
+    store.compact()?;
+    // besides the new active log, which is still empty
+    assert_eq!(log_lens().len(), 1);
+    assert!(log_lens()[0] < 2 * 2048);
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some(format!("{:02048}", 999))
+    );
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some(format!("{:02048}", 999))
+    );
+    Ok(())
+}
+
+// The estimate should predict the bytes a compaction reclaims.
+#[test]
+fn compaction_estimate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir_size = || -> u64 {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..5 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    let estimate = store.compaction_estimate()?;
+    let before = dir_size();
+    assert_eq!(estimate.current_bytes, before);
+    assert!(estimate.reclaimable_bytes > estimate.live_bytes * 3);
+
+    store.compact()?;
+    assert_eq!(before - dir_size(), estimate.reclaimable_bytes);
+    assert_eq!(dir_size(), estimate.live_bytes);
+    assert_eq!(store.compaction_estimate()?.reclaimable_bytes, 0);
+    Ok(())
+}
+
+// Compaction should reclaim old generations, but leave the active one until it ages out.
+#[test]
+fn compaction_skips_hot_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let gen_exists = |gen: u64| temp_dir.path().join(format!("{}.log", gen)).exists();
+    let opts = KvStoreOpts {
+        compaction_min_age: 1,
+        ..Default::default()
+    };
+
+    // stale data in generation 1
+    let store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    for iter in 0..5000 {
+        store.set("cold".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+
+    // overwrite a hot key in generation 2 until a compaction reclaims generation 1
+    let store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    let value = "v".repeat(100);
+    let mut iter = 0;
+    while gen_exists(1) {
+        assert!(iter < 100_000, "No compaction detected");
+        store.set("hot".to_owned(), format!("{}{}", value, iter))?;
+        iter += 1;
+    }
+    assert!(gen_exists(2));
+    assert_eq!(store.get("cold".to_owned())?, Some("4999".to_owned()));
+
+    // the hot generation is compacted once it is old enough
+    while gen_exists(2) {
+        assert!(iter < 100_000, "No compaction detected");
+        store.set("hot".to_owned(), format!("{}{}", value, iter))?;
+        iter += 1;
+    }
+
+    drop(store);
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert_eq!(store.get("cold".to_owned())?, Some("4999".to_owned()));
+    assert_eq!(
+        store.get("hot".to_owned())?,
+        Some(format!("{}{}", value, iter - 1))
+    );
+    Ok(())
+}
+
+// The memory used by the index should grow linearly with the number of keys.
+#[test]
+fn memory_usage_grows_with_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let empty = store.memory_usage();
+    assert!(empty.reader_cache_bytes > 0);
+
+    let mut index_bytes = Vec::new();
+    for round in 0..2 {
+        for i in 0..1000 {
+            store.set(format!("key{:05}", round * 1000 + i), "value".to_owned())?;
+        }
+        index_bytes.push(store.memory_usage().index_bytes - empty.index_bytes);
+    }
+    assert!(index_bytes[0] >= 1000 * "key00000".len() as u64);
+    let ratio = index_bytes[1] as f64 / index_bytes[0] as f64;
+    assert!((1.9..2.1).contains(&ratio), "ratio {}", ratio);
+    Ok(())
+}
+
+// Exported NDJSON should be readable line by line and import into a fresh store.
+#[test]
+fn export_import_json() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    store.set("key000".to_owned(), "new \"value\"\n".to_owned())?;
+    store.remove("key099".to_owned())?;
+
+    let mut exported = Vec::new();
+    store.export_json(&mut exported)?;
+    let exported = String::from_utf8(exported)?;
+    let lines: Vec<&str> = exported.lines().collect();
+    assert_eq!(lines.len(), 99);
+    let first: serde_json::Value = serde_json::from_str(lines[0])?;
+    assert_eq!(first["key"], "key000");
+    assert_eq!(first["value"], "new \"value\"\n");
+
+    let import_dir = TempDir::new().expect("unable to create temporary working directory");
+    let imported = KvStore::open(import_dir.path())?;
+    assert_eq!(imported.import_json(exported.as_bytes())?, 99);
+    for i in 0..99 {
+        let key = format!("key{:03}", i);
+        assert_eq!(imported.get(key.clone())?, store.get(key)?);
+    }
+    assert_eq!(imported.get("key099".to_owned())?, None);
+    Ok(())
+}
+
+// A dump should restore every key into a new store, of the same engine or of sled.
+#[test]
+fn dump_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    store.set("key000".to_owned(), "new \"value\"\n".to_owned())?;
+    store.remove("key099".to_owned())?;
+
+    let mut dump = Vec::new();
+    store.dump(&mut dump)?;
+    let restore_dir = TempDir::new().expect("unable to create temporary working directory");
+    let restored = KvStore::restore(restore_dir.path(), &mut dump.as_slice())?;
+    assert_eq!(restored.scan(..)?, store.scan(..)?);
+    drop(restored);
+    // the restored logs hold the pairs
+    let reopened = KvStore::open(restore_dir.path())?;
+    assert_eq!(reopened.scan(..)?.len(), 99);
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let migrated = SledStore::restore(sled_dir.path(), &mut dump.as_slice())?;
+    assert_eq!(migrated.scan(..)?, store.scan(..)?);
+    let mut sled_dump = Vec::new();
+    migrated.dump(&mut sled_dump)?;
+    assert_eq!(sled_dump, dump);
+
+    // a store opened read-only dumps the same pairs
+    drop(store);
+    let read_only = KvStore::open_read_only(temp_dir.path())?;
+    let mut read_only_dump = Vec::new();
+    read_only.dump(&mut read_only_dump)?;
+    assert_eq!(read_only_dump, dump);
+    Ok(())
+}
+
+static MOCK_TIME: AtomicU64 = AtomicU64::new(0);
+
+fn mock_clock() -> u64 {
+    MOCK_TIME.load(Ordering::SeqCst)
+}
+
+// `last_modified` should return the time of the latest write, also after reopening.
+#[test]
+fn last_modified() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOpts {
+        clock: mock_clock,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    assert_eq!(store.last_modified("key1".to_owned())?, None);
+
+    MOCK_TIME.store(1_000, Ordering::SeqCst);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.last_modified("key1".to_owned())?, Some(1_000));
+
+    MOCK_TIME.store(2_000, Ordering::SeqCst);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.last_modified("key1".to_owned())?, Some(2_000));
+
+    drop(store);
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert_eq!(store.last_modified("key1".to_owned())?, Some(2_000));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.last_modified("key1".to_owned())?, None);
+    Ok(())
+}
+
+static TTL_TIME: AtomicU64 = AtomicU64::new(0);
+
+fn ttl_clock() -> u64 {
+    TTL_TIME.load(Ordering::SeqCst)
+}
+
+// A key set with a TTL should read back until it expires, also after reopening, and be dropped
+// by a compaction after that.
+#[test]
+fn ttl_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOpts {
+        clock: ttl_clock,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    TTL_TIME.store(1_000, Ordering::SeqCst);
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_secs(1),
+    )?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    TTL_TIME.store(1_999, Ordering::SeqCst);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    let store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    TTL_TIME.store(2_000, Ordering::SeqCst);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.last_modified("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // the expired record is gone for good, even if the clock goes back
+    store.compact()?;
+    TTL_TIME.store(1_000, Ordering::SeqCst);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn sled_ttl_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
-    for i in 0..1000 {
-        let store = store.clone();
-        let barrier = barrier.clone();
-        thread::spawn(move || {
-            store
-                .set(format!("key{}", i), format!("value{}", i))
-                .unwrap();
-            barrier.wait();
-        });
-    }
+    let writers: Vec<_> = (0..1000)
+        .map(|i| {
+            let store = store.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                store
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .unwrap();
+                barrier.wait();
+            })
+        })
+        .collect();
     barrier.wait();
 
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
-    // Open from disk again and check persistent data
+    // Open from disk again and check persistent data, once every clone is dropped
     drop(store);
+    for writer in writers {
+        writer.join().unwrap();
+    }
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
@@ -158,6 +1379,43 @@ fn concurrent_set() -> Result<()> {
     Ok(())
 }
 
+// Gets interleaved with compactions removing the logs of their keys should find the values.
+#[test]
+fn gets_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let done = Arc::new(AtomicU64::new(0));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let (store, done) = (store.clone(), done.clone());
+            thread::spawn(move || -> Result<()> {
+                while done.load(Ordering::SeqCst) == 0 {
+                    for i in 0..100 {
+                        let value = store.get(format!("key{}", i))?.unwrap();
+                        assert!(value.starts_with(&format!("value{}", i)));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for round in 0..20 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+        store.compact()?;
+    }
+    done.store(1, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    Ok(())
+}
+
 #[test]
 fn concurrent_get() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
@@ -209,3 +1467,320 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Keys and values up to the limits should be written, and larger ones rejected without
+// appending anything to the log.
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOpts {
+        max_key_size: Some(8),
+        max_value_size: Some(16),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    let log_bytes = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+
+    store.set("k".repeat(8), "v".repeat(16))?;
+    store.set_bytes("b".repeat(8), vec![0; 16])?;
+    let written = log_bytes();
+
+    let err = store.set("k".repeat(9), "v".to_owned()).unwrap_err();
+    assert!(matches!(*err, ErrorCode::KeyTooLarge { size: 9, limit: 8 }));
+    let err = store.set("key".to_owned(), "v".repeat(17)).unwrap_err();
+    assert!(matches!(
+        *err,
+        ErrorCode::ValueTooLarge {
+            size: 17,
+            limit: 16
+        }
+    ));
+    let err = store.set_bytes("key".to_owned(), vec![0; 17]).unwrap_err();
+    assert!(matches!(*err, ErrorCode::ValueTooLarge { .. }));
+    let ops = vec![
+        BatchOp::Set {
+            key: "first".to_owned(),
+            value: "v".to_owned(),
+        },
+        BatchOp::Set {
+            key: "second".to_owned(),
+            value: "v".repeat(17),
+        },
+    ];
+    let err = store.write_batch(ops).unwrap_err();
+    assert!(matches!(*err, ErrorCode::ValueTooLarge { .. }));
+
+    assert_eq!(log_bytes(), written);
+    assert_eq!(store.get("key".to_owned())?, None);
+    assert_eq!(store.get("first".to_owned())?, None);
+    assert_eq!(store.get("k".repeat(8))?, Some("v".repeat(16)));
+    Ok(())
+}
+
+// Deleting a prefix range should remove exactly its keys, also after a restart.
+#[test]
+fn delete_range() -> Result<()> {
+    fn check<E: KvsEngine>(path: &Path) -> Result<()> {
+        let store = E::open(path)?;
+        for i in 1..=100 {
+            store.set(format!("app:{}", i), "value".to_owned())?;
+        }
+        for key in ["app", "apple", "app;", "user:1"] {
+            store.set(key.to_owned(), "kept".to_owned())?;
+        }
+
+        let prefix = "app:".to_owned().."app;".to_owned();
+        assert_eq!(store.delete_range(prefix.clone())?, 100);
+        assert_eq!(store.delete_range(prefix)?, 0);
+        drop(store);
+
+        let store = E::open(path)?;
+        assert_eq!(store.get("app:1".to_owned())?, None);
+        assert_eq!(store.get("app:100".to_owned())?, None);
+        let keys: Vec<String> = store.scan(..)?.into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["app", "app;", "apple", "user:1"]);
+        Ok(())
+    }
+
+    check::<KvStore>(TempDir::new()?.path())?;
+    check::<SledStore>(TempDir::new()?.path())
+}
+
+// Repeated gets of a key should read it from the log once, until it is written again or
+// evicted by a more recently read key.
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOpts {
+        value_cache_capacity: Some(2),
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    let disk_reads = || store.stats().unwrap().disk_reads;
+    for key in ["key1", "key2", "key3"] {
+        store.set(key.to_owned(), format!("{}-value", key))?;
+    }
+
+    for _ in 0..10 {
+        assert_eq!(store.get("key1".to_owned())?, Some("key1-value".to_owned()));
+    }
+    assert_eq!(disk_reads(), 1);
+
+    store.set("key1".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(disk_reads(), 2);
+
+    // key2 evicts key3, the least recently read
+    store.get("key3".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key2".to_owned())?;
+    assert_eq!(disk_reads(), 4);
+    store.get("key1".to_owned())?;
+    assert_eq!(disk_reads(), 4);
+    store.get("key3".to_owned())?;
+    assert_eq!(disk_reads(), 5);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Records are written with checksums by default. A record failing its checksum at the end of the
+// newest log was torn by a crash, so open should cut it off, while a corrupted record followed by
+// others should fail the open.
+#[test]
+fn checksums() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = |gen: u64| temp_dir.path().join(format!("{}.log", gen));
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // the second log holds a single record, which is appended with its value garbled
+    let record = fs::read(log(2))?;
+    let mut garbled = record.clone();
+    *garbled.last_mut().unwrap() ^= 0xFF;
+    OpenOptions::new()
+        .append(true)
+        .open(log(2))?
+        .write_all(&garbled)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(fs::read(log(2))?, record);
+    drop(store);
+
+    // a garbled length runs past the end of the newest log, which is cut off the same
+    let mut garbled_len = record.clone();
+    garbled_len[5..9].copy_from_slice(&u32::MAX.to_be_bytes());
+    fs::write(log(3), &garbled_len)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(fs::read(log(3))?, b"");
+    drop(store);
+
+    let mut corrupted = garbled;
+    corrupted.extend(&record);
+    fs::write(log(3), corrupted)?;
+    let err = KvStore::open(temp_dir.path()).err().unwrap();
+    assert!(matches!(*err, ErrorCode::ChecksumMismatch));
+    Ok(())
+}
+
+// A command cut short at the end of the newest log by a crash should be cut off on open, with
+// the commands before it kept.
+#[test]
+fn torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = temp_dir.path().join("1.log");
+    // without checksums, so the log is plain JSON
+    let opts = KvStoreOpts {
+        checksums: false,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let record = fs::read(&log)?;
+    let second = String::from_utf8(record.clone())
+        .unwrap()
+        .replace("key1", "key2");
+    OpenOptions::new()
+        .append(true)
+        .open(&log)?
+        .write_all(&second.as_bytes()[..second.len() / 2])?;
+
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(fs::read(&log)?, record);
+    Ok(())
+}
+
+// A write crossing the compaction threshold should only request a compaction, which runs in the
+// background while other writes go on.
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = |round: usize| format!("{}", round).repeat(10_000);
+    let keys = 3000;
+    let mut opts = KvStoreOpts {
+        compaction_threshold: u64::MAX,
+        verify_compaction: true,
+        codec: LogCodec::Binary,
+        ..Default::default()
+    };
+    let store = KvStore::open_with_opts(temp_dir.path(), opts.clone())?;
+    for i in 0..keys {
+        store.set(format!("key{:04}", i), value(0))?;
+    }
+    let record_len = store.stats()?.bytes_written / keys as u64;
+    drop(store);
+
+    // overwriting the last of half of the keys crosses the threshold, and the compaction has
+    // the 15MB of the other half to rewrite
+    let overwritten = keys / 2;
+    opts.compaction_threshold = (overwritten as u64 - 1) * record_len;
+    let store = KvStore::open_with_opts(temp_dir.path(), opts)?;
+    // the compaction requested can't start before the writes are checked
+    store.with_compactions_paused(|| -> Result<()> {
+        for i in 0..overwritten {
+            store.set(format!("key{:04}", i), value(1))?;
+        }
+        assert_eq!(store.stats()?.compactions, 0);
+        let writer = {
+            let store = store.clone();
+            thread::spawn(move || store.set("other".to_owned(), "value".to_owned()))
+        };
+        writer.join().unwrap()?;
+        assert_eq!(store.stats()?.compactions, 0);
+        Ok(())
+    })?;
+
+    wait_for_compactions(&store, 1)?;
+    assert!(store.stats()?.uncompacted_bytes < record_len);
+    for i in 0..keys {
+        let round = if i < overwritten { 1 } else { 0 };
+        assert_eq!(store.get(format!("key{:04}", i))?, Some(value(round)));
+    }
+    assert_eq!(store.get("other".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Keys of a sharded store should be kept in exactly one shard each, which compacts on its own,
+// and be read back from the same shard after a restart
+#[test]
+fn sharded_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let opts = KvStoreOpts {
+        compaction_threshold: 4096,
+        ..Default::default()
+    };
+    let store = ShardedKvStore::open_with_opts(temp_dir.path(), 4, opts.clone())?;
+    let writers: Vec<_> = (0..4)
+        .map(|writer| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for round in 0..20 {
+                    for i in (writer..200).step_by(4) {
+                        store.set(format!("key{:03}", i), format!("value{}", round))?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    store.remove("key000".to_owned())?;
+    assert!(store.stats()?.compactions > 0);
+    store.compact()?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.num_keys, 199);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    assert_eq!(store.keys(.., 3)?, vec!["key001", "key002", "key003"]);
+    assert_eq!(store.scan("key198".to_owned()..)?.len(), 2);
+    drop(store);
+
+    let mut keys = Vec::new();
+    for shard in 0..4 {
+        let dir = temp_dir.path().join(format!("shard-{}", shard));
+        let shard = KvStore::open_with_opts(&dir, opts.clone())?;
+        let shard_keys = shard.keys(.., usize::MAX)?;
+        assert!(!shard_keys.is_empty());
+        assert_eq!(shard.stats()?.uncompacted_bytes, 0);
+        keys.extend(shard_keys);
+    }
+    keys.sort_unstable();
+    let expected: Vec<String> = (1..200).map(|i| format!("key{:03}", i)).collect();
+    assert_eq!(keys, expected);
+
+    let store = ShardedKvStore::open(temp_dir.path())?;
+    assert_eq!(store.num_shards(), 4);
+    assert_eq!(store.get("key000".to_owned())?, None);
+    for key in expected {
+        assert_eq!(store.get(key)?, Some("value19".to_owned()));
+    }
+    drop(store);
+
+    let err = ShardedKvStore::open_with_opts(temp_dir.path(), 8, opts)
+        .err()
+        .unwrap();
+    assert!(matches!(*err, ErrorCode::ShardMismatch { expected: 8, .. }));
+    Ok(())
+}
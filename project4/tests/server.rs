@@ -0,0 +1,1522 @@
+use kvs::common::{
+    decode_frame, encode_frame, frame_len, handle_receive, handle_send, Codec, KvsRequest,
+    KvsResponse, RemoteError, HANDSHAKE, LEN_PREFIX, MAX_FRAME_LEN, MAX_KEYS_PER_RESPONSE,
+};
+use kvs::error::ErrorCode;
+use kvs::middleware::{Middleware, Next, RateLimit};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::transport::Stream;
+use kvs::{
+    IoStats, KvClient, KvClientPool, KvServer, KvStore, KvsEngine, Result, ServerOpts, ShardConfig,
+};
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tempfile::TempDir;
+
+// A response over the budget should be aborted with a clear error, and the connection kept usable.
+#[test]
+fn response_over_budget() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4010".parse().unwrap();
+    let opts = ServerOpts {
+        max_response_size: Some(64),
+        ..Default::default()
+    };
+    let handle = KvServer::serve_with_opts(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+        opts,
+    )?;
+
+    let mut client = KvClient::new(addr)?;
+    client.set("small".to_owned(), "value".to_owned())?;
+    client.set("large".to_owned(), "v".repeat(100))?;
+
+    let err = client.get("large".to_owned()).unwrap_err();
+    assert!(matches!(
+        *err,
+        ErrorCode::ResponseTooLarge { limit: 64, .. }
+    ));
+    assert_eq!(client.get("small".to_owned())?, Some("value".to_owned()));
+
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// The owner of a key should be the CRC32 of the key modulo the shards, so it doesn't change with
+// the release of Rust a server is built with.
+#[test]
+fn shard_owner_is_stable() {
+    let shard = ShardConfig {
+        shards: vec!["127.0.0.1:4011".parse().unwrap(); 3],
+        index: 0,
+    };
+    let owners: Vec<usize> = ["key0", "key1", "key2", "key3", "a", "b"]
+        .iter()
+        .map(|key| shard.owner(key))
+        .collect();
+    assert_eq!(owners, vec![2, 1, 1, 0, 0, 2]);
+}
+
+// A client in redirect-aware mode should follow the wrong shard to the owner of the key.
+#[test]
+fn follow_shard_redirect() -> Result<()> {
+    let shards: Vec<SocketAddr> = vec![
+        "127.0.0.1:4011".parse().unwrap(),
+        "127.0.0.1:4012".parse().unwrap(),
+    ];
+    let temp_dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let mut handles = Vec::new();
+    for (index, temp_dir) in temp_dirs.iter().enumerate() {
+        let opts = ServerOpts {
+            shard: Some(ShardConfig {
+                shards: shards.clone(),
+                index,
+            }),
+            ..Default::default()
+        };
+        handles.push(KvServer::serve_with_opts(
+            KvStore::open(temp_dir.path())?,
+            SharedQueueThreadPool::new(2)?,
+            shards[index],
+            opts,
+        )?);
+    }
+
+    // a key owned by the second shard
+    let shard = ShardConfig {
+        shards: shards.clone(),
+        index: 0,
+    };
+    let key = (0..)
+        .map(|i| format!("key{}", i))
+        .find(|key| shard.owner(key) == 1)
+        .unwrap();
+
+    let mut client = KvClient::new(shards[0])?;
+    let err = client.set(key.clone(), "value".to_owned()).unwrap_err();
+    assert!(matches!(*err, ErrorCode::Redirect { addr } if addr == shards[1]));
+
+    client.set_follow_redirect(true);
+    client.set(key.clone(), "value".to_owned())?;
+    assert_eq!(client.get(key.clone())?, Some("value".to_owned()));
+    client.shutdown()?;
+
+    // the value is stored by the owner
+    let mut client = KvClient::new(shards[1])?;
+    assert_eq!(client.get(key)?, Some("value".to_owned()));
+    client.shutdown()?;
+
+    for handle in handles {
+        handle.shutdown()?;
+    }
+    Ok(())
+}
+
+/// A pool keeping every job in its queue for a while before running it
+struct SlowThreadPool;
+
+impl ThreadPool for SlowThreadPool {
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(SlowThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            job()
+        });
+    }
+}
+
+// A request which waited in the queue past its deadline should be skipped.
+#[test]
+fn skip_request_after_deadline() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4013".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SlowThreadPool::new(1)?,
+        addr,
+    )?;
+
+    let mut client = KvClient::new(addr)?;
+    client.set_deadline(Some(SystemTime::now() + Duration::from_millis(50)));
+    let err = client
+        .set("key1".to_owned(), "value1".to_owned())
+        .unwrap_err();
+    assert!(matches!(*err, ErrorCode::DeadlineExceeded));
+
+    client.set_deadline(Some(SystemTime::now() + Duration::from_secs(60)));
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.set_deadline(None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// Frames at the length limit should go through, while larger and empty ones fail cleanly
+// without breaking the connection.
+#[test]
+fn frame_length_boundary() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4016".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?;
+
+    let overhead = serde_json::to_vec(&KvsRequest::Set {
+        key: "key".to_owned(),
+        value: String::new(),
+    })?
+    .len();
+    let max_value = "v".repeat(MAX_FRAME_LEN - overhead);
+
+    let mut client = KvClient::new(addr)?;
+    client.set("key".to_owned(), max_value.clone())?;
+    assert_eq!(client.get("key".to_owned())?, Some(max_value.clone()));
+
+    let err = client
+        .set("key".to_owned(), format!("{}v", max_value))
+        .unwrap_err();
+    assert!(err.to_string().contains("exceeds the limit"));
+    assert_eq!(client.get("key".to_owned())?, Some(max_value));
+    client.set("key".to_owned(), "v".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("v".to_owned()));
+    client.shutdown()?;
+
+    // an empty frame is rejected, then the next request is served as usual
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&[0, 0, 0, 0])?;
+    let res = handle_receive::<KvsResponse>(&mut stream)?;
+    assert!(matches!(
+        res,
+        Some(KvsResponse::Error(RemoteError::SerDe(_)))
+    ));
+    handle_send(
+        &mut stream,
+        &KvsRequest::Get {
+            key: "key".to_owned(),
+        },
+    )?;
+    let res = handle_receive::<KvsResponse>(&mut stream)?;
+    assert!(matches!(res, Some(KvsResponse::Get(Ok(Some(value)))) if value == "v"));
+
+    handle.shutdown()
+}
+
+// A batch too large for one frame should be split, with the values returned in key order.
+#[test]
+fn get_multi_split_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4017".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?;
+
+    let mut client = KvClient::new(addr)?;
+    let padding = "k".repeat(1024);
+    let count = MAX_FRAME_LEN / padding.len() + 100;
+    let keys: Vec<String> = (0..count).map(|i| format!("{}{:05}", padding, i)).collect();
+    for (i, key) in keys.iter().enumerate().filter(|(i, _)| i % 1000 == 1) {
+        client.set(key.clone(), format!("value{}", i))?;
+    }
+    // at least two requests are needed
+    let single = serde_json::to_vec(&KvsRequest::GetMulti { keys: keys.clone() })?;
+    assert!(single.len() > MAX_FRAME_LEN);
+
+    let values = client.get_multi(keys.clone())?;
+    assert_eq!(values.len(), keys.len());
+    for (i, value) in values.into_iter().enumerate() {
+        let expected = if i % 1000 == 1 {
+            Some(format!("value{}", i))
+        } else {
+            None
+        };
+        assert_eq!(value, expected);
+    }
+
+    // the connection is still in sync
+    assert_eq!(client.get(keys[1].clone())?, Some("value1".to_owned()));
+    assert_eq!(client.get_multi(vec![])?, Vec::<Option<String>>::new());
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// A batch split into several requests whose values are all present should not block the server
+// writing a response while the client is still writing the next request.
+#[test]
+fn get_multi_many_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4054".parse().unwrap();
+    let padding = "k".repeat(1024);
+    let count = 3 * MAX_FRAME_LEN / padding.len() + 100;
+    let keys: Vec<String> = (0..count).map(|i| format!("{}{:05}", padding, i)).collect();
+    let value = |i: usize| format!("{:0500}", i);
+    let store = KvStore::open(temp_dir.path())?;
+    for (i, key) in keys.iter().enumerate() {
+        store.set(key.clone(), value(i))?;
+    }
+    let handle = KvServer::serve(store, SharedQueueThreadPool::new(2)?, addr)?;
+
+    let mut client = KvClient::new(addr)?;
+    let values = client.get_multi(keys.clone())?;
+    assert_eq!(values.len(), keys.len());
+    for (i, value_read) in values.into_iter().enumerate() {
+        assert_eq!(value_read, Some(value(i)));
+    }
+
+    // the connection is still in sync
+    assert_eq!(client.get(keys[1].clone())?, Some(value(1)));
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// Values far beyond the old `u16` length prefix should round trip through a server.
+#[test]
+fn large_value_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4018".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?;
+
+    let value = "v".repeat(1 << 20);
+    let mut client = KvClient::new(addr)?;
+    client.set("key".to_owned(), value.clone())?;
+    assert_eq!(client.get("key".to_owned())?, Some(value));
+    client.shutdown()?;
+
+    // a length over the limit is refused before anything is allocated for it
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&u32::MAX.to_be_bytes())?;
+    assert!(handle_receive::<KvsResponse>(&mut stream)?.is_none());
+
+    handle.shutdown()
+}
+
+// The byte counters of a client should match the frames of its requests and responses.
+#[test]
+fn client_io_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4019".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?;
+
+    // every frame is prefixed by its length on 4 bytes
+    fn frame_len<T: serde::Serialize>(value: &T) -> u64 {
+        serde_json::to_vec(value).unwrap().len() as u64 + 4
+    }
+    let mut client = KvClient::new(addr)?;
+    assert_eq!(client.io_stats(), IoStats::default());
+
+    client.set("key".to_owned(), "value".to_owned())?;
+    let sent = frame_len(&KvsRequest::Set {
+        key: "key".to_owned(),
+        value: "value".to_owned(),
+    });
+    let received = frame_len(&KvsResponse::Set(Ok(())));
+    assert_eq!(
+        client.io_stats(),
+        IoStats {
+            bytes_sent: sent,
+            bytes_received: received,
+        }
+    );
+
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    let sent = sent
+        + frame_len(&KvsRequest::Get {
+            key: "key".to_owned(),
+        });
+    let received = received + frame_len(&KvsResponse::Get(Ok(Some("value".to_owned()))));
+    assert_eq!(
+        client.io_stats(),
+        IoStats {
+            bytes_sent: sent,
+            bytes_received: received,
+        }
+    );
+
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// Sets applied by one batch request should all be readable afterwards.
+#[test]
+fn batch_set_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4020".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?;
+
+    let mut client = KvClient::new(addr)?;
+    let pairs: Vec<(String, String)> = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    client.set_many(pairs.clone())?;
+    // the whole batch is a single request
+    assert_eq!(
+        client.io_stats().bytes_sent,
+        serde_json::to_vec(&KvsRequest::Batch(
+            pairs
+                .iter()
+                .map(|(key, value)| KvsRequest::Set {
+                    key: key.clone(),
+                    value: value.clone(),
+                })
+                .collect()
+        ))?
+        .len() as u64
+            + 4
+    );
+
+    for (key, value) in pairs {
+        assert_eq!(client.get(key)?, Some(value));
+    }
+    client.set_many(vec![])?;
+
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+/// Listen on `addr`, closing the first `drops` connections right after reading a request
+/// and forwarding the requests of the later ones to `backend`
+fn flaky_proxy(addr: SocketAddr, backend: SocketAddr, drops: usize) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    thread::spawn(move || {
+        for (i, conn) in listener.incoming().enumerate() {
+            let mut conn = conn.unwrap();
+            if i < drops {
+                handle_receive::<KvsRequest>(&mut conn).unwrap();
+                continue;
+            }
+            let mut backend = TcpStream::connect(backend).unwrap();
+            while let Some(req) = handle_receive::<KvsRequest>(&mut conn).unwrap() {
+                handle_send(&mut backend, &req).unwrap();
+                let res = handle_receive::<KvsResponse>(&mut backend)
+                    .unwrap()
+                    .unwrap();
+                handle_send(&mut conn, &res).unwrap();
+            }
+        }
+    });
+    Ok(())
+}
+
+// A connection closed before the response should fail with a typed error, or be retried on a
+// new connection when the client has retries.
+#[test]
+fn retry_closed_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4021".parse().unwrap();
+    let backend: SocketAddr = "127.0.0.1:4022".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        backend,
+    )?;
+    flaky_proxy(addr, backend, 2)?;
+
+    let mut client = KvClient::new(addr)?;
+    let err = client
+        .set("key".to_owned(), "value".to_owned())
+        .unwrap_err();
+    assert!(matches!(*err, ErrorCode::ConnectionClosed));
+
+    let mut client = KvClient::new(addr)?;
+    client.set_retries(1);
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// An increment or a get-delete may have been applied when its connection is closed, so it should
+// fail rather than be retried, even when the client has retries.
+#[test]
+fn non_idempotent_not_retried() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4051".parse().unwrap();
+    let backend: SocketAddr = "127.0.0.1:4052".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        backend,
+    )?;
+    flaky_proxy(addr, backend, 1)?;
+
+    let mut client = KvClient::new(addr)?;
+    client.set_retries(3);
+    let err = client.increment("counter".to_owned(), 1).unwrap_err();
+    assert!(matches!(*err, ErrorCode::ConnectionClosed));
+    assert_eq!(client.get("counter".to_owned())?, None);
+    assert_eq!(client.increment("counter".to_owned(), 1)?, 1);
+    client.shutdown()?;
+
+    let addr: SocketAddr = "127.0.0.1:4053".parse().unwrap();
+    flaky_proxy(addr, backend, 1)?;
+    let mut client = KvClient::new(addr)?;
+    client.set_retries(3);
+    let err = client.get_delete("counter".to_owned()).unwrap_err();
+    assert!(matches!(*err, ErrorCode::ConnectionClosed));
+    assert_eq!(client.get("counter".to_owned())?, Some("1".to_owned()));
+
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// A server going down between two requests should be waited for with backoff, and the second
+// request sent again once it is back.
+#[test]
+fn retry_restarted_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4038".parse().unwrap();
+    let backend: SocketAddr = "127.0.0.1:4039".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        backend,
+    )?;
+
+    // the proxy stands for the server: it serves one request, goes down, and comes back
+    let listener = TcpListener::bind(addr)?;
+    let (restarted_tx, restarted_rx) = crossbeam_channel::bounded(1);
+    thread::spawn(move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut backend_conn = TcpStream::connect(backend).unwrap();
+        let req = handle_receive::<KvsRequest>(&mut conn).unwrap().unwrap();
+        handle_send(&mut backend_conn, &req).unwrap();
+        let res = handle_receive::<KvsResponse>(&mut backend_conn).unwrap();
+        handle_send(&mut conn, &res.unwrap()).unwrap();
+        drop(listener);
+        conn.shutdown(Shutdown::Both).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        flaky_proxy(addr, backend, 0).unwrap();
+        restarted_tx.send(()).unwrap();
+    });
+
+    let mut client = KvClient::new(addr)?;
+    client.set_retries(6);
+    client.set_backoff(Duration::from_millis(20));
+    client.set("key".to_owned(), "value".to_owned())?;
+    // the request is sent while the server is down
+    assert!(restarted_rx.try_recv().is_err());
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// Workers popping the same keys concurrently should each get a value only once.
+#[test]
+fn get_delete_races() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4023".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(8)?,
+        addr,
+    )?;
+
+    let pairs: Vec<(String, String)> = (0..200)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    KvClient::new(addr)?.set_many(pairs.clone())?;
+
+    let workers: Vec<_> = (0..8)
+        .map(|_| {
+            let keys: Vec<String> = pairs.iter().map(|(key, _)| key.clone()).collect();
+            thread::spawn(move || -> Result<Vec<String>> {
+                let mut client = KvClient::new(addr)?;
+                let mut popped = Vec::new();
+                for key in keys {
+                    popped.extend(client.get_delete(key)?);
+                }
+                client.shutdown()?;
+                Ok(popped)
+            })
+        })
+        .collect();
+    let mut popped = Vec::new();
+    for worker in workers {
+        popped.extend(worker.join().unwrap()?);
+    }
+    popped.sort();
+    let mut values: Vec<String> = pairs.into_iter().map(|(_, value)| value).collect();
+    values.sort();
+    assert_eq!(popped, values);
+
+    let mut client = KvClient::new(addr)?;
+    assert_eq!(client.get_delete("key0".to_owned())?, None);
+    assert_eq!(client.get("key0".to_owned())?, None);
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+/// Records when each request enters and leaves it
+struct Recorder {
+    name: &'static str,
+    events: Arc<Mutex<Vec<String>>>,
+}
+
+impl Middleware for Recorder {
+    fn handle(&self, req: KvsRequest, next: Next<'_>) -> KvsResponse {
+        let op = req.op();
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("{} before {}", self.name, op));
+        let res = next.run(req);
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("{} after {}", self.name, op));
+        res
+    }
+}
+
+// Middlewares should run in order around each request.
+#[test]
+fn middleware_chain_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4024".parse().unwrap();
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let opts = ServerOpts {
+        middlewares: vec![
+            Arc::new(Recorder {
+                name: "log",
+                events: events.clone(),
+            }),
+            Arc::new(Recorder {
+                name: "count",
+                events: events.clone(),
+            }),
+        ],
+        ..Default::default()
+    };
+    let handle = KvServer::serve_with_opts(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+        opts,
+    )?;
+
+    let mut client = KvClient::new(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    client.shutdown()?;
+
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            "log before set",
+            "count before set",
+            "count after set",
+            "log after set",
+            "log before get",
+            "count before get",
+            "count after get",
+            "log after get",
+        ]
+    );
+    handle.shutdown()
+}
+
+// Requests over the rate limit should be rejected without reaching the engine.
+#[test]
+fn rate_limit_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4025".parse().unwrap();
+    let opts = ServerOpts {
+        middlewares: vec![Arc::new(RateLimit::new(3))],
+        ..Default::default()
+    };
+    let handle = KvServer::serve_with_opts(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+        opts,
+    )?;
+
+    let mut client = KvClient::new(addr)?;
+    for i in 0..3 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+    }
+    let err = client
+        .set("key3".to_owned(), "value".to_owned())
+        .unwrap_err();
+    assert!(err.to_string().contains("Rate limit exceeded"));
+
+    // a token is back after a third of a second
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(client.get("key3".to_owned())?, None);
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// The metrics endpoint should serve the counters of a workload in the Prometheus text format.
+#[cfg(feature = "metrics-http")]
+#[test]
+fn scrape_metrics() -> Result<()> {
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4014".parse().unwrap();
+    let metrics_addr: SocketAddr = "127.0.0.1:4015".parse().unwrap();
+    let opts = ServerOpts {
+        metrics_addr: Some(metrics_addr),
+        ..Default::default()
+    };
+    let handle = KvServer::serve_with_opts(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+        opts,
+    )?;
+
+    let mut client = KvClient::new(addr)?;
+    for i in 0..3 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+    }
+    client.set("key0".to_owned(), "value".to_owned())?;
+    client.get("key1".to_owned())?;
+    assert!(client.rm("missing".to_owned()).is_err());
+    client.shutdown()?;
+
+    let mut stream = TcpStream::connect(metrics_addr)?;
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+
+    let samples: HashMap<&str, f64> = body
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(|line| {
+            let (name, value) = line.rsplit_once(' ').unwrap();
+            (name, value.parse().unwrap())
+        })
+        .collect();
+    assert_eq!(samples[r#"kvs_requests_total{op="set"}"#], 4.0);
+    assert_eq!(samples[r#"kvs_requests_total{op="get"}"#], 1.0);
+    assert_eq!(samples[r#"kvs_request_errors_total{op="rm"}"#], 1.0);
+    assert_eq!(samples[r#"kvs_request_errors_total{op="set"}"#], 0.0);
+    assert_eq!(
+        samples[r#"kvs_request_duration_seconds_count{op="set"}"#],
+        4.0
+    );
+    assert!(samples[r#"kvs_request_duration_seconds_sum{op="set"}"#] > 0.0);
+    assert!(samples["kvs_uncompacted_bytes"] > 0.0);
+    assert_eq!(samples["kvs_keys"], 3.0);
+    assert_eq!(samples["kvs_get_hits_total"], 1.0);
+    assert_eq!(samples["kvs_get_misses_total"], 0.0);
+    assert!(samples["kvs_written_bytes_total"] > 0.0);
+
+    handle.shutdown()
+}
+
+/// Hold every request for a while before serving it
+struct Slow;
+
+impl Middleware for Slow {
+    fn handle(&self, req: KvsRequest, next: Next<'_>) -> KvsResponse {
+        thread::sleep(Duration::from_millis(300));
+        next.run(req)
+    }
+}
+
+// A graceful shutdown should wait for the request being served to complete.
+#[test]
+fn graceful_shutdown_drains_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4026".parse().unwrap();
+    let opts = ServerOpts {
+        middlewares: vec![Arc::new(Slow)],
+        ..Default::default()
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    let handle =
+        KvServer::serve_with_opts(store.clone(), SharedQueueThreadPool::new(2)?, addr, opts)?;
+
+    let mut client = KvClient::new(addr)?;
+    let slow = thread::spawn(move || client.set("key1".to_owned(), "value1".to_owned()));
+    thread::sleep(Duration::from_millis(100));
+    handle.shutdown_graceful(Duration::from_secs(5))?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    slow.join().unwrap()?;
+    assert!(TcpStream::connect(addr).is_err());
+    Ok(())
+}
+
+// A shutdown should close the connections left open and release the store, which can then be
+// opened again while the clients are still around.
+#[test]
+fn shutdown_releases_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4056".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+        addr,
+    )?;
+
+    let mut clients = (0..3)
+        .map(|_| KvClient::new(addr))
+        .collect::<Result<Vec<_>>>()?;
+    clients[0].set("key1".to_owned(), "value1".to_owned())?;
+    handle.shutdown()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let err = clients[0].get("key1".to_owned()).unwrap_err();
+    assert!(matches!(*err, ErrorCode::ConnectionClosed));
+    Ok(())
+}
+
+// Operations from many threads should share the connections of a client pool.
+#[test]
+fn client_pool_ops() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4027".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+        addr,
+    )?;
+
+    let clients = KvClientPool::new(addr, 4)?;
+    let workers: Vec<_> = (0..8)
+        .map(|t| {
+            let clients = clients.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in (t..200).step_by(8) {
+                    clients
+                        .get()
+                        .set(format!("key{}", i), format!("value{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap()?;
+    }
+
+    let mut client = clients.get();
+    for i in 0..200 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    drop(client);
+    drop(clients);
+    handle.shutdown()
+}
+
+// `contains` over the wire should agree with `get` on present and removed keys.
+#[test]
+fn client_contains() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4028".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?;
+
+    let mut client = KvClient::new(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    client.rm("key2".to_owned())?;
+    for key in ["key1", "key2", "key3"] {
+        assert_eq!(
+            client.contains(key.to_owned())?,
+            client.get(key.to_owned())?.is_some()
+        );
+    }
+    assert!(client.contains("key1".to_owned())?);
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// Removing a missing key should fail with `KeyNotFound` rather than an internal error.
+#[test]
+fn rm_missing_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4029".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?;
+
+    // the error is typed on the wire as well
+    let mut stream = TcpStream::connect(addr)?;
+    let req = KvsRequest::Rm {
+        key: "key1".to_owned(),
+    };
+    handle_send(&mut stream, &req)?;
+    let res = handle_receive::<KvsResponse>(&mut stream)?;
+    assert!(matches!(
+        res,
+        Some(KvsResponse::Rm(Err(RemoteError::KeyNotFound)))
+    ));
+
+    let mut client = KvClient::new(addr)?;
+    let err = client.rm("key1".to_owned()).unwrap_err();
+    assert!(matches!(*err, ErrorCode::KeyNotFound));
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.rm("key1".to_owned())?;
+    let err = client.rm("key1".to_owned()).unwrap_err();
+    assert!(matches!(*err, ErrorCode::KeyNotFound));
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// Clients racing to swap a counter should only succeed from the value they read, so that
+// the swaps which succeed add up to the counter.
+#[test]
+fn cas_races() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4030".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(8)?,
+        addr,
+    )?;
+    KvClient::new(addr)?.set("counter".to_owned(), "0".to_owned())?;
+
+    let barrier = Arc::new(Barrier::new(8));
+    let workers: Vec<_> = (0..8)
+        .map(|_| {
+            let barrier = barrier.clone();
+            thread::spawn(move || -> Result<(bool, u32)> {
+                let mut client = KvClient::new(addr)?;
+                let counter = || "counter".to_owned();
+                // all of them swap from the same value, only one can win
+                barrier.wait();
+                let first = client.compare_and_swap(
+                    counter(),
+                    Some("0".to_owned()),
+                    Some("1".to_owned()),
+                )?;
+
+                let mut wins = 0;
+                for _ in 0..50 {
+                    let current = client.get(counter())?.unwrap();
+                    let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                    if client.compare_and_swap(counter(), Some(current), Some(next))? {
+                        wins += 1;
+                    }
+                }
+                client.shutdown()?;
+                Ok((first, wins))
+            })
+        })
+        .collect();
+    let mut first_winners = 0;
+    let mut wins = 0;
+    for worker in workers {
+        let (first, worker_wins) = worker.join().unwrap()?;
+        first_winners += first as u32;
+        wins += worker_wins;
+    }
+    assert_eq!(first_winners, 1);
+    let counter = KvClient::new(addr)?.get("counter".to_owned())?;
+    assert_eq!(counter, Some((1 + wins).to_string()));
+    handle.shutdown()
+}
+
+// Increments from many clients should all be counted, and a value which is not an integer
+// should fail with a typed error.
+#[test]
+fn client_increment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4031".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(10)?,
+        addr,
+    )?;
+
+    let workers: Vec<_> = (0..10)
+        .map(|_| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvClient::new(addr)?;
+                for _ in 0..1000 {
+                    client.increment("counter".to_owned(), 1)?;
+                }
+                client.shutdown()
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap()?;
+    }
+
+    let mut client = KvClient::new(addr)?;
+    assert_eq!(client.get("counter".to_owned())?, Some("10000".to_owned()));
+    assert_eq!(client.increment("counter".to_owned(), -10)?, 9990);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let err = client.increment("key1".to_owned(), 1).unwrap_err();
+    assert!(matches!(&*err, ErrorCode::NotAnInteger { key } if key == "key1"));
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// A client should read the stats of the engine behind the server.
+#[test]
+fn client_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4032".parse().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvServer::serve(store.clone(), SharedQueueThreadPool::new(2)?, addr)?;
+
+    let mut client = KvClient::new(addr)?;
+    for i in 0..10 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    client.set("key0".to_owned(), "overwritten".to_owned())?;
+    let stats = client.stats()?;
+    assert_eq!(stats.num_keys, 10);
+    assert!(stats.uncompacted_bytes > 0);
+    assert!(stats.memory.index_bytes > 0);
+    assert_eq!(stats.memory, store.memory_usage());
+    assert_eq!(stats, store.stats()?);
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// The frame functions alone should be enough to speak the protocol over any transport.
+#[test]
+fn raw_frames() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4033".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut call = |req: &KvsRequest| -> Result<KvsResponse> {
+        stream.write_all(&encode_frame(req, None)?)?;
+        let mut prefix = [0_u8; LEN_PREFIX];
+        stream.read_exact(&mut prefix)?;
+        let mut payload = vec![0_u8; frame_len(prefix)?];
+        stream.read_exact(&mut payload)?;
+        decode_frame(&payload)
+    };
+    let res = call(&KvsRequest::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+    })?;
+    assert!(matches!(res, KvsResponse::Set(Ok(()))));
+    let res = call(&KvsRequest::Get {
+        key: "key1".to_owned(),
+    })?;
+    assert!(matches!(res, KvsResponse::Get(Ok(Some(value))) if value == "value1"));
+
+    assert!(frame_len((MAX_FRAME_LEN as u32 + 1).to_be_bytes()).is_err());
+    handle.shutdown()
+}
+
+// Connections over the limit should be answered as busy and closed, and served again once
+// a connection is closed.
+#[test]
+fn reject_over_max_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4034".parse().unwrap();
+    let max_connections = 3;
+    let opts = ServerOpts {
+        max_connections: Some(max_connections),
+        ..Default::default()
+    };
+    let handle = KvServer::serve_with_opts(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(8)?,
+        addr,
+        opts,
+    )?;
+
+    // a served request makes sure the connection is counted
+    let mut clients = Vec::new();
+    for i in 0..max_connections {
+        let mut client = KvClient::new(addr)?;
+        client.set(format!("key{}", i), "value".to_owned())?;
+        clients.push(client);
+    }
+    for _ in 0..5 {
+        let mut stream = TcpStream::connect(addr)?;
+        let res = handle_receive::<KvsResponse>(&mut stream)?;
+        assert!(matches!(
+            res,
+            Some(KvsResponse::Error(RemoteError::ServerBusy))
+        ));
+        assert!(handle_receive::<KvsResponse>(&mut stream)?.is_none());
+    }
+
+    clients.pop().unwrap().shutdown()?;
+    let mut client = loop {
+        let mut client = KvClient::new(addr)?;
+        match client.get("key0".to_owned()) {
+            Err(e) if matches!(*e, ErrorCode::ServerBusy | ErrorCode::ConnectionClosed) => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            res => {
+                assert_eq!(res?, Some("value".to_owned()));
+                break client;
+            }
+        }
+    };
+    client.shutdown()?;
+    for mut client in clients {
+        client.shutdown()?;
+    }
+    handle.shutdown()
+}
+
+// Same-host clients can skip TCP and talk to the server over a Unix socket.
+#[cfg(unix)]
+#[test]
+fn unix_socket_round_trip() -> Result<()> {
+    use kvs::Endpoint;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let socket_dir = TempDir::new().expect("unable to create temporary socket directory");
+    let path = socket_dir.path().join("kvs.sock");
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        Endpoint::Unix(path.clone()),
+    )?;
+
+    let mut client = KvClient::connect(Endpoint::Unix(path.clone()))?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.rm("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.shutdown()?;
+
+    // the socket file goes with the server, so the path can be served again
+    handle.shutdown_graceful(Duration::from_secs(1))?;
+    assert!(!path.exists());
+    Ok(())
+}
+
+// A client asking for MessagePack should be served with it, with smaller frames than JSON.
+#[test]
+fn msgpack_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4035".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?;
+
+    let mut client = KvClient::with_codec(addr, Codec::MsgPack)?;
+    assert_eq!(client.codec(), Codec::MsgPack);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // a JSON client still works next to it
+    let mut json_client = KvClient::new(addr)?;
+    json_client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        json_client.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert!(client.io_stats().bytes_sent < json_client.io_stats().bytes_sent);
+
+    assert_eq!(client.get("key2".to_owned())?, None);
+    let err = client.rm("key2".to_owned()).unwrap_err();
+    assert!(matches!(*err, ErrorCode::KeyNotFound));
+    assert_eq!(
+        client.get_multi(vec!["key1".to_owned(), "key2".to_owned()])?,
+        vec![Some("value1".to_owned()), None]
+    );
+    client.shutdown()?;
+    json_client.shutdown()?;
+    handle.shutdown()
+}
+
+// A handshake asking for an unknown codec should be answered with JSON, which the connection
+// then speaks.
+#[test]
+fn unknown_codec_falls_back_to_json() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4036".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&[HANDSHAKE, 0x7F])?;
+    let mut answer = [0_u8; 2];
+    stream.read_exact(&mut answer)?;
+    assert_eq!(answer, [HANDSHAKE, Codec::Json as u8]);
+
+    handle_send(
+        &mut stream,
+        &KvsRequest::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        },
+    )?;
+    let res = handle_receive::<KvsResponse>(&mut stream)?;
+    assert!(matches!(res, Some(KvsResponse::Set(Ok(())))));
+    handle.shutdown()
+}
+
+// A server answering the handshake with a response of another type should fail the connection
+// with a typed error.
+#[test]
+fn unexpected_handshake_answer() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4055".parse().unwrap();
+    let listener = TcpListener::bind(addr)?;
+    thread::spawn(move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut handshake = [0_u8; 2];
+        conn.read_exact(&mut handshake).unwrap();
+        handle_send(&mut conn, &KvsResponse::Set(Ok(()))).unwrap();
+    });
+
+    let err = KvClient::with_codec(addr, Codec::MsgPack).err().unwrap();
+    assert!(matches!(*err, ErrorCode::UnexpectedResponse(_)), "{}", err);
+    Ok(())
+}
+
+// A request to a server which accepts the connection but never answers should fail with a
+// timeout instead of blocking forever.
+#[test]
+fn client_timeout() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4037".parse().unwrap();
+    let listener = TcpListener::bind(addr)?;
+    thread::spawn(move || {
+        // keep the connections open without reading from them
+        let conns: Vec<TcpStream> = listener.incoming().map(|conn| conn.unwrap()).collect();
+        drop(conns);
+    });
+
+    let timeout = Duration::from_millis(200);
+    let mut client = KvClient::with_timeout(addr, timeout)?;
+    for _ in 0..2 {
+        let start = Instant::now();
+        let err = client.get("key1".to_owned()).unwrap_err();
+        assert!(matches!(*err, ErrorCode::Timeout), "{}", err);
+        assert!(start.elapsed() >= timeout);
+        assert!(start.elapsed() < timeout * 5);
+    }
+    let err = client.get_multi(vec!["key1".to_owned()]).unwrap_err();
+    assert!(matches!(*err, ErrorCode::Timeout), "{}", err);
+    Ok(())
+}
+
+// The metrics snapshot should count the requests of a known workload, and the engine's writes
+// and compactions.
+#[test]
+fn metrics_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4040".parse().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    let handle = KvServer::serve(store.clone(), SharedQueueThreadPool::new(2)?, addr)?;
+
+    let mut client = KvClient::new(addr)?;
+    for i in 0..3 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+    }
+    client.set("key0".to_owned(), "value".to_owned())?;
+    client.get("key1".to_owned())?;
+    client.get("missing".to_owned())?;
+    client.get_multi(vec![
+        "key0".to_owned(),
+        "key2".to_owned(),
+        "missing".to_owned(),
+    ])?;
+    client.rm("key2".to_owned())?;
+    assert!(client.rm("missing".to_owned()).is_err());
+    store.compact()?;
+
+    let snapshot = handle.metrics_snapshot()?;
+    assert_eq!(snapshot.requests["set"], 4);
+    assert_eq!(snapshot.requests["get"], 2);
+    assert_eq!(snapshot.requests["rm"], 2);
+    assert_eq!(snapshot.errors["rm"], 1);
+    assert_eq!(snapshot.errors["set"], 0);
+    assert_eq!((snapshot.hits, snapshot.misses), (3, 2));
+    assert_eq!(snapshot.hit_ratio(), Some(0.6));
+    assert_eq!(snapshot.connections, 1);
+    assert_eq!(snapshot.store.num_keys, 2);
+    assert_eq!(snapshot.store.compactions, 1);
+    assert!(snapshot.store.bytes_written > 0);
+
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// Writes over the size limits of the server should be rejected with typed errors, whatever the
+// limits of the engine.
+#[test]
+fn server_size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4045".parse().unwrap();
+    let opts = ServerOpts {
+        max_key_size: Some(4),
+        max_value_size: Some(8),
+        ..Default::default()
+    };
+    let handle = KvServer::serve_with_opts(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+        opts,
+    )?;
+
+    let mut client = KvClient::new(addr)?;
+    client.set("key1".to_owned(), "v".repeat(8))?;
+    let err = client.set("key12".to_owned(), "v".to_owned()).unwrap_err();
+    assert!(matches!(*err, ErrorCode::KeyTooLarge { size: 5, limit: 4 }));
+    let err = client.set("key2".to_owned(), "v".repeat(9)).unwrap_err();
+    assert!(matches!(
+        *err,
+        ErrorCode::ValueTooLarge { size: 9, limit: 8 }
+    ));
+    let err = client
+        .compare_and_swap("key1".to_owned(), Some("v".repeat(8)), Some("v".repeat(9)))
+        .unwrap_err();
+    assert!(matches!(*err, ErrorCode::ValueTooLarge { .. }));
+    assert_eq!(client.get("key1".to_owned())?, Some("v".repeat(8)));
+    assert_eq!(client.get("key2".to_owned())?, None);
+
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// Listing keys should only return those of the prefix, across as many pages as they take.
+#[test]
+fn list_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..MAX_KEYS_PER_RESPONSE + 200 {
+        store.set(format!("user:{:04}", i), "value".to_owned())?;
+    }
+    for i in 0..3 {
+        store.set(format!("order:{}", i), "value".to_owned())?;
+    }
+    store.set("user".to_owned(), "value".to_owned())?;
+    store.remove("order:1".to_owned())?;
+
+    let addr: SocketAddr = "127.0.0.1:4046".parse().unwrap();
+    let handle = KvServer::serve(store, SharedQueueThreadPool::new(2)?, addr)?;
+    let mut client = KvClient::new(addr)?;
+    assert_eq!(
+        client.keys(Some("order:".to_owned()))?,
+        vec!["order:0", "order:2"]
+    );
+    let users = client.keys(Some("user:".to_owned()))?;
+    assert_eq!(users.len(), MAX_KEYS_PER_RESPONSE + 200);
+    assert!(users.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(
+        client.keys(None)?.len(),
+        MAX_KEYS_PER_RESPONSE + 200 + 2 + 1
+    );
+
+    let page = client.keys_page(Some("user:".to_owned()), None)?;
+    assert_eq!(page.keys.len(), MAX_KEYS_PER_RESPONSE);
+    assert_eq!(page.next.as_deref(), page.keys.last().map(String::as_str));
+    let page = client.keys_page(Some("user:".to_owned()), page.next)?;
+    assert_eq!(page.keys.len(), 200);
+    assert_eq!(page.next, None);
+    assert_eq!(client.keys(Some("none:".to_owned()))?, Vec::<String>::new());
+
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// Pipelined requests should be applied and answered in the order they were pushed.
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4048".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?;
+    let mut client = KvClient::new(addr)?;
+
+    let mut pipeline = client.pipeline();
+    for i in 0..50 {
+        pipeline.push(KvsRequest::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+        });
+    }
+    pipeline.push(KvsRequest::Rm {
+        key: "missing".to_owned(),
+    });
+    let responses = pipeline.execute()?;
+    assert_eq!(responses.len(), 51);
+    assert!(responses[..50]
+        .iter()
+        .all(|res| matches!(res, KvsResponse::Set(Ok(())))));
+    assert!(matches!(
+        responses[50],
+        KvsResponse::Rm(Err(RemoteError::KeyNotFound))
+    ));
+
+    let mut pipeline = client.pipeline();
+    for i in 0..50 {
+        pipeline.push(KvsRequest::Get {
+            key: format!("key{}", i),
+        });
+    }
+    for (i, res) in pipeline.execute()?.into_iter().enumerate() {
+        match res {
+            KvsResponse::Get(Ok(value)) => assert_eq!(value, Some(format!("value{}", i))),
+            res => panic!("unexpected response {:?}", res),
+        }
+    }
+    assert_eq!(client.get("key49".to_owned())?, Some("value49".to_owned()));
+
+    client.shutdown()?;
+    handle.shutdown()
+}
+
+// A server should answer a ping as soon as `serve` returns, and a stopped one shouldn't.
+#[test]
+fn ping_server() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4049".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?;
+
+    let mut client = KvClient::new(addr)?;
+    client.ping()?;
+    client.ping()?;
+    client.shutdown()?;
+    handle.shutdown_graceful(Duration::from_secs(1))?;
+
+    assert!(KvClient::new(addr)
+        .and_then(|mut client| client.ping())
+        .is_err());
+    Ok(())
+}
+
+// Clients should connect right after `serve` returns without waiting, and serving on an
+// address in use should fail at once.
+#[test]
+fn connect_right_after_serve() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4050".parse().unwrap();
+    for round in 0..20 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let handle = KvServer::serve(
+            KvStore::open(temp_dir.path())?,
+            SharedQueueThreadPool::new(2)?,
+            addr,
+        )?;
+        let mut client = KvClient::new(addr)?;
+        client.set("key".to_owned(), format!("value{}", round))?;
+
+        let other_dir = TempDir::new().expect("unable to create temporary working directory");
+        let taken = KvServer::serve(
+            KvStore::open(other_dir.path())?,
+            SharedQueueThreadPool::new(2)?,
+            addr,
+        );
+        assert!(matches!(*taken.err().unwrap(), ErrorCode::NetworkError(_)));
+
+        client.shutdown()?;
+        // the next server binds the address once this one has returned
+        handle.shutdown_graceful(Duration::from_secs(1))?;
+    }
+    Ok(())
+}
+
+// A server asked for port 0 should tell the port the OS picked, which clients connect to and
+// the shutdown stops.
+#[test]
+fn serve_on_ephemeral_port() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        "127.0.0.1:0".parse::<SocketAddr>().unwrap(),
+    )?;
+    let addr = handle.local_addr().unwrap();
+    assert_ne!(addr.port(), 0);
+
+    let mut client = KvClient::new(addr)?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    client.shutdown()?;
+    handle.shutdown_graceful(Duration::from_secs(1))?;
+
+    assert!(KvClient::new(addr).is_err());
+    Ok(())
+}
+
+// Connections of clients should have `TCP_NODELAY` set unless it's turned off, whichever
+// way they connect.
+#[test]
+fn client_nodelay() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        "127.0.0.1:0".parse::<SocketAddr>().unwrap(),
+    )?;
+    let addr = handle.local_addr().unwrap();
+    let nodelay = |client: &KvClient| match &client.stream {
+        Stream::Tcp(stream) => stream.nodelay().unwrap(),
+        _ => panic!("not a TCP connection"),
+    };
+
+    let mut client = KvClient::new(addr)?;
+    assert!(nodelay(&client));
+    client.set("key".to_owned(), "value".to_owned())?;
+    client.set_nodelay(false)?;
+    assert!(!nodelay(&client));
+    assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+    client.shutdown()?;
+
+    let mut client = KvClient::connect(addr)?;
+    assert!(nodelay(&client));
+    client.shutdown()?;
+    handle.shutdown()
+}
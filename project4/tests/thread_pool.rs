@@ -1,5 +1,8 @@
+use std::collections::HashSet;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use kvs::thread_pool::*;
 use kvs::Result;
@@ -52,6 +55,35 @@ fn naive_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+#[test]
+fn naive_thread_pool_thread_per_job() -> Result<()> {
+    const TASK_NUM: usize = 50;
+
+    assert!(NaiveThreadPool::new(0).is_err());
+    let pool = NaiveThreadPool::new(4)?;
+    assert_eq!(pool.threads(), 4);
+
+    let wg = WaitGroup::new();
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..TASK_NUM {
+        let (counter, tx, wg) = (counter.clone(), tx.clone(), wg.clone());
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            tx.send(thread::current().id()).unwrap();
+            drop(wg);
+        })
+    }
+    drop(tx);
+
+    wg.wait();
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    // thread ids are never reused, so every job ran on a thread of its own
+    let ids: HashSet<_> = rx.iter().collect();
+    assert_eq!(ids.len(), TASK_NUM);
+    Ok(())
+}
+
 #[test]
 fn shared_queue_thread_pool_spawn_counter() -> Result<()> {
     let pool = SharedQueueThreadPool::new(4)?;
@@ -64,7 +96,171 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+#[test]
+fn dynamic_thread_pool_spawn_counter() -> Result<()> {
+    let pool = DynamicThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+/// Time `DynamicThreadPool` takes to run 16 jobs sleeping for 20ms each
+fn run_sleeping_jobs(pool: &DynamicThreadPool) -> Duration {
+    let start = Instant::now();
+    let wg = WaitGroup::new();
+    for _ in 0..16 {
+        let wg = wg.clone();
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(wg);
+        });
+    }
+    wg.wait();
+    start.elapsed()
+}
+
+#[test]
+fn dynamic_thread_pool_resize() -> Result<()> {
+    let pool = DynamicThreadPool::new(2)?;
+    assert_eq!(pool.threads(), 2);
+    let slow = run_sleeping_jobs(&pool);
+
+    // jobs still queued are run by the new workers too
+    let wg = WaitGroup::new();
+    for _ in 0..8 {
+        let wg = wg.clone();
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            drop(wg);
+        });
+    }
+    pool.resize(8)?;
+    assert_eq!(pool.threads(), 8);
+    wg.wait();
+    let fast = run_sleeping_jobs(&pool);
+    assert!(
+        fast * 2 < slow,
+        "{:?} with 8 threads, {:?} with 2",
+        fast,
+        slow
+    );
+
+    pool.resize(3)?;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while pool.threads() > 3 {
+        assert!(Instant::now() < deadline, "{} threads left", pool.threads());
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(pool.threads(), 3);
+    spawn_counter(pool)
+}
+
+#[test]
+fn shared_queue_thread_pool_start_latency() -> Result<()> {
+    const TASK_NUM: usize = 20;
+
+    let pool = SharedQueueThreadPool::new(4)?;
+    let mut latencies = Vec::with_capacity(TASK_NUM);
+    for _ in 0..TASK_NUM {
+        // every job starts on an idle worker
+        thread::sleep(Duration::from_millis(5));
+        let (tx, rx) = mpsc::channel();
+        let spawned = Instant::now();
+        pool.spawn(move || tx.send(spawned.elapsed()).unwrap());
+        latencies.push(rx.recv().unwrap());
+    }
+
+    latencies.sort();
+    let median = latencies[TASK_NUM / 2];
+    assert!(
+        median < Duration::from_millis(10),
+        "median latency {:?}",
+        median
+    );
+    Ok(())
+}
+
+/// A panic payload which panics again when the worker drops it, outside of the job
+struct PanicOnDrop;
+
+impl Drop for PanicOnDrop {
+    fn drop(&mut self) {
+        panic!("drop a panic payload");
+    }
+}
+
+#[test]
+fn shared_queue_thread_pool_respawn_worker() -> Result<()> {
+    const THREADS: usize = 2;
+
+    let pool = SharedQueueThreadPool::new(THREADS as u32)?;
+    for _ in 0..THREADS {
+        pool.spawn(|| {
+            panic_control::disable_hook_in_current_thread();
+            std::panic::panic_any(PanicOnDrop);
+        });
+    }
+
+    // the jobs only finish once all of them run at the same time, which needs all threads back
+    for _ in 0..10 {
+        let wg = WaitGroup::new();
+        let barrier = Arc::new(Barrier::new(THREADS));
+        for _ in 0..THREADS {
+            let (barrier, wg) = (barrier.clone(), wg.clone());
+            pool.spawn(move || {
+                barrier.wait();
+                drop(wg);
+            });
+        }
+        wg.wait();
+    }
+    Ok(())
+}
+
+#[test]
+fn shared_queue_thread_pool_drop_joins_workers() -> Result<()> {
+    const TASK_NUM: usize = 8;
+
+    let pool = SharedQueueThreadPool::new(4)?;
+    // a worker replaced after a panic is joined as well
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+        std::panic::panic_any(PanicOnDrop);
+    });
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..TASK_NUM {
+        let counter = counter.clone();
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    drop(pool);
+    assert_eq!(counter.load(Ordering::SeqCst), TASK_NUM);
+    Ok(())
+}
+
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn rayon_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<RayonThreadPool>()
+}
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn dynamic_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<DynamicThreadPool>()
+}
@@ -0,0 +1,90 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvClient, KvServer, KvStore, KvsEngine, Result};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tempfile::TempDir;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::reload;
+
+/// Collects the lines written by a subscriber
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Captured {
+    /// Take the lines written so far
+    fn take(&self) -> Vec<String> {
+        let bytes = std::mem::take(&mut *self.0.lock().unwrap());
+        String::from_utf8(bytes)
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Every request should be served in a span of its operation ending with its latency, and its
+// key should only be recorded at the TRACE level.
+#[test]
+fn request_spans() -> Result<()> {
+    let captured = Captured::default();
+    let (filter, level) = reload::Layer::new(LevelFilter::INFO);
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::registry().with(filter).with(
+        tracing_subscriber::fmt::layer()
+            .with_ansi(false)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(move || writer.clone()),
+    );
+    tracing::subscriber::set_global_default(subscriber).unwrap();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4041".parse().unwrap();
+    let handle = KvServer::serve(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        addr,
+    )?;
+    let mut client = KvClient::new(addr)?;
+    client.set("secret-key".to_owned(), "value".to_owned())?;
+    client.get("secret-key".to_owned())?;
+
+    let lines = captured.take();
+    let served: Vec<&String> = lines
+        .iter()
+        .filter(|line| line.contains("served"))
+        .collect();
+    assert_eq!(served.len(), 2, "{:#?}", lines);
+    assert!(served[0].contains(r#"request{op="set"}"#));
+    assert!(served[1].contains(r#"request{op="get"}"#));
+    assert!(served.iter().all(|line| line.contains("latency_us=")));
+    let closed = lines.iter().filter(|line| line.contains("close")).count();
+    assert_eq!(closed, 2, "{:#?}", lines);
+    assert!(lines.iter().all(|line| !line.contains("secret-key")));
+
+    level.reload(LevelFilter::TRACE).unwrap();
+    client.get("secret-key".to_owned())?;
+    let lines = captured.take();
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.contains(r#"request{op="get" keys=["secret-key"]}"#)),
+        "{:#?}",
+        lines
+    );
+
+    client.shutdown()?;
+    handle.shutdown()
+}